@@ -0,0 +1,106 @@
+//! Intersection of a rare term with a common one, the case skip data and galloping are for.
+//!
+//! Compares a linear merge with galloping on decoded lists, and decoding the whole common list
+//! from a segment with decoding only the blocks `Segment::postings_in` can't skip.
+//!
+//! ```text
+//! cargo bench --bench intersection
+//! ```
+
+use criterion::{BenchmarkId, Criterion, black_box, criterion_group, criterion_main};
+use mongodb::bson::oid::ObjectId;
+use std::collections::HashMap;
+
+use harvest::data_models::DocId;
+use harvest::query_engine::intersect_two_postings;
+use harvest::segment::{Segment, SegmentWriter};
+
+const NUM_DOCS: u32 = 1_000_000;
+/// Doc ids of the common term, every other document.
+const COMMON_STEP: usize = 2;
+/// Postings of the rare term in every run.
+const RARE_LENGTHS: [usize; 3] = [10, 1_000, 100_000];
+
+/// The intersection before skip data, advancing one posting at a time.
+fn linear_intersect(a: &[DocId], b: &[DocId], out: &mut Vec<DocId>) {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Equal => {
+                out.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+        }
+    }
+}
+
+/// `len` doc ids spread evenly over the collection.
+fn rare_postings(len: usize) -> Vec<DocId> {
+    let step = NUM_DOCS / len as u32;
+    (0..len as u32).map(|i| i * step + i % 2).collect()
+}
+
+fn write_segment(common: &[DocId]) -> Segment {
+    let path = std::env::temp_dir()
+        .join(format!("harvest_bench_{}", std::process::id()))
+        .join("intersection.seg");
+    let positions: HashMap<DocId, Vec<usize>> =
+        common.iter().map(|&doc_id| (doc_id, vec![1])).collect();
+    let mut writer = SegmentWriter::new(vec![ObjectId::new(); NUM_DOCS as usize]);
+    writer.add_term("common", common, &positions).unwrap();
+    writer.finish(&path).unwrap();
+    Segment::open(&path).unwrap()
+}
+
+fn bench_intersection(c: &mut Criterion) {
+    let common: Vec<DocId> = (0..NUM_DOCS).step_by(COMMON_STEP).collect();
+
+    let mut group = c.benchmark_group("intersect_decoded");
+    for len in RARE_LENGTHS {
+        let rare = rare_postings(len);
+        let mut out = Vec::with_capacity(len);
+        group.bench_with_input(BenchmarkId::new("linear", len), &rare, |b, rare| {
+            b.iter(|| {
+                out.clear();
+                linear_intersect(rare, &common, &mut out);
+                black_box(out.len())
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("galloping", len), &rare, |b, rare| {
+            b.iter(|| {
+                out.clear();
+                intersect_two_postings(rare, &common, &mut out);
+                black_box(out.len())
+            })
+        });
+    }
+    group.finish();
+
+    let segment = write_segment(&common);
+    let mut group = c.benchmark_group("intersect_segment");
+    for len in RARE_LENGTHS {
+        let rare = rare_postings(len);
+        let mut out = Vec::with_capacity(len);
+        group.bench_with_input(BenchmarkId::new("full_decode", len), &rare, |b, rare| {
+            b.iter(|| {
+                out.clear();
+                let postings = segment.postings("common").unwrap().unwrap();
+                intersect_two_postings(rare, &postings.doc_ids, &mut out);
+                black_box(out.len())
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("skip_blocks", len), &rare, |b, rare| {
+            b.iter(|| {
+                let postings = segment.postings_in("common", rare).unwrap().unwrap();
+                black_box(postings.doc_ids.len())
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_intersection);
+criterion_main!(benches);
@@ -0,0 +1,168 @@
+//! Size and decode speed of the posting codecs.
+//!
+//! Postings are exported from the segment in `$INDEX_DIR/index.seg` (`index` by default) when
+//! there is one, otherwise from a synthetic index with Zipf distributed term frequencies.
+//!
+//! ```text
+//! INDEX_DIR=index cargo bench --bench postings_codec
+//! ```
+
+use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use std::path::PathBuf;
+
+use harvest::postings::codec::Codec;
+use harvest::segment::{SEGMENT_FILE, Segment};
+
+/// Terms with the most postings taken from a real index, to keep a run short.
+const MAX_TERMS: usize = 2_000;
+
+/// Doc ids and positions of one term.
+struct TermPostings {
+    doc_ids: Vec<u32>,
+    positions: Vec<Vec<u32>>,
+}
+
+fn load_postings() -> (String, Vec<TermPostings>) {
+    let index_dir = std::env::var("INDEX_DIR").unwrap_or_else(|_| "index".to_string());
+    let path = PathBuf::from(index_dir).join(SEGMENT_FILE);
+    match Segment::open(&path) {
+        Ok(segment) => (path.display().to_string(), export_segment(&segment)),
+        Err(_) => ("synthetic index".to_string(), synthetic_postings()),
+    }
+}
+
+fn export_segment(segment: &Segment) -> Vec<TermPostings> {
+    let mut terms = segment.term_document_frequencies().unwrap();
+    terms.sort_by_key(|(_, document_frequency)| std::cmp::Reverse(*document_frequency));
+    terms
+        .into_iter()
+        .take(MAX_TERMS)
+        .map(|(term, _)| {
+            let postings = segment.postings(&term).unwrap().unwrap();
+            TermPostings {
+                doc_ids: postings.doc_ids,
+                positions: postings
+                    .positions
+                    .into_iter()
+                    .map(|p| p.into_iter().map(|p| p as u32).collect())
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+/// 100K docs and 1K terms, the term of rank r is in about 1/r of the docs.
+fn synthetic_postings() -> Vec<TermPostings> {
+    const NUM_DOCS: u32 = 100_000;
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+    (1..=1_000_u32)
+        .map(|rank| {
+            let mut doc_ids = Vec::new();
+            let mut positions = Vec::new();
+            for doc_id in 0..NUM_DOCS {
+                if next() % rank as u64 == 0 {
+                    doc_ids.push(doc_id);
+                    let mut position = 0;
+                    let doc_positions = (0..1 + next() % 4)
+                        .map(|_| {
+                            position += 1 + (next() % 200) as u32;
+                            position
+                        })
+                        .collect();
+                    positions.push(doc_positions);
+                }
+            }
+            TermPostings { doc_ids, positions }
+        })
+        .collect()
+}
+
+/// Encoded doc id lists and position lists of every term, back to back.
+fn encode(codec: Codec, terms: &[TermPostings]) -> (Vec<u8>, Vec<u8>) {
+    let (mut doc_ids, mut positions) = (Vec::new(), Vec::new());
+    for term in terms {
+        codec.encode_sorted(&term.doc_ids, &mut doc_ids);
+        for doc_positions in &term.positions {
+            codec.encode_sorted(doc_positions, &mut positions);
+        }
+    }
+    (doc_ids, positions)
+}
+
+fn bench_codecs(c: &mut Criterion) {
+    let (source, terms) = load_postings();
+    let num_postings: usize = terms.iter().map(|t| t.doc_ids.len()).sum();
+    let num_positions: usize = terms.iter().flat_map(|t| &t.positions).map(Vec::len).sum();
+    println!(
+        "{} terms, {} postings, {} positions from {}",
+        terms.len(),
+        num_postings,
+        num_positions,
+        source
+    );
+    println!(
+        "{:<12} {:>14} {:>10} {:>14} {:>10}",
+        "codec", "doc id bytes", "bits/doc", "position bytes", "bits/pos"
+    );
+    for codec in Codec::ALL {
+        let (doc_ids, positions) = encode(codec, &terms);
+        println!(
+            "{:<12} {:>14} {:>10.2} {:>14} {:>10.2}",
+            codec.to_string(),
+            doc_ids.len(),
+            doc_ids.len() as f64 * 8.0 / num_postings.max(1) as f64,
+            positions.len(),
+            positions.len() as f64 * 8.0 / num_positions.max(1) as f64
+        );
+    }
+
+    let mut group = c.benchmark_group("decode_doc_ids");
+    group.throughput(Throughput::Elements(num_postings as u64));
+    for codec in Codec::ALL {
+        let (doc_ids, _) = encode(codec, &terms);
+        group.bench_with_input(BenchmarkId::from_parameter(codec), &doc_ids, |b, bytes| {
+            let mut out = Vec::with_capacity(num_postings);
+            b.iter(|| {
+                out.clear();
+                let mut pos = 0;
+                for _ in &terms {
+                    codec.decode_sorted(bytes, &mut pos, &mut out).unwrap();
+                }
+                black_box(out.len())
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("decode_positions");
+    group.throughput(Throughput::Elements(num_positions as u64));
+    let num_lists: usize = terms.iter().map(|t| t.positions.len()).sum();
+    for codec in Codec::ALL {
+        let (_, positions) = encode(codec, &terms);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(codec),
+            &positions,
+            |b, bytes| {
+                let mut out = Vec::with_capacity(num_positions);
+                b.iter(|| {
+                    out.clear();
+                    let mut pos = 0;
+                    for _ in 0..num_lists {
+                        codec.decode_sorted(bytes, &mut pos, &mut out).unwrap();
+                    }
+                    black_box(out.len())
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_codecs);
+criterion_main!(benches);
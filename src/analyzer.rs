@@ -10,9 +10,18 @@ use std::sync::OnceLock;
 use tokio::sync::Mutex;
 use tokio::sync::Semaphore;
 use tokio::sync::mpsc;
+use unicode_segmentation::UnicodeSegmentation;
 
+use crate::analyzer_config::AnalyzerRegistry;
 use crate::data_models::Page;
-use crate::db::PageRepo;
+use crate::storage::PageStore;
+
+/// Analyzer of indexes built before the analyzer was recorded in the index manifest.
+pub const DEFAULT_ANALYZER: &str = "default";
+/// Built-in analyzers, `TextAnalyzer::named` knows them and config files can't redefine them.
+pub const ANALYZERS: &[&str] = &[DEFAULT_ANALYZER, "simple"];
+/// Name of analyzers built with `TextAnalyzer::new` and not named.
+const CUSTOM_ANALYZER: &str = "custom";
 
 static STOP_WORDS: OnceLock<HashSet<String>> = OnceLock::new();
 
@@ -296,6 +305,271 @@ impl Tokenizer for WhiteSpaceTokenizer {
     }
 }
 
+/// Splits text at Unicode word boundaries (UAX #29) and keeps the words, dropping whitespace and
+/// punctuation: "foo/bar" -> [foo, bar], "don't" stays one word and every CJK ideograph is a word.
+/// Hyphenated words, apostrophes and URLs or emails can be kept as single tokens.
+pub struct UnicodeWordTokenizer {
+    hyphenated_words: bool,
+    apostrophes: bool,
+    urls_and_emails: bool,
+}
+
+impl Default for UnicodeWordTokenizer {
+    fn default() -> Self {
+        Self {
+            hyphenated_words: false,
+            apostrophes: true,
+            urls_and_emails: false,
+        }
+    }
+}
+
+impl UnicodeWordTokenizer {
+    /// Keep words joined by single hyphens as one token, "e-mail" -> [e-mail] instead of [e, mail].
+    pub fn with_hyphenated_words(mut self, hyphenated_words: bool) -> Self {
+        self.hyphenated_words = hyphenated_words;
+        self
+    }
+
+    /// Keep apostrophes inside words, as UAX #29 does: "don't" -> [don't]. When unset, words are
+    /// split at them: [don, t].
+    pub fn with_apostrophes(mut self, apostrophes: bool) -> Self {
+        self.apostrophes = apostrophes;
+        self
+    }
+
+    /// Keep URLs (`scheme://...`, `www.`) and email addresses as one token, without the
+    /// punctuation around them.
+    pub fn with_urls_and_emails(mut self, urls_and_emails: bool) -> Self {
+        self.urls_and_emails = urls_and_emails;
+        self
+    }
+
+    fn push_words(&self, text: &str, words: &mut Vec<String>) {
+        // the start of a hyphenated word, waiting for the word after the hyphen
+        let mut joined: Option<String> = None;
+        let mut segments = text.split_word_bounds().peekable();
+        while let Some(segment) = segments.next() {
+            if !segment.chars().any(char::is_alphanumeric) {
+                continue;
+            }
+            let mut word = match joined.take() {
+                Some(mut prefix) => {
+                    prefix.push_str(segment);
+                    prefix
+                }
+                None => segment.to_string(),
+            };
+            if self.hyphenated_words
+                && segments.peek().is_some_and(|next| is_hyphen(next))
+                && segments
+                    .clone()
+                    .nth(1)
+                    .is_some_and(|after| after.chars().any(char::is_alphanumeric))
+            {
+                word.push_str(segments.next().unwrap());
+                joined = Some(word);
+                continue;
+            }
+            if self.apostrophes {
+                words.push(word);
+            } else {
+                words.extend(
+                    word.split(is_apostrophe)
+                        .filter(|part| !part.is_empty())
+                        .map(str::to_string),
+                );
+            }
+        }
+    }
+}
+
+fn is_hyphen(segment: &str) -> bool {
+    matches!(segment, "-" | "\u{2010}" | "\u{2011}")
+}
+
+fn is_apostrophe(c: char) -> bool {
+    matches!(c, '\'' | '\u{2019}')
+}
+
+/// `chunk` without surrounding punctuation when it's a URL or an email address.
+fn url_or_email(chunk: &str) -> Option<&str> {
+    let trimmed = chunk
+        .trim_start_matches(['(', '<', '[', '"', '\''])
+        .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '>', ']', '"', '\'']);
+    let lower = trimmed.to_lowercase();
+    let url = ["http://", "https://", "ftp://", "www."]
+        .iter()
+        .any(|prefix| lower.starts_with(prefix) && lower.len() > prefix.len());
+    let email = match trimmed.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+        }
+        None => false,
+    };
+    (url || email).then_some(trimmed)
+}
+
+impl Tokenizer for UnicodeWordTokenizer {
+    fn tokenize(&self, text: String) -> Vec<String> {
+        let mut words = Vec::new();
+        if !self.urls_and_emails {
+            self.push_words(&text, &mut words);
+            return words;
+        }
+        // word boundaries always surround whitespace, so chunks segment like the whole text
+        for chunk in text.split_whitespace() {
+            match url_or_email(chunk) {
+                Some(token) => words.push(token.to_string()),
+                None => self.push_words(chunk, &mut words),
+            }
+        }
+        words
+    }
+}
+
+/// Whether `c` is a Han ideograph, kana or hangul, scripts written without spaces between words.
+pub fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{1100}'..='\u{11FF}'       // Hangul Jamo
+        | '\u{3040}'..='\u{30FF}'     // Hiragana, Katakana
+        | '\u{3130}'..='\u{318F}'     // Hangul Compatibility Jamo
+        | '\u{31F0}'..='\u{31FF}'     // Katakana Phonetic Extensions
+        | '\u{3400}'..='\u{4DBF}'     // CJK Extension A
+        | '\u{4E00}'..='\u{9FFF}'     // CJK Unified Ideographs
+        | '\u{AC00}'..='\u{D7AF}'     // Hangul Syllables
+        | '\u{F900}'..='\u{FAFF}'     // CJK Compatibility Ideographs
+        | '\u{FF66}'..='\u{FF9D}'     // Halfwidth Katakana
+        | '\u{20000}'..='\u{2FA1F}' // CJK Extensions B-F, Compatibility Supplement
+    )
+}
+
+/// Splits text at Unicode word boundaries like `UnicodeWordTokenizer`, but turns every run of
+/// CJK characters into overlapping bigrams: "搜索引擎" -> [搜索, 索引, 引擎]. A lone CJK character
+/// stays a unigram, other words are kept as they are. Consecutive bigrams take consecutive
+/// positions, so a CJK query matches as a phrase of its bigrams.
+#[derive(Default)]
+pub struct CjkBigramTokenizer;
+
+impl CjkBigramTokenizer {
+    fn push_bigrams(run: &mut Vec<char>, tokens: &mut Vec<String>) {
+        match run.len() {
+            0 => {}
+            1 => tokens.push(run[0].to_string()),
+            _ => tokens.extend(run.windows(2).map(|pair| pair.iter().collect::<String>())),
+        }
+        run.clear();
+    }
+}
+
+impl Tokenizer for CjkBigramTokenizer {
+    fn tokenize(&self, text: String) -> Vec<String> {
+        let mut tokens = Vec::new();
+        let mut run = Vec::new();
+        for segment in text.split_word_bounds() {
+            let mut letters = segment.chars().filter(|c| c.is_alphanumeric()).peekable();
+            if letters.peek().is_some() && letters.all(is_cjk) {
+                run.extend(segment.chars().filter(|c| c.is_alphanumeric()));
+                continue;
+            }
+            Self::push_bigrams(&mut run, &mut tokens);
+            if segment.chars().any(char::is_alphanumeric) {
+                tokens.push(segment.to_string());
+            }
+        }
+        Self::push_bigrams(&mut run, &mut tokens);
+        tokens
+    }
+}
+
+/// Runs of letters and digits in `text`, CJK text without spaces is a single run.
+fn alphanumeric_runs(text: &str) -> impl Iterator<Item = Vec<char>> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|run| !run.is_empty())
+        .map(|run| run.chars().collect())
+}
+
+/// Every character n-gram of `min_gram` to `max_gram` characters of every run of letters and
+/// digits, by start then length: "harvest" with 3..=4 -> [har, harv, arv, arve, ...]. Lets
+/// queries match substrings of words at the cost of a much larger index. Runs shorter than
+/// `min_gram` produce no token.
+pub struct NGramTokenizer {
+    min_gram: usize,
+    max_gram: usize,
+}
+
+impl NGramTokenizer {
+    pub fn new(min_gram: usize, max_gram: usize) -> Self {
+        assert!(
+            1 <= min_gram && min_gram <= max_gram,
+            "n-grams need 1 <= min_gram <= max_gram"
+        );
+        Self { min_gram, max_gram }
+    }
+}
+
+impl Default for NGramTokenizer {
+    fn default() -> Self {
+        Self::new(2, 3)
+    }
+}
+
+impl Tokenizer for NGramTokenizer {
+    fn tokenize(&self, text: String) -> Vec<String> {
+        let mut tokens = Vec::new();
+        for run in alphanumeric_runs(&text) {
+            for start in 0..run.len() {
+                let longest = self.max_gram.min(run.len() - start);
+                for len in self.min_gram..=longest {
+                    tokens.push(run[start..start + len].iter().collect());
+                }
+            }
+        }
+        tokens
+    }
+}
+
+/// The prefixes of `min_gram` to `max_gram` characters of every run of letters and digits:
+/// "harvest" with 1..=3 -> [h, ha, har]. For search-as-you-type, where the query is the start of
+/// a word. Runs shorter than `min_gram` produce no token.
+pub struct EdgeNGramTokenizer {
+    min_gram: usize,
+    max_gram: usize,
+}
+
+impl EdgeNGramTokenizer {
+    pub fn new(min_gram: usize, max_gram: usize) -> Self {
+        assert!(
+            1 <= min_gram && min_gram <= max_gram,
+            "n-grams need 1 <= min_gram <= max_gram"
+        );
+        Self { min_gram, max_gram }
+    }
+}
+
+impl Default for EdgeNGramTokenizer {
+    fn default() -> Self {
+        Self::new(1, 10)
+    }
+}
+
+impl Tokenizer for EdgeNGramTokenizer {
+    fn tokenize(&self, text: String) -> Vec<String> {
+        let mut tokens = Vec::new();
+        for run in alphanumeric_runs(&text) {
+            let longest = self.max_gram.min(run.len());
+            for len in self.min_gram..=longest {
+                tokens.push(run[..len].iter().collect());
+            }
+        }
+        tokens
+    }
+}
+
 /// A token filter receives the token stream and may add, remove, or change tokens.
 /// For example, a lowercase token filter converts all tokens to lowercase, a stop token
 /// filter removes common words (stop words) like the from the token stream,
@@ -328,6 +602,26 @@ impl TokenFilter for StopWordTokenFilter {
     }
 }
 
+/// Drops the tokens of a given stop word list, e.g. one loaded from a file in an analyzer config.
+pub struct StopWordListFilter {
+    words: HashSet<String>,
+}
+
+impl StopWordListFilter {
+    pub fn new(words: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            words: words.into_iter().collect(),
+        }
+    }
+}
+
+impl TokenFilter for StopWordListFilter {
+    fn filter(&self, mut tokens: Vec<TextToken>) -> Vec<TextToken> {
+        tokens.retain(|w| !self.words.contains(&w.term));
+        tokens
+    }
+}
+
 pub struct PorterStemmerTokenFilter;
 
 impl TokenFilter for PorterStemmerTokenFilter {
@@ -403,6 +697,8 @@ impl TokenFilter for NumericTokenFilter {
 
 /// Pure text analysis pipeline - no async, no DB, just text transformations
 pub struct TextAnalyzer {
+    /// Recorded with the index generations it builds, queries must analyze with the same one.
+    name: String,
     char_filters: Vec<Box<dyn CharacterFilter>>,
     tokenizer: Box<dyn Tokenizer>,
     token_filters: Vec<Box<dyn TokenFilter>>,
@@ -419,8 +715,9 @@ impl Default for TextAnalyzer {
                 Box::new(NumericTokenFilter),
                 Box::new(StopWordTokenFilter),
                 Box::new(PorterStemmerTokenFilter),
-            ]
+            ],
         )
+        .with_name(DEFAULT_ANALYZER)
     }
 }
 
@@ -451,12 +748,30 @@ impl TextAnalyzer {
         token_filters: Vec<Box<dyn TokenFilter>>,
     ) -> Self {
         Self {
+            name: CUSTOM_ANALYZER.to_string(),
             char_filters,
             tokenizer,
             token_filters,
         }
     }
 
+    /// The built-in analyzer called `name`, one of `ANALYZERS`: `default` stems English words and
+    /// drops stop words and numbers, `simple` only lowercases words stripped of punctuation.
+    /// Analyzers defined in a config file are built through `AnalyzerRegistry`.
+    pub fn named(name: &str) -> Result<Self> {
+        AnalyzerRegistry::builtin().build(name)
+    }
+
+    /// Name the analyzer is recorded under in the index it builds.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn char_filter(&self, mut content: String) -> String {
         for filter in self.char_filters.iter() {
             content = filter.filter(content);
@@ -465,18 +780,13 @@ impl TextAnalyzer {
     }
 
     pub fn tokenize(&self, content: String) -> Vec<TextToken> {
+        // positions are the original token offsets, token filters must never renumber them:
+        // a dropped token (e.g. a stop word) leaves a hole so phrase queries can check exact gaps.
         let tokens = self.tokenizer.tokenize(content);
         tokens
-            .iter()
+            .into_iter()
             .enumerate()
-            .map(|(idx, tok)| {
-                let r = TextToken {
-                    term: tok.clone(),
-                    pos: idx, // BUG: is this the correct position? if we preserve the original offset instead of having incremental positions
-                              // then we will need to preserve the distance b/w terms in the query as well and use that distance instead of k = 1, for phrase queries.
-                };
-                r
-            })
+            .map(|(pos, term)| TextToken { term, pos })
             .collect()
     }
 
@@ -487,15 +797,43 @@ impl TextAnalyzer {
         tokens
     }
 
-    /// Analyzes raw content and returns a list of tokens
+    /// Analyzes raw content and returns a list of tokens.
+    /// Token positions are offsets in the unfiltered token stream, so removed tokens leave gaps.
     pub fn analyze(&self, raw_content: String) -> Result<Vec<TextToken>> {
         let content = self.char_filter(raw_content);
+        let tokens = self.tokenize(content);
+        Ok(self.token_filter(tokens))
+    }
 
-        let mut tokens = self.tokenize(content);
+    /// Like `analyze`, but pairs every token with its surface form: the original word lowercased
+    /// and stripped of surrounding punctuation, e.g. "Running," -> ("run", "running").
+    pub fn analyze_with_surface_forms(
+        &self,
+        raw_content: String,
+    ) -> Result<Vec<(TextToken, String)>> {
+        let tokens = self.tokenize(self.char_filter(raw_content));
+        // positions are offsets in the tokenizer output, so they index the original words
+        let words: Vec<String> = tokens.iter().map(|t| t.term.clone()).collect();
+        Ok(self
+            .token_filter(tokens)
+            .into_iter()
+            .map(|token| {
+                let surface = words[token.pos]
+                    .trim_matches(|c: char| !c.is_alphanumeric())
+                    .to_lowercase();
+                (token, surface)
+            })
+            .collect())
+    }
 
-        tokens = self.token_filter(tokens);
-        for (idx, token) in tokens.iter_mut().enumerate() {
-            token.pos = idx;
+    /// Analyzes a query and returns its tokens with positions relative to the first kept token.
+    /// e.g. "state of the art" -> [state@0, art@3], which is the gap a matching document must have.
+    pub fn analyze_query(&self, query: &str) -> Result<Vec<TextToken>> {
+        let mut tokens = self.analyze(query.to_string())?;
+        if let Some(first) = tokens.first().map(|t| t.pos) {
+            for token in tokens.iter_mut() {
+                token.pos -= first;
+            }
         }
         Ok(tokens)
     }
@@ -506,7 +844,7 @@ pub struct PageProcessor {
     pub process_tx: mpsc::UnboundedSender<Page>,
     process_rx: Mutex<mpsc::UnboundedReceiver<Page>>,
     concurrent_processing: Arc<Semaphore>,
-    pages_repo: Arc<PageRepo>,
+    pages_repo: Arc<dyn PageStore>,
     text_analyzer: TextAnalyzer,
 }
 
@@ -514,7 +852,7 @@ impl PageProcessor {
     pub fn new(
         text_analyzer: TextAnalyzer,
         max_concurrent_processing: usize,
-        pages_repo: Arc<PageRepo>,
+        pages_repo: Arc<dyn PageStore>,
     ) -> Self {
         let (tx, rx) = mpsc::unbounded_channel();
         Self {
@@ -755,4 +1093,71 @@ mod tests {
         assert_not_contains(&tokens, "!=");
         assert_not_contains(&tokens, "!==");
     }
+
+    fn plain_analyzer() -> TextAnalyzer {
+        TextAnalyzer::new(
+            vec![],
+            Box::new(WhiteSpaceTokenizer),
+            vec![
+                Box::new(PunctuationStripFilter::default()),
+                Box::new(LowerCaseTokenFilter),
+                Box::new(StopWordTokenFilter),
+                Box::new(PorterStemmerTokenFilter),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_analyze_keeps_original_positions() {
+        let tokens = plain_analyzer()
+            .analyze("castle of the king".to_string())
+            .unwrap();
+        let got: Vec<(&str, usize)> = tokens.iter().map(|t| (t.term.as_str(), t.pos)).collect();
+        assert_eq!(got, vec![("castl", 0), ("king", 3)]);
+    }
+
+    #[test]
+    fn test_analyze_query_positions_are_relative() {
+        let tokens = plain_analyzer().analyze_query("the cat sat").unwrap();
+        let got: Vec<(&str, usize)> = tokens.iter().map(|t| (t.term.as_str(), t.pos)).collect();
+        assert_eq!(got, vec![("cat", 0), ("sat", 1)]);
+
+        let tokens = plain_analyzer().analyze_query("the of and").unwrap();
+        assert!(tokens.is_empty());
+    }
+
+    #[test]
+    fn test_analyze_with_surface_forms() {
+        let tokens = plain_analyzer()
+            .analyze_with_surface_forms("Running, in the Castles".to_string())
+            .unwrap();
+        let got: Vec<(&str, &str)> = tokens
+            .iter()
+            .map(|(t, surface)| (t.term.as_str(), surface.as_str()))
+            .collect();
+        assert_eq!(got, vec![("run", "running"), ("castl", "castles")]);
+    }
+
+    #[test]
+    fn test_named_analyzers() {
+        let default = TextAnalyzer::named(DEFAULT_ANALYZER).unwrap();
+        assert_eq!(default.name(), DEFAULT_ANALYZER);
+        let terms = |analyzer: &TextAnalyzer| -> Vec<String> {
+            analyzer
+                .analyze("Harvesting the 2 Moons".to_string())
+                .unwrap()
+                .into_iter()
+                .map(|t| t.term)
+                .collect()
+        };
+        assert_eq!(terms(&default), vec!["harvest", "moon"]);
+
+        let simple = TextAnalyzer::named("simple").unwrap();
+        assert_eq!(simple.name(), "simple");
+        assert_eq!(terms(&simple), vec!["harvesting", "the", "moons"]);
+
+        assert_eq!(plain_analyzer().name(), CUSTOM_ANALYZER);
+        let err = TextAnalyzer::named("klingon").err().unwrap();
+        assert!(err.to_string().contains("default, simple"), "{err}");
+    }
 }
@@ -0,0 +1,465 @@
+use anyhow::{Context, Result, bail, ensure};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::analyzer::{
+    ANALYZERS, CharacterFilter, CjkBigramTokenizer, DEFAULT_ANALYZER, EdgeNGramTokenizer,
+    HTMLTagFilter, LowerCaseTokenFilter, NGramTokenizer, NumericTokenFilter,
+    PorterStemmerTokenFilter, PunctuationStripFilter, StopWordListFilter, StopWordTokenFilter,
+    TextAnalyzer, TokenFilter, Tokenizer, UnicodeWordTokenizer, WhiteSpaceTokenizer,
+};
+use crate::config::CONFIG;
+
+/// Page fields analyzed into the index, only the body so far.
+pub const FIELDS: &[&str] = &[BODY_FIELD];
+/// The page text, analyzed with the analyzer the index manifest records.
+pub const BODY_FIELD: &str = "body";
+
+/// A character filter of an analyzer definition, e.g. `{"type": "html_strip"}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum CharFilterDefinition {
+    /// Text of the HTML without tags, scripts and boilerplate (`HTMLTagFilter`)
+    HtmlStrip,
+}
+
+/// The tokenizer of an analyzer definition, e.g. `{"type": "whitespace"}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TokenizerDefinition {
+    Whitespace,
+    /// Unicode word boundaries, see `UnicodeWordTokenizer` for the options
+    UnicodeWords {
+        #[serde(default)]
+        hyphenated_words: bool,
+        #[serde(default = "default_apostrophes")]
+        apostrophes: bool,
+        #[serde(default)]
+        urls_and_emails: bool,
+    },
+    /// Overlapping bigrams of CJK text, words elsewhere
+    CjkBigram,
+    /// Character n-grams of every word, for substring search
+    Ngram {
+        #[serde(default = "default_min_gram")]
+        min_gram: usize,
+        #[serde(default = "default_max_gram")]
+        max_gram: usize,
+    },
+    /// Word prefixes, for search-as-you-type
+    EdgeNgram {
+        #[serde(default = "default_min_edge_gram")]
+        min_gram: usize,
+        #[serde(default = "default_max_edge_gram")]
+        max_gram: usize,
+    },
+}
+
+/// A token filter of an analyzer definition, e.g. `{"type": "punctuation_strip", "min_length": 3}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TokenFilterDefinition {
+    PunctuationStrip {
+        #[serde(default = "default_min_length")]
+        min_length: usize,
+    },
+    Lowercase,
+    /// Drops tokens without a letter
+    Numeric,
+    /// Drops the words of `path`, one per line with `#` comments, and of `words`. The English
+    /// stop words when neither is given.
+    StopWords {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<PathBuf>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        words: Vec<String>,
+    },
+    PorterStem,
+}
+
+fn default_min_length() -> usize {
+    2
+}
+
+fn default_apostrophes() -> bool {
+    true
+}
+
+fn default_min_gram() -> usize {
+    2
+}
+
+fn default_max_gram() -> usize {
+    3
+}
+
+fn default_min_edge_gram() -> usize {
+    1
+}
+
+fn default_max_edge_gram() -> usize {
+    10
+}
+
+fn ensure_gram_lengths(min_gram: usize, max_gram: usize) -> Result<()> {
+    ensure!(
+        1 <= min_gram && min_gram <= max_gram,
+        "Invalid n-gram lengths {}..{}, expected 1 <= min_gram <= max_gram",
+        min_gram,
+        max_gram
+    );
+    Ok(())
+}
+
+/// The pipeline of a named analyzer: character filters, a tokenizer and token filters in order.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AnalyzerDefinition {
+    #[serde(default)]
+    pub char_filters: Vec<CharFilterDefinition>,
+    pub tokenizer: TokenizerDefinition,
+    #[serde(default)]
+    pub token_filters: Vec<TokenFilterDefinition>,
+}
+
+impl AnalyzerDefinition {
+    /// The analyzer named `name`, relative stop word paths are resolved against `base_dir`.
+    pub fn build(&self, name: &str, base_dir: &Path) -> Result<TextAnalyzer> {
+        let char_filters = self
+            .char_filters
+            .iter()
+            .map(|filter| -> Box<dyn CharacterFilter> {
+                match filter {
+                    CharFilterDefinition::HtmlStrip => Box::new(HTMLTagFilter),
+                }
+            })
+            .collect();
+        let tokenizer: Box<dyn Tokenizer> = match self.tokenizer {
+            TokenizerDefinition::Whitespace => Box::new(WhiteSpaceTokenizer),
+            TokenizerDefinition::UnicodeWords {
+                hyphenated_words,
+                apostrophes,
+                urls_and_emails,
+            } => Box::new(
+                UnicodeWordTokenizer::default()
+                    .with_hyphenated_words(hyphenated_words)
+                    .with_apostrophes(apostrophes)
+                    .with_urls_and_emails(urls_and_emails),
+            ),
+            TokenizerDefinition::CjkBigram => Box::new(CjkBigramTokenizer),
+            TokenizerDefinition::Ngram { min_gram, max_gram } => {
+                ensure_gram_lengths(min_gram, max_gram)?;
+                Box::new(NGramTokenizer::new(min_gram, max_gram))
+            }
+            TokenizerDefinition::EdgeNgram { min_gram, max_gram } => {
+                ensure_gram_lengths(min_gram, max_gram)?;
+                Box::new(EdgeNGramTokenizer::new(min_gram, max_gram))
+            }
+        };
+        let mut token_filters: Vec<Box<dyn TokenFilter>> = Vec::new();
+        for filter in &self.token_filters {
+            token_filters.push(match filter {
+                TokenFilterDefinition::PunctuationStrip { min_length } => {
+                    Box::new(PunctuationStripFilter::new(*min_length))
+                }
+                TokenFilterDefinition::Lowercase => Box::new(LowerCaseTokenFilter),
+                TokenFilterDefinition::Numeric => Box::new(NumericTokenFilter),
+                TokenFilterDefinition::StopWords { path: None, words } if words.is_empty() => {
+                    Box::new(StopWordTokenFilter)
+                }
+                TokenFilterDefinition::StopWords { path, words } => {
+                    let mut list = words.clone();
+                    if let Some(path) = path {
+                        list.extend(read_stop_words(&base_dir.join(path))?);
+                    }
+                    Box::new(StopWordListFilter::new(list))
+                }
+                TokenFilterDefinition::PorterStem => Box::new(PorterStemmerTokenFilter),
+            });
+        }
+        Ok(TextAnalyzer::new(char_filters, tokenizer, token_filters).with_name(name))
+    }
+}
+
+fn read_stop_words(path: &Path) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read stop words from {}", path.display()))?;
+    Ok(text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Layout of an analyzer config file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct AnalyzerConfigFile {
+    #[serde(default)]
+    analyzers: BTreeMap<String, AnalyzerDefinition>,
+    /// Analyzer of every field in `FIELDS` that doesn't use the default one
+    #[serde(default)]
+    fields: BTreeMap<String, String>,
+}
+
+/// Named analyzer definitions, the built-in ones and those of an analyzer config file:
+///
+/// ```json
+/// {
+///   "analyzers": {
+///     "short_words": {
+///       "char_filters": [{"type": "html_strip"}],
+///       "tokenizer": {"type": "whitespace"},
+///       "token_filters": [
+///         {"type": "punctuation_strip", "min_length": 1},
+///         {"type": "lowercase"},
+///         {"type": "stop_words", "path": "stop_words.txt"}
+///       ]
+///     }
+///   },
+///   "fields": {"body": "short_words"}
+/// }
+/// ```
+///
+/// The index manifest records analyzers by name, so changing the definition of an analyzer an
+/// index is built with takes an `index rebuild`.
+#[derive(Debug, Clone)]
+pub struct AnalyzerRegistry {
+    analyzers: BTreeMap<String, AnalyzerDefinition>,
+    fields: BTreeMap<String, String>,
+    /// Directory relative stop word paths are resolved against
+    base_dir: PathBuf,
+}
+
+impl AnalyzerRegistry {
+    /// Only the analyzers of `ANALYZERS`.
+    pub fn builtin() -> Self {
+        let default = AnalyzerDefinition {
+            char_filters: vec![CharFilterDefinition::HtmlStrip],
+            tokenizer: TokenizerDefinition::Whitespace,
+            token_filters: vec![
+                TokenFilterDefinition::PunctuationStrip {
+                    min_length: default_min_length(),
+                },
+                TokenFilterDefinition::Lowercase,
+                TokenFilterDefinition::Numeric,
+                TokenFilterDefinition::StopWords {
+                    path: None,
+                    words: Vec::new(),
+                },
+                TokenFilterDefinition::PorterStem,
+            ],
+        };
+        let simple = AnalyzerDefinition {
+            char_filters: vec![CharFilterDefinition::HtmlStrip],
+            tokenizer: TokenizerDefinition::Whitespace,
+            token_filters: vec![
+                TokenFilterDefinition::PunctuationStrip {
+                    min_length: default_min_length(),
+                },
+                TokenFilterDefinition::Lowercase,
+            ],
+        };
+        Self {
+            analyzers: BTreeMap::from([
+                (DEFAULT_ANALYZER.to_string(), default),
+                ("simple".to_string(), simple),
+            ]),
+            fields: BTreeMap::new(),
+            base_dir: PathBuf::new(),
+        }
+    }
+
+    /// The built-in analyzers and those of the `ANALYZERS_FILE` config file, if set.
+    pub fn from_config() -> Result<Self> {
+        match &CONFIG.analyzers_file {
+            Some(path) => Self::load(Path::new(path)),
+            None => Ok(Self::builtin()),
+        }
+    }
+
+    /// The built-in analyzers and those of the JSON config file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read analyzer config {}", path.display()))?;
+        let base_dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        Self::from_json(&json, base_dir)
+            .with_context(|| format!("Invalid analyzer config {}", path.display()))
+    }
+
+    /// The built-in analyzers and those of a JSON config, stop word paths are relative to `base_dir`.
+    pub fn from_json(json: &str, base_dir: PathBuf) -> Result<Self> {
+        let file: AnalyzerConfigFile = serde_json::from_str(json)?;
+        let mut registry = Self {
+            base_dir,
+            ..Self::builtin()
+        };
+        for (name, definition) in file.analyzers {
+            ensure!(
+                !ANALYZERS.contains(&name.as_str()),
+                "Analyzer '{}' is built in and can't be redefined",
+                name
+            );
+            ensure!(!name.trim().is_empty(), "Analyzer names can't be empty");
+            registry.analyzers.insert(name, definition);
+        }
+        for (field, analyzer) in file.fields {
+            ensure!(
+                FIELDS.contains(&field.as_str()),
+                "Unknown field '{}', expected one of: {}",
+                field,
+                FIELDS.join(", ")
+            );
+            registry.ensure_defined(&analyzer)?;
+            registry.fields.insert(field, analyzer);
+        }
+        // a broken definition (e.g. a missing stop word file) fails when loading, not mid-run
+        for name in registry.analyzers.keys() {
+            registry.build(name)?;
+        }
+        Ok(registry)
+    }
+
+    /// Names of every defined analyzer, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        self.analyzers.keys().map(String::as_str).collect()
+    }
+
+    pub fn definition(&self, name: &str) -> Option<&AnalyzerDefinition> {
+        self.analyzers.get(name)
+    }
+
+    /// Analyzer of `field`, the default analyzer unless the config file picks another.
+    pub fn field_analyzer(&self, field: &str) -> &str {
+        self.fields
+            .get(field)
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_ANALYZER)
+    }
+
+    /// The analyzer called `name`.
+    pub fn build(&self, name: &str) -> Result<TextAnalyzer> {
+        self.ensure_defined(name)?;
+        self.analyzers[name]
+            .build(name, &self.base_dir)
+            .with_context(|| format!("Failed to build analyzer '{}'", name))
+    }
+
+    fn ensure_defined(&self, name: &str) -> Result<()> {
+        if !self.analyzers.contains_key(name) {
+            bail!(
+                "Unknown analyzer '{}', expected one of: {}",
+                name,
+                self.names().join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(analyzer: &TextAnalyzer, text: &str) -> Vec<String> {
+        analyzer
+            .analyze(text.to_string())
+            .unwrap()
+            .into_iter()
+            .map(|t| t.term)
+            .collect()
+    }
+
+    #[test]
+    fn test_builtin_default_matches_default_analyzer() {
+        let built = AnalyzerRegistry::builtin().build(DEFAULT_ANALYZER).unwrap();
+        let text = "<p>The Harvesters harvested 42 moons, e.g. Io!</p>";
+        assert_eq!(terms(&built, text), terms(&TextAnalyzer::default(), text));
+    }
+
+    #[test]
+    fn test_config_defines_analyzers_and_fields() {
+        let dir = std::env::temp_dir().join(format!("harvest-analyzers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("stop.txt"), "# planets\nmoons\n\nio # a moon\n").unwrap();
+        let json = r#"{
+            "analyzers": {
+                "cjk": {"tokenizer": {"type": "cjk_bigram"}},
+                "prefixes": {"tokenizer": {"type": "edge_ngram", "max_gram": 3}},
+                "words": {
+                    "tokenizer": {"type": "unicode_words", "urls_and_emails": true},
+                    "token_filters": [{"type": "lowercase"}]
+                },
+                "short": {
+                    "tokenizer": {"type": "whitespace"},
+                    "token_filters": [
+                        {"type": "punctuation_strip", "min_length": 1},
+                        {"type": "lowercase"},
+                        {"type": "stop_words", "path": "stop.txt", "words": ["the"]}
+                    ]
+                }
+            },
+            "fields": {"body": "short"}
+        }"#;
+        let registry = AnalyzerRegistry::from_json(json, dir.clone()).unwrap();
+        let short = registry.build("short").unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            registry.names(),
+            vec!["cjk", "default", "prefixes", "short", "simple", "words"]
+        );
+        assert_eq!(
+            terms(&registry.build("cjk").unwrap(), "Rust 搜索引擎"),
+            vec!["Rust", "搜索", "索引", "引擎"]
+        );
+        assert_eq!(
+            terms(&registry.build("prefixes").unwrap(), "Harvest"),
+            vec!["H", "Ha", "Har"]
+        );
+        assert_eq!(
+            terms(
+                &registry.build("words").unwrap(),
+                "Mail bob@example.com, don't wait!"
+            ),
+            vec!["mail", "bob@example.com", "don't", "wait"]
+        );
+        assert_eq!(registry.field_analyzer(BODY_FIELD), "short");
+        assert_eq!(short.name(), "short");
+        assert_eq!(
+            terms(&short, "The 2 Moons of a planet, Io!"),
+            vec!["2", "of", "a", "planet"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_configs() {
+        let error = |json: &str| {
+            AnalyzerRegistry::from_json(json, PathBuf::new())
+                .err()
+                .map(|e| format!("{:#}", e))
+                .unwrap_or_default()
+        };
+        let whitespace = r#"{"tokenizer": {"type": "whitespace"}}"#;
+        assert!(
+            error(&format!(r#"{{"analyzers": {{"simple": {whitespace}}}}}"#)).contains("built in")
+        );
+        assert!(error(r#"{"fields": {"title": "simple"}}"#).contains("Unknown field 'title'"));
+        assert!(error(r#"{"fields": {"body": "klingon"}}"#).contains("Unknown analyzer"));
+        assert!(
+            error(r#"{"analyzers": {"a": {"tokenizer": {"type": "trigram"}}}}"#)
+                .contains("unknown variant")
+        );
+        let grams = r#"{"analyzers": {"a": {"tokenizer": {"type": "ngram", "min_gram": 3, "max_gram": 2}}}}"#;
+        assert!(error(grams).contains("Invalid n-gram lengths 3..2"));
+        let missing = r#"{"analyzers": {"a": {"tokenizer": {"type": "whitespace"},
+            "token_filters": [{"type": "stop_words", "path": "missing.txt"}]}}}"#;
+        assert!(error(missing).contains("missing.txt"));
+        assert_eq!(
+            AnalyzerRegistry::builtin().field_analyzer(BODY_FIELD),
+            DEFAULT_ANALYZER
+        );
+    }
+}
@@ -1,14 +1,23 @@
-use axum::{Json, extract::State, http::StatusCode};
-use futures::TryStreamExt;
-use mongodb::bson::doc;
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+};
 use std::sync::Arc;
 use std::time::Instant;
 
+use crate::completion::{DEFAULT_COMPLETIONS, normalize};
 use crate::data_models::Page;
-use crate::db::collections;
 use crate::query_engine::QueryEngine;
+use crate::term_dict::TermPattern;
 
-use super::models::{PageResult, SearchRequest, SearchResponse};
+use super::models::{PageResult, SearchRequest, SearchResponse, SuggestParams, SuggestResponse};
+
+/// Upper bound on the `limit` of autocomplete requests.
+const MAX_COMPLETIONS: usize = 50;
+
+/// Queries with fewer hits than this get spelling suggestions.
+const SUGGEST_BELOW_HITS: usize = 3;
 
 pub async fn search_handler(
     State(query_engine): State<Arc<QueryEngine>>,
@@ -33,26 +42,18 @@ pub async fn search_handler(
             format!("Search error: {}", e),
         )
     })?;
+    let page_ids = query_engine.page_ids(&document_ids).await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Search error: {}", e),
+        )
+    })?;
 
     // Fetch full page documents for the matching IDs
-    let pages_collection = query_engine.db().collection::<Page>(collections::PAGES);
-
-    let filter = doc! {
-        "_id": {
-            "$in": document_ids.clone()
-        }
-    };
-
-    let pages: Vec<Page> = pages_collection
-        .find(filter)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Database error: {}", e),
-            )
-        })?
-        .try_collect()
+    let pages: Vec<Page> = query_engine
+        .storage()
+        .pages
+        .find_by_ids(&page_ids)
         .await
         .map_err(|e| {
             (
@@ -89,6 +90,18 @@ pub async fn search_handler(
         .collect();
 
     let total_results = results.len();
+    if total_results > 0 {
+        record_query(&query_engine, &request.query);
+    }
+    // suggestions are best-effort, a failing suggester must not fail the search
+    let suggestions = if total_results < SUGGEST_BELOW_HITS {
+        query_engine.suggest(&request.query).unwrap_or_else(|e| {
+            log::warn!("Suggestions for '{}' failed: {:#}", request.query, e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
     let processing_time_ms = start.elapsed().as_millis();
 
     Ok(Json(SearchResponse {
@@ -97,5 +110,48 @@ pub async fn search_handler(
         total_results,
         processing_time_ms,
         highlighted_terms,
+        suggestions,
     }))
 }
+
+pub async fn suggest_handler(
+    State(query_engine): State<Arc<QueryEngine>>,
+    Query(params): Query<SuggestParams>,
+) -> Json<SuggestResponse> {
+    let start = Instant::now();
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_COMPLETIONS)
+        .min(MAX_COMPLETIONS);
+
+    let completions = query_engine
+        .complete(&params.q, limit)
+        .into_iter()
+        .map(|c| c.text)
+        .collect();
+
+    Json(SuggestResponse {
+        query: params.q,
+        completions,
+        processing_time_ms: start.elapsed().as_millis(),
+    })
+}
+
+/// Logs a query that had hits so the next indexer run can offer it as a completion.
+/// Pattern queries (`harv*`, `/regex/`, ...) are not something to complete to.
+fn record_query(query_engine: &QueryEngine, query: &str) {
+    if query
+        .split_whitespace()
+        .any(|word| TermPattern::parse(word).is_some())
+    {
+        return;
+    }
+    let query = normalize(query);
+    let query_log = query_engine.storage().query_log.clone();
+    // recording must not slow down the search response
+    tokio::spawn(async move {
+        if let Err(e) = query_log.record(&query).await {
+            log::warn!("Failed to record query '{}': {:#}", query, e);
+        }
+    });
+}
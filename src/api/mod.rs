@@ -1,4 +1,7 @@
-use axum::{Router, routing::post};
+use axum::{
+    Router,
+    routing::{get, post},
+};
 use std::sync::Arc;
 use tower_http::{
     cors::{Any, CorsLayer},
@@ -20,6 +23,7 @@ pub fn create_router(query_engine: Arc<QueryEngine>) -> Router {
     Router::new()
         // API routes
         .route("/api/search", post(handlers::search_handler))
+        .route("/api/suggest", get(handlers::suggest_handler))
         .with_state(query_engine)
         // Static file serving for the UI
         .nest_service("/", ServeDir::new("static"))
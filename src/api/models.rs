@@ -12,6 +12,22 @@ pub struct SearchResponse {
     pub total_results: usize,
     pub processing_time_ms: u128,
     pub highlighted_terms: Vec<String>,
+    /// "Did you mean" corrections, only computed when the query has few hits.
+    pub suggestions: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SuggestParams {
+    pub q: String,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SuggestResponse {
+    pub query: String,
+    /// Completions of the query, best first, as the words appear in pages (not stemmed)
+    pub completions: Vec<String>,
+    pub processing_time_ms: u128,
 }
 
 #[derive(Debug, Serialize)]
@@ -0,0 +1,547 @@
+use anyhow::{Context, Result, bail, ensure};
+use mongodb::bson::{self, DateTime};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::data_models::{DocId, DocIdEntry, InvertedIndexDoc, Page};
+use crate::indexer::TERMS_PER_FETCH;
+use crate::storage::Storage;
+
+const MAGIC: &[u8; 8] = b"HVSTARC1";
+/// Version of the archive layout, archives of a newer version are refused.
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// Pages fetched at a time by the export.
+const PAGES_PER_FETCH: usize = 1_000;
+/// Pages or buckets written at a time by the import.
+const IMPORT_BATCH: usize = 1_000;
+/// Records larger than this are corrupt, a BSON document is at most 16 MiB.
+const MAX_RECORD_BYTES: u32 = 64 << 20;
+
+/// Kinds of records, in the order they appear in an archive.
+const HEADER: u8 = 1;
+const DOC_ID: u8 = 2;
+const DELETED: u8 = 3;
+const PAGE: u8 = 4;
+const BUCKET: u8 = 5;
+const END: u8 = 6;
+
+/// Exports the current index generation with its doc ids and pages to a portable archive, and
+/// imports such an archive into another storage.
+///
+/// An archive is the magic and `ARCHIVE_VERSION` as a little endian u32, then records, each one
+/// a kind byte, its length as a little endian u32 and a BSON document: the header, every live doc
+/// id, every tombstone, the page of every live doc id, every bucket in (term, bucket) order and
+/// finally the number of records of each kind. It ends with the CRC-32 of everything before it.
+pub struct IndexArchive {
+    storage: Storage,
+}
+
+/// Where an archive comes from, its first record.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchiveHeader {
+    pub created_at: DateTime,
+    /// Index generation that was exported.
+    pub generation: u64,
+    /// Analyzer the generation was built with.
+    pub analyzer: String,
+}
+
+/// Number of records of each kind, the last record of an archive.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ArchiveCounts {
+    pub doc_ids: u64,
+    pub deleted: u64,
+    pub pages: u64,
+    pub buckets: u64,
+}
+
+/// What an archive holds, returned by export and import.
+#[derive(Debug, Clone)]
+pub struct ArchiveSummary {
+    pub version: u32,
+    pub header: ArchiveHeader,
+    pub counts: ArchiveCounts,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeletedDocId {
+    doc_id: DocId,
+}
+
+enum Record {
+    Header(ArchiveHeader),
+    DocId(DocIdEntry),
+    Deleted(DocId),
+    Page(Box<Page>),
+    Bucket(InvertedIndexDoc),
+    End(ArchiveCounts),
+}
+
+impl IndexArchive {
+    pub fn from_storage(storage: Storage) -> Self {
+        Self { storage }
+    }
+
+    /// Writes the current index generation, its doc ids and the pages they point to to `path`.
+    /// The archive is written next to `path` and renamed once complete. Fails if an index run
+    /// commits a generation meanwhile.
+    pub async fn export(&self, path: &Path) -> Result<ArchiveSummary> {
+        let manifest = self.storage.index.manifest().await?;
+        let header = ArchiveHeader {
+            created_at: DateTime::now(),
+            generation: manifest.current,
+            analyzer: manifest.analyzer().to_string(),
+        };
+        let tmp_path = tmp_path(path);
+        let mut writer = ArchiveWriter::create(&tmp_path).await?;
+        writer.write(HEADER, &header).await?;
+
+        let mut counts = ArchiveCounts::default();
+        let page_ids = self.storage.doc_ids.page_ids().await?;
+        for (doc_id, page_id) in &page_ids {
+            let entry = DocIdEntry {
+                doc_id: *doc_id,
+                page_id: *page_id,
+            };
+            writer.write(DOC_ID, &entry).await?;
+            counts.doc_ids += 1;
+        }
+        for doc_id in self.storage.doc_ids.deleted().await? {
+            writer.write(DELETED, &DeletedDocId { doc_id }).await?;
+            counts.deleted += 1;
+        }
+
+        for chunk in page_ids.chunks(PAGES_PER_FETCH) {
+            let ids: Vec<_> = chunk.iter().map(|(_, page_id)| *page_id).collect();
+            let mut pages = self.storage.pages.find_by_ids(&ids).await?;
+            if pages.len() < ids.len() {
+                log::warn!(
+                    "{} pages of doc ids are missing, they are not exported",
+                    ids.len() - pages.len()
+                );
+            }
+            pages.sort_by_key(|page| page.id);
+            for page in pages {
+                writer.write(PAGE, &page).await?;
+                counts.pages += 1;
+            }
+        }
+
+        let terms: Vec<String> = self
+            .storage
+            .index
+            .term_document_frequencies()
+            .await?
+            .into_iter()
+            .map(|(term, _)| term)
+            .collect();
+        for chunk in terms.chunks(TERMS_PER_FETCH) {
+            let mut buckets = self.storage.index.find_by_terms(chunk).await?;
+            buckets.sort_by(|a, b| a.term.cmp(&b.term).then(a.bucket.cmp(&b.bucket)));
+            for bucket in buckets {
+                writer.write(BUCKET, &bucket).await?;
+                counts.buckets += 1;
+            }
+        }
+
+        let generation = self.storage.index.manifest().await?.current;
+        if generation != header.generation {
+            tokio::fs::remove_file(&tmp_path).await.ok();
+            bail!(
+                "Index generation {} was committed during the export of generation {}, export again",
+                generation,
+                header.generation
+            );
+        }
+        writer.write(END, &counts).await?;
+        writer.finish().await?;
+        tokio::fs::rename(&tmp_path, path)
+            .await
+            .with_context(|| format!("Failed to write archive {}", path.display()))?;
+        Ok(ArchiveSummary {
+            version: ARCHIVE_VERSION,
+            header,
+            counts,
+        })
+    }
+
+    /// Reads the whole archive at `path`, checking its checksum and its record counts.
+    pub async fn read_summary(path: &Path) -> Result<ArchiveSummary> {
+        let mut reader = ArchiveReader::open(path).await?;
+        let mut header = None;
+        let mut counts = ArchiveCounts::default();
+        while let Some(record) = reader.next().await? {
+            match record {
+                Record::Header(read) => header = Some(read),
+                Record::DocId(_) => counts.doc_ids += 1,
+                Record::Deleted(_) => counts.deleted += 1,
+                Record::Page(_) => counts.pages += 1,
+                Record::Bucket(_) => counts.buckets += 1,
+                Record::End(expected) => ensure!(
+                    counts == expected,
+                    "Archive {} is incomplete: has {:?}, expected {:?}",
+                    path.display(),
+                    counts,
+                    expected
+                ),
+            }
+        }
+        Ok(ArchiveSummary {
+            version: reader.version,
+            header: header.context("Archive without a header")?,
+            counts,
+        })
+    }
+
+    /// Replaces the index, the doc ids and the pages of the storage by those of the archive at
+    /// `path`, checked first. The index is written to a new generation, and the doc ids and pages
+    /// once it's committed. The archive's doc ids are not those of the replaced generation, which
+    /// is dropped and can't be rolled back to. Pages that are not in the archive are marked
+    /// unindexed, so the next index run indexes them again. Fails while an index run is building a
+    /// generation or has blocks left to merge.
+    pub async fn import(&self, path: &Path) -> Result<ArchiveSummary> {
+        let summary = Self::read_summary(path).await?;
+        if let Some(generation) = self.storage.index.manifest().await?.building {
+            bail!(
+                "Index generation {} is being built, finish the index run first",
+                generation
+            );
+        }
+        ensure!(
+            self.storage.checkpoints.get_incomplete().await?.is_empty(),
+            "An interrupted index run has blocks left to merge, finish the index run first"
+        );
+
+        let index = self
+            .storage
+            .index
+            .begin_generation(false, &summary.header.analyzer)
+            .await?;
+        let (mut page_ids, mut deleted, mut buckets) = (Vec::new(), Vec::new(), Vec::new());
+        let mut reader = ArchiveReader::open(path).await?;
+        while let Some(record) = reader.next().await? {
+            match record {
+                Record::DocId(entry) => page_ids.push((entry.doc_id, entry.page_id)),
+                Record::Deleted(doc_id) => deleted.push(doc_id),
+                Record::Bucket(bucket) => {
+                    buckets.push(bucket);
+                    if buckets.len() == IMPORT_BATCH {
+                        index.insert_many(std::mem::take(&mut buckets)).await?;
+                    }
+                }
+                Record::Header(_) | Record::Page(_) | Record::End(_) => {}
+            }
+        }
+        index.insert_many(buckets).await?;
+        let generation = self.storage.index.commit_generation().await?;
+        self.storage.index.drop_previous_generation().await?;
+        self.storage.doc_ids.restore(&page_ids, &deleted).await?;
+
+        // the pages of the archive are restored with their indexed flag
+        self.storage.pages.mark_all_as_unindexed().await?;
+        let mut pages = Vec::new();
+        let mut reader = ArchiveReader::open(path).await?;
+        while let Some(record) = reader.next().await? {
+            if let Record::Page(page) = record {
+                pages.push(*page);
+                if pages.len() == IMPORT_BATCH {
+                    self.storage.pages.restore(&pages).await?;
+                    pages.clear();
+                }
+            }
+        }
+        self.storage.pages.restore(&pages).await?;
+        log::info!(
+            "Imported generation {} of {} as generation {}",
+            summary.header.generation,
+            path.display(),
+            generation
+        );
+        Ok(summary)
+    }
+}
+
+/// Path an archive is written to before it's renamed to `path`.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+struct ArchiveWriter {
+    file: BufWriter<File>,
+    hasher: crc32fast::Hasher,
+}
+
+impl ArchiveWriter {
+    async fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .await
+            .with_context(|| format!("Failed to create archive {}", path.display()))?;
+        let mut writer = Self {
+            file: BufWriter::new(file),
+            hasher: crc32fast::Hasher::new(),
+        };
+        writer.write_bytes(MAGIC).await?;
+        writer.write_bytes(&ARCHIVE_VERSION.to_le_bytes()).await?;
+        Ok(writer)
+    }
+
+    async fn write(&mut self, kind: u8, record: &impl Serialize) -> Result<()> {
+        let bytes = bson::to_vec(record).context("Failed to serialize archive record")?;
+        self.write_bytes(&[kind]).await?;
+        self.write_bytes(&(bytes.len() as u32).to_le_bytes())
+            .await?;
+        self.write_bytes(&bytes).await
+    }
+
+    async fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.hasher.update(bytes);
+        self.file
+            .write_all(bytes)
+            .await
+            .context("Failed to write archive")
+    }
+
+    async fn finish(mut self) -> Result<()> {
+        let checksum = self.hasher.clone().finalize();
+        self.file.write_u32_le(checksum).await?;
+        self.file.flush().await?;
+        self.file.get_mut().sync_all().await?;
+        Ok(())
+    }
+}
+
+struct ArchiveReader {
+    file: BufReader<File>,
+    hasher: crc32fast::Hasher,
+    version: u32,
+    /// Kind of the last record read, records must come in kind order.
+    last_kind: u8,
+}
+
+impl ArchiveReader {
+    async fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .await
+            .with_context(|| format!("Failed to open archive {}", path.display()))?;
+        let mut reader = Self {
+            file: BufReader::new(file),
+            hasher: crc32fast::Hasher::new(),
+            version: 0,
+            last_kind: 0,
+        };
+        let mut magic = [0; 8];
+        reader.read_bytes(&mut magic).await?;
+        ensure!(
+            &magic == MAGIC,
+            "{} is not an index archive",
+            path.display()
+        );
+        let mut version = [0; 4];
+        reader.read_bytes(&mut version).await?;
+        reader.version = u32::from_le_bytes(version);
+        ensure!(
+            reader.version <= ARCHIVE_VERSION,
+            "Archive {} has version {}, this build reads up to version {}",
+            path.display(),
+            reader.version,
+            ARCHIVE_VERSION
+        );
+        Ok(reader)
+    }
+
+    /// The next record, `None` after the last one once the checksum was checked.
+    async fn next(&mut self) -> Result<Option<Record>> {
+        if self.last_kind == END {
+            let expected = self.hasher.clone().finalize();
+            let checksum = self
+                .file
+                .read_u32_le()
+                .await
+                .context("Archive truncated before its checksum")?;
+            ensure!(
+                checksum == expected,
+                "Archive checksum mismatch, it is corrupt"
+            );
+            let mut rest = [0; 1];
+            ensure!(
+                self.file.read(&mut rest).await? == 0,
+                "Archive has trailing bytes after its checksum"
+            );
+            return Ok(None);
+        }
+
+        let mut kind = [0; 1];
+        self.read_bytes(&mut kind).await?;
+        let kind = kind[0];
+        ensure!(
+            (HEADER..=END).contains(&kind),
+            "Unknown archive record kind {kind}"
+        );
+        ensure!(
+            kind >= self.last_kind && (kind == HEADER) == (self.last_kind == 0),
+            "Archive record of kind {} after kind {}",
+            kind,
+            self.last_kind
+        );
+        self.last_kind = kind;
+
+        let mut len = [0; 4];
+        self.read_bytes(&mut len).await?;
+        let len = u32::from_le_bytes(len);
+        ensure!(len <= MAX_RECORD_BYTES, "Archive record of {len} bytes");
+        let mut bytes = vec![0; len as usize];
+        self.read_bytes(&mut bytes).await?;
+        Ok(Some(match kind {
+            HEADER => Record::Header(decode(&bytes)?),
+            DOC_ID => Record::DocId(decode(&bytes)?),
+            DELETED => Record::Deleted(decode::<DeletedDocId>(&bytes)?.doc_id),
+            PAGE => Record::Page(Box::new(decode(&bytes)?)),
+            BUCKET => Record::Bucket(decode(&bytes)?),
+            _ => Record::End(decode(&bytes)?),
+        }))
+    }
+
+    async fn read_bytes(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.file
+            .read_exact(buf)
+            .await
+            .context("Archive truncated")?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    bson::from_slice(bytes).context("Corrupt archive record")
+}
+
+impl fmt::Display for ArchiveSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Index archive version {}, generation {} ({} analyzer) exported at {}",
+            self.version,
+            self.header.generation,
+            self.header.analyzer,
+            self.header
+                .created_at
+                .try_to_rfc3339_string()
+                .unwrap_or_default()
+        )?;
+        writeln!(
+            f,
+            "  doc ids:  {} ({} deleted)",
+            self.counts.doc_ids, self.counts.deleted
+        )?;
+        writeln!(f, "  pages:    {}", self.counts.pages)?;
+        writeln!(f, "  buckets:  {}", self.counts.buckets)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::DEFAULT_ANALYZER;
+    use std::collections::HashMap;
+
+    fn archive_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("harvest_archive_{}_{}", name, std::process::id()))
+    }
+
+    async fn storage_with_index() -> Result<Storage> {
+        let storage = Storage::in_memory();
+        let page = Page::new(
+            "https://example.com/moon".to_string(),
+            "Moon".to_string(),
+            "<p>harvest moon</p>".to_string(),
+            vec![],
+            0,
+            true,
+        );
+        storage.pages.insert(&page).await?;
+        storage.pages.mark_many_as_indexed(&[page.id]).await?;
+        storage.doc_ids.assign(&[page.id]).await?;
+        let index = storage
+            .index
+            .begin_generation(false, DEFAULT_ANALYZER)
+            .await?;
+        index
+            .insert(InvertedIndexDoc::new(
+                "moon".to_string(),
+                0,
+                1,
+                vec![0],
+                HashMap::from([(0, vec![1])]),
+            ))
+            .await?;
+        storage.index.commit_generation().await?;
+        Ok(storage)
+    }
+
+    #[tokio::test]
+    async fn test_export_and_read_summary() -> Result<()> {
+        let path = archive_path("summary");
+        let storage = storage_with_index().await?;
+        let exported = IndexArchive::from_storage(storage).export(&path).await?;
+        let read = IndexArchive::read_summary(&path).await?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(read.version, ARCHIVE_VERSION);
+        assert_eq!(read.header.generation, exported.header.generation);
+        assert_eq!(
+            read.counts,
+            ArchiveCounts {
+                doc_ids: 1,
+                deleted: 0,
+                pages: 1,
+                buckets: 1
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_archives_are_refused() -> Result<()> {
+        let path = archive_path("corrupt");
+        IndexArchive::from_storage(storage_with_index().await?)
+            .export(&path)
+            .await?;
+        let bytes = std::fs::read(&path)?;
+
+        let mut flipped = bytes.clone();
+        let last_record = flipped.len() - 10;
+        flipped[last_record] ^= 1;
+        std::fs::write(&path, &flipped)?;
+        assert!(IndexArchive::read_summary(&path).await.is_err());
+
+        std::fs::write(&path, &bytes[..bytes.len() - 2])?;
+        let err = IndexArchive::read_summary(&path).await.unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{err}");
+
+        let mut newer = bytes.clone();
+        newer[8..12].copy_from_slice(&(ARCHIVE_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, &newer)?;
+        let err = IndexArchive::read_summary(&path).await.unwrap_err();
+        assert!(err.to_string().contains("version"), "{err}");
+
+        // importing a refused archive leaves the storage untouched
+        let target = Storage::in_memory();
+        assert!(
+            IndexArchive::from_storage(target.clone())
+                .import(&path)
+                .await
+                .is_err()
+        );
+        assert_eq!(target.index.manifest().await?.current, 0);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
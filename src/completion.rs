@@ -0,0 +1,655 @@
+use anyhow::{Context, Result};
+use fst::raw::{CompiledAddr, Fst, Node};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::term_dict::TermDictionary;
+
+/// File name of the completion index inside the index directory.
+pub const COMPLETIONS_FILE: &str = "completions.fst";
+
+/// File name of the phrase completions (page titles and past queries) inside the index directory,
+/// the completion index is them plus the words of the surface forms.
+pub const COMPLETION_PHRASES_FILE: &str = "completion_phrases.fst";
+
+/// File name of the surface forms of indexed terms inside the index directory.
+pub const SURFACE_FORMS_FILE: &str = "surface_forms.fst";
+
+/// Directory inside the index directory the completion counts of SPIMI blocks are spilled to,
+/// until the merge of their run adds them to the completions.
+pub const COMPLETION_COUNTS_DIR: &str = "completion_counts";
+
+/// Default number of completions returned for a prefix.
+pub const DEFAULT_COMPLETIONS: usize = 10;
+
+/// Weight of a page title, a title is a much better completion than a word of a few documents.
+pub const TITLE_WEIGHT: u64 = 5;
+
+/// Weight of a past query (with hits) each time it was searched.
+pub const QUERY_WEIGHT: u64 = 20;
+
+/// Documents a word's term must be in for the word to become a completion.
+pub const MIN_WORD_DOCUMENTS: u64 = 2;
+
+/// Titles longer than this (in chars) are not worth completing to.
+const MAX_COMPLETION_LEN: usize = 80;
+
+/// Weighted completions for search-as-you-type, built at index time from page titles,
+/// the surface forms of indexed words in at least `MIN_WORD_DOCUMENTS` documents and past
+/// queries.
+///
+/// Entries are normalized (lowercased, single spaced) and stored in the same FST format as the
+/// term dictionary, with the completion weight in place of the document frequency.
+#[derive(Default)]
+pub struct CompletionIndex {
+    entries: TermDictionary,
+    /// Weight of the heaviest entry below each FST node, past the outputs of the path to it.
+    /// Computed by the first lookup.
+    heaviest_below: OnceLock<HashMap<CompiledAddr, u64>>,
+}
+
+/// A ranked completion of the typed query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub text: String,
+    pub weight: u64,
+}
+
+impl CompletionIndex {
+    fn new(entries: TermDictionary) -> Self {
+        Self {
+            entries,
+            heaviest_below: OnceLock::new(),
+        }
+    }
+
+    /// Builds the index from `(text, weight)` pairs, texts are normalized and equal texts summed.
+    pub fn build<I, S>(entries: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (S, u64)>,
+        S: AsRef<str>,
+    {
+        let entries = entries.into_iter().filter_map(|(text, weight)| {
+            let text = normalize(text.as_ref());
+            (!text.is_empty() && text.chars().count() <= MAX_COMPLETION_LEN)
+                .then_some((text, weight))
+        });
+        Ok(Self::new(TermDictionary::build(entries)?))
+    }
+
+    /// The word completions of `surface_forms`: the words of every term in at least
+    /// `MIN_WORD_DOCUMENTS` documents by `document_frequency`, weighted by the term's document
+    /// frequency split between its words by their share of its occurrences, at least 1 each.
+    pub fn words<F>(surface_forms: &SurfaceForms, document_frequency: F) -> Result<Self>
+    where
+        F: Fn(&str) -> Option<u64>,
+    {
+        let mut words: Vec<(String, u64)> = Vec::new();
+        let mut term = String::new();
+        let mut forms: Vec<(String, u64)> = Vec::new();
+        // keys are in term order, so the words of a term come one after the other
+        surface_forms
+            .entries
+            .for_each_with_prefix("", |key, occurrences| {
+                let Some((key_term, word)) = key.split_once('\0') else {
+                    return;
+                };
+                if key_term != term {
+                    split_document_frequency(document_frequency(&term), &mut forms, &mut words);
+                    term = key_term.to_string();
+                }
+                forms.push((word.to_string(), occurrences));
+            });
+        split_document_frequency(document_frequency(&term), &mut forms, &mut words);
+        Self::build(words)
+    }
+
+    /// Loads the index stored at `path`, an index without one yields no completions.
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self::new(TermDictionary::open(path)?))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.entries.save(path)
+    }
+
+    /// Returns a new index with the entries of both, adding up weights of shared entries.
+    pub fn merge(&self, other: &CompletionIndex) -> Result<Self> {
+        Ok(Self::new(self.entries.merge(&other.entries)?))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Best `limit` completions of `query`, heaviest first.
+    ///
+    /// Whole entries (titles, past queries, words) starting with the query come first in weight order.
+    /// For multi word queries the last word is also completed on its own, so "harvest mo" can
+    /// complete to "harvest moon" even if no title or past query starts with it.
+    pub fn complete(&self, query: &str, limit: usize) -> Vec<Completion> {
+        let prefix = normalize(query);
+        if prefix.is_empty() || limit == 0 {
+            return Vec::new();
+        }
+        // a trailing space means the last word is finished
+        let prefix = if query.ends_with(char::is_whitespace) {
+            format!("{prefix} ")
+        } else {
+            prefix
+        };
+
+        let mut best: HashMap<String, u64> = self
+            .top_entries(&prefix, limit, false)
+            .into_iter()
+            .collect();
+        if let Some((head, last)) = prefix.rsplit_once(' ')
+            && !last.is_empty()
+        {
+            for (word, weight) in self.top_entries(last, limit, true) {
+                let text = format!("{head} {word}");
+                let entry = best.entry(text).or_default();
+                *entry = (*entry).max(weight);
+            }
+        }
+
+        let mut completions: Vec<Completion> = best
+            .into_iter()
+            .map(|(text, weight)| Completion { text, weight })
+            .collect();
+        completions.sort_by(|a, b| b.weight.cmp(&a.weight).then_with(|| a.text.cmp(&b.text)));
+        completions.truncate(limit);
+        completions
+    }
+
+    /// The `limit` heaviest entries starting with `prefix`, heaviest first and then in text
+    /// order, only single words when `single_word`.
+    ///
+    /// A best-first search over the FST nodes below the prefix: a node is bounded by the heaviest
+    /// entry below it, so only the branches that can still hold one of the best entries are
+    /// walked, however many entries share the prefix.
+    fn top_entries(&self, prefix: &str, limit: usize, single_word: bool) -> Vec<(String, u64)> {
+        let fst = self.entries.as_fst();
+        let heaviest_below = self.heaviest_below.get_or_init(|| {
+            let mut heaviest_below = HashMap::new();
+            heaviest_entry_below(fst, fst.root(), &mut heaviest_below);
+            heaviest_below
+        });
+
+        let mut node = fst.root();
+        let mut output = 0;
+        for &byte in prefix.as_bytes() {
+            let Some(i) = node.find_input(byte) else {
+                return Vec::new();
+            };
+            let transition = node.transition(i);
+            output += transition.out.value();
+            node = fst.node(transition.addr);
+        }
+
+        let mut frontier = Frontier::new();
+        frontier.push((
+            output + heaviest_below[&node.addr()],
+            Reverse(prefix.as_bytes().to_vec()),
+            Some((node.addr(), output)),
+        ));
+        let mut best = Vec::with_capacity(limit);
+        while best.len() < limit
+            && let Some((bound, Reverse(key), next)) = frontier.pop()
+        {
+            let Some((addr, output)) = next else {
+                // no entry left in the frontier can be heavier
+                best.push((String::from_utf8_lossy(&key).into_owned(), bound));
+                continue;
+            };
+            let node = fst.node(addr);
+            if node.is_final() {
+                let weight = output + node.final_output().value();
+                frontier.push((weight, Reverse(key.clone()), None));
+            }
+            for transition in node.transitions() {
+                if single_word && transition.inp == b' ' {
+                    continue;
+                }
+                let output = output + transition.out.value();
+                let mut key = key.clone();
+                key.push(transition.inp);
+                frontier.push((
+                    output + heaviest_below[&transition.addr],
+                    Reverse(key),
+                    Some((transition.addr, output)),
+                ));
+            }
+        }
+        best
+    }
+}
+
+/// The FST nodes and entries left to visit by `CompletionIndex::top_entries`, as (bound, key,
+/// node and output of its path). Entries have no node and their weight as bound. The lowest key
+/// goes first among equal bounds, a node before the entries after its key.
+type Frontier = BinaryHeap<(u64, Reverse<Vec<u8>>, Option<(CompiledAddr, u64)>)>;
+
+/// Records the weight of the heaviest entry below `node` and every node under it in
+/// `heaviest_below`, past the outputs of the path to `node`. Returns the one of `node`.
+fn heaviest_entry_below(
+    fst: &Fst<Vec<u8>>,
+    node: Node,
+    heaviest_below: &mut HashMap<CompiledAddr, u64>,
+) -> u64 {
+    if let Some(heaviest) = heaviest_below.get(&node.addr()) {
+        return *heaviest;
+    }
+    // entries are at most `MAX_COMPLETION_LEN` chars, which bounds the recursion
+    let mut heaviest = if node.is_final() {
+        node.final_output().value()
+    } else {
+        0
+    };
+    for transition in node.transitions() {
+        let below = heaviest_entry_below(fst, fst.node(transition.addr), heaviest_below);
+        heaviest = heaviest.max(transition.out.value() + below);
+    }
+    heaviest_below.insert(node.addr(), heaviest);
+    heaviest
+}
+
+/// Moves the words of a term in `document_frequency` documents from `forms` to `words`, the
+/// frequency split between them by their share of the term's occurrences. Words of a term in
+/// fewer than `MIN_WORD_DOCUMENTS` documents are dropped, they are mostly typos, ids and numbers.
+fn split_document_frequency(
+    document_frequency: Option<u64>,
+    forms: &mut Vec<(String, u64)>,
+    words: &mut Vec<(String, u64)>,
+) {
+    let df = document_frequency.unwrap_or(0);
+    if df >= MIN_WORD_DOCUMENTS {
+        let occurrences = forms.iter().map(|(_, o)| *o).sum::<u64>().max(1);
+        words.extend(
+            forms
+                .drain(..)
+                .map(|(word, o)| (word, (df.saturating_mul(o) / occurrences).max(1))),
+        );
+    }
+    forms.clear();
+}
+
+/// The words indexed terms were analyzed from, counted at index time, so a term can be shown as
+/// the word it most often stands for, e.g. "castle" for "castl".
+///
+/// Stored in the term dictionary format under `term \0 word` keys weighted by occurrences, the
+/// words of a term are the keys under its prefix.
+#[derive(Default)]
+pub struct SurfaceForms {
+    entries: TermDictionary,
+}
+
+impl SurfaceForms {
+    /// Builds the forms from `(term, word, occurrences)` triples, words are lowercased and equal
+    /// pairs summed.
+    pub fn build<I, T, W>(forms: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (T, W, u64)>,
+        T: AsRef<str>,
+        W: AsRef<str>,
+    {
+        let entries = forms.into_iter().map(|(term, word, occurrences)| {
+            (
+                format!("{}\0{}", term.as_ref(), word.as_ref().to_lowercase()),
+                occurrences,
+            )
+        });
+        Ok(Self {
+            entries: TermDictionary::build(entries)?,
+        })
+    }
+
+    /// Loads the forms stored at `path`, an index without them has none.
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            entries: TermDictionary::open(path)?,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.entries.save(path)
+    }
+
+    /// Returns new forms with the entries of both, adding up occurrences of shared entries.
+    pub fn merge(&self, other: &SurfaceForms) -> Result<Self> {
+        Ok(Self {
+            entries: self.entries.merge(&other.entries)?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the forms of the terms `keep` returns true for.
+    pub fn retain_terms<F>(&self, keep: F) -> Result<Self>
+    where
+        F: Fn(&str) -> bool,
+    {
+        Ok(Self {
+            entries: self
+                .entries
+                .filter(|key, _| key.split_once('\0').is_some_and(|(term, _)| keep(term)))?,
+        })
+    }
+
+    /// The word `term` was most often analyzed from, the first in order among equally frequent ones.
+    pub fn most_frequent(&self, term: &str) -> Option<String> {
+        let prefix = format!("{term}\0");
+        let mut best: Option<(u64, String)> = None;
+        self.entries
+            .for_each_with_prefix(&prefix, |key, occurrences| {
+                if best.as_ref().is_none_or(|(max, _)| occurrences > *max) {
+                    best = Some((occurrences, key[prefix.len()..].to_string()));
+                }
+            });
+        best.map(|(_, word)| word)
+    }
+}
+
+/// The page titles and surface forms of the documents a SPIMI block inverts, counted by the
+/// inverter so they take from the block's memory budget, and spilled to `COMPLETION_COUNTS_DIR`
+/// with the block.
+#[derive(Default)]
+pub struct CompletionCounts {
+    /// Normalized titles and the pages they are the title of.
+    titles: HashMap<String, u64>,
+    /// Occurrences of `term \0 word` keys, as in `SurfaceForms`.
+    surface_forms: HashMap<String, u64>,
+    heap_bytes: usize,
+}
+
+impl CompletionCounts {
+    /// Counts one more page titled `title`, titles not worth completing to are skipped.
+    pub fn add_title(&mut self, title: &str) {
+        let title = normalize(title);
+        if title.is_empty() || title.chars().count() > MAX_COMPLETION_LEN {
+            return;
+        }
+        Self::add(&mut self.titles, &mut self.heap_bytes, title, 1);
+    }
+
+    /// Counts `occurrences` more of `word` analyzed to `term`.
+    pub fn add_surface_form(&mut self, term: &str, word: &str, occurrences: u64) {
+        let key = format!("{term}\0{}", word.to_lowercase());
+        Self::add(
+            &mut self.surface_forms,
+            &mut self.heap_bytes,
+            key,
+            occurrences,
+        );
+    }
+
+    fn add(counts: &mut HashMap<String, u64>, heap_bytes: &mut usize, key: String, count: u64) {
+        let capacity = counts.capacity();
+        let key_bytes = key.capacity();
+        let entry = counts.entry(key).or_insert_with(|| {
+            *heap_bytes += key_bytes;
+            0
+        });
+        *entry += count;
+        // a slot and a control byte per bucket, roughly
+        *heap_bytes += (counts.capacity() - capacity) * (size_of::<(String, u64)>() + 1);
+    }
+
+    /// Heap bytes held by the counts, estimated like those of the block dictionary.
+    pub fn heap_bytes(&self) -> usize {
+        self.heap_bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.titles.is_empty() && self.surface_forms.is_empty()
+    }
+
+    /// Writes the counts to the files `name.titles.fst` and `name.forms.fst` in `dir`.
+    pub fn spill(self, dir: &Path, name: &str) -> Result<()> {
+        let titles = self
+            .titles
+            .into_iter()
+            .map(|(title, pages)| (title, pages * TITLE_WEIGHT));
+        TermDictionary::build(titles)?.save(&dir.join(format!("{name}.titles.fst")))?;
+        TermDictionary::build(self.surface_forms)?.save(&dir.join(format!("{name}.forms.fst")))
+    }
+
+    /// Adds up the counts spilled to `dir`, as title completions weighted by `TITLE_WEIGHT` and
+    /// surface forms.
+    pub fn read_spilled(dir: &Path) -> Result<(CompletionIndex, SurfaceForms)> {
+        let mut titles = CompletionIndex::default();
+        let mut surface_forms = SurfaceForms::default();
+        if !dir.exists() {
+            return Ok((titles, surface_forms));
+        }
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to list completion counts {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.ends_with(".titles.fst") {
+                titles = titles.merge(&CompletionIndex::open(&path)?)?;
+            } else if name.ends_with(".forms.fst") {
+                surface_forms = surface_forms.merge(&SurfaceForms::open(&path)?)?;
+            }
+        }
+        Ok((titles, surface_forms))
+    }
+
+    /// Removes the counts spilled to `dir`.
+    pub fn remove_spilled(dir: &Path) -> Result<()> {
+        match std::fs::remove_dir_all(dir) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err)
+                .with_context(|| format!("Failed to remove completion counts {}", dir.display())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Lowercases `text` and collapses its whitespace, the form completions are stored and looked up in.
+pub fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .map(|w| w.to_lowercase())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completions() -> CompletionIndex {
+        CompletionIndex::build([
+            ("harvest", 12),
+            ("harvesting", 4),
+            ("Harvard University", 10),
+            ("harvest moon", 20),
+            ("moon", 30),
+            ("mooring", 2),
+        ])
+        .unwrap()
+    }
+
+    fn texts(completions: Vec<Completion>) -> Vec<String> {
+        completions.into_iter().map(|c| c.text).collect()
+    }
+
+    #[test]
+    fn test_complete_ranks_by_weight() {
+        let index = completions();
+        assert_eq!(
+            texts(index.complete("Harv", 10)),
+            vec![
+                "harvest moon",
+                "harvest",
+                "harvard university",
+                "harvesting"
+            ]
+        );
+        assert_eq!(
+            texts(index.complete("harv", 2)),
+            vec!["harvest moon", "harvest"]
+        );
+        assert!(index.complete("  ", 10).is_empty());
+        assert!(index.complete("zebra", 10).is_empty());
+    }
+
+    #[test]
+    fn test_complete_last_word_of_phrase() {
+        let index = completions();
+        assert_eq!(
+            texts(index.complete("harvest  mo", 10)),
+            vec!["harvest moon", "harvest mooring"]
+        );
+        // the finished word "harvest" only completes to phrases
+        assert_eq!(texts(index.complete("harvest ", 10)), vec!["harvest moon"]);
+    }
+
+    #[test]
+    fn test_surface_forms_most_frequent() {
+        let forms = SurfaceForms::build([
+            ("castl", "castles", 4),
+            ("castl", "Castle", 5),
+            ("castl", "castle", 4),
+            ("cast", "cast", 20),
+            ("castli", "castling", 30),
+        ])
+        .unwrap();
+        assert_eq!(forms.most_frequent("castl").as_deref(), Some("castle"));
+        assert_eq!(forms.most_frequent("cast").as_deref(), Some("cast"));
+        assert_eq!(forms.most_frequent("cas"), None);
+
+        let merged = forms
+            .merge(&SurfaceForms::build([("castl", "castles", 10)]).unwrap())
+            .unwrap();
+        assert_eq!(merged.most_frequent("castl").as_deref(), Some("castles"));
+    }
+
+    #[test]
+    fn test_merge_adds_weights() {
+        let merged = completions()
+            .merge(&CompletionIndex::build([("harvesting", 10)]).unwrap())
+            .unwrap();
+        assert_eq!(
+            merged.complete("harvesti", 1),
+            vec![Completion {
+                text: "harvesting".to_string(),
+                weight: 14
+            }]
+        );
+    }
+
+    #[test]
+    fn test_complete_matches_a_full_scan() {
+        // weights cycling through a few values, so many entries tie
+        let entries: Vec<(String, u64)> = (0..2_000_u64)
+            .map(|i| (format!("h{:x} w{}", i * 7919 % 4096, i % 3), i * 31 % 97))
+            .collect();
+        let index = CompletionIndex::build(entries.clone()).unwrap();
+        for prefix in ["h", "h1", "hab", "h4 w", "hfff w2"] {
+            let mut scanned: Vec<(String, u64)> = entries
+                .iter()
+                .filter(|(text, _)| text.starts_with(prefix))
+                .cloned()
+                .collect();
+            scanned.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            scanned.truncate(7);
+            assert_eq!(
+                index.top_entries(prefix, 7, false),
+                scanned,
+                "prefix {prefix}"
+            );
+        }
+        assert!(index.top_entries("x", 7, false).is_empty());
+        assert!(
+            index
+                .top_entries("h1", 20, true)
+                .iter()
+                .all(|(text, _)| !text.contains(' '))
+        );
+    }
+
+    #[test]
+    fn test_words_split_document_frequency() {
+        let forms = SurfaceForms::build([
+            ("castl", "castles", 1),
+            ("castl", "castle", 3),
+            ("moat", "moat", 1),
+            ("tower", "towers", 1),
+        ])
+        .unwrap();
+        let df = |term: &str| match term {
+            "castl" => Some(8),
+            "moat" => Some(1),
+            _ => None,
+        };
+        let words = CompletionIndex::words(&forms, df).unwrap();
+        assert_eq!(
+            words.complete("c", 10),
+            vec![
+                Completion {
+                    text: "castle".to_string(),
+                    weight: 6
+                },
+                Completion {
+                    text: "castles".to_string(),
+                    weight: 2
+                }
+            ]
+        );
+        // a word of a single document or of no indexed term is not completed
+        assert!(words.complete("m", 10).is_empty());
+        assert!(words.complete("t", 10).is_empty());
+
+        let forms = forms.retain_terms(|term| df(term).is_some()).unwrap();
+        assert_eq!(forms.len(), 3);
+        assert_eq!(forms.most_frequent("tower"), None);
+    }
+
+    #[test]
+    fn test_spilled_counts_add_up() {
+        let dir =
+            std::env::temp_dir().join(format!("harvest_completion_counts_{}", std::process::id()));
+        let mut counts = CompletionCounts::default();
+        counts.add_title("Harvest  Moon");
+        counts.add_title("");
+        counts.add_surface_form("castl", "Castle", 2);
+        assert!(counts.heap_bytes() > 0);
+        counts.spill(&dir, "a").unwrap();
+        let mut counts = CompletionCounts::default();
+        counts.add_title("harvest moon");
+        counts.add_surface_form("castl", "castles", 3);
+        counts.spill(&dir, "b").unwrap();
+
+        let (titles, surface_forms) = CompletionCounts::read_spilled(&dir).unwrap();
+        assert_eq!(
+            titles.complete("harv", 10),
+            vec![Completion {
+                text: "harvest moon".to_string(),
+                weight: 2 * TITLE_WEIGHT
+            }]
+        );
+        assert_eq!(surface_forms.len(), 2);
+        assert_eq!(
+            surface_forms.most_frequent("castl").as_deref(),
+            Some("castles")
+        );
+
+        CompletionCounts::remove_spilled(&dir).unwrap();
+        assert!(!dir.exists());
+        assert!(CompletionCounts::read_spilled(&dir).unwrap().0.is_empty());
+        CompletionCounts::remove_spilled(&dir).unwrap();
+    }
+}
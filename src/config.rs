@@ -7,12 +7,18 @@ pub static CONFIG: Lazy<Config> = Lazy::new(|| {
     Config {
         mongo_uri: get_env("MONGO_URI"),
         mongo_db_name: get_env_or_default("MONGO_DB_NAME", "harvest"),
+        index_dir: get_env_or_default("INDEX_DIR", "index"),
+        analyzers_file: env::var("ANALYZERS_FILE").ok(),
     }
 });
 
 pub struct Config {
     pub mongo_uri: String,
     pub mongo_db_name: String,
+    /// Directory holding the on-disk index files (term dictionary, ...)
+    pub index_dir: String,
+    /// JSON file defining analyzers besides the built-in ones, see `AnalyzerRegistry`
+    pub analyzers_file: Option<String>,
 }
 
 fn get_env(key: &str) -> String {
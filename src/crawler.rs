@@ -10,7 +10,7 @@ use tokio::sync::Semaphore;
 use tokio::sync::mpsc;
 
 use crate::data_models::Page;
-use crate::db::PageRepo;
+use crate::storage::PageStore;
 
 const MAX_FETCH_RETRIES: usize = 4;
 const MAX_DOCUMENT_SIZE_BYTES: usize = 15 * 1024 * 1024; // 15 MB (leaving margin for MongoDB's 16MB limit)
@@ -47,7 +47,7 @@ impl From<reqwest::Error> for FetchPageError {
 pub struct Crawler {
     visited_urls: DashSet<String>,
     max_depth: usize,
-    pages_repo: Arc<PageRepo>,
+    pages_repo: Arc<dyn PageStore>,
     crawl_tx: mpsc::Sender<(String, usize, bool)>,
     crawl_rx: Mutex<mpsc::Receiver<(String, usize, bool)>>,
     fetched_tx: mpsc::UnboundedSender<Page>,
@@ -58,7 +58,7 @@ pub struct Crawler {
 impl Crawler {
     pub fn new(
         max_depth: usize,
-        pages_repo: PageRepo,
+        pages_repo: impl PageStore + 'static,
         max_concurrent_fetches: usize,
         frontier_size: usize,
     ) -> Crawler {
@@ -3,22 +3,28 @@ use std::collections::HashMap;
 use mongodb::bson::{DateTime, oid::ObjectId};
 use serde::{Deserialize, Serialize};
 
-// Helper module for serializing HashMap<ObjectId, T> as HashMap<String, T>
-mod objectid_hashmap_serde {
+use crate::analyzer::DEFAULT_ANALYZER;
+
+/// Dense internal id of an indexed page, assigned by the indexer in indexing order.
+/// Postings refer to pages by doc id, the `doc_ids` table maps them back to page `ObjectId`s.
+pub type DocId = u32;
+
+// Helper module for serializing HashMap<DocId, T> as HashMap<String, T>, BSON keys are strings
+mod doc_id_hashmap_serde {
     use super::*;
     use serde::{Deserializer, Serializer};
     use std::collections::HashMap;
 
-    pub fn serialize<S, T>(map: &HashMap<ObjectId, T>, serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S, T>(map: &HashMap<DocId, T>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
         T: Serialize,
     {
-        let string_map: HashMap<String, &T> = map.iter().map(|(k, v)| (k.to_hex(), v)).collect();
+        let string_map: HashMap<String, &T> = map.iter().map(|(k, v)| (k.to_string(), v)).collect();
         string_map.serialize(serializer)
     }
 
-    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<HashMap<ObjectId, T>, D::Error>
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<HashMap<DocId, T>, D::Error>
     where
         D: Deserializer<'de>,
         T: Deserialize<'de>,
@@ -27,8 +33,8 @@ mod objectid_hashmap_serde {
         string_map
             .into_iter()
             .map(|(k, v)| {
-                ObjectId::parse_str(&k)
-                    .map(|oid| (oid, v))
+                k.parse::<DocId>()
+                    .map(|doc_id| (doc_id, v))
                     .map_err(serde::de::Error::custom)
             })
             .collect()
@@ -50,6 +56,9 @@ pub struct Page {
     pub crawled_at: DateTime,
     #[serde(default)]
     pub indexed: bool,
+    /// Index run that tokenized the page, it's indexed once that run's merge commits
+    #[serde(default)]
+    pub index_run: Option<ObjectId>,
 }
 
 impl Page {
@@ -72,6 +81,7 @@ impl Page {
             is_seed,
             crawled_at: DateTime::now(),
             indexed: false,
+            index_run: None,
         }
     }
 }
@@ -84,9 +94,9 @@ pub struct SpimiDoc {
     pub term: String,
     pub bucket: i16,
     pub document_frequency: u64,
-    pub postings: Vec<ObjectId>,
-    #[serde(with = "objectid_hashmap_serde")]
-    pub positions: HashMap<ObjectId, Vec<usize>>,
+    pub postings: Vec<DocId>,
+    #[serde(with = "doc_id_hashmap_serde")]
+    pub positions: HashMap<DocId, Vec<usize>>,
 }
 
 impl SpimiDoc {
@@ -94,8 +104,8 @@ impl SpimiDoc {
         term: String,
         bucket: i16,
         document_frequency: u64,
-        postings: Vec<ObjectId>,
-        positions: HashMap<ObjectId, Vec<usize>>,
+        postings: Vec<DocId>,
+        positions: HashMap<DocId, Vec<usize>>,
     ) -> SpimiDoc {
         SpimiDoc {
             id: ObjectId::new(),
@@ -115,9 +125,9 @@ pub struct InvertedIndexDoc {
     pub term: String,
     pub bucket: i16,
     pub document_frequency: u64,
-    pub postings: Vec<ObjectId>,
-    #[serde(with = "objectid_hashmap_serde")]
-    pub positions: HashMap<ObjectId, Vec<usize>>,
+    pub postings: Vec<DocId>,
+    #[serde(with = "doc_id_hashmap_serde")]
+    pub positions: HashMap<DocId, Vec<usize>>,
 }
 
 impl InvertedIndexDoc {
@@ -125,8 +135,8 @@ impl InvertedIndexDoc {
         term: String,
         bucket: i16,
         document_frequency: u64,
-        postings: Vec<ObjectId>,
-        positions: HashMap<ObjectId, Vec<usize>>,
+        postings: Vec<DocId>,
+        positions: HashMap<DocId, Vec<usize>>,
     ) -> InvertedIndexDoc {
         InvertedIndexDoc {
             id: ObjectId::new(),
@@ -140,21 +150,31 @@ impl InvertedIndexDoc {
 }
 
 /// Checkpoint for tracking merge progress to enable resumption after crashes.
-/// Stores the last successfully merged term + bucket per SPIMI block collection.
+/// Stores the last successfully merged term + bucket per SPIMI block.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MergeCheckpoint {
     #[serde(rename = "_id")]
     pub id: ObjectId,
-    /// Name of the SPIMI block collection being merged
+    /// Name of the SPIMI block being merged
     pub collection_name: String,
     /// Last term that was successfully flushed to inverted_index
     pub last_merged_term: Option<String>,
-    /// Bucket number for the last term (for multi-bucket terms)
+    /// Bucket number for the last term (for multi-bucket terms), counted from the first bucket
+    /// the merge wrote for the term
     pub last_merged_bucket: i16,
     /// Timestamp of last update
     pub updated_at: DateTime,
     /// Whether this block has been fully merged
     pub completed: bool,
+    /// First term of the range of the block this checkpoint tracks, `None` from the first term
+    #[serde(default)]
+    pub range_start: Option<String>,
+    /// Term the range ends before, `None` up to the last term
+    #[serde(default)]
+    pub range_end: Option<String>,
+    /// Index run whose blocks are merged, its pages are marked indexed once the merge commits
+    #[serde(default)]
+    pub index_run: Option<ObjectId>,
 }
 
 impl MergeCheckpoint {
@@ -166,6 +186,258 @@ impl MergeCheckpoint {
             last_merged_bucket: -1,
             updated_at: DateTime::now(),
             completed: false,
+            range_start: None,
+            range_end: None,
+            index_run: None,
+        }
+    }
+
+    /// Checkpoint of merging the terms of a block from `range_start` to before `range_end`.
+    pub fn with_range(
+        collection_name: String,
+        range_start: Option<String>,
+        range_end: Option<String>,
+    ) -> Self {
+        Self {
+            range_start,
+            range_end,
+            ..Self::new(collection_name)
         }
     }
 }
+
+/// Generations a delta generation can be layered on before an index run folds them into a new
+/// base generation.
+pub const MAX_GENERATION_LAYERS: usize = 8;
+
+/// The generations of the inverted index, each one in its own collection. A base generation is a
+/// complete index, a delta one holds the buckets of a single index run and is read on top of the
+/// generations it's layered on. Index runs build a new generation while queries keep reading the
+/// current one, committing it swaps the two in a single write.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexManifest {
+    /// Generation queries read, 0 is the index from before generations
+    pub current: u64,
+    /// Generation `harvest index rollback` returns to
+    pub previous: Option<u64>,
+    /// Generation an index run is writing, left behind by an interrupted run until it resumes
+    pub building: Option<u64>,
+    /// Generations `current`, `previous` and `building` are layered on, newest first, empty for
+    /// base generations
+    #[serde(default)]
+    pub current_layers: Vec<u64>,
+    #[serde(default)]
+    pub previous_layers: Vec<u64>,
+    #[serde(default)]
+    pub building_layers: Vec<u64>,
+    /// Analyzers `current`, `previous` and `building` were built with, `None` for generations
+    /// from before analyzers were recorded, built with the default one
+    #[serde(default)]
+    pub current_analyzer: Option<String>,
+    #[serde(default)]
+    pub previous_analyzer: Option<String>,
+    #[serde(default)]
+    pub building_analyzer: Option<String>,
+    pub updated_at: DateTime,
+}
+
+impl Default for IndexManifest {
+    fn default() -> Self {
+        Self {
+            current: 0,
+            previous: None,
+            building: None,
+            current_layers: Vec::new(),
+            previous_layers: Vec::new(),
+            building_layers: Vec::new(),
+            current_analyzer: None,
+            previous_analyzer: None,
+            building_analyzer: None,
+            updated_at: DateTime::now(),
+        }
+    }
+}
+
+impl IndexManifest {
+    /// Analyzer of the generation queries read.
+    pub fn analyzer(&self) -> &str {
+        self.current_analyzer.as_deref().unwrap_or(DEFAULT_ANALYZER)
+    }
+
+    /// Analyzer of the generation index runs write to: the one being built, or else the current one.
+    pub fn run_analyzer(&self) -> &str {
+        match self.building {
+            Some(_) => self
+                .building_analyzer
+                .as_deref()
+                .unwrap_or(DEFAULT_ANALYZER),
+            None => self.analyzer(),
+        }
+    }
+
+    /// Fails unless a run analyzing with `analyzer` can write to the generation being built, or
+    /// to one on top of the current one when `extend_current` is set.
+    pub fn ensure_run_analyzer(&self, extend_current: bool, analyzer: &str) -> anyhow::Result<()> {
+        if let Some(building) = self.building {
+            anyhow::ensure!(
+                self.run_analyzer() == analyzer,
+                "Index generation {} is being built with analyzer '{}', not '{}'",
+                building,
+                self.run_analyzer(),
+                analyzer
+            );
+        } else if extend_current {
+            anyhow::ensure!(
+                self.analyzer() == analyzer,
+                "Index generation {} is built with analyzer '{}', not '{}', rebuild the index to change it",
+                self.current,
+                self.analyzer(),
+                analyzer
+            );
+        }
+        Ok(())
+    }
+
+    /// `generation` followed by the generations it's layered on, newest first, the generations
+    /// reads of `generation` go through.
+    pub fn stack(&self, generation: u64) -> Vec<u64> {
+        let layers: &[u64] = if self.building == Some(generation) {
+            &self.building_layers
+        } else if self.current == generation {
+            &self.current_layers
+        } else if self.previous == Some(generation) {
+            &self.previous_layers
+        } else {
+            &[]
+        };
+        std::iter::once(generation)
+            .chain(layers.iter().copied())
+            .collect()
+    }
+
+    /// Layers of a new delta generation on top of the current one, `None` once the current one
+    /// has `MAX_GENERATION_LAYERS` layers and the next run starts a base generation instead.
+    pub fn delta_layers(&self) -> Option<Vec<u64>> {
+        (self.current_layers.len() < MAX_GENERATION_LAYERS).then(|| self.stack(self.current))
+    }
+
+    /// Records `building` as the generation being built with `analyzer`, layered on `layers`.
+    pub fn begin_building(&mut self, building: u64, layers: Vec<u64>, analyzer: &str) {
+        self.building = Some(building);
+        self.building_layers = layers;
+        self.building_analyzer = Some(analyzer.to_string());
+        self.updated_at = DateTime::now();
+    }
+
+    /// Makes the generation being built current, keeping the one it replaces as previous.
+    /// Returns the generations no longer read by the current or previous one.
+    pub fn commit_building(&mut self, building: u64) -> Vec<u64> {
+        let old = self
+            .previous
+            .map(|previous| self.stack(previous))
+            .unwrap_or_default();
+        self.previous = Some(self.current);
+        self.previous_layers = std::mem::take(&mut self.current_layers);
+        self.current = building;
+        self.current_layers = std::mem::take(&mut self.building_layers);
+        self.building = None;
+        self.previous_analyzer = self.current_analyzer.take();
+        self.current_analyzer = self.building_analyzer.take();
+        self.updated_at = DateTime::now();
+        self.unreferenced(old)
+    }
+
+    /// Makes `previous` current again. Returns the generations no longer read by it.
+    pub fn rollback_to(&mut self, previous: u64) -> Vec<u64> {
+        let old = self.stack(self.current);
+        self.current = previous;
+        self.current_layers = std::mem::take(&mut self.previous_layers);
+        self.previous = None;
+        self.current_analyzer = self.previous_analyzer.take();
+        self.updated_at = DateTime::now();
+        self.unreferenced(old)
+    }
+
+    /// Forgets the previous generation, which can't be rolled back to anymore. Returns the
+    /// generations no longer read.
+    pub fn drop_previous(&mut self) -> Vec<u64> {
+        let Some(previous) = self.previous.take() else {
+            return Vec::new();
+        };
+        let old = std::iter::once(previous)
+            .chain(std::mem::take(&mut self.previous_layers))
+            .collect();
+        self.previous_analyzer = None;
+        self.updated_at = DateTime::now();
+        self.unreferenced(old)
+    }
+
+    /// The oldest generation the current or the previous one reads.
+    pub fn oldest_kept(&self) -> u64 {
+        self.kept().into_iter().min().unwrap_or(self.current)
+    }
+
+    /// The generations the current and the previous one read.
+    fn kept(&self) -> Vec<u64> {
+        let mut kept = self.stack(self.current);
+        if let Some(previous) = self.previous {
+            kept.extend(self.stack(previous));
+        }
+        kept
+    }
+
+    /// The generations of `generations` neither the current nor the previous one reads.
+    fn unreferenced(&self, generations: Vec<u64>) -> Vec<u64> {
+        let kept = self.kept();
+        generations
+            .into_iter()
+            .filter(|generation| !kept.contains(generation))
+            .collect()
+    }
+}
+
+/// A query searched through the API that had hits, feeding the autocomplete completions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueryLogEntry {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    /// Normalized (lowercased, single spaced) query text
+    pub query: String,
+    /// How many times the query was searched
+    pub count: i64,
+    /// Searches not yet counted into the completion index by an indexer run
+    pub pending: i64,
+    pub last_searched_at: DateTime,
+}
+
+/// Maps a dense doc id back to the page it was assigned to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DocIdEntry {
+    #[serde(rename = "_id")]
+    pub doc_id: DocId,
+    pub page_id: ObjectId,
+}
+
+/// A tombstoned doc id, whose postings queries ignore.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeletedDocId {
+    #[serde(rename = "_id")]
+    pub doc_id: DocId,
+    pub page_id: ObjectId,
+    /// The generation whose commit replaced it, when its page was indexed again
+    #[serde(default)]
+    pub replaced_in: Option<u64>,
+    /// The generation written without its postings, once compaction purged it
+    #[serde(default)]
+    pub purged_in: Option<u64>,
+}
+
+/// A doc id replaced by the one an index run assigned its page, tombstoned once the generation
+/// of that run is committed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplacedDocId {
+    #[serde(rename = "_id")]
+    pub doc_id: DocId,
+    pub page_id: ObjectId,
+    pub index_run: ObjectId,
+}
@@ -1,8 +1,8 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use mongodb::options::ClientOptions;
 use mongodb::{
     Client, Collection, Database as MongoDatabase,
-    bson::{Document, doc, oid::ObjectId, to_document},
+    bson::{Bson, Document, doc, oid::ObjectId, to_document},
 };
 use once_cell::sync::OnceCell;
 use serde::{Serialize, de::DeserializeOwned};
@@ -17,7 +17,13 @@ static DB: OnceCell<Database> = OnceCell::new();
 pub mod collections {
     pub const PAGES: &str = "pages";
     pub const INDEX: &str = "inverted_index";
+    pub const INDEX_MANIFEST: &str = "index_manifest";
     pub const MERGE_CHECKPOINTS: &str = "merge_checkpoints";
+    pub const QUERY_LOG: &str = "query_log";
+    pub const DOC_IDS: &str = "doc_ids";
+    pub const COUNTERS: &str = "counters";
+    pub const DELETED_DOCS: &str = "deleted_docs";
+    pub const REPLACED_DOCS: &str = "replaced_docs";
 }
 
 /// Main database wrapper providing connection management and collection access
@@ -99,6 +105,10 @@ impl Database {
         &self.db
     }
 
+    pub fn name(&self) -> &str {
+        self.db.name()
+    }
+
     // Collection accessors - add typed accessors for each collection
 
     /// Get the pages collection
@@ -295,6 +305,11 @@ impl PageRepo {
         serialized.remove("_id");
         let exists = self.find_by_url(&page.url).await?;
         if let Some(existing) = exists {
+            if existing.html_body == page.html_body {
+                // unchanged pages are not reindexed
+                serialized.remove("indexed");
+                serialized.remove("index_run");
+            }
             self.repo
                 .collection
                 .update_one(doc! { "url": &page.url}, doc! {"$set": serialized})
@@ -327,15 +342,15 @@ impl PageRepo {
         self.repo.find(doc! { "depth": depth }).await
     }
 
-    /// Delete by URL
-    pub async fn delete_by_url(&self, url: &str) -> Result<bool> {
-        let result = self
+    /// Delete by URL, returning the id of the deleted page
+    pub async fn delete_by_url(&self, url: &str) -> Result<Option<ObjectId>> {
+        let deleted = self
             .repo
             .collection
-            .delete_one(doc! { "url": url })
+            .find_one_and_delete(doc! { "url": url })
             .await
             .context("Failed to delete by URL")?;
-        Ok(result.deleted_count > 0)
+        Ok(deleted.map(|page| page.id))
     }
 
     /// List all pages
@@ -426,6 +441,11 @@ impl PageRepo {
         Ok((pages, next_cursor))
     }
 
+    /// Find the pages with the given IDs
+    pub async fn find_by_ids(&self, ids: &[ObjectId]) -> Result<Vec<Page>> {
+        self.repo.find(doc! { "_id": { "$in": ids } }).await
+    }
+
     /// Mark a page as indexed
     pub async fn mark_as_indexed(&self, id: ObjectId) -> Result<bool> {
         self.update(id, doc! { "indexed": true }).await
@@ -437,32 +457,317 @@ impl PageRepo {
             .update_many(doc! { "_id": {"$in": ids}}, doc! { "indexed": true })
             .await
     }
+
+    /// Record that the pages were tokenized by the index run `index_run`
+    pub async fn mark_many_as_tokenized(
+        &self,
+        ids: &[ObjectId],
+        index_run: ObjectId,
+    ) -> Result<u64> {
+        self.repo
+            .update_many(doc! { "_id": {"$in": ids}}, doc! { "index_run": index_run })
+            .await
+    }
+
+    /// Mark the pages tokenized by the given index runs as indexed
+    pub async fn mark_runs_as_indexed(&self, index_runs: &[ObjectId]) -> Result<u64> {
+        self.repo
+            .update_many(
+                doc! { "index_run": {"$in": index_runs}, "indexed": {"$ne": true}},
+                doc! { "indexed": true },
+            )
+            .await
+    }
+
+    /// Mark every page unindexed
+    pub async fn mark_all_as_unindexed(&self) -> Result<u64> {
+        self.repo
+            .update_many(doc! { "indexed": true }, doc! { "indexed": false })
+            .await
+    }
+
+    /// Mark multiple pages as unindexed
+    pub async fn mark_many_as_unindexed(&self, ids: &[ObjectId]) -> Result<u64> {
+        self.repo
+            .update_many(doc! { "_id": {"$in": ids}}, doc! { "indexed": false })
+            .await
+    }
+
+    /// Write the pages as they are, replacing the pages with the same id or URL
+    pub async fn restore(&self, pages: &[Page]) -> Result<u64> {
+        for page in pages {
+            self.repo
+                .delete_many(doc! { "url": &page.url, "_id": { "$ne": page.id } })
+                .await?;
+            self.repo
+                .collection
+                .replace_one(doc! { "_id": page.id }, page)
+                .upsert(true)
+                .await
+                .context("Failed to restore page")?;
+        }
+        Ok(pages.len() as u64)
+    }
 }
 
 // InvertedIndex-specific operations for incremental indexing
 
-use crate::data_models::InvertedIndexDoc;
+use crate::data_models::{IndexManifest, InvertedIndexDoc};
+
+/// Collection of an index generation, generation 0 is the `inverted_index` collection from
+/// before generations.
+pub fn generation_collection(generation: u64) -> String {
+    match generation {
+        0 => collections::INDEX.to_string(),
+        generation => format!("{}_v{}", collections::INDEX, generation),
+    }
+}
 
-/// Extended operations specific to InvertedIndex collection
+/// Extended operations specific to InvertedIndex collection.
+/// Reads go through the current generation of the manifest and the ones it's layered on, writes
+/// go to the current generation. A pinned repo reads and writes its generation alone.
 pub struct InvertedIndexRepo {
-    collection: Collection<InvertedIndexDoc>,
+    db: Database,
+    manifest: Collection<IndexManifest>,
+    generation: Option<u64>,
 }
 
 impl InvertedIndexRepo {
     pub fn new(db: &Database) -> Self {
         Self {
-            collection: db.collection(collections::INDEX),
+            db: db.clone(),
+            manifest: db.collection(collections::INDEX_MANIFEST),
+            generation: None,
+        }
+    }
+
+    /// Repo on `generation` alone.
+    pub fn pinned(&self, generation: u64) -> InvertedIndexRepo {
+        Self {
+            db: self.db.clone(),
+            manifest: self.manifest.clone(),
+            generation: Some(generation),
+        }
+    }
+
+    /// Collection of the generation this repo writes.
+    pub async fn collection(&self) -> Result<Collection<InvertedIndexDoc>> {
+        let generation = match self.generation {
+            Some(generation) => generation,
+            None => self.manifest().await?.current,
+        };
+        Ok(self.db.collection(&generation_collection(generation)))
+    }
+
+    /// Collections of the generations this repo reads, oldest first.
+    async fn read_collections(&self) -> Result<Vec<Collection<InvertedIndexDoc>>> {
+        let stack = match self.generation {
+            Some(generation) => vec![generation],
+            None => {
+                let manifest = self.manifest().await?;
+                manifest.stack(manifest.current)
+            }
+        };
+        Ok(stack
+            .into_iter()
+            .rev()
+            .map(|generation| self.db.collection(&generation_collection(generation)))
+            .collect())
+    }
+
+    /// The manifest of the index generations, the default one before the first commit.
+    pub async fn manifest(&self) -> Result<IndexManifest> {
+        Ok(self
+            .manifest
+            .find_one(doc! { "_id": collections::INDEX })
+            .await
+            .context("Failed to read the index manifest")?
+            .unwrap_or_default())
+    }
+
+    /// Storage and index bytes of the collections this repo reads, 0 for those not created yet.
+    pub async fn storage_bytes(&self) -> Result<u64> {
+        let mut total = 0;
+        for collection in self.read_collections().await? {
+            let existing = self
+                .db
+                .database()
+                .list_collection_names()
+                .filter(doc! { "name": collection.name() })
+                .await
+                .context("Failed to list collections")?;
+            if existing.is_empty() {
+                continue;
+            }
+            let stats = self
+                .db
+                .database()
+                .run_command(doc! { "collStats": collection.name() })
+                .await
+                .context("Failed to read the index collection stats")?;
+            let bytes = |field: &str| match stats.get(field) {
+                Some(Bson::Int32(n)) => *n as u64,
+                Some(Bson::Int64(n)) => *n as u64,
+                Some(Bson::Double(n)) => *n as u64,
+                _ => 0,
+            };
+            total += bytes("storageSize") + bytes("totalIndexSize");
+        }
+        Ok(total)
+    }
+
+    async fn save_manifest(&self, manifest: &IndexManifest) -> Result<()> {
+        let manifest = to_document(manifest).context("Failed to serialize the index manifest")?;
+        self.manifest
+            .update_one(
+                doc! { "_id": collections::INDEX },
+                doc! { "$set": manifest },
+            )
+            .upsert(true)
+            .await
+            .context("Failed to write the index manifest")?;
+        Ok(())
+    }
+
+    async fn drop_generation(&self, generation: u64) -> Result<()> {
+        self.db
+            .collection::<InvertedIndexDoc>(&generation_collection(generation))
+            .drop()
+            .await
+            .with_context(|| format!("Failed to drop index generation {generation}"))
+    }
+
+    /// Repo on the generation being built, the one an interrupted run left behind or else a new
+    /// one built with `analyzer`. With `extend_current` the new generation starts empty as a
+    /// delta layered on the current one, or once that has `MAX_GENERATION_LAYERS` layers, as a
+    /// base generation the layers are folded into with `$out`, which writes every bucket again.
+    pub async fn begin_generation(
+        &self,
+        extend_current: bool,
+        analyzer: &str,
+    ) -> Result<InvertedIndexRepo> {
+        let mut manifest = self.manifest().await?;
+        manifest.ensure_run_analyzer(extend_current, analyzer)?;
+        let generation = match manifest.building {
+            Some(generation) => {
+                log::info!("Resuming index generation {}", generation);
+                generation
+            }
+            None => {
+                let generation = manifest.current + 1;
+                let layers = match (extend_current, manifest.delta_layers()) {
+                    (true, Some(layers)) => {
+                        self.drop_generation(generation).await?;
+                        layers
+                    }
+                    (true, None) => {
+                        self.fold_generation(&manifest, generation).await?;
+                        Vec::new()
+                    }
+                    (false, _) => {
+                        self.drop_generation(generation).await?;
+                        Vec::new()
+                    }
+                };
+                manifest.begin_building(generation, layers, analyzer);
+                self.save_manifest(&manifest).await?;
+                generation
+            }
+        };
+        Ok(self.pinned(generation))
+    }
+
+    /// Writes the buckets of the current generation and its layers into `generation`, numbering
+    /// the buckets of every term in layer order.
+    async fn fold_generation(&self, manifest: &IndexManifest, generation: u64) -> Result<()> {
+        log::info!(
+            "Folding index generation {} and its {} layers into generation {}",
+            manifest.current,
+            manifest.current_layers.len(),
+            generation
+        );
+        let mut stack = manifest.stack(manifest.current);
+        stack.reverse();
+        let mut pipeline = vec![doc! { "$set": { "layer": 0 } }];
+        for (layer, layer_generation) in stack.iter().enumerate().skip(1) {
+            pipeline.push(doc! { "$unionWith": {
+                "coll": generation_collection(*layer_generation),
+                "pipeline": [{ "$set": { "layer": layer as i32 } }],
+            }});
+        }
+        pipeline.extend([
+            doc! { "$setWindowFields": {
+                "partitionBy": "$term",
+                "sortBy": { "layer": 1, "bucket": 1 },
+                "output": { "rank": { "$documentNumber": {} } },
+            }},
+            doc! { "$set": { "bucket": { "$toInt": { "$subtract": ["$rank", 1] } } } },
+            doc! { "$unset": ["layer", "rank"] },
+            doc! { "$out": generation_collection(generation) },
+        ]);
+        self.db
+            .collection::<InvertedIndexDoc>(&generation_collection(stack[0]))
+            .aggregate(pipeline)
+            .await
+            .with_context(|| format!("Failed to fold the index into generation {generation}"))?;
+        Ok(())
+    }
+
+    /// Makes the generation being built current in a single manifest write, then drops the
+    /// generations neither it nor the replaced one reads.
+    pub async fn commit_generation(&self) -> Result<u64> {
+        let mut manifest = self.manifest().await?;
+        let Some(building) = manifest.building else {
+            bail!("No index generation is being built");
+        };
+        let dropped = manifest.commit_building(building);
+        self.save_manifest(&manifest).await?;
+        for generation in dropped {
+            self.drop_generation(generation).await?;
+        }
+        Ok(building)
+    }
+
+    /// Makes the previous generation current again and drops the generations it doesn't read.
+    pub async fn rollback_generation(&self) -> Result<u64> {
+        let mut manifest = self.manifest().await?;
+        if let Some(building) = manifest.building {
+            bail!("Index generation {building} is being built, finish the index run first");
         }
+        let Some(previous) = manifest.previous else {
+            bail!("No previous index generation to roll back to");
+        };
+        let dropped = manifest.rollback_to(previous);
+        self.save_manifest(&manifest).await?;
+        for generation in dropped {
+            self.drop_generation(generation).await?;
+        }
+        Ok(previous)
+    }
+
+    /// Drops the previous generation and the generations only it reads.
+    pub async fn drop_previous_generation(&self) -> Result<()> {
+        let mut manifest = self.manifest().await?;
+        let dropped = manifest.drop_previous();
+        self.save_manifest(&manifest).await?;
+        for generation in dropped {
+            self.drop_generation(generation).await?;
+        }
+        Ok(())
     }
 
     /// Get the last bucket for a term (highest bucket number).
     /// Used for incremental indexing to continue from existing buckets.
     pub async fn get_last_bucket(&self, term: &str) -> Result<Option<InvertedIndexDoc>> {
+        if self.generation.is_none() {
+            return Ok(self.find_by_terms(&[term.to_string()]).await?.pop());
+        }
         let options = mongodb::options::FindOneOptions::builder()
             .sort(doc! { "bucket": -1 })
             .build();
 
-        self.collection
+        self.collection()
+            .await?
             .find_one(doc! { "term": term })
             .with_options(options)
             .await
@@ -474,13 +779,13 @@ impl InvertedIndexRepo {
     pub async fn append_to_bucket(
         &self,
         doc_id: ObjectId,
-        new_postings: &[ObjectId],
-        new_positions: &std::collections::HashMap<ObjectId, Vec<usize>>,
+        new_postings: &[DocId],
+        new_positions: &std::collections::HashMap<DocId, Vec<usize>>,
     ) -> Result<bool> {
         // Build the $set for positions - each new doc_id gets its positions added
         let mut positions_set = Document::new();
         for (posting_id, positions) in new_positions {
-            let key = format!("positions.{}", posting_id.to_hex());
+            let key = format!("positions.{}", posting_id);
             // Convert usize to i64 for BSON compatibility
             let positions_i64: Vec<i64> = positions.iter().map(|&p| p as i64).collect();
             positions_set.insert(key, positions_i64);
@@ -493,7 +798,8 @@ impl InvertedIndexRepo {
         };
 
         let result = self
-            .collection
+            .collection()
+            .await?
             .update_one(doc! { "_id": doc_id }, update)
             .await
             .context("Failed to append to existing bucket")?;
@@ -501,10 +807,78 @@ impl InvertedIndexRepo {
         Ok(result.modified_count > 0)
     }
 
+    /// Every bucket document of the given terms, sorted by bucket, see `stack_layers`
+    pub async fn find_by_terms(&self, terms: &[String]) -> Result<Vec<InvertedIndexDoc>> {
+        use futures::TryStreamExt;
+
+        let mut layers = Vec::new();
+        for collection in self.read_collections().await? {
+            let options = mongodb::options::FindOptions::builder()
+                .sort(doc! { "bucket": 1 })
+                .build();
+            layers.push(
+                collection
+                    .find(doc! { "term": { "$in": terms } })
+                    .with_options(options)
+                    .await
+                    .context("Failed to find terms")?
+                    .try_collect()
+                    .await
+                    .context("Failed to collect terms")?,
+            );
+        }
+        Ok(crate::storage::stack_layers(layers))
+    }
+
+    /// Document frequency of every term in the index, summed over its buckets and sorted by term.
+    /// Used to (re)build the term dictionary for an existing index.
+    pub async fn term_document_frequencies(&self) -> Result<Vec<(String, u64)>> {
+        use futures::TryStreamExt;
+
+        let pipeline = vec![
+            doc! { "$group": { "_id": "$term", "df": { "$sum": "$document_frequency" } } },
+            doc! { "$sort": { "_id": 1 } },
+        ];
+        let mut frequencies: std::collections::BTreeMap<String, u64> = Default::default();
+        for collection in self.read_collections().await? {
+            let docs: Vec<Document> = collection
+                .aggregate(pipeline.clone())
+                .await
+                .context("Failed to aggregate term document frequencies")?
+                .try_collect()
+                .await
+                .context("Failed to collect term document frequencies")?;
+            for d in docs {
+                let term = d.get_str("_id")?.to_string();
+                let df = match d.get("df") {
+                    Some(mongodb::bson::Bson::Int32(v)) => *v as u64,
+                    Some(mongodb::bson::Bson::Int64(v)) => *v as u64,
+                    _ => 0,
+                };
+                *frequencies.entry(term).or_default() += df;
+            }
+        }
+        Ok(frequencies.into_iter().collect())
+    }
+
+    /// Insert new bucket documents in a single write
+    pub async fn insert_many(&self, docs: Vec<InvertedIndexDoc>) -> Result<()> {
+        if docs.is_empty() {
+            return Ok(());
+        }
+        self.collection()
+            .await?
+            .insert_many(docs)
+            .await
+            .context("Failed to insert inverted index documents")?;
+        Ok(())
+    }
+
     /// Insert a new bucket document
     pub async fn insert(&self, doc: InvertedIndexDoc) -> Result<ObjectId> {
         let result = self
-            .collection
+            .collection()
+            .await?
             .insert_one(doc)
             .await
             .context("Failed to insert inverted index document")?;
@@ -533,19 +907,24 @@ impl MergeCheckpointRepo {
         }
     }
 
-    /// Get or create a checkpoint for a SPIMI block collection
+    /// Get or create a checkpoint for a SPIMI block
     pub async fn get_or_create(&self, collection_name: &str) -> Result<MergeCheckpoint> {
-        let filter = doc! { "collection_name": collection_name };
+        self.get_or_insert(MergeCheckpoint::new(collection_name.to_string()))
+            .await
+    }
+
+    /// The checkpoint named like `checkpoint`, which is inserted if there is none yet
+    pub async fn get_or_insert(&self, checkpoint: MergeCheckpoint) -> Result<MergeCheckpoint> {
+        let filter = doc! { "collection_name": &checkpoint.collection_name };
 
-        if let Some(checkpoint) = self
+        if let Some(existing) = self
             .collection
             .find_one(filter.clone())
             .await
             .context("Failed to find checkpoint")?
         {
-            Ok(checkpoint)
+            Ok(existing)
         } else {
-            let checkpoint = MergeCheckpoint::new(collection_name.to_string());
             self.collection
                 .insert_one(&checkpoint)
                 .await
@@ -578,7 +957,7 @@ impl MergeCheckpointRepo {
         Ok(())
     }
 
-    /// Mark a block collection as fully merged
+    /// Mark a block as fully merged
     pub async fn mark_completed(&self, collection_name: &str) -> Result<()> {
         let filter = doc! { "collection_name": collection_name };
         let update = doc! {
@@ -626,6 +1005,642 @@ impl MergeCheckpointRepo {
     }
 }
 
+// Query log operations for autocomplete
+
+use crate::data_models::QueryLogEntry;
+
+/// Repository of past queries, the indexer turns them into completions
+pub struct QueryLogRepo {
+    collection: Collection<QueryLogEntry>,
+}
+
+impl QueryLogRepo {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection(collections::QUERY_LOG),
+        }
+    }
+
+    /// Count one more search of `query` (expected to be normalized)
+    pub async fn record(&self, query: &str) -> Result<()> {
+        let update = doc! {
+            "$inc": { "count": 1_i64, "pending": 1_i64 },
+            "$set": { "last_searched_at": DateTime::now() },
+            "$setOnInsert": { "_id": ObjectId::new() },
+        };
+        self.collection
+            .update_one(doc! { "query": query }, update)
+            .upsert(true)
+            .await
+            .context("Failed to record query")?;
+        Ok(())
+    }
+
+    /// Returns the searches recorded since the last call as `(query, searches)`
+    /// and marks them as taken, so every search is counted into the completions once.
+    pub async fn take_pending(&self) -> Result<Vec<(String, u64)>> {
+        use futures::TryStreamExt;
+
+        let entries: Vec<QueryLogEntry> = self
+            .collection
+            .find(doc! { "pending": { "$gt": 0_i64 } })
+            .await
+            .context("Failed to find pending queries")?
+            .try_collect()
+            .await
+            .context("Failed to collect pending queries")?;
+
+        let mut taken = Vec::with_capacity(entries.len());
+        for entry in entries {
+            // decrement rather than reset, searches recorded meanwhile stay pending
+            self.collection
+                .update_one(
+                    doc! { "_id": entry.id },
+                    doc! { "$inc": { "pending": -entry.pending } },
+                )
+                .await
+                .context("Failed to mark queries as taken")?;
+            taken.push((entry.query, entry.pending as u64));
+        }
+        Ok(taken)
+    }
+
+    /// Returns every search recorded as `(query, searches)` and marks the pending ones as taken,
+    /// for rebuilding the completions from scratch.
+    pub async fn take_all(&self) -> Result<Vec<(String, u64)>> {
+        use futures::TryStreamExt;
+
+        let entries: Vec<QueryLogEntry> = self
+            .collection
+            .find(doc! {})
+            .await
+            .context("Failed to find queries")?
+            .try_collect()
+            .await
+            .context("Failed to collect queries")?;
+
+        let mut taken = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if entry.pending > 0 {
+                self.collection
+                    .update_one(
+                        doc! { "_id": entry.id },
+                        doc! { "$inc": { "pending": -entry.pending } },
+                    )
+                    .await
+                    .context("Failed to mark queries as taken")?;
+            }
+            taken.push((entry.query, entry.count as u64));
+        }
+        Ok(taken)
+    }
+}
+
+// Doc id operations
+
+use crate::data_models::{DeletedDocId, DocId, DocIdEntry, ReplacedDocId};
+
+/// Repository of the dense doc ids assigned to indexed pages.
+/// Doc ids replaced by an index run move from `doc_ids` to `replaced_docs` until the run commits,
+/// tombstoned doc ids move to `deleted_docs`.
+pub struct DocIdRepo {
+    collection: Collection<DocIdEntry>,
+    replaced: Collection<ReplacedDocId>,
+    deleted: Collection<DeletedDocId>,
+    counters: Collection<Document>,
+    page_id_index: tokio::sync::OnceCell<()>,
+}
+
+impl DocIdRepo {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection(collections::DOC_IDS),
+            replaced: db.collection(collections::REPLACED_DOCS),
+            deleted: db.collection(collections::DELETED_DOCS),
+            counters: db.collection(collections::COUNTERS),
+            page_id_index: tokio::sync::OnceCell::new(),
+        }
+    }
+
+    /// Doc ids of `page_ids` in the same order, pages seen for the first time get the next free ids
+    pub async fn assign(&self, page_ids: &[ObjectId]) -> Result<Vec<DocId>> {
+        use futures::TryStreamExt;
+
+        self.page_id_index
+            .get_or_try_init(|| async {
+                let options = mongodb::options::IndexOptions::builder()
+                    .unique(true)
+                    .build();
+                self.collection
+                    .create_index(
+                        mongodb::IndexModel::builder()
+                            .keys(doc! { "page_id": 1 })
+                            .options(options)
+                            .build(),
+                    )
+                    .await
+                    .context("Failed to create doc id index")
+                    .map(|_| ())
+            })
+            .await?;
+
+        let existing: Vec<DocIdEntry> = self
+            .collection
+            .find(doc! { "page_id": { "$in": page_ids } })
+            .await
+            .context("Failed to find doc ids")?
+            .try_collect()
+            .await
+            .context("Failed to collect doc ids")?;
+        let mut assigned: std::collections::HashMap<ObjectId, DocId> = existing
+            .into_iter()
+            .map(|entry| (entry.page_id, entry.doc_id))
+            .collect();
+
+        let mut missing: Vec<ObjectId> = Vec::new();
+        for page_id in page_ids {
+            if !assigned.contains_key(page_id) && !missing.contains(page_id) {
+                missing.push(*page_id);
+            }
+        }
+        if !missing.is_empty() {
+            let first = self.allocate(missing.len()).await?;
+            let entries: Vec<DocIdEntry> = missing
+                .iter()
+                .enumerate()
+                .map(|(i, page_id)| DocIdEntry {
+                    doc_id: first + i as DocId,
+                    page_id: *page_id,
+                })
+                .collect();
+            self.collection
+                .insert_many(&entries)
+                .await
+                .context("Failed to insert doc ids")?;
+            assigned.extend(entries.into_iter().map(|e| (e.page_id, e.doc_id)));
+        }
+        Ok(page_ids.iter().map(|page_id| assigned[page_id]).collect())
+    }
+
+    /// Reserves `count` consecutive doc ids and returns the first one
+    async fn allocate(&self, count: usize) -> Result<DocId> {
+        let counter = self
+            .counters
+            .find_one_and_update(
+                doc! { "_id": "doc_id" },
+                doc! { "$inc": { "next": count as i64 } },
+            )
+            .upsert(true)
+            .return_document(mongodb::options::ReturnDocument::After)
+            .await
+            .context("Failed to allocate doc ids")?
+            .context("Doc id counter missing after upsert")?;
+        let next = counter.get_i64("next")?;
+        anyhow::ensure!(next <= DocId::MAX as i64 + 1, "Doc id space exhausted");
+        Ok((next - count as i64) as DocId)
+    }
+
+    /// Page ids of `doc_ids` in the same order, replaced ones included, unknown doc ids are an error
+    pub async fn resolve(&self, doc_ids: &[DocId]) -> Result<Vec<ObjectId>> {
+        use futures::TryStreamExt;
+
+        let entries: Vec<DocIdEntry> = self
+            .collection
+            .find(doc! { "_id": { "$in": doc_ids } })
+            .await
+            .context("Failed to find page ids")?
+            .try_collect()
+            .await
+            .context("Failed to collect page ids")?;
+        let mut page_ids: std::collections::HashMap<DocId, ObjectId> = entries
+            .into_iter()
+            .map(|entry| (entry.doc_id, entry.page_id))
+            .collect();
+        let missing: Vec<DocId> = doc_ids
+            .iter()
+            .filter(|doc_id| !page_ids.contains_key(doc_id))
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            let replaced: Vec<ReplacedDocId> = self
+                .replaced
+                .find(doc! { "_id": { "$in": &missing } })
+                .await
+                .context("Failed to find replaced doc ids")?
+                .try_collect()
+                .await
+                .context("Failed to collect replaced doc ids")?;
+            page_ids.extend(
+                replaced
+                    .into_iter()
+                    .map(|entry| (entry.doc_id, entry.page_id)),
+            );
+        }
+        doc_ids
+            .iter()
+            .map(|doc_id| {
+                page_ids
+                    .get(doc_id)
+                    .copied()
+                    .with_context(|| format!("Unknown doc id {doc_id}"))
+            })
+            .collect()
+    }
+
+    /// Every live `(doc id, page id)` pair, replaced ones included, sorted by doc id
+    pub async fn page_ids(&self) -> Result<Vec<(DocId, ObjectId)>> {
+        use futures::TryStreamExt;
+
+        let entries: Vec<DocIdEntry> = self
+            .collection
+            .find(doc! {})
+            .await
+            .context("Failed to list doc ids")?
+            .try_collect()
+            .await
+            .context("Failed to collect doc ids")?;
+        let replaced: Vec<ReplacedDocId> = self
+            .replaced
+            .find(doc! {})
+            .await
+            .context("Failed to list replaced doc ids")?
+            .try_collect()
+            .await
+            .context("Failed to collect replaced doc ids")?;
+        let mut page_ids: Vec<(DocId, ObjectId)> = entries
+            .into_iter()
+            .map(|entry| (entry.doc_id, entry.page_id))
+            .chain(
+                replaced
+                    .into_iter()
+                    .map(|entry| (entry.doc_id, entry.page_id)),
+            )
+            .collect();
+        // a replace interrupted before deleting the live entry lists a doc id twice
+        page_ids.sort_unstable_by_key(|(doc_id, _)| *doc_id);
+        page_ids.dedup();
+        Ok(page_ids)
+    }
+
+    /// Moves the doc ids of `page_ids` to the replaced ones of `index_run` and returns them,
+    /// pages without one are skipped. Doc ids of these pages replaced before move to `index_run`.
+    pub async fn replace_pages(
+        &self,
+        page_ids: &[ObjectId],
+        index_run: ObjectId,
+    ) -> Result<Vec<DocId>> {
+        use futures::TryStreamExt;
+
+        self.replaced
+            .update_many(
+                doc! { "page_id": { "$in": page_ids } },
+                doc! { "$set": { "index_run": index_run } },
+            )
+            .await
+            .context("Failed to update replaced doc ids")?;
+        let entries: Vec<DocIdEntry> = self
+            .collection
+            .find(doc! { "page_id": { "$in": page_ids } })
+            .await
+            .context("Failed to find doc ids")?
+            .try_collect()
+            .await
+            .context("Failed to collect doc ids")?;
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        // replaced entries are written first, a crash in between leaves the doc id live and
+        // replaced, which resolves the same and the next call completes
+        for entry in &entries {
+            let replaced = ReplacedDocId {
+                doc_id: entry.doc_id,
+                page_id: entry.page_id,
+                index_run,
+            };
+            self.replaced
+                .replace_one(doc! { "_id": entry.doc_id }, &replaced)
+                .upsert(true)
+                .await
+                .context("Failed to record replaced doc id")?;
+        }
+        let mut doc_ids: Vec<DocId> = entries.iter().map(|entry| entry.doc_id).collect();
+        self.collection
+            .delete_many(doc! { "_id": { "$in": &doc_ids } })
+            .await
+            .context("Failed to delete doc ids")?;
+        doc_ids.sort_unstable();
+        Ok(doc_ids)
+    }
+
+    /// Tombstones the doc ids replaced by `index_runs` as replaced in `generation` and returns them
+    pub async fn commit_replaced(
+        &self,
+        index_runs: &[ObjectId],
+        generation: u64,
+    ) -> Result<Vec<DocId>> {
+        use futures::TryStreamExt;
+
+        let replaced: Vec<ReplacedDocId> = self
+            .replaced
+            .find(doc! { "index_run": { "$in": index_runs } })
+            .await
+            .context("Failed to find replaced doc ids")?
+            .try_collect()
+            .await
+            .context("Failed to collect replaced doc ids")?;
+        let entries: Vec<DocIdEntry> = replaced
+            .into_iter()
+            .map(|entry| DocIdEntry {
+                doc_id: entry.doc_id,
+                page_id: entry.page_id,
+            })
+            .collect();
+        self.tombstone(&entries, Some(generation)).await?;
+        let mut doc_ids: Vec<DocId> = entries.iter().map(|entry| entry.doc_id).collect();
+        self.replaced
+            .delete_many(doc! { "_id": { "$in": &doc_ids } })
+            .await
+            .context("Failed to delete replaced doc ids")?;
+        doc_ids.sort_unstable();
+        Ok(doc_ids)
+    }
+
+    /// Gives the live pages whose doc ids were replaced by a generation after `generation` the
+    /// lowest of their replaced doc ids back, dropping their newer ones, and returns them
+    pub async fn revive_replaced(&self, generation: u64) -> Result<Vec<ObjectId>> {
+        use futures::TryStreamExt;
+
+        let options = mongodb::options::FindOptions::builder()
+            .sort(doc! { "_id": 1 })
+            .build();
+        let tombstones: Vec<DeletedDocId> = self
+            .deleted
+            .find(doc! { "replaced_in": { "$gt": generation as i64 } })
+            .with_options(options)
+            .await
+            .context("Failed to find replaced tombstones")?
+            .try_collect()
+            .await
+            .context("Failed to collect replaced tombstones")?;
+        let page_ids: Vec<ObjectId> = tombstones.iter().map(|entry| entry.page_id).collect();
+        let live: Vec<DocIdEntry> = self
+            .collection
+            .find(doc! { "page_id": { "$in": &page_ids } })
+            .await
+            .context("Failed to find doc ids")?
+            .try_collect()
+            .await
+            .context("Failed to collect doc ids")?;
+        let mut newer: std::collections::HashMap<ObjectId, DocId> = live
+            .into_iter()
+            .map(|entry| (entry.page_id, entry.doc_id))
+            .collect();
+
+        // tombstones are sorted, the first of a page is the doc id it had before
+        let mut revived = Vec::new();
+        for tombstone in tombstones {
+            let Some(doc_id) = newer.remove(&tombstone.page_id) else {
+                continue;
+            };
+            // the newer doc id goes first, page ids are unique. The tombstone goes last, a crash
+            // before leaves the old doc id live and deleted, which queries treat as deleted
+            self.collection
+                .delete_one(doc! { "_id": doc_id })
+                .await
+                .context("Failed to drop doc id")?;
+            let entry = DocIdEntry {
+                doc_id: tombstone.doc_id,
+                page_id: tombstone.page_id,
+            };
+            self.collection
+                .replace_one(doc! { "_id": entry.doc_id }, &entry)
+                .upsert(true)
+                .await
+                .context("Failed to revive doc id")?;
+            self.deleted
+                .delete_one(doc! { "_id": entry.doc_id })
+                .await
+                .context("Failed to drop tombstone")?;
+            revived.push(entry.page_id);
+        }
+        self.deleted
+            .update_many(
+                doc! { "replaced_in": { "$gt": generation as i64 } },
+                doc! { "$set": { "replaced_in": null } },
+            )
+            .await
+            .context("Failed to reset tombstones replaced by a rolled back generation")?;
+        if !revived.is_empty() {
+            self.bump_deleted_version().await?;
+        }
+        Ok(revived)
+    }
+
+    /// Tombstones the doc ids of `page_ids`, replaced ones included, and returns them, pages
+    /// without one are skipped
+    pub async fn delete_pages(&self, page_ids: &[ObjectId]) -> Result<Vec<DocId>> {
+        use futures::TryStreamExt;
+
+        let mut entries: Vec<DocIdEntry> = self
+            .collection
+            .find(doc! { "page_id": { "$in": page_ids } })
+            .await
+            .context("Failed to find doc ids")?
+            .try_collect()
+            .await
+            .context("Failed to collect doc ids")?;
+        let replaced: Vec<ReplacedDocId> = self
+            .replaced
+            .find(doc! { "page_id": { "$in": page_ids } })
+            .await
+            .context("Failed to find replaced doc ids")?
+            .try_collect()
+            .await
+            .context("Failed to collect replaced doc ids")?;
+        entries.extend(replaced.into_iter().map(|entry| DocIdEntry {
+            doc_id: entry.doc_id,
+            page_id: entry.page_id,
+        }));
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        // tombstones are written first, a crash in between leaves the doc id live and deleted,
+        // which queries treat as deleted and the next call completes
+        self.tombstone(&entries, None).await?;
+        let mut doc_ids: Vec<DocId> = entries.iter().map(|entry| entry.doc_id).collect();
+        self.collection
+            .delete_many(doc! { "_id": { "$in": &doc_ids } })
+            .await
+            .context("Failed to delete doc ids")?;
+        self.replaced
+            .delete_many(doc! { "_id": { "$in": &doc_ids } })
+            .await
+            .context("Failed to delete replaced doc ids")?;
+        doc_ids.sort_unstable();
+        Ok(doc_ids)
+    }
+
+    async fn tombstone(&self, entries: &[DocIdEntry], replaced_in: Option<u64>) -> Result<()> {
+        for entry in entries {
+            let deleted = DeletedDocId {
+                doc_id: entry.doc_id,
+                page_id: entry.page_id,
+                replaced_in,
+                purged_in: None,
+            };
+            self.deleted
+                .replace_one(doc! { "_id": entry.doc_id }, &deleted)
+                .upsert(true)
+                .await
+                .context("Failed to tombstone doc id")?;
+        }
+        self.bump_deleted_version().await
+    }
+
+    /// Changes the version of the tombstones, after they changed
+    async fn bump_deleted_version(&self) -> Result<()> {
+        self.counters
+            .update_one(
+                doc! { "_id": "deleted" },
+                doc! { "$inc": { "version": 1_i64 } },
+            )
+            .upsert(true)
+            .await
+            .context("Failed to bump the tombstone version")?;
+        Ok(())
+    }
+
+    /// A number that changes whenever the tombstones do
+    pub async fn deleted_version(&self) -> Result<u64> {
+        let counter = self
+            .counters
+            .find_one(doc! { "_id": "deleted" })
+            .await
+            .context("Failed to read the tombstone version")?;
+        Ok(counter.map_or(Ok(0), |counter| counter.get_i64("version"))? as u64)
+    }
+
+    /// Tombstoned doc ids, ascending
+    pub async fn deleted(&self) -> Result<Vec<DocId>> {
+        use futures::TryStreamExt;
+
+        let options = mongodb::options::FindOptions::builder()
+            .sort(doc! { "_id": 1 })
+            .build();
+        let entries: Vec<DeletedDocId> = self
+            .deleted
+            .find(doc! {})
+            .with_options(options)
+            .await
+            .context("Failed to list deleted doc ids")?
+            .try_collect()
+            .await
+            .context("Failed to collect deleted doc ids")?;
+        Ok(entries.into_iter().map(|entry| entry.doc_id).collect())
+    }
+
+    /// Records that `generation` was written without the postings of the tombstoned `doc_ids`
+    pub async fn purge_deleted(&self, doc_ids: &[DocId], generation: u64) -> Result<()> {
+        self.deleted
+            .update_many(
+                doc! { "_id": { "$in": doc_ids } },
+                doc! { "$set": { "purged_in": generation as i64 } },
+            )
+            .await
+            .context("Failed to record purged tombstones")?;
+        Ok(())
+    }
+
+    /// Drops the tombstones purged by `oldest` or an older generation, and counts those purged
+    /// by a generation after `current` as not purged. Returns the number of tombstones dropped.
+    pub async fn forget_purged(&self, oldest: u64, current: u64) -> Result<u64> {
+        self.deleted
+            .update_many(
+                doc! { "purged_in": { "$gt": current as i64 } },
+                doc! { "$set": { "purged_in": null } },
+            )
+            .await
+            .context("Failed to reset tombstones purged by a rolled back generation")?;
+        let result = self
+            .deleted
+            .delete_many(doc! { "purged_in": { "$lte": oldest as i64 } })
+            .await
+            .context("Failed to drop purged tombstones")?;
+        if result.deleted_count > 0 {
+            self.bump_deleted_version().await?;
+        }
+        Ok(result.deleted_count)
+    }
+
+    /// Replace every doc id and tombstone, the doc id counter restarts after the highest one.
+    /// A page listed with several doc ids keeps the lowest, the others are tombstoned.
+    pub async fn restore(&self, page_ids: &[(DocId, ObjectId)], deleted: &[DocId]) -> Result<()> {
+        self.collection
+            .delete_many(doc! {})
+            .await
+            .context("Failed to drop doc ids")?;
+        self.replaced
+            .delete_many(doc! {})
+            .await
+            .context("Failed to drop replaced doc ids")?;
+        self.deleted
+            .delete_many(doc! {})
+            .await
+            .context("Failed to drop tombstones")?;
+        let mut kept: std::collections::HashMap<ObjectId, DocId> = std::collections::HashMap::new();
+        for (doc_id, page_id) in page_ids {
+            let lowest = kept.entry(*page_id).or_insert(*doc_id);
+            *lowest = (*lowest).min(*doc_id);
+        }
+        let entries: Vec<DocIdEntry> = kept
+            .into_iter()
+            .map(|(page_id, doc_id)| DocIdEntry { doc_id, page_id })
+            .collect();
+        let live: std::collections::HashSet<DocId> =
+            entries.iter().map(|entry| entry.doc_id).collect();
+        let deleted: Vec<DocId> = page_ids
+            .iter()
+            .map(|(doc_id, _)| *doc_id)
+            .filter(|doc_id| !live.contains(doc_id))
+            .chain(deleted.iter().copied())
+            .collect();
+        if !entries.is_empty() {
+            self.collection
+                .insert_many(&entries)
+                .await
+                .context("Failed to restore doc ids")?;
+        }
+        // the page id of a tombstone is gone, only its doc id matters
+        let tombstones: Vec<DeletedDocId> = deleted
+            .iter()
+            .map(|doc_id| DeletedDocId {
+                doc_id: *doc_id,
+                page_id: ObjectId::new(),
+                replaced_in: None,
+                purged_in: None,
+            })
+            .collect();
+        if !tombstones.is_empty() {
+            self.deleted
+                .insert_many(&tombstones)
+                .await
+                .context("Failed to restore tombstones")?;
+        }
+        let next = page_ids
+            .iter()
+            .map(|(doc_id, _)| doc_id)
+            .chain(&deleted)
+            .max()
+            .map_or(0, |doc_id| *doc_id as i64 + 1);
+        self.counters
+            .update_one(doc! { "_id": "doc_id" }, doc! { "$set": { "next": next } })
+            .upsert(true)
+            .await
+            .context("Failed to restore the doc id counter")?;
+        self.bump_deleted_version().await
+    }
+}
+
 // Test utilities
 #[cfg(test)]
 pub mod test_utils {
@@ -669,6 +1684,7 @@ pub mod test_utils {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::analyzer::DEFAULT_ANALYZER;
     use test_utils::*;
 
     #[tokio::test]
@@ -720,4 +1736,140 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_query_log_take_pending() -> Result<()> {
+        let (db, db_name) = create_test_db().await?;
+        let repo = QueryLogRepo::new(&db);
+
+        repo.record("harvest moon").await?;
+        repo.record("harvest moon").await?;
+        repo.record("crawler").await?;
+
+        let mut taken = repo.take_pending().await?;
+        taken.sort();
+        assert_eq!(
+            taken,
+            vec![("crawler".to_string(), 1), ("harvest moon".to_string(), 2)]
+        );
+
+        // already taken searches are not returned again
+        assert!(repo.take_pending().await?.is_empty());
+        repo.record("crawler").await?;
+        assert_eq!(repo.take_pending().await?, vec![("crawler".to_string(), 1)]);
+
+        repo.record("crawler").await?;
+        let mut all = repo.take_all().await?;
+        all.sort();
+        assert_eq!(
+            all,
+            vec![("crawler".to_string(), 3), ("harvest moon".to_string(), 2)]
+        );
+        assert!(repo.take_pending().await?.is_empty());
+
+        cleanup_test_db(&db, &db_name).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_doc_ids_assign_and_resolve() -> Result<()> {
+        let (db, db_name) = create_test_db().await?;
+        let repo = DocIdRepo::new(&db);
+        let (a, b, c) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+
+        assert_eq!(repo.assign(&[a, b, a]).await?, vec![0, 1, 0]);
+        // known pages keep their doc id
+        assert_eq!(repo.assign(&[c, b]).await?, vec![2, 1]);
+
+        assert_eq!(repo.resolve(&[2, 0]).await?, vec![c, a]);
+        assert!(repo.resolve(&[3]).await.is_err());
+        assert_eq!(repo.page_ids().await?, vec![(0, a), (1, b), (2, c)]);
+
+        cleanup_test_db(&db, &db_name).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_doc_ids_delete_pages() -> Result<()> {
+        let (db, db_name) = create_test_db().await?;
+        let repo = DocIdRepo::new(&db);
+        let (a, b) = (ObjectId::new(), ObjectId::new());
+
+        assert_eq!(repo.assign(&[a, b]).await?, vec![0, 1]);
+        assert_eq!(repo.delete_pages(&[a, ObjectId::new()]).await?, vec![0]);
+        assert_eq!(repo.deleted().await?, vec![0]);
+        assert!(repo.resolve(&[0]).await.is_err());
+        // a deleted page indexed again gets a new doc id
+        assert_eq!(repo.assign(&[a]).await?, vec![2]);
+        assert_eq!(repo.page_ids().await?, vec![(1, b), (2, a)]);
+
+        let version = repo.deleted_version().await?;
+        repo.purge_deleted(&[0], 2).await?;
+        assert_eq!(repo.forget_purged(1, 2).await?, 0);
+        assert_eq!(repo.forget_purged(2, 2).await?, 1);
+        assert!(repo.deleted().await?.is_empty());
+        assert_ne!(repo.deleted_version().await?, version);
+
+        cleanup_test_db(&db, &db_name).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_doc_ids_replace_pages() -> Result<()> {
+        let (db, db_name) = create_test_db().await?;
+        let repo = DocIdRepo::new(&db);
+        let (a, b) = (ObjectId::new(), ObjectId::new());
+        let (run, next_run) = (ObjectId::new(), ObjectId::new());
+
+        assert_eq!(repo.assign(&[a, b]).await?, vec![0, 1]);
+        assert_eq!(repo.replace_pages(&[a], run).await?, vec![0]);
+        assert_eq!(repo.assign(&[a]).await?, vec![2]);
+        // the replaced doc id stays live until its run commits
+        assert_eq!(repo.resolve(&[0]).await?, vec![a]);
+        assert_eq!(repo.page_ids().await?, vec![(0, a), (1, b), (2, a)]);
+        assert!(repo.deleted().await?.is_empty());
+
+        // a run that replaces the page again takes over the pending doc id
+        assert_eq!(repo.replace_pages(&[a], next_run).await?, vec![2]);
+        assert!(repo.commit_replaced(&[run], 1).await?.is_empty());
+        assert_eq!(repo.commit_replaced(&[next_run], 1).await?, vec![0, 2]);
+        assert_eq!(repo.deleted().await?, vec![0, 2]);
+        assert!(repo.resolve(&[0]).await.is_err());
+
+        // rolling back the generation that replaced them gives the page its first doc id back
+        assert_eq!(repo.assign(&[a]).await?, vec![3]);
+        assert!(repo.revive_replaced(1).await?.is_empty());
+        assert_eq!(repo.revive_replaced(0).await?, vec![a]);
+        assert_eq!(repo.assign(&[a]).await?, vec![0]);
+        assert_eq!(repo.deleted().await?, vec![2]);
+
+        cleanup_test_db(&db, &db_name).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_generations() -> Result<()> {
+        let (db, db_name) = create_test_db().await?;
+        let repo = InvertedIndexRepo::new(&db);
+        let bucket =
+            |term: &str| InvertedIndexDoc::new(term.to_string(), 0, 1, vec![1], Default::default());
+        repo.insert(bucket("moon")).await?;
+
+        let next = repo.begin_generation(true, DEFAULT_ANALYZER).await?;
+        next.insert(bucket("harvest")).await?;
+        // readers see the current generation until the commit
+        let terms = repo.term_document_frequencies().await?;
+        assert_eq!(terms, vec![("moon".to_string(), 1)]);
+        assert_eq!(repo.commit_generation().await?, 1);
+        assert_eq!(repo.collection().await?.name(), "inverted_index_v1");
+        assert_eq!(repo.term_document_frequencies().await?.len(), 2);
+
+        assert_eq!(repo.rollback_generation().await?, 0);
+        let terms = repo.term_document_frequencies().await?;
+        assert_eq!(terms, vec![("moon".to_string(), 1)]);
+        assert!(repo.rollback_generation().await.is_err());
+
+        cleanup_test_db(&db, &db_name).await?;
+        Ok(())
+    }
 }
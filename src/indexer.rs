@@ -1,32 +1,49 @@
-use anyhow::Result;
+use anyhow::{Context, Result, bail, ensure};
 use futures::StreamExt;
+use futures::stream::BoxStream;
 use mongodb::bson::oid::ObjectId;
-use mongodb::options::IndexOptions;
-use nanoid::nanoid;
 
-use mongodb::IndexModel;
-use mongodb::bson::doc;
 use std::cmp::Reverse;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::sync::Mutex;
+use tokio::sync::Semaphore;
 use tokio::sync::mpsc;
 
 use crate::analyzer::TextAnalyzer;
+use crate::archive::{ArchiveSummary, IndexArchive};
+use crate::data_models::DocId;
 use crate::data_models::InvertedIndexDoc;
 use crate::data_models::MergeCheckpoint;
 use crate::data_models::Page;
 use crate::data_models::SpimiDoc;
 use crate::db::Database;
-use crate::db::InvertedIndexRepo;
+use crate::postings::codec::Codec;
 
-use crate::db::MergeCheckpointRepo;
+use crate::completion::{
+    COMPLETION_COUNTS_DIR, COMPLETION_PHRASES_FILE, COMPLETIONS_FILE, CompletionCounts,
+    CompletionIndex, QUERY_WEIGHT, SURFACE_FORMS_FILE, SurfaceForms, TITLE_WEIGHT,
+};
 use crate::db::PageRepo;
+use crate::heap;
+use crate::segment::{
+    SEGMENT_FILE, SegmentGeneration, delta_segment_file, delta_segments, read_generation,
+    write_generation, write_segment,
+};
+use crate::storage::file::new_block_name;
+use crate::storage::{BlockStore, IndexStore, Storage};
+use crate::term_dict::{TERM_DICT_FILE, TermDictionary};
+use crate::verify::VerifyReport;
 
 /// Single Pass In Memory Indexing
-/// using mongo db
+/// on top of a `Storage` backend (mongo db in production)
 ///
 /// token = tuple[term, docId]
 /// token_stream is sorted by docIds
@@ -47,21 +64,43 @@ use crate::db::PageRepo;
 /// ```
 ///
 
-const DOCID_BYTES: usize = size_of::<ObjectId>();
+const DOCID_BYTES: usize = size_of::<DocId>();
 /// Maximum number of document IDs per MongoDB document.
-/// MongoDB has a 16MB document limit. With ObjectIds (12 bytes each) plus
+/// MongoDB has a 16MB document limit. With doc ids (4 bytes each, stored as 8 byte BSON ints) plus
 /// positions (HashMap with Vec<usize>), each entry uses roughly 50 bytes.
 /// 100K entries * 50 bytes = 5MB, providing safe margin under 16MB.
 const DOCIDS_PER_MONGO_DOCUMENT: usize = 100_000;
+/// Terms whose buckets are read at a time when going over the whole index.
+pub const TERMS_PER_FETCH: usize = 1_000;
+/// Bytes a token takes in the token stream, plus the bytes of its term.
+const TOKEN_BYTES: usize = size_of::<Token>();
+/// Bytes a surface form takes in the token stream, plus the bytes of its term and word.
+const SURFACE_FORM_BYTES: usize = size_of::<(String, String, u64)>();
+/// The token stream gets 1 / TOKEN_STREAM_BUDGET_DIVISOR of the memory budget, SPIMI the rest.
+const TOKEN_STREAM_BUDGET_DIVISOR: usize = 8;
+/// Blocks merged at once, more blocks are first merged into intermediate blocks.
+const DEFAULT_MERGE_FAN_IN: usize = 64;
+/// Index buckets a term range merge writes at a time, its checkpoints move after every batch.
+const MERGE_WRITE_BATCH: usize = 1_000;
+/// Separates the block from the term range in checkpoint names.
+const CHECKPOINT_RANGE_SEPARATOR: char = '#';
 
 pub struct Token {
     pub term: String,
-    pub doc_id: ObjectId,
+    pub doc_id: DocId,
     pub pos: usize,
 }
 
 pub enum StreamMsg {
-    Token(Token),
+    /// The tokens of one document in position order, holding `reserved_bytes` of the token
+    /// stream budget until they are inverted. Its title and `(term, word, occurrences)` surface
+    /// forms are counted for the completions, both are empty when the indexer keeps none.
+    Document {
+        tokens: Vec<Token>,
+        title: String,
+        surface_forms: Vec<(String, String, u64)>,
+        reserved_bytes: usize,
+    },
     End,
 }
 
@@ -71,19 +110,36 @@ pub struct SpimiBlock {
 }
 
 pub struct Indexer {
-    db: Database,
-    pages_repo: Arc<PageRepo>,
-    inverted_index_repo: Arc<InvertedIndexRepo>,
+    storage: Storage,
     page_fetch_limit: i64,
     token_stream_tx: mpsc::UnboundedSender<StreamMsg>,
     token_stream_rx: Mutex<mpsc::UnboundedReceiver<StreamMsg>>,
     text_analyzer: Arc<TextAnalyzer>,
-    merge_checkpoint_repo: Arc<MergeCheckpointRepo>,
+    /// Where the term dictionary, completions and segment are kept, `None` skips maintaining them.
+    index_dir: Option<PathBuf>,
+    /// Codec of the postings in the segment.
+    codec: Codec,
+    /// Pages analyzed at the same time on the blocking thread pool.
+    tokenize_parallelism: usize,
+    /// Bytes of tokens the token stream may hold, senders wait for permits so tokenizing can't
+    /// run ahead of the inversion.
+    token_stream_budget: Semaphore,
+    /// Permits `token_stream_budget` was opened with by `run`, 0 leaves the token stream unbounded.
+    token_stream_bytes: AtomicUsize,
+    /// How SPIMI measures a block against the memory budget.
+    memory_accounting: MemoryAccounting,
+    /// Blocks merged at once, more are merged in levels of intermediate blocks first.
+    merge_fan_in: usize,
+    /// Disjoint term ranges merged into the index at the same time.
+    merge_parallelism: usize,
+    /// Recorded on the pages this indexer tokenizes and on its merge checkpoints, the pages are
+    /// marked indexed once the merge of their postings commits.
+    index_run: ObjectId,
 }
 
 pub struct DictItem {
-    pub postings: Vec<ObjectId>,
-    pub positions: BTreeMap<ObjectId, Vec<usize>>,
+    pub postings: Vec<DocId>,
+    pub positions: BTreeMap<DocId, Vec<usize>>,
 }
 
 impl DictItem {
@@ -95,42 +151,253 @@ impl DictItem {
     }
 }
 
+/// How SPIMI measures a block against the memory budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum MemoryAccounting {
+    /// Heap bytes of the block dictionary, computed from the capacities of its tables and lists.
+    #[default]
+    Estimated,
+    /// Heap bytes allocated since the inversion started, counted by `heap::CountingAllocator`.
+    Allocator,
+}
+
+impl fmt::Display for MemoryAccounting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MemoryAccounting::Estimated => "estimated",
+            MemoryAccounting::Allocator => "allocator",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Bytes of a B-tree leaf node of `positions`: parent pointer, index and length, then
+/// `BTREE_CAPACITY` keys and values.
+const BTREE_LEAF_BYTES: usize =
+    (12 + BTREE_CAPACITY * (size_of::<DocId>() + size_of::<Vec<usize>>())).next_multiple_of(8);
+/// Bytes of an internal B-tree node, a leaf with `BTREE_CAPACITY + 1` child pointers.
+const BTREE_INTERNAL_BYTES: usize = BTREE_LEAF_BYTES + (BTREE_CAPACITY + 1) * size_of::<usize>();
+/// Entries of a B-tree node.
+const BTREE_CAPACITY: usize = 11;
+
+/// The dictionary of a SPIMI block, keeping count of the heap bytes it holds.
+#[derive(Default)]
+pub struct SpimiDictionary {
+    terms: HashMap<String, DictItem>,
+    heap_bytes: usize,
+}
+
+impl SpimiDictionary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the occurrence of `term` at `pos` of `doc_id`.
+    pub fn add(&mut self, term: String, doc_id: DocId, pos: usize) {
+        let table_capacity = self.terms.capacity();
+        let mut bytes = 0;
+        let dict_item = match self.terms.entry(term) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                bytes += e.key().capacity();
+                e.insert(DictItem::new())
+            }
+        };
+
+        let postings = &mut dict_item.postings;
+        let positions = &mut dict_item.positions;
+        let positions_len = positions.len();
+        match positions.entry(doc_id) {
+            std::collections::btree_map::Entry::Vacant(e) => {
+                // first time seeing this term in this document
+                // adding to postings only when vacant to keep it unique
+                let postings_capacity = postings.capacity();
+                postings.push(doc_id);
+                bytes += (postings.capacity() - postings_capacity) * DOCID_BYTES;
+                bytes += e.insert(vec![pos]).capacity() * size_of::<usize>();
+                bytes += btree_bytes(positions_len + 1) - btree_bytes(positions_len);
+            }
+            std::collections::btree_map::Entry::Occupied(mut e) => {
+                // already seen this term in this document
+                let doc_positions = e.get_mut();
+                let positions_capacity = doc_positions.capacity();
+                doc_positions.push(pos);
+                bytes += (doc_positions.capacity() - positions_capacity) * size_of::<usize>();
+            }
+        }
+
+        bytes += hash_table_bytes(self.terms.capacity()) - hash_table_bytes(table_capacity);
+        self.heap_bytes += bytes;
+    }
+
+    /// Heap bytes held by the dictionary.
+    pub fn heap_bytes(&self) -> usize {
+        self.heap_bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.terms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// The block to persist, its terms sorted.
+    pub fn into_block(self) -> SpimiBlock {
+        let mut sorted_terms = self.terms.keys().cloned().collect::<Vec<String>>();
+        sorted_terms.sort();
+        SpimiBlock {
+            sorted_terms,
+            dictionary: self.terms,
+        }
+    }
+}
+
+/// Bytes of the table of a `HashMap<String, DictItem>` holding `capacity` entries: a slot and a
+/// control byte per bucket, plus a group of trailing control bytes. Tables have a power of two
+/// buckets, 7 / 8 of them usable from 8 buckets on.
+fn hash_table_bytes(capacity: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = if capacity < 8 {
+        capacity + 1
+    } else {
+        capacity / 7 * 8
+    };
+    buckets * (size_of::<(String, DictItem)>() + 1) + HASH_TABLE_GROUP_BYTES
+}
+
+/// Trailing control bytes of a hash table, the width of the SIMD group probed at once.
+const HASH_TABLE_GROUP_BYTES: usize = 16;
+
+/// Bytes of the B-tree nodes of a `positions` map of `len` entries. Doc ids of a block arrive in
+/// ascending order, so full nodes split with 7 of their entries on the left: past the first
+/// split, every 7 entries take a leaf and every 49 an internal node.
+fn btree_bytes(len: usize) -> usize {
+    match len {
+        0 => 0,
+        1..=BTREE_CAPACITY => BTREE_LEAF_BYTES,
+        _ => (2 * BTREE_LEAF_BYTES + BTREE_INTERNAL_BYTES)
+            .max(len * (7 * BTREE_LEAF_BYTES + BTREE_INTERNAL_BYTES) / 49),
+    }
+}
+
 impl Indexer {
     pub fn new(pages_repo: Arc<PageRepo>, page_fetch_limit: i64, db: Database) -> Self {
+        Self::from_storage(
+            Storage {
+                pages: pages_repo,
+                ..Storage::mongo(&db)
+            },
+            page_fetch_limit,
+        )
+    }
+
+    /// Indexer reading pages from and writing the index to any storage backend.
+    pub fn from_storage(storage: Storage, page_fetch_limit: i64) -> Self {
         let (tx, rx) = mpsc::unbounded_channel();
         let text_analyzer = TextAnalyzer::default();
         Self {
+            storage,
             page_fetch_limit,
-            pages_repo,
-            inverted_index_repo: Arc::new(InvertedIndexRepo::new(&db)),
-            merge_checkpoint_repo: Arc::new(MergeCheckpointRepo::new(&db)),
             token_stream_tx: tx,
             token_stream_rx: Mutex::new(rx),
-            db,
             text_analyzer: Arc::new(text_analyzer),
+            index_dir: None,
+            codec: Codec::default(),
+            tokenize_parallelism: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            token_stream_budget: Semaphore::new(0),
+            token_stream_bytes: AtomicUsize::new(0),
+            memory_accounting: MemoryAccounting::default(),
+            merge_fan_in: DEFAULT_MERGE_FAN_IN,
+            merge_parallelism: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            index_run: ObjectId::new(),
         }
     }
 
+    /// Keep the term dictionary, completions and segment of the index in `index_dir`, updated after every merge.
+    pub fn with_index_dir(mut self, index_dir: impl Into<PathBuf>) -> Self {
+        self.index_dir = Some(index_dir.into());
+        self
+    }
+
+    /// Analyze pages with `analyzer` instead of the default one. Runs fail on an index built with
+    /// another analyzer, `rebuild` changes it.
+    pub fn with_analyzer(mut self, analyzer: TextAnalyzer) -> Self {
+        self.text_analyzer = Arc::new(analyzer);
+        self
+    }
+
+    /// Encode the postings of the segment with `codec` instead of the default one.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Analyze up to `parallelism` pages at the same time instead of one per CPU core.
+    pub fn with_tokenize_parallelism(mut self, parallelism: usize) -> Self {
+        self.tokenize_parallelism = parallelism.max(1);
+        self
+    }
+
+    /// Measure SPIMI blocks against the memory budget with `accounting` instead of the estimate.
+    pub fn with_memory_accounting(mut self, accounting: MemoryAccounting) -> Self {
+        self.memory_accounting = accounting;
+        self
+    }
+
+    /// Merge at most `fan_in` blocks at once, merging the others into intermediate blocks first.
+    pub fn with_merge_fan_in(mut self, fan_in: usize) -> Self {
+        self.merge_fan_in = fan_in.max(2);
+        self
+    }
+
+    /// Merge up to `parallelism` term ranges at the same time instead of one per CPU core.
+    pub fn with_merge_parallelism(mut self, parallelism: usize) -> Self {
+        self.merge_parallelism = parallelism.max(1);
+        self
+    }
+
     pub async fn run(self: Arc<Self>, budget_bytes: usize) -> Result<()> {
+        ensure!(
+            self.memory_accounting != MemoryAccounting::Allocator || heap::is_counting(),
+            "Allocator memory accounting needs the counting allocator, build with --features count-allocations"
+        );
         log::info!(
-            "Starting indexer with {}GB memory budget",
+            "Starting index run {} with {}GB memory budget",
+            self.index_run,
             budget_bytes / 1_000_000_000
         );
+        self.storage
+            .index
+            .manifest()
+            .await?
+            .ensure_run_analyzer(true, self.text_analyzer.name())?;
+        self.recover_interrupted_run().await?;
         // list the unindexed pages to prevent duplicated indexing on the same pages.
 
         let (mut pages, mut cursor) = self
-            .pages_repo
+            .storage
+            .pages
             .list_unindexed_paginated(self.page_fetch_limit, Option::None)
             .await?;
 
         log::info!("Fetched initial batch of {} unindexed pages", pages.len());
 
+        self.open_token_stream(budget_bytes / TOKEN_STREAM_BUDGET_DIVISOR);
         let self_clone = self.clone();
         // convert all the pages to a stream of tokens (text terms like hello, world, planet, etc)
         // all of those tokens are sent to a channel `token_stream` which then is processed in `spimi_invert`
         tokio::spawn(async move {
-            log::info!("Starting page tokenization stream");
+            log::info!(
+                "Starting page tokenization stream, {} pages in parallel",
+                self_clone.tokenize_parallelism
+            );
             let mut total_pages_processed = 0;
+            let mut total_tokens = 0;
+            let started = std::time::Instant::now();
             while pages.len() != 0 {
                 total_pages_processed += pages.len();
 
@@ -138,14 +405,31 @@ impl Indexer {
                 let page_ids: Vec<ObjectId> = pages.iter().map(|p| p.id).collect();
                 let rc_pages = pages.into_iter().map(Arc::new).collect();
 
-                if let Err(e) = self_clone.pages_to_token_stream(&rc_pages) {
-                    log::error!("Error converting pages to token stream: {:#}", e);
-                } else {
-                    // Mark pages as indexed after successful processing
-                    if let Err(e) = self_clone.pages_repo.mark_many_as_indexed(&page_ids).await {
-                        log::error!("Error marking pages as indexed: {:#}", e);
-                    } else {
-                        log::debug!("Marked {} pages as indexed", page_ids.len());
+                match self_clone.pages_to_token_stream(&rc_pages).await {
+                    // the inversion failed, nothing reads the token stream anymore
+                    Err(_) if self_clone.token_stream_budget.is_closed() => break,
+                    Err(e) => log::error!("Error converting pages to token stream: {:#}", e),
+                    Ok(tokens) => {
+                        total_tokens += tokens;
+                        let secs = started.elapsed().as_secs_f64();
+                        log::info!(
+                            "Tokenized {} pages, {} tokens ({:.0} pages/s, {:.0} tokens/s)",
+                            total_pages_processed,
+                            total_tokens,
+                            total_pages_processed as f64 / secs,
+                            total_tokens as f64 / secs
+                        );
+                        // the pages are marked indexed once the merge of this run commits
+                        if let Err(e) = self_clone
+                            .storage
+                            .pages
+                            .mark_many_as_tokenized(&page_ids, self_clone.index_run)
+                            .await
+                        {
+                            log::error!("Error marking pages as tokenized: {:#}", e);
+                        } else {
+                            log::debug!("Marked {} pages as tokenized", page_ids.len());
+                        }
                     }
                 }
 
@@ -156,7 +440,8 @@ impl Indexer {
                 );
 
                 let res = self_clone
-                    .pages_repo
+                    .storage
+                    .pages
                     .list_unindexed_paginated(self_clone.page_fetch_limit, cursor)
                     .await;
                 if let Err(e) = res {
@@ -173,33 +458,101 @@ impl Indexer {
             );
         });
 
-        let self_clone = self.clone();
-        self_clone.clone().spimi_invert(budget_bytes).await?;
+        let inverted = self.clone().spimi_invert(budget_bytes).await;
+        if inverted.is_err() {
+            self.token_stream_budget.close();
+        }
+        inverted?;
 
         log::info!("Indexer run completed successfully");
         Ok(())
     }
 
-    pub fn pages_to_token_stream(&self, pages: &Vec<Arc<Page>>) -> Result<()> {
+    /// Sends the tokens of `pages` to the token stream under new doc ids. Pages indexed before
+    /// changed since keep their old doc ids matching until this run commits, which tombstones them.
+    ///
+    /// Pages are analyzed on the blocking thread pool, `tokenize_parallelism` at a time, and their
+    /// tokens sent in page order, so every document's tokens stay together and in position order.
+    /// Returns the number of tokens sent.
+    pub async fn pages_to_token_stream(&self, pages: &Vec<Arc<Page>>) -> Result<usize> {
+        let page_ids: Vec<ObjectId> = pages.iter().map(|p| p.id).collect();
+        let replaced = self
+            .storage
+            .doc_ids
+            .replace_pages(&page_ids, self.index_run)
+            .await?;
+        if !replaced.is_empty() {
+            log::info!("Reindexing {} updated pages", replaced.len());
+        }
+        let doc_ids = self.storage.doc_ids.assign(&page_ids).await?;
         let token_stream = self.token_stream_tx.clone();
         let mut total_tokens = 0;
 
-        let text_analyzer = self.text_analyzer.clone();
-        for page in pages {
-            let cleaned_terms = text_analyzer.analyze(page.html_body.clone())?;
-            for text_token in cleaned_terms {
+        let track_completions = self.index_dir.is_some();
+        let analyzed = futures::stream::iter(pages.iter().cloned().zip(doc_ids))
+            .map(|(page, doc_id)| {
+                let text_analyzer = self.text_analyzer.clone();
+                async move {
+                    // parsing the html is CPU bound, keep it off the async workers
+                    let cleaned_terms = tokio::task::spawn_blocking({
+                        let page = page.clone();
+                        move || text_analyzer.analyze_with_surface_forms(page.html_body.clone())
+                    })
+                    .await
+                    .with_context(|| format!("Analyzing page {} failed", page.url))??;
+                    anyhow::Ok((page, doc_id, cleaned_terms))
+                }
+            })
+            .buffered(self.tokenize_parallelism);
+        let mut analyzed = std::pin::pin!(analyzed);
+        while let Some(result) = analyzed.next().await {
+            let (page, doc_id, cleaned_terms) = result?;
+            let mut tokens = Vec::with_capacity(cleaned_terms.len());
+            let mut words: HashMap<String, (String, u64)> = HashMap::new();
+            let mut bytes = 0;
+            for (text_token, surface) in cleaned_terms {
                 let term = text_token.term.trim();
                 if term.is_empty() {
                     continue;
                 }
-                if let Err(e) = token_stream.send(StreamMsg::Token(Token {
+                if track_completions {
+                    words
+                        .entry(surface)
+                        .or_insert_with(|| (term.to_string(), 0))
+                        .1 += 1;
+                }
+                bytes += TOKEN_BYTES + term.len();
+                tokens.push(Token {
                     term: term.to_string(),
-                    doc_id: page.id,
+                    doc_id,
                     pos: text_token.pos,
-                })) {
-                    log::error!("Error sending token to token stream: {:#}", e);
-                }
-                total_tokens += 1;
+                });
+            }
+            if tokens.is_empty() {
+                continue;
+            }
+            total_tokens += tokens.len();
+            let title = match track_completions {
+                true => page.title.clone(),
+                false => String::new(),
+            };
+            let surface_forms: Vec<(String, String, u64)> = words
+                .into_iter()
+                .map(|(word, (term, occurrences))| (term, word, occurrences))
+                .collect();
+            bytes += title.len()
+                + surface_forms
+                    .iter()
+                    .map(|(term, word, _)| SURFACE_FORM_BYTES + term.len() + word.len())
+                    .sum::<usize>();
+            let reserved_bytes = self.reserve_token_stream(bytes).await?;
+            if let Err(e) = token_stream.send(StreamMsg::Document {
+                tokens,
+                title,
+                surface_forms,
+                reserved_bytes,
+            }) {
+                log::error!("Error sending tokens to token stream: {:#}", e);
             }
         }
         log::debug!(
@@ -207,98 +560,122 @@ impl Indexer {
             total_tokens,
             pages.len()
         );
-        Ok(())
+        Ok(total_tokens)
+    }
+
+    /// Gives the token stream a budget of `bytes`, must be called while it is empty.
+    fn open_token_stream(&self, bytes: usize) {
+        // a single acquire takes at most u32::MAX permits
+        let bytes = bytes.clamp(1, u32::MAX as usize);
+        let previous = self.token_stream_bytes.swap(bytes, Ordering::SeqCst);
+        if bytes > previous {
+            self.token_stream_budget.add_permits(bytes - previous);
+        } else {
+            self.token_stream_budget.forget_permits(previous - bytes);
+        }
+    }
+
+    /// Waits until the token stream has room for `bytes` more and reserves them. Returns the
+    /// bytes reserved, a document larger than the whole budget waits for the stream to drain.
+    async fn reserve_token_stream(&self, bytes: usize) -> Result<usize> {
+        let capacity = self.token_stream_bytes.load(Ordering::SeqCst);
+        if capacity == 0 {
+            return Ok(0);
+        }
+        let reserved = bytes.min(capacity);
+        self.token_stream_budget
+            .acquire_many(reserved as u32)
+            .await
+            .context("Token stream closed")?
+            .forget();
+        Ok(reserved)
     }
 
     // SPIMI invert is an algorithm that is an optimization on top of block sort based index (BSBI)
     // see ARCHITECTURE for details on both the algorithms.
     pub async fn spimi_invert(self: Arc<Self>, budget_bytes: usize) -> Result<()> {
+        // buffered tokens count against the budget too, the stream fills up while a block flushes
+        let budget_bytes =
+            budget_bytes.saturating_sub(self.token_stream_bytes.load(Ordering::SeqCst));
         log::info!("Starting SPIMI inversion");
 
-        let mut dict: HashMap<String, DictItem> = HashMap::new();
-        let mut used_bytes = 0_usize;
+        let mut dict = SpimiDictionary::new();
+        // the completion counts of the block's documents, spilled along with it
+        let mut counts = CompletionCounts::default();
+        let mut heap_start = heap::allocated_bytes();
         let mut token_stream = self.token_stream_rx.lock().await;
         let mut tokens_processed = 0;
         let mut blocks_written = 0;
 
         // we receive the tokens already sorted by the term, so once a term is processed fully, we don't need to worry
         // that after processing some other term after this we again receive the older term.
-        while let Some(token) = token_stream.recv().await {
-            let token = match token {
-                StreamMsg::Token(token) => token,
+        while let Some(msg) = token_stream.recv().await {
+            let (tokens, reserved_bytes) = match msg {
+                StreamMsg::Document {
+                    tokens,
+                    title,
+                    surface_forms,
+                    reserved_bytes,
+                } => {
+                    counts.add_title(&title);
+                    for (term, word, occurrences) in surface_forms {
+                        counts.add_surface_form(&term, &word, occurrences);
+                    }
+                    (tokens, reserved_bytes)
+                }
                 StreamMsg::End => break,
             };
+            self.token_stream_budget.add_permits(reserved_bytes);
 
-            let (term, doc_id, pos) = (token.term, token.doc_id, token.pos);
-            tokens_processed += 1;
-            if !dict.contains_key(&term) {
-                used_bytes += term.len();
-                used_bytes += std::mem::size_of::<DictItem>(); // struct overhead
-            }
-
-            let dict_item = dict.entry(term.clone()).or_insert_with(DictItem::new);
-
-            let postings = &mut dict_item.postings;
-            let positions = &mut dict_item.positions;
+            for token in tokens {
+                tokens_processed += 1;
+                dict.add(token.term, token.doc_id, token.pos);
 
-            match positions.entry(doc_id) {
-                std::collections::btree_map::Entry::Vacant(e) => {
-                    // first time seeing this term in this document
-                    // adding to postings only when vacant to keep it unique
-                    postings.push(doc_id);
-                    e.insert(vec![pos]);
-                    used_bytes += DOCID_BYTES + std::mem::size_of::<Vec<u32>>() + 4;
+                let used_bytes = match self.memory_accounting {
+                    MemoryAccounting::Estimated => dict.heap_bytes() + counts.heap_bytes(),
+                    MemoryAccounting::Allocator => {
+                        heap::allocated_bytes().saturating_sub(heap_start)
+                    }
+                };
+
+                if tokens_processed % 100_000 == 0 {
+                    log::debug!(
+                        "Processed {} tokens, memory usage: {:.2}MB / {:.2}MB",
+                        tokens_processed,
+                        used_bytes as f64 / 1_000_000.0,
+                        budget_bytes as f64 / 1_000_000.0
+                    );
                 }
-                std::collections::btree_map::Entry::Occupied(mut e) => {
-                    // already seen this term in this document
-                    e.get_mut().push(pos);
 
-                    used_bytes += 4; // just above pos
+                if used_bytes >= budget_bytes {
+                    blocks_written += 1;
+                    log::info!(
+                        "Memory budget reached. Flushing block #{} to disk ({} unique terms, {} tokens processed)",
+                        blocks_written,
+                        dict.len(),
+                        tokens_processed
+                    );
+
+                    // flush to the disk
+                    self.persist_dictionary(std::mem::take(&mut dict)).await?;
+                    self.spill_completion_counts(std::mem::take(&mut counts))?;
+
+                    log::info!("Block #{} persisted successfully", blocks_written);
+                    if self.memory_accounting == MemoryAccounting::Allocator
+                        && heap::allocated_bytes().saturating_sub(heap_start) >= budget_bytes
+                    {
+                        log::warn!(
+                            "The heap outside the block dictionary takes the whole budget, measuring the next block from here"
+                        );
+                        heap_start = heap::allocated_bytes();
+                    }
                 }
             }
-
-            if tokens_processed % 100_000 == 0 {
-                log::debug!(
-                    "Processed {} tokens, memory usage: {:.2}MB / {:.2}MB",
-                    tokens_processed,
-                    used_bytes as f64 / 1_000_000.0,
-                    budget_bytes as f64 / 1_000_000.0
-                );
-            }
-
-            if used_bytes >= budget_bytes {
-                blocks_written += 1;
-                log::info!(
-                    "Memory budget reached. Flushing block #{} to disk ({} unique terms, {} tokens processed)",
-                    blocks_written,
-                    dict.keys().len(),
-                    tokens_processed
-                );
-
-                // flush to the disk
-                let mut sorted_terms = dict.keys().cloned().collect::<Vec<String>>();
-                sorted_terms.sort();
-                self.persist_block_to_disk(SpimiBlock {
-                    sorted_terms: sorted_terms,
-                    dictionary: dict,
-                })
-                .await?;
-
-                log::info!("Block #{} persisted successfully", blocks_written);
-                dict = HashMap::new();
-                used_bytes = 0;
-            }
         }
 
         // final flush
-        let mut sorted_terms = dict.keys().cloned().collect::<Vec<String>>();
-        sorted_terms.sort();
-
-        self.persist_block_to_disk(SpimiBlock {
-            sorted_terms: sorted_terms,
-            dictionary: dict,
-        })
-        .await?;
+        self.persist_dictionary(dict).await?;
+        self.spill_completion_counts(counts)?;
 
         log::info!(
             "SPIMI inversion complete. Processed {} tokens across {} blocks",
@@ -316,25 +693,67 @@ impl Indexer {
         token_stream.try_recv().ok()
     }
 
-    pub async fn drain_tokens(&mut self) -> Vec<StreamMsg> {
+    /// Every token in the token stream, in the order sent.
+    pub async fn drain_tokens(&mut self) -> Vec<Token> {
         let mut tokens = Vec::new();
         let mut token_stream = self.token_stream_rx.lock().await;
-        while let Ok(token) = token_stream.try_recv() {
-            tokens.push(token);
+        while let Ok(msg) = token_stream.try_recv() {
+            if let StreamMsg::Document {
+                tokens: document,
+                reserved_bytes,
+                ..
+            } = msg
+            {
+                self.token_stream_budget.add_permits(reserved_bytes);
+                tokens.extend(document);
+            }
         }
         tokens
     }
 
     pub async fn persist_block_to_disk(&self, block: SpimiBlock) -> Result<()> {
-        let collection_name = format!("spimi_block_{}", nanoid!(4));
-        log::debug!("Persisting block to collection: {}", collection_name);
+        self.write_block(&block.sorted_terms, &block.dictionary)
+            .await
+    }
 
-        let collection = self.db.collection::<SpimiDoc>(&collection_name);
-        let total_terms = block.sorted_terms.len();
+    /// Persists the block of `dict`, sorting references to its terms rather than copies of them
+    /// so the flush takes little heap on top of the dictionary.
+    async fn persist_dictionary(&self, dict: SpimiDictionary) -> Result<()> {
+        let mut sorted_terms = dict.terms.keys().collect::<Vec<_>>();
+        sorted_terms.sort_unstable();
+        self.write_block(&sorted_terms, &dict.terms).await
+    }
+
+    /// Writes the completion counts of a flushed block to `COMPLETION_COUNTS_DIR`, the merge of
+    /// the run adds them to the completions.
+    fn spill_completion_counts(&self, counts: CompletionCounts) -> Result<()> {
+        let Some(index_dir) = &self.index_dir else {
+            return Ok(());
+        };
+        if counts.is_empty() {
+            return Ok(());
+        }
+        counts.spill(
+            &index_dir.join(COMPLETION_COUNTS_DIR),
+            &ObjectId::new().to_hex(),
+        )
+    }
+
+    async fn write_block(
+        &self,
+        sorted_terms: &[impl AsRef<str>],
+        dictionary: &HashMap<String, DictItem>,
+    ) -> Result<()> {
+        let block_name = new_block_name();
+        log::debug!("Persisting block: {}", block_name);
+
+        let blocks = &self.storage.blocks;
+        let total_terms = sorted_terms.len();
         let mut terms_written = 0;
 
-        for term in block.sorted_terms {
-            if let Some(dict_item) = block.dictionary.get(&term) {
+        for term in sorted_terms {
+            let term = term.as_ref();
+            if let Some(dict_item) = dictionary.get(term) {
                 let postings = &dict_item.postings;
 
                 // part the postings by 1 Million
@@ -346,15 +765,14 @@ impl Indexer {
                     // Since postings are sorted, the range is simply [first_doc..last_doc]
                     let start_doc = part.first().expect("chunk should not be empty, start doc");
                     let end_doc = part.last().expect("chunk should not be empty, end doc");
-                    let this_positions: HashMap<ObjectId, Vec<usize>> = dict_item
+                    let this_positions: HashMap<DocId, Vec<usize>> = dict_item
                         .positions
                         .range(start_doc..=end_doc)
                         .map(|(k, v)| (*k, v.clone()))
                         .collect();
-                    // TODO: abstract out the persistance in a separate interface
                     let doc =
-                        SpimiDoc::new(term.clone(), bucket, df, part.to_vec(), this_positions); // NOTE: can we optimize part.to_vec() ?
-                    let _ = collection.insert_one(doc).await?;
+                        SpimiDoc::new(term.to_string(), bucket, df, part.to_vec(), this_positions); // NOTE: can we optimize part.to_vec() ?
+                    blocks.append_to_block(&block_name, doc).await?;
                     bucket += 1;
                 }
 
@@ -367,409 +785,760 @@ impl Indexer {
             }
         }
 
-        // create index in background
-        let options = IndexOptions::builder().background(Some(true)).build();
-        collection
-            .create_index(
-                IndexModel::builder()
-                    .options(options)
-                    .keys(doc! { "term": 1 })
-                    .build(),
-            )
-            .await?;
-        log::debug!(
-            "Block persisted: {} ({} terms)",
-            collection_name,
-            total_terms
-        );
+        blocks.seal_block(&block_name).await?;
+        log::debug!("Block persisted: {} ({} terms)", block_name, total_terms);
         Ok(())
     }
 
+    /// Merges the SPIMI blocks into a new delta generation on top of the current one and commits
+    /// it, then updates the term dictionary, completions and segments.
     pub async fn merge_persisted_blocks(&self) -> Result<()> {
         log::info!("Starting merge of persisted blocks");
 
-        let filter = doc! {
-            "name": {
-                "$regex": r"^spimi_block_*"
-            }
-        };
-        let collections = self
-            .db
-            .database()
-            .list_collection_names()
-            .filter(filter)
-            .await
-            .unwrap();
+        // the pages of this run and of a merge interrupted before are indexed once this one commits
+        let incomplete = self.storage.checkpoints.get_incomplete().await?;
+        let mut index_runs: Vec<ObjectId> =
+            incomplete.iter().filter_map(|cp| cp.index_run).collect();
+        index_runs.push(self.index_run);
+        index_runs.sort();
+        index_runs.dedup();
 
-        let num_blocks = collections.len();
-        if num_blocks == 0 {
+        let blocks = self.storage.blocks.list_blocks().await?;
+        if blocks.is_empty() {
             log::warn!("No SPIMI blocks found to merge");
-            return Ok(());
+            let generation = self.storage.index.manifest().await?.current;
+            return self.commit_index_runs(&index_runs, generation).await;
         }
+        log::info!("Found {} blocks to merge", blocks.len());
+
+        // blocks a crashed merge already wrote terms from can't be merged into other blocks, the
+        // index would get their terms twice
+        let started: HashSet<&str> = incomplete
+            .iter()
+            .filter(|cp| cp.last_merged_term.is_some())
+            .map(|cp| checkpoint_block(&cp.collection_name))
+            .collect();
+        let blocks = self.merge_block_levels(blocks, &started).await?;
+
+        // a resumed merge keeps the term ranges it started with
+        let resumed: Vec<&MergeCheckpoint> = incomplete
+            .iter()
+            .filter(|cp| {
+                blocks
+                    .iter()
+                    .any(|b| b == checkpoint_block(&cp.collection_name))
+            })
+            .collect();
+        let boundaries = if resumed.is_empty() {
+            self.sample_range_boundaries(&blocks[0]).await?
+        } else {
+            let mut boundaries: Vec<String> = resumed
+                .iter()
+                .filter_map(|cp| cp.range_start.clone())
+                .collect();
+            boundaries.sort();
+            boundaries.dedup();
+            boundaries
+        };
+        let ranges = term_ranges(boundaries);
+        log::info!(
+            "Merging {} blocks in {} term ranges",
+            blocks.len(),
+            ranges.len()
+        );
 
-        log::info!("Found {} blocks to merge", num_blocks);
-
-        // 1. Ensure checkpoints exist for all blocks
-        let mut checkpoint_map = HashMap::new();
-        for coll in &collections {
-            let cp = self.merge_checkpoint_repo.get_or_create(coll).await?;
-            // If the checkpoint is marked completed but the collection still exists (e.g. crash before cleanup),
-            // we should probably respect that it's completed or handle it.
-            // But get_or_create doesn't check 'completed'.
-            // NOTE: the merge loop logic relies on 'last_merged_term'.
-            // If it was completed, 'last_merged_term' will be the last term.
-            // But we filter primarily by 'incomplete' in previous logic.
-            // Let's rely on the fact that if it's completed we might re-verify or cleanup later.
-            // For now, just map it.
-            checkpoint_map.insert(coll.clone(), cp);
-        }
-
-        // 2. Open cursors
-        let mut streamers = Vec::new();
-        let mut active_collections = Vec::new();
-
-        for coll in collections {
-            log::debug!("  Opening cursor for block: {}", coll);
-
-            // Check for checkpoint
-            let checkpoint = checkpoint_map.get(&coll);
-            let filter = if let Some(cp) = checkpoint {
-                if let Some(ref term) = cp.last_merged_term {
-                    log::info!("  Resuming {} from term '{}'", coll, term);
-                    doc! { "term": { "$gte": term } }
+        // queries keep reading the current generation until the merged one is committed
+        let index = self
+            .storage
+            .index
+            .begin_generation(true, self.text_analyzer.name())
+            .await?;
+
+        let mut merges = tokio::task::JoinSet::new();
+        for (range_idx, range) in ranges.iter().enumerate() {
+            let mut checkpoints = Vec::with_capacity(blocks.len());
+            for block in &blocks {
+                let name = if ranges.len() == 1 {
+                    block.clone()
                 } else {
-                    doc! {}
+                    format!("{block}{CHECKPOINT_RANGE_SEPARATOR}{range_idx}")
+                };
+                let cp = self
+                    .storage
+                    .checkpoints
+                    .get_or_insert(MergeCheckpoint {
+                        index_run: Some(self.index_run),
+                        ..MergeCheckpoint::with_range(name, range.start.clone(), range.end.clone())
+                    })
+                    .await?;
+                if let Some(term) = &cp.last_merged_term {
+                    log::info!("  Resuming {} from term '{}'", cp.collection_name, term);
                 }
-            } else {
-                doc! {}
-            };
-
-            let collection = self.db.collection::<SpimiDoc>(&coll);
-            let options = mongodb::options::FindOptions::builder()
-                .sort(doc! { "term": 1, "bucket": 1})
-                .build();
-            let cursor = collection.find(filter).with_options(options).await.unwrap();
-            streamers.push(cursor);
-            active_collections.push(coll);
-        }
-
-        let mut min_terms: BinaryHeap<Reverse<HeapItem>> = BinaryHeap::new();
-        // prime the min terms heap
-        for (idx, streamer) in streamers.iter_mut().enumerate() {
-            if streamer.has_next() {
-                let doc = streamer.next().await.unwrap().unwrap();
-                min_terms.push(Reverse(HeapItem {
-                    term: doc.term.clone(),
-                    streamer_idx: idx,
-                    doc,
-                }));
+                checkpoints.push(cp);
             }
+            let merge = merge_term_range(
+                self.storage.clone(),
+                index.clone(),
+                range.clone(),
+                checkpoints,
+            );
+            merges.spawn(async move { (range_idx, merge.await) });
         }
 
-        let mut terms_merged = 0;
+        // (term, docs added) for every bucket written, in term order, to update the term dictionary
+        let mut merged = Vec::with_capacity(ranges.len());
+        while let Some(res) = merges.join_next().await {
+            let (range_idx, res) = res.context("Term range merge panicked")?;
+            merged.push((range_idx, res?));
+        }
+        merged.sort_by_key(|(range_idx, _)| *range_idx);
+        let mut merged_terms = Vec::new();
         let mut docs_written = 0;
+        for (_, range) in merged {
+            merged_terms.extend(range.terms);
+            docs_written += range.docs_written;
+        }
 
-        // STATE TRACKING
-        let mut current_postings = vec![];
-        let mut current_positions = HashMap::new();
-        let mut bucket = 0_i16;
-        let mut active_term: Option<String> = Option::None;
-        // Track if we're appending to an existing bucket (for incremental indexing)
-        let mut existing_bucket_id: Option<ObjectId> = None;
+        log::info!(
+            "Merge complete! Processed {} unique terms, wrote {} documents to inverted index",
+            merged_terms.len(),
+            docs_written
+        );
+        let generation = self.storage.index.commit_generation().await?;
+        log::info!("Index generation {} committed", generation);
+        self.commit_index_runs(&index_runs, generation).await?;
+        self.forget_purged().await?;
 
-        while let Some(Reverse(item)) = min_terms.pop() {
-            let doc = item.doc;
+        self.update_term_dictionary(merged_terms)?;
+        self.update_completions().await?;
+        self.update_segment().await?;
 
-            // When term changes, flush the current term and load state for new term
-            if let Some(ref term) = active_term {
-                if *term != doc.term {
-                    // Flush the old term
-                    self.flush_term_to_db(
-                        term,
-                        &mut current_postings,
-                        &mut current_positions,
-                        &mut bucket,
-                        &mut docs_written,
-                        &mut existing_bucket_id,
-                        &active_collections,
-                        &mut checkpoint_map,
-                    )
-                    .await?;
-                    current_positions.clear();
-                    current_postings.clear();
+        // Clean up temporary SPIMI blocks
+        self.cleanup_spimi_blocks().await?;
 
-                    // Load existing bucket state for the new term (incremental indexing)
-                    if let Some(last_bucket) =
-                        self.inverted_index_repo.get_last_bucket(&doc.term).await?
-                    {
-                        let space_used = last_bucket.postings.len();
-                        if space_used < DOCIDS_PER_MONGO_DOCUMENT {
-                            // We can continue appending to this bucket
-                            log::debug!(
-                                "Continuing from existing bucket {} for term '{}' ({}/{} docs)",
-                                last_bucket.bucket,
-                                doc.term,
-                                space_used,
-                                DOCIDS_PER_MONGO_DOCUMENT
-                            );
-                            bucket = last_bucket.bucket;
-                            existing_bucket_id = Some(last_bucket.id);
-                            // Note: We don't load existing postings/positions - we only append new ones
-                        } else {
-                            // Bucket is full, start a new one
-                            bucket = last_bucket.bucket + 1;
-                            existing_bucket_id = None;
-                        }
-                    } else {
-                        // No existing bucket, start fresh
-                        bucket = 0;
-                        existing_bucket_id = None;
-                    }
-                }
-            } else {
-                // First term - check if it exists in the index
-                if let Some(last_bucket) =
-                    self.inverted_index_repo.get_last_bucket(&doc.term).await?
-                {
-                    let space_used = last_bucket.postings.len();
-                    if space_used < DOCIDS_PER_MONGO_DOCUMENT {
-                        bucket = last_bucket.bucket;
-                        existing_bucket_id = Some(last_bucket.id);
-                    } else {
-                        bucket = last_bucket.bucket + 1;
-                        existing_bucket_id = None;
-                    }
-                }
-            }
-            active_term = Some(doc.term.clone());
-            let merged_postings = merge_sorted_lists_dedup(&current_postings, &doc.postings);
-            current_postings = merged_postings;
-            current_positions = merge_hashmaps(current_positions, doc.positions);
-
-            if current_postings.len() >= DOCIDS_PER_MONGO_DOCUMENT {
-                let overflow_postings = current_postings.split_off(DOCIDS_PER_MONGO_DOCUMENT);
-                let mut flush_positions = HashMap::new();
-                for doc_id in &current_postings {
-                    // current only have the postings that we are going to flush.
-                    if let Some(pos) = current_positions.remove(doc_id) {
-                        // remove all these postions from `current_positions` because we are going to flush them.
-                        flush_positions.insert(*doc_id, pos);
-                    }
-                }
-                self.flush_term_to_db(
-                    active_term.as_ref().unwrap(),
-                    &mut current_postings,
-                    &mut flush_positions,
-                    &mut bucket,
-                    &mut docs_written,
-                    &mut existing_bucket_id,
-                    &active_collections,
-                    &mut checkpoint_map,
-                )
-                .await?;
-                current_postings = overflow_postings;
-                // no need to do anything with `current_positions` as we have already removed.
-            }
+        log::info!("Indexing complete! Safe to quit now.");
 
-            // ADVANCE the streamer where this term came from.
-            let cursor = &mut streamers[item.streamer_idx];
-            if cursor.has_next() {
-                if let Some(res) = cursor.next().await {
-                    if res.is_err() {
-                        let err = res.clone().unwrap_err();
-                        log::error!("Error fetching next document from cursor: {:#}", err);
-                    } else {
-                        let next_spimi = res.unwrap();
-                        min_terms.push(Reverse(HeapItem {
-                            term: next_spimi.term.clone(),
-                            streamer_idx: item.streamer_idx,
-                            doc: next_spimi,
-                        }));
-                    }
-                }
+        Ok(())
+    }
+
+    /// Completes a run interrupted while merging, so its pages are indexed before this run lists
+    /// the unindexed ones. The blocks of a run interrupted before merging are dropped instead, its
+    /// pages are still unindexed and get tokenized again.
+    async fn recover_interrupted_run(&self) -> Result<()> {
+        if !self.storage.checkpoints.get_incomplete().await?.is_empty() {
+            log::info!("Resuming the merge of an interrupted index run");
+            return self.merge_persisted_blocks().await;
+        }
+        let blocks = self.storage.blocks.list_blocks().await?;
+        if !blocks.is_empty() {
+            log::warn!(
+                "Dropping {} blocks of an index run interrupted before merging, its pages are tokenized again",
+                blocks.len()
+            );
+            for block in blocks {
+                self.storage.blocks.drop_block(&block).await?;
             }
-            terms_merged += 1;
-            if terms_merged % 10_000 == 0 {
-                log::info!(
-                    "  Merged {} terms, written {} documents to inverted index",
-                    terms_merged,
-                    docs_written
-                );
+            if let Some(index_dir) = &self.index_dir {
+                CompletionCounts::remove_spilled(&index_dir.join(COMPLETION_COUNTS_DIR))?;
             }
         }
+        Ok(())
+    }
 
-        if let Some(last_term) = active_term {
-            if !current_postings.is_empty() {
-                self.flush_term_to_db(
-                    &last_term,
-                    &mut current_postings,
-                    &mut current_positions,
-                    &mut bucket,
-                    &mut docs_written,
-                    &mut existing_bucket_id,
-                    &active_collections,
-                    &mut checkpoint_map,
-                )
+    /// Tombstones the doc ids `index_runs` replaced as replaced by `generation` and marks the pages
+    /// they tokenized as indexed, then completes the merge checkpoints. Until then an interrupted run resumes the merge and
+    /// does it. A resumed merge skips what the checkpoints record as merged, and postings a crashed
+    /// write got into the index.
+    async fn commit_index_runs(&self, index_runs: &[ObjectId], generation: u64) -> Result<()> {
+        let replaced = self
+            .storage
+            .doc_ids
+            .commit_replaced(index_runs, generation)
+            .await?;
+        if !replaced.is_empty() {
+            log::info!("Tombstoned {} replaced doc ids", replaced.len());
+        }
+        let indexed = self.storage.pages.mark_runs_as_indexed(index_runs).await?;
+        log::info!("Marked {} pages as indexed", indexed);
+
+        // every block was merged, this also completes checkpoints left by ranges of a crashed merge
+        for cp in self.storage.checkpoints.get_incomplete().await? {
+            self.storage
+                .checkpoints
+                .mark_completed(&cp.collection_name)
                 .await?;
-            }
         }
+        Ok(())
+    }
+
+    /// Merges the blocks no merge started on, `merge_fan_in` at a time, into intermediate blocks
+    /// until at most `merge_fan_in` blocks are left. Returns the blocks left.
+    async fn merge_block_levels(
+        &self,
+        blocks: Vec<String>,
+        started: &HashSet<&str>,
+    ) -> Result<Vec<String>> {
+        let (started, mut fresh): (Vec<String>, Vec<String>) = blocks
+            .into_iter()
+            .partition(|block| started.contains(block.as_str()));
+
+        let mut level = 0;
+        while fresh.len() > 1 && started.len() + fresh.len() > self.merge_fan_in {
+            level += 1;
+            log::info!(
+                "Merge level {}: merging {} blocks {} at a time",
+                level,
+                fresh.len(),
+                self.merge_fan_in
+            );
+            let groups: Vec<Vec<String>> = fresh
+                .chunks(self.merge_fan_in)
+                .map(|group| group.to_vec())
+                .collect();
+            fresh = futures::stream::iter(groups)
+                .map(|group| tokio::spawn(merge_into_block(self.storage.blocks.clone(), group)))
+                .buffered(self.merge_parallelism)
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .map(|merged| merged.context("Block merge panicked")?)
+                .collect::<Result<_>>()?;
+        }
+
+        Ok(started.into_iter().chain(fresh).collect())
+    }
 
-        // Mark all collections as completed
-        for coll in active_collections {
-            self.merge_checkpoint_repo.mark_completed(&coll).await?;
+    /// First terms of all but the first of `merge_parallelism` term ranges, evenly spaced over the
+    /// terms of `block`.
+    async fn sample_range_boundaries(&self, block: &str) -> Result<Vec<String>> {
+        if self.merge_parallelism <= 1 {
+            return Ok(Vec::new());
+        }
+        let mut terms: Vec<String> = Vec::new();
+        let mut docs = self.storage.blocks.read_block(block, None).await?;
+        while let Some(doc) = docs.next().await {
+            let doc = doc?;
+            if terms.last() != Some(&doc.term) {
+                terms.push(doc.term);
+            }
         }
 
+        let mut boundaries: Vec<String> = (1..self.merge_parallelism)
+            .map(|i| i * terms.len() / self.merge_parallelism)
+            .filter(|&i| i > 0)
+            .map(|i| terms[i].clone())
+            .collect();
+        boundaries.dedup();
+        Ok(boundaries)
+    }
+
+    /// Deletes the pages at `urls` and tombstones their doc ids, so they stop matching queries right
+    /// away. Their postings stay in the index until `compact`. Returns the number of pages deleted.
+    pub async fn delete_pages(&self, urls: &[String]) -> Result<usize> {
+        let mut page_ids = Vec::new();
+        for url in urls {
+            match self.storage.pages.delete_by_url(url).await? {
+                Some(page_id) => page_ids.push(page_id),
+                None => log::warn!("No page at {}", url),
+            }
+        }
+        let doc_ids = self.storage.doc_ids.delete_pages(&page_ids).await?;
         log::info!(
-            "Merge complete! Processed {} unique terms, wrote {} documents to inverted index",
-            terms_merged,
-            docs_written
+            "Deleted {} pages, {} of them were indexed",
+            page_ids.len(),
+            doc_ids.len()
         );
+        Ok(page_ids.len())
+    }
 
-        // Clean up temporary SPIMI block collections
-        self.cleanup_spimi_blocks().await?;
+    /// Rewrites the index without the postings and positions of deleted documents into a new
+    /// generation and commits it, like `optimize`. Fails while an index run is building a
+    /// generation. Returns the number of documents purged.
+    pub async fn compact(&self) -> Result<usize> {
+        self.ensure_no_generation_building().await?;
+        let deleted = self.storage.doc_ids.deleted().await?;
+        if deleted.is_empty() {
+            log::info!("No deleted documents, nothing to compact");
+            return Ok(0);
+        }
+        self.rewrite_index(&BTreeSet::new()).await?;
+        Ok(deleted.len())
+    }
 
-        log::info!("Indexing complete! Safe to quit now.");
+    /// Rewrites the buckets of every term into sorted, densely packed buckets without the postings
+    /// of deleted documents into a new generation and commits it, then rewrites the term
+    /// dictionary, completions and segment. The tombstones are dropped once no generation the index keeps has
+    /// their postings, see `forget_purged`. Fails while an index run is building a generation.
+    /// Returns the number of buckets written.
+    pub async fn optimize(&self) -> Result<usize> {
+        self.rewrite_index(&BTreeSet::new()).await
+    }
 
-        Ok(())
+    /// Repairs what `verify` reported: completes the merge of an interrupted index run or drops
+    /// its blocks, then rewrites the index like `optimize` when buckets have issues, dropping the
+    /// postings of dangling doc ids too. Returns the number of buckets written.
+    pub async fn repair(&self, report: &VerifyReport) -> Result<usize> {
+        if report.has_run_leftovers() {
+            self.recover_interrupted_run().await?;
+        }
+        if !report.has_bucket_issues() {
+            return Ok(0);
+        }
+        self.rewrite_index(&report.dangling_doc_ids).await
     }
 
-    async fn cleanup_spimi_blocks(&self) -> Result<()> {
-        log::info!("Cleaning up temporary SPIMI block collections");
+    /// Indexes every page again with the analyzer of this indexer into a new, empty generation
+    /// recorded as built with it and commits it, then rewrites the term dictionary, completions and
+    /// segment. Queries keep matching the current generation under the pages' old
+    /// doc ids until then, and again after a `rollback`. An interrupted rebuild leaves its
+    /// generation being built, the next index run finishes it. Fails while an index run is
+    /// building a generation.
+    pub async fn rebuild(self: Arc<Self>, budget_bytes: usize) -> Result<()> {
+        self.ensure_no_generation_building().await?;
+        // the generation is begun before the pages are marked, so a run after a crash resumes it
+        // instead of adding every page to a delta on the current generation
+        self.storage
+            .index
+            .begin_generation(false, self.text_analyzer.name())
+            .await?;
+        let pages = self.storage.pages.mark_all_as_unindexed().await?;
+        log::info!(
+            "Rebuilding the index of {} pages with analyzer '{}'",
+            pages,
+            self.text_analyzer.name()
+        );
+        self.clone().run(budget_bytes).await?;
+        // without any page nothing was merged
+        if self.storage.index.manifest().await?.building.is_some() {
+            self.storage.index.commit_generation().await?;
+        }
 
-        let filter = doc! {
-            "name": {
-                "$regex": r"^spimi_block_*"
-            }
-        };
-        let collections = self
-            .db
-            .database()
-            .list_collection_names()
-            .filter(filter)
+        // every page got a new doc id, the old ones have no postings in the new generation
+        let generation = self.storage.index.manifest().await?.current;
+        let deleted = self.storage.doc_ids.deleted().await?;
+        self.storage
+            .doc_ids
+            .purge_deleted(&deleted, generation)
             .await?;
+        self.forget_purged().await?;
+        self.rebuild_term_dictionary().await?;
+        self.rebuild_completions().await?;
+        self.update_segment().await?;
+        Ok(())
+    }
 
-        let num_collections = collections.len();
-        // Only delete completed collections
-        if num_collections == 0 {
-            log::info!("No SPIMI block collections to clean up");
-            return Ok(());
-        }
+    /// Replaces the index, doc ids and pages by those of the archive at `path` in a new generation,
+    /// then rewrites the term dictionary, completions and segment. See `IndexArchive::import`.
+    pub async fn import(&self, path: &Path) -> Result<ArchiveSummary> {
+        let summary = IndexArchive::from_storage(self.storage.clone())
+            .import(path)
+            .await?;
+        self.rebuild_term_dictionary().await?;
+        self.rebuild_completions().await?;
+        self.update_segment().await?;
+        Ok(summary)
+    }
 
-        // Get list of incomplete checkpoints to avoid deleting them
-        let incomplete = self.merge_checkpoint_repo.get_incomplete().await?;
-        let incomplete_names: Vec<String> = incomplete
+    /// `optimize`, also dropping the postings of `dangling` doc ids.
+    async fn rewrite_index(&self, dangling: &BTreeSet<DocId>) -> Result<usize> {
+        self.ensure_no_generation_building().await?;
+        let deleted = self.storage.doc_ids.deleted().await?;
+        let purged: HashSet<DocId> = deleted.iter().chain(dangling).copied().collect();
+        let terms: Vec<String> = self
+            .storage
+            .index
+            .term_document_frequencies()
+            .await?
             .into_iter()
-            .map(|cp| cp.collection_name)
+            .map(|(term, _)| term)
             .collect();
 
+        // the rewrite keeps the terms, so the analyzer they were built with
+        let analyzer = self.storage.index.manifest().await?.analyzer().to_string();
+        let index = self
+            .storage
+            .index
+            .begin_generation(false, &analyzer)
+            .await?;
+        let (mut buckets_read, mut buckets_written) = (0, 0);
+        for chunk in terms.chunks(TERMS_PER_FETCH) {
+            let mut merged: BTreeMap<String, DictItem> = BTreeMap::new();
+            for doc in self.storage.index.find_by_terms(chunk).await? {
+                buckets_read += 1;
+                let item = merged.entry(doc.term).or_insert_with(DictItem::new);
+                item.postings.extend(doc.postings);
+                for (doc_id, doc_positions) in doc.positions {
+                    item.positions
+                        .entry(doc_id)
+                        .or_default()
+                        .extend(doc_positions);
+                }
+            }
+
+            for (term, item) in merged {
+                let (mut postings, mut positions) = (item.postings, item.positions);
+                postings.retain(|doc_id| !purged.contains(doc_id));
+                postings.sort_unstable();
+                postings.dedup();
+                for (bucket, part) in postings.chunks(DOCIDS_PER_MONGO_DOCUMENT).enumerate() {
+                    let part_positions: HashMap<DocId, Vec<usize>> = part
+                        .iter()
+                        .filter_map(|doc_id| positions.remove_entry(doc_id))
+                        .map(|(doc_id, mut doc_positions)| {
+                            doc_positions.sort_unstable();
+                            doc_positions.dedup();
+                            (doc_id, doc_positions)
+                        })
+                        .collect();
+                    index
+                        .insert(InvertedIndexDoc::new(
+                            term.clone(),
+                            bucket as i16,
+                            part.len() as u64,
+                            part.to_vec(),
+                            part_positions,
+                        ))
+                        .await?;
+                    buckets_written += 1;
+                }
+            }
+        }
+        let generation = self.storage.index.commit_generation().await?;
+        self.storage
+            .doc_ids
+            .purge_deleted(&deleted, generation)
+            .await?;
+        self.forget_purged().await?;
         log::info!(
-            "Found {} SPIMI block collections to delete",
-            num_collections
+            "Optimized {} terms into generation {}: {} buckets rewritten into {}, {} deleted and {} dangling documents dropped",
+            terms.len(),
+            generation,
+            buckets_read,
+            buckets_written,
+            deleted.len(),
+            dangling.len()
         );
 
-        for collection_name in collections {
-            if incomplete_names.contains(&collection_name) {
-                log::info!("  Skipping incomplete collection: {}", collection_name);
-                continue;
-            }
+        self.rebuild_term_dictionary().await?;
+        self.rebuild_completions().await?;
+        self.update_segment().await?;
+        Ok(buckets_written)
+    }
 
-            log::debug!("  Dropping collection: {}", collection_name);
-            self.db
-                .database()
-                .collection::<SpimiDoc>(&collection_name)
-                .drop()
+    /// Makes the index generation before the last committed one current again and rewrites the
+    /// term dictionary, completions and segment from it. Pages indexed again since get back the doc ids the
+    /// generation has their postings under, and are marked unindexed with the pages indexed for
+    /// the first time since, so the next run indexes their current content. Tombstones the
+    /// rolled back generation purged are kept until a later one purges them. Returns the
+    /// generation now current.
+    pub async fn rollback(&self) -> Result<u64> {
+        let generation = self.storage.index.rollback_generation().await?;
+        log::info!("Rolled the index back to generation {}", generation);
+        let revived = self.storage.doc_ids.revive_replaced(generation).await?;
+        if !revived.is_empty() {
+            log::info!(
+                "Revived the doc ids of {} pages indexed again since",
+                revived.len()
+            );
+        }
+        self.forget_purged().await?;
+
+        let page_ids = self.storage.doc_ids.page_ids().await?;
+        let live: HashSet<DocId> = page_ids.iter().map(|(doc_id, _)| *doc_id).collect();
+        let terms: Vec<String> = self
+            .storage
+            .index
+            .term_document_frequencies()
+            .await?
+            .into_iter()
+            .map(|(term, _)| term)
+            .collect();
+        let mut indexed = HashSet::new();
+        for chunk in terms.chunks(TERMS_PER_FETCH) {
+            for doc in self.storage.index.find_by_terms(chunk).await? {
+                indexed.extend(
+                    doc.postings
+                        .into_iter()
+                        .filter(|doc_id| live.contains(doc_id)),
+                );
+            }
+        }
+        // a page indexed for the first time since has no postings in the generation, a revived
+        // one has those of the content it had then
+        let in_generation: HashSet<ObjectId> = page_ids
+            .iter()
+            .filter(|(doc_id, _)| indexed.contains(doc_id))
+            .map(|(_, page_id)| *page_id)
+            .collect();
+        let unindexed: Vec<ObjectId> = page_ids
+            .iter()
+            .map(|(_, page_id)| *page_id)
+            .filter(|page_id| !in_generation.contains(page_id))
+            .chain(revived)
+            .collect();
+        if !unindexed.is_empty() {
+            let marked = self
+                .storage
+                .pages
+                .mark_many_as_unindexed(&unindexed)
                 .await?;
+            log::info!("Marked {} pages indexed since as unindexed", marked);
         }
 
-        // Cleanup completed checkpoints
-        self.merge_checkpoint_repo.delete_completed().await?;
+        self.rebuild_term_dictionary().await?;
+        self.rebuild_completions().await?;
+        self.update_segment().await?;
+        Ok(generation)
+    }
+
+    /// Drops the tombstones purged by the oldest generation the index keeps or an older one, no
+    /// generation it reads has their postings anymore.
+    async fn forget_purged(&self) -> Result<()> {
+        let manifest = self.storage.index.manifest().await?;
+        let forgotten = self
+            .storage
+            .doc_ids
+            .forget_purged(manifest.oldest_kept(), manifest.current)
+            .await?;
+        if forgotten > 0 {
+            log::info!("Dropped {} tombstones of purged documents", forgotten);
+        }
+        Ok(())
+    }
+
+    /// Fails while an index run is building a generation, a generation rewritten meanwhile
+    /// would be replaced when the run commits.
+    async fn ensure_no_generation_building(&self) -> Result<()> {
+        if let Some(generation) = self.storage.index.manifest().await?.building {
+            bail!(
+                "Index generation {} is being built, finish the index run first",
+                generation
+            );
+        }
+        Ok(())
+    }
 
+    /// Rebuilds the term dictionary on disk from the document frequencies of the index store.
+    async fn rebuild_term_dictionary(&self) -> Result<()> {
+        let Some(index_dir) = &self.index_dir else {
+            return Ok(());
+        };
+        let path = index_dir.join(TERM_DICT_FILE);
+        let dictionary =
+            TermDictionary::build(self.storage.index.term_document_frequencies().await?)?;
+        dictionary.save(&path)?;
         log::info!(
-            "Successfully deleted {} SPIMI block collections",
-            num_collections
+            "Term dictionary rebuilt: {} terms ({})",
+            dictionary.len(),
+            path.display()
         );
         Ok(())
     }
 
-    async fn flush_term_to_db(
-        &self,
-        term: &str,
-        postings: &mut Vec<ObjectId>,
-        positions: &mut HashMap<ObjectId, Vec<usize>>,
-        bucket: &mut i16,
-        docs_written: &mut usize,
-        existing_bucket_id: &mut Option<ObjectId>,
-        active_collections: &[String],
-        checkpoints: &mut HashMap<String, MergeCheckpoint>,
-    ) -> Result<()> {
-        if postings.is_empty() {
+    /// Adds the terms merged in this run to the term dictionary on disk.
+    fn update_term_dictionary(&self, merged_terms: Vec<(String, u64)>) -> Result<()> {
+        let Some(index_dir) = &self.index_dir else {
+            log::debug!("No index dir configured, skipping term dictionary update");
             return Ok(());
+        };
+        let path = index_dir.join(TERM_DICT_FILE);
+        let dictionary =
+            TermDictionary::open(&path)?.merge(&TermDictionary::build(merged_terms)?)?;
+        dictionary.save(&path)?;
+        log::info!(
+            "Term dictionary updated: {} terms ({})",
+            dictionary.len(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Adds the titles and surface forms spilled by this run, plus the queries searched since the
+    /// last run, to the phrases and surface forms on disk, then rewrites the completion index.
+    async fn update_completions(&self) -> Result<()> {
+        let Some(index_dir) = &self.index_dir else {
+            return Ok(());
+        };
+        let counts_dir = index_dir.join(COMPLETION_COUNTS_DIR);
+        let (titles, surface_forms) = CompletionCounts::read_spilled(&counts_dir)?;
+        let path = index_dir.join(SURFACE_FORMS_FILE);
+        let surface_forms = SurfaceForms::open(&path)?.merge(&surface_forms)?;
+        surface_forms.save(&path)?;
+
+        let queries = self.storage.query_log.take_pending().await?;
+        let path = index_dir.join(COMPLETION_PHRASES_FILE);
+        let phrases =
+            CompletionIndex::open(&path)?
+                .merge(&titles)?
+                .merge(&CompletionIndex::build(
+                    queries
+                        .into_iter()
+                        .map(|(query, searches)| (query, searches * QUERY_WEIGHT)),
+                )?)?;
+        phrases.save(&path)?;
+
+        self.write_completions(index_dir, &phrases, &surface_forms)?;
+        CompletionCounts::remove_spilled(&counts_dir)
+    }
+
+    /// Recomputes the phrases from the titles of the indexed pages and every query searched, and
+    /// drops the surface forms of terms no longer indexed, then rewrites the completion index.
+    /// Completions of deleted pages and rolled back generations lose their weight this way.
+    async fn rebuild_completions(&self) -> Result<()> {
+        let Some(index_dir) = &self.index_dir else {
+            return Ok(());
+        };
+        let page_ids: Vec<ObjectId> = self
+            .storage
+            .doc_ids
+            .page_ids()
+            .await?
+            .into_iter()
+            .map(|(_, page_id)| page_id)
+            .collect();
+        let mut phrases = CompletionIndex::default();
+        for chunk in page_ids.chunks(TERMS_PER_FETCH) {
+            let pages = self.storage.pages.find_by_ids(chunk).await?;
+            let titles = CompletionIndex::build(
+                pages
+                    .into_iter()
+                    .filter(|page| page.indexed)
+                    .map(|page| (page.title, TITLE_WEIGHT)),
+            )?;
+            phrases = phrases.merge(&titles)?;
         }
+        let queries = self.storage.query_log.take_all().await?;
+        phrases = phrases.merge(&CompletionIndex::build(
+            queries
+                .into_iter()
+                .map(|(query, searches)| (query, searches * QUERY_WEIGHT)),
+        )?)?;
+        phrases.save(&index_dir.join(COMPLETION_PHRASES_FILE))?;
+
+        let term_dict = TermDictionary::open(&index_dir.join(TERM_DICT_FILE))?;
+        let path = index_dir.join(SURFACE_FORMS_FILE);
+        let surface_forms =
+            SurfaceForms::open(&path)?.retain_terms(|term| term_dict.get(term).is_some())?;
+        surface_forms.save(&path)?;
+
+        self.write_completions(index_dir, &phrases, &surface_forms)
+    }
 
-        // CHECKPOINT LOGIC: Check if this bucket is already merged
-        // We check against the first available checkpoint since all should be synced
-        let mut already_merged = false;
-        if let Some(first_coll) = active_collections.first() {
-            if let Some(cp) = checkpoints.get(first_coll) {
-                if let Some(ref last_term) = cp.last_merged_term {
-                    if last_term == term && cp.last_merged_bucket >= *bucket {
-                        already_merged = true;
-                    }
-                }
+    /// Writes the completion index: the phrases plus the words of the surface forms, weighted by
+    /// the document frequencies of the term dictionary on disk.
+    fn write_completions(
+        &self,
+        index_dir: &Path,
+        phrases: &CompletionIndex,
+        surface_forms: &SurfaceForms,
+    ) -> Result<()> {
+        let term_dict = TermDictionary::open(&index_dir.join(TERM_DICT_FILE))?;
+        let words = CompletionIndex::words(surface_forms, |term| term_dict.get(term))?;
+        let path = index_dir.join(COMPLETIONS_FILE);
+        let completions = phrases.merge(&words)?;
+        completions.save(&path)?;
+        log::info!(
+            "Completions written: {} entries ({})",
+            completions.len(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Writes the segments the query engine reads postings from, then records the current
+    /// generation as the one the index files were written for. A delta generation on top of the
+    /// one the segments were written for gets a delta segment of its own buckets, any other
+    /// generation a base segment of the whole index replacing every delta segment.
+    async fn update_segment(&self) -> Result<()> {
+        let Some(index_dir) = &self.index_dir else {
+            return Ok(());
+        };
+        let manifest = self.storage.index.manifest().await?;
+        let written = read_generation(index_dir)?
+            .filter(|_| index_dir.join(SEGMENT_FILE).exists())
+            .filter(|written| manifest.current_layers.first() == Some(&written.generation));
+        let (record, path, segment) = match written {
+            Some(mut record) => {
+                let path = index_dir.join(delta_segment_file(manifest.current));
+                let index = self.storage.index.generation(manifest.current);
+                let segment = write_segment(
+                    index.as_ref(),
+                    self.storage.doc_ids.as_ref(),
+                    self.codec,
+                    &path,
+                )
+                .await?;
+                record.generation = manifest.current;
+                record.deltas.push(manifest.current);
+                (record, path, segment)
+            }
+            None => {
+                let path = index_dir.join(SEGMENT_FILE);
+                let segment = write_segment(
+                    self.storage.index.as_ref(),
+                    self.storage.doc_ids.as_ref(),
+                    self.codec,
+                    &path,
+                )
+                .await?;
+                let record = SegmentGeneration {
+                    generation: manifest.current,
+                    deltas: Vec::new(),
+                };
+                (record, path, segment)
+            }
+        };
+        log::info!(
+            "Segment written: {} terms, {} docs ({})",
+            segment.num_terms(),
+            segment.num_docs(),
+            path.display()
+        );
+        // written last, servers reload the index files once it names their current generation
+        write_generation(index_dir, &record)?;
+        for generation in delta_segments(index_dir)? {
+            if !record.deltas.contains(&generation) {
+                std::fs::remove_file(index_dir.join(delta_segment_file(generation)))?;
             }
         }
+        Ok(())
+    }
 
-        if !already_merged {
-            // If we have an existing bucket to append to, use update instead of insert
-            if let Some(doc_id) = existing_bucket_id.take() {
-                log::debug!(
-                    "Appending {} docs to existing bucket for term '{}'",
-                    postings.len(),
-                    term
-                );
-                self.inverted_index_repo
-                    .append_to_bucket(doc_id, postings, positions)
-                    .await?;
-            } else {
-                // Insert a new bucket document
-                let doc = InvertedIndexDoc::new(
-                    term.to_string(),
-                    *bucket,
-                    postings.len() as u64,
-                    postings.clone(),
-                    positions.clone(),
-                );
-                self.inverted_index_repo.insert(doc).await?;
-            }
-            *docs_written += 1;
-        } else {
-            log::debug!(
-                "Skipping write for term '{}' bucket {} (already merged)",
-                term,
-                bucket
-            );
+    async fn cleanup_spimi_blocks(&self) -> Result<()> {
+        log::info!("Cleaning up temporary SPIMI blocks");
+
+        let collections = self.storage.blocks.list_blocks().await?;
+
+        let num_collections = collections.len();
+        // Only delete completed collections
+        if num_collections == 0 {
+            log::info!("No SPIMI blocks to clean up");
+            return Ok(());
         }
 
-        // Side effect: Update checkpoints
-        for coll in active_collections {
-            // Update DB
-            self.merge_checkpoint_repo
-                .update_progress(coll, term, *bucket)
-                .await?;
+        // Get list of incomplete checkpoints to avoid deleting them
+        let incomplete = self.storage.checkpoints.get_incomplete().await?;
+        let incomplete_blocks: HashSet<&str> = incomplete
+            .iter()
+            .map(|cp| checkpoint_block(&cp.collection_name))
+            .collect();
 
-            // Update in-memory map
-            if let Some(cp) = checkpoints.get_mut(coll) {
-                cp.last_merged_term = Some(term.to_string());
-                cp.last_merged_bucket = *bucket;
-                cp.updated_at = mongodb::bson::DateTime::now();
-            } else {
-                // Should exist, but handle creation if missing
-                // In memory we only update if it was tracked.
-                // If not tracked (maybe new collection?), we ignore for now as it wasn't in the initial map.
+        log::info!("Found {} SPIMI blocks to delete", num_collections);
+
+        for collection_name in collections {
+            if incomplete_blocks.contains(collection_name.as_str()) {
+                log::info!("  Skipping incomplete collection: {}", collection_name);
+                continue;
             }
+
+            log::debug!("  Dropping collection: {}", collection_name);
+            self.storage.blocks.drop_block(&collection_name).await?;
         }
 
-        *bucket += 1;
+        // Cleanup completed checkpoints
+        self.storage.checkpoints.delete_completed().await?;
+
+        log::info!("Successfully deleted {} SPIMI blocks", num_collections);
         Ok(())
     }
 }
@@ -777,23 +1546,360 @@ impl Indexer {
 // Helper to keep the main loop clean
 
 fn merge_hashmaps(
-    mut map_a: HashMap<ObjectId, Vec<usize>>,
-    map_b: HashMap<ObjectId, Vec<usize>>,
-) -> HashMap<ObjectId, Vec<usize>> {
+    mut map_a: HashMap<DocId, Vec<usize>>,
+    map_b: HashMap<DocId, Vec<usize>>,
+) -> HashMap<DocId, Vec<usize>> {
     for (doc_id, mut new_positions) in map_b {
         match map_a.entry(doc_id) {
             std::collections::hash_map::Entry::Vacant(e) => {
                 e.insert(new_positions);
             }
             std::collections::hash_map::Entry::Occupied(mut e) => {
+                // a document split across blocks can arrive in any block order, phrase matching
+                // relies on positions being sorted.
                 let x = e.get_mut();
                 x.append(&mut new_positions);
+                x.sort_unstable();
+                x.dedup();
             }
         }
     }
     map_a
 }
 
+/// Terms from `start` (inclusive) to `end` (exclusive), `None` leaves a side unbounded.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct TermRange {
+    start: Option<String>,
+    end: Option<String>,
+}
+
+/// The ranges split at `boundaries`, which are sorted, covering every term.
+fn term_ranges(boundaries: Vec<String>) -> Vec<TermRange> {
+    let mut ranges = vec![TermRange::default()];
+    for boundary in boundaries {
+        ranges.last_mut().unwrap().end = Some(boundary.clone());
+        ranges.push(TermRange {
+            start: Some(boundary),
+            end: None,
+        });
+    }
+    ranges
+}
+
+/// The block a checkpoint tracks, checkpoints of a term range are named `{block}#{range}`.
+fn checkpoint_block(checkpoint_name: &str) -> &str {
+    checkpoint_name
+        .split_once(CHECKPOINT_RANGE_SEPARATOR)
+        .map_or(checkpoint_name, |(block, _)| block)
+}
+
+/// Postings of a term merged across blocks, at most `DOCIDS_PER_MONGO_DOCUMENT` of them.
+struct MergedPostings {
+    term: String,
+    postings: Vec<DocId>,
+    positions: HashMap<DocId, Vec<usize>>,
+}
+
+/// K-way merge of SPIMI blocks, yields the postings of every term in term order.
+struct BlockMerger {
+    streams: Vec<BoxStream<'static, Result<SpimiDoc>>>,
+    min_terms: BinaryHeap<Reverse<HeapItem>>,
+    /// Terms from this one on are left out.
+    end: Option<String>,
+    /// Postings of the current term not yielded yet.
+    pending: Option<MergedPostings>,
+}
+
+impl BlockMerger {
+    /// Merges `(block, from_term)` sources, each block read from `from_term` if given.
+    async fn open(
+        blocks: &dyn BlockStore,
+        sources: &[(String, Option<String>)],
+        end: Option<String>,
+    ) -> Result<Self> {
+        let mut merger = Self {
+            streams: Vec::with_capacity(sources.len()),
+            min_terms: BinaryHeap::new(),
+            end,
+            pending: None,
+        };
+        for (block, from_term) in sources {
+            log::debug!("  Opening cursor for block: {}", block);
+            let stream = blocks.read_block(block, from_term.as_deref()).await?;
+            merger.streams.push(stream);
+            merger.advance(merger.streams.len() - 1).await?;
+        }
+        Ok(merger)
+    }
+
+    /// Pushes the next document of stream `idx` on the heap, unless it's past the end term.
+    async fn advance(&mut self, idx: usize) -> Result<()> {
+        if let Some(doc) = self.streams[idx].next().await.transpose()?
+            && self.end.as_ref().is_none_or(|end| doc.term < *end)
+        {
+            self.min_terms.push(Reverse(HeapItem {
+                term: doc.term.clone(),
+                streamer_idx: idx,
+                doc,
+            }));
+        }
+        Ok(())
+    }
+
+    /// The next postings, split when a term has more than `DOCIDS_PER_MONGO_DOCUMENT` of them.
+    /// `None` once every block is merged.
+    async fn next(&mut self) -> Result<Option<MergedPostings>> {
+        loop {
+            let same_term = match (&self.pending, self.min_terms.peek()) {
+                (Some(pending), Some(Reverse(item))) => pending.term == item.term,
+                (None, Some(_)) => true,
+                (_, None) => false,
+            };
+            if !same_term {
+                return Ok(self.pending.take());
+            }
+
+            let Reverse(item) = self.min_terms.pop().unwrap();
+            self.advance(item.streamer_idx).await?;
+            let doc = item.doc;
+            let merged = self.pending.get_or_insert_with(|| MergedPostings {
+                term: doc.term,
+                postings: Vec::new(),
+                positions: HashMap::new(),
+            });
+            merged.postings = merge_sorted_lists_dedup(&merged.postings, &doc.postings);
+            merged.positions = merge_hashmaps(std::mem::take(&mut merged.positions), doc.positions);
+
+            if merged.postings.len() >= DOCIDS_PER_MONGO_DOCUMENT {
+                let overflow = merged.postings.split_off(DOCIDS_PER_MONGO_DOCUMENT);
+                let positions = overflow
+                    .iter()
+                    .filter_map(|doc_id| merged.positions.remove_entry(doc_id))
+                    .collect();
+                let rest = (!overflow.is_empty()).then(|| MergedPostings {
+                    term: merged.term.clone(),
+                    postings: overflow,
+                    positions,
+                });
+                return Ok(std::mem::replace(&mut self.pending, rest));
+            }
+        }
+    }
+}
+
+/// Merges `blocks` into a new sealed block and drops them, returns the new block.
+async fn merge_into_block(store: Arc<dyn BlockStore>, blocks: Vec<String>) -> Result<String> {
+    if let [block] = blocks.as_slice() {
+        return Ok(block.clone());
+    }
+    let merged_block = new_block_name();
+    let sources: Vec<_> = blocks.iter().map(|block| (block.clone(), None)).collect();
+    let mut merger = BlockMerger::open(store.as_ref(), &sources, None).await?;
+    let mut last_term: Option<String> = None;
+    let mut bucket = 0_i16;
+    while let Some(merged) = merger.next().await? {
+        bucket = if last_term.as_ref() == Some(&merged.term) {
+            bucket + 1
+        } else {
+            0
+        };
+        last_term = Some(merged.term.clone());
+        let doc = SpimiDoc::new(
+            merged.term,
+            bucket,
+            merged.postings.len() as u64,
+            merged.postings,
+            merged.positions,
+        );
+        store.append_to_block(&merged_block, doc).await?;
+    }
+    store.seal_block(&merged_block).await?;
+
+    // a crash before the inputs are dropped merges their terms twice, which merging dedups
+    for block in &blocks {
+        store.drop_block(block).await?;
+    }
+    log::debug!("Merged {} blocks into {}", blocks.len(), merged_block);
+    Ok(merged_block)
+}
+
+/// What merging a term range added to the index.
+#[derive(Default)]
+struct RangeMerged {
+    /// (term, docs added) for every bucket written, in term order.
+    terms: Vec<(String, u64)>,
+    docs_written: usize,
+}
+
+/// Buckets merged but not written yet.
+#[derive(Default)]
+struct MergeBatch {
+    inserts: Vec<InvertedIndexDoc>,
+    /// Postings appended to the existing bucket of their term.
+    appends: Vec<(ObjectId, MergedPostings)>,
+    /// Term and bucket merged last, the checkpoints are moved to it once the batch is written.
+    last: Option<(String, i16)>,
+}
+
+impl MergeBatch {
+    fn len(&self) -> usize {
+        self.inserts.len() + self.appends.len()
+    }
+
+    async fn write(
+        &mut self,
+        index: &dyn IndexStore,
+        storage: &Storage,
+        checkpoints: &[MergeCheckpoint],
+    ) -> Result<()> {
+        let Some((term, bucket)) = self.last.take() else {
+            return Ok(());
+        };
+        for (doc_id, merged) in self.appends.drain(..) {
+            log::debug!(
+                "Appending {} docs to existing bucket for term '{}'",
+                merged.postings.len(),
+                merged.term
+            );
+            index
+                .append_to_bucket(doc_id, &merged.postings, &merged.positions)
+                .await?;
+        }
+        index.insert_many(std::mem::take(&mut self.inserts)).await?;
+        for cp in checkpoints {
+            storage
+                .checkpoints
+                .update_progress(&cp.collection_name, &term, bucket)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Merges the terms of `range` from every block into `index`, one checkpoint per block tracks
+/// how far the range got.
+async fn merge_term_range(
+    storage: Storage,
+    index: Arc<dyn IndexStore>,
+    range: TermRange,
+    checkpoints: Vec<MergeCheckpoint>,
+) -> Result<RangeMerged> {
+    let sources: Vec<_> = checkpoints
+        .iter()
+        .map(|cp| {
+            let from_term = cp.last_merged_term.clone().or(range.start.clone());
+            (checkpoint_block(&cp.collection_name).to_string(), from_term)
+        })
+        .collect();
+    let mut merger =
+        BlockMerger::open(storage.blocks.as_ref(), &sources, range.end.clone()).await?;
+    // the checkpoints of a range move together, the furthest one is where the crashed merge stopped
+    let resume_at = checkpoints
+        .iter()
+        .filter_map(|cp| Some((cp.last_merged_term.clone()?, cp.last_merged_bucket)))
+        .max();
+
+    let mut merged = RangeMerged::default();
+    let mut batch = MergeBatch::default();
+    let mut active_term: Option<String> = None;
+    // postings of the active term merged so far, checkpoints count buckets from the first one
+    // the merge wrote, so a resumed merge skips what was written whatever the index held before
+    let mut term_bucket = 0_i16;
+    // bucket the next postings of the active term go to, `None` until some were written
+    let mut next_bucket: Option<i16> = None;
+
+    while let Some(mut postings) = merger.next().await? {
+        if active_term.as_ref() == Some(&postings.term) {
+            term_bucket += 1;
+        } else {
+            active_term = Some(postings.term.clone());
+            term_bucket = 0;
+            next_bucket = None;
+        }
+
+        let already_merged = resume_at.as_ref().is_some_and(|(term, last_bucket)| {
+            *term == postings.term && *last_bucket >= term_bucket
+        });
+        if already_merged {
+            log::debug!(
+                "Skipping write for term '{}' bucket {} (already merged)",
+                postings.term,
+                term_bucket
+            );
+            continue;
+        }
+
+        // the first postings written continue the last bucket of the term (incremental indexing)
+        let last = match next_bucket {
+            Some(_) => None,
+            None => index.get_last_bucket(&postings.term).await?,
+        };
+        // a run's doc ids come after every doc id in the index, so postings up to the last one of
+        // the bucket were written by a merge that crashed before recording its checkpoint
+        if let Some(&last_doc_id) = last.as_ref().and_then(|last| last.postings.last())
+            && postings.postings.first().is_some_and(|&d| d <= last_doc_id)
+        {
+            postings.postings.retain(|&d| d > last_doc_id);
+            postings.positions.retain(|d, _| *d > last_doc_id);
+            if postings.postings.is_empty() {
+                log::debug!(
+                    "Skipping write for term '{}' bucket {} (already written)",
+                    postings.term,
+                    term_bucket
+                );
+                continue;
+            }
+        }
+        let (bucket, existing_bucket_id) = match next_bucket {
+            Some(bucket) => (bucket, None),
+            None => match last {
+                Some(last) if last.postings.len() < DOCIDS_PER_MONGO_DOCUMENT => {
+                    log::debug!(
+                        "Continuing from existing bucket {} for term '{}' ({}/{} docs)",
+                        last.bucket,
+                        postings.term,
+                        last.postings.len(),
+                        DOCIDS_PER_MONGO_DOCUMENT
+                    );
+                    (last.bucket, Some(last.id))
+                }
+                Some(last) => (last.bucket + 1, None),
+                None => (0, None),
+            },
+        };
+        next_bucket = Some(bucket + 1);
+
+        merged
+            .terms
+            .push((postings.term.clone(), postings.postings.len() as u64));
+        batch.last = Some((postings.term.clone(), term_bucket));
+        match existing_bucket_id {
+            Some(doc_id) => {
+                batch.appends.push((doc_id, postings));
+            }
+            None => batch.inserts.push(InvertedIndexDoc::new(
+                postings.term,
+                bucket,
+                postings.postings.len() as u64,
+                postings.postings,
+                postings.positions,
+            )),
+        }
+        merged.docs_written += 1;
+        if batch.len() >= MERGE_WRITE_BATCH {
+            batch.write(index.as_ref(), &storage, &checkpoints).await?;
+            log::info!(
+                "  Range from {:?}: written {} documents to inverted index",
+                range.start,
+                merged.docs_written
+            );
+        }
+    }
+    batch.write(index.as_ref(), &storage, &checkpoints).await?;
+    Ok(merged)
+}
+
 struct HeapItem {
     term: String,
     streamer_idx: usize,
@@ -855,3 +1961,45 @@ where
     }
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token_stream_backpressure() -> Result<()> {
+        let indexer = Arc::new(Indexer::from_storage(Storage::in_memory(), 10));
+        // room for one document of three tokens
+        indexer.open_token_stream(TOKEN_BYTES * 4);
+        let pages: Vec<Arc<Page>> = (0..3)
+            .map(|i| {
+                Arc::new(Page::new(
+                    format!("https://example.com/{i}"),
+                    String::new(),
+                    "<p>alpha beta gamma</p>".to_string(),
+                    vec![],
+                    0,
+                    false,
+                ))
+            })
+            .collect();
+        let sender = tokio::spawn({
+            let indexer = indexer.clone();
+            async move { indexer.pages_to_token_stream(&pages).await }
+        });
+
+        // the second document waits until the first one is inverted
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!sender.is_finished());
+        let mut token_stream = indexer.token_stream_rx.lock().await;
+        for _ in 0..3 {
+            let Some(StreamMsg::Document { reserved_bytes, .. }) = token_stream.recv().await else {
+                panic!("Expected a document");
+            };
+            assert!(reserved_bytes > TOKEN_BYTES * 3);
+            indexer.token_stream_budget.add_permits(reserved_bytes);
+        }
+        assert_eq!(sender.await??, 9);
+        Ok(())
+    }
+}
@@ -0,0 +1,413 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::analyzer::TextAnalyzer;
+use crate::completion::{COMPLETION_PHRASES_FILE, COMPLETIONS_FILE, SURFACE_FORMS_FILE};
+use crate::data_models::DocId;
+use crate::indexer::TERMS_PER_FETCH;
+use crate::segment::{SEGMENT_FILE, delta_segment_file, delta_segments};
+use crate::storage::Storage;
+use crate::term_dict::TERM_DICT_FILE;
+
+/// Default number of terms `harvest index stats` lists by document frequency.
+pub const DEFAULT_TOP_TERMS: usize = 20;
+
+/// Default number of postings `harvest index inspect` shows per term.
+pub const DEFAULT_SAMPLE_POSTINGS: usize = 10;
+
+/// Read-only views of the current index generation, to debug what got indexed and why a query
+/// ranks the way it does.
+pub struct IndexInspector {
+    storage: Storage,
+    analyzer: TextAnalyzer,
+    /// Where the segment, term dictionary and completions are, `None` leaves them out of the stats.
+    index_dir: Option<PathBuf>,
+}
+
+/// What the index holds, from `IndexInspector::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexStats {
+    /// Generation the stats were read from.
+    pub generation: u64,
+    /// Documents with postings in the index, deleted ones excluded.
+    pub documents: usize,
+    /// Deleted documents whose postings wait for compaction.
+    pub deleted_documents: usize,
+    pub unique_terms: usize,
+    /// Sum of the document frequencies of every term.
+    pub total_postings: u64,
+    /// Positions per document, stop words excluded.
+    pub avg_doc_length: f64,
+    pub buckets: usize,
+    /// Number of terms by the number of buckets they span.
+    pub buckets_per_term: BTreeMap<usize, usize>,
+    /// Bytes of the generation in the database, `None` when it's not stored on disk.
+    pub index_bytes: Option<u64>,
+    /// Bytes of the segment, term dictionary and completions in the index directory.
+    pub file_bytes: u64,
+    /// Terms with the highest document frequency, highest first.
+    pub top_terms: Vec<(String, u64)>,
+}
+
+/// An analyzed term of the text given to `IndexInspector::inspect`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TermReport {
+    /// The term as indexed.
+    pub term: String,
+    /// Position of the term in the analyzed text, relative to the first term.
+    pub position: usize,
+    pub document_frequency: u64,
+    pub buckets: Vec<BucketReport>,
+    /// The first postings of the term, in doc id order.
+    pub sample: Vec<PostingSample>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BucketReport {
+    pub bucket: i16,
+    /// Document frequency stored in the bucket.
+    pub document_frequency: u64,
+    pub postings: usize,
+    pub first_doc_id: Option<DocId>,
+    pub last_doc_id: Option<DocId>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostingSample {
+    pub doc_id: DocId,
+    /// URL of the page, `None` when the document was deleted or its page is gone.
+    pub url: Option<String>,
+    pub deleted: bool,
+    pub positions: Vec<usize>,
+}
+
+impl IndexInspector {
+    pub fn from_storage(storage: Storage, analyzer: TextAnalyzer) -> Self {
+        Self {
+            storage,
+            analyzer,
+            index_dir: None,
+        }
+    }
+
+    /// Count the files of the index kept in `index_dir` into the size on disk.
+    pub fn with_index_dir(mut self, index_dir: impl Into<PathBuf>) -> Self {
+        self.index_dir = Some(index_dir.into());
+        self
+    }
+
+    /// Statistics of the current generation, listing the `top_n` terms with the highest document
+    /// frequency. Reads every bucket once.
+    pub async fn stats(&self, top_n: usize) -> Result<IndexStats> {
+        let index = &self.storage.index;
+        let deleted: HashSet<DocId> = self.storage.doc_ids.deleted().await?.into_iter().collect();
+        let frequencies = index.term_document_frequencies().await?;
+
+        let mut stats = IndexStats {
+            generation: index.manifest().await?.current,
+            unique_terms: frequencies.len(),
+            total_postings: frequencies.iter().map(|(_, df)| df).sum(),
+            index_bytes: index.storage_bytes().await?,
+            file_bytes: self.file_bytes()?,
+            ..IndexStats::default()
+        };
+
+        let mut top_terms = frequencies.clone();
+        top_terms.sort_by(|(a, a_df), (b, b_df)| b_df.cmp(a_df).then_with(|| a.cmp(b)));
+        top_terms.truncate(top_n);
+        stats.top_terms = top_terms;
+
+        // positions of every live document, their count is the document length
+        let mut doc_lengths: HashMap<DocId, u64> = HashMap::new();
+        let mut seen_deleted: HashSet<DocId> = HashSet::new();
+        let terms: Vec<String> = frequencies.into_iter().map(|(term, _)| term).collect();
+        for chunk in terms.chunks(TERMS_PER_FETCH) {
+            let mut term_buckets: HashMap<String, usize> = HashMap::new();
+            for doc in index.find_by_terms(chunk).await? {
+                stats.buckets += 1;
+                for doc_id in &doc.postings {
+                    let length = doc.positions.get(doc_id).map_or(0, Vec::len) as u64;
+                    if deleted.contains(doc_id) {
+                        seen_deleted.insert(*doc_id);
+                    } else {
+                        *doc_lengths.entry(*doc_id).or_default() += length;
+                    }
+                }
+                *term_buckets.entry(doc.term).or_default() += 1;
+            }
+            for buckets in term_buckets.into_values() {
+                *stats.buckets_per_term.entry(buckets).or_default() += 1;
+            }
+        }
+
+        stats.documents = doc_lengths.len();
+        stats.deleted_documents = seen_deleted.len();
+        if !doc_lengths.is_empty() {
+            stats.avg_doc_length =
+                doc_lengths.values().sum::<u64>() as f64 / doc_lengths.len() as f64;
+        }
+        Ok(stats)
+    }
+
+    /// Analyzes `text` like a query and reports every term it yields: its buckets and the first
+    /// `sample_size` postings with their positions and pages. Stop words yield no terms.
+    pub async fn inspect(&self, text: &str, sample_size: usize) -> Result<Vec<TermReport>> {
+        let deleted: HashSet<DocId> = self.storage.doc_ids.deleted().await?.into_iter().collect();
+        let mut reports = Vec::new();
+        for token in self.analyzer.analyze_query(text)? {
+            let buckets = self
+                .storage
+                .index
+                .find_by_terms(std::slice::from_ref(&token.term))
+                .await?;
+
+            let mut report = TermReport {
+                term: token.term.clone(),
+                position: token.pos,
+                document_frequency: buckets.iter().map(|b| b.document_frequency).sum(),
+                buckets: Vec::with_capacity(buckets.len()),
+                sample: Vec::new(),
+            };
+            for bucket in buckets {
+                report.buckets.push(BucketReport {
+                    bucket: bucket.bucket,
+                    document_frequency: bucket.document_frequency,
+                    postings: bucket.postings.len(),
+                    first_doc_id: bucket.postings.first().copied(),
+                    last_doc_id: bucket.postings.last().copied(),
+                });
+                let mut positions = bucket.positions;
+                for doc_id in bucket.postings {
+                    if report.sample.len() == sample_size {
+                        break;
+                    }
+                    report.sample.push(PostingSample {
+                        doc_id,
+                        url: None,
+                        deleted: deleted.contains(&doc_id),
+                        positions: positions.remove(&doc_id).unwrap_or_default(),
+                    });
+                }
+            }
+            self.resolve_urls(&mut report.sample).await?;
+            reports.push(report);
+        }
+        Ok(reports)
+    }
+
+    /// Fills in the page URLs of the live documents of `sample`.
+    async fn resolve_urls(&self, sample: &mut [PostingSample]) -> Result<()> {
+        let doc_ids: Vec<DocId> = sample
+            .iter()
+            .filter(|posting| !posting.deleted)
+            .map(|posting| posting.doc_id)
+            .collect();
+        let page_ids = self.storage.doc_ids.resolve(&doc_ids).await?;
+        let urls: HashMap<_, _> = self
+            .storage
+            .pages
+            .find_by_ids(&page_ids)
+            .await?
+            .into_iter()
+            .map(|page| (page.id, page.url))
+            .collect();
+        let doc_urls: HashMap<DocId, &String> = doc_ids
+            .into_iter()
+            .zip(&page_ids)
+            .filter_map(|(doc_id, page_id)| Some((doc_id, urls.get(page_id)?)))
+            .collect();
+        for posting in sample {
+            posting.url = doc_urls.get(&posting.doc_id).map(|url| url.to_string());
+        }
+        Ok(())
+    }
+
+    /// Bytes of the index files in the index directory.
+    fn file_bytes(&self) -> Result<u64> {
+        let Some(index_dir) = &self.index_dir else {
+            return Ok(0);
+        };
+        let mut bytes = 0;
+        let deltas = match index_dir.exists() {
+            true => delta_segments(index_dir)?,
+            false => Vec::new(),
+        };
+        let files = [
+            SEGMENT_FILE,
+            TERM_DICT_FILE,
+            COMPLETIONS_FILE,
+            COMPLETION_PHRASES_FILE,
+            SURFACE_FORMS_FILE,
+        ]
+        .map(String::from)
+        .into_iter()
+        .chain(deltas.into_iter().map(delta_segment_file));
+        for file in files {
+            match std::fs::metadata(index_dir.join(file)) {
+                Ok(metadata) => bytes += metadata.len(),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+impl fmt::Display for IndexStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Index generation {}", self.generation)?;
+        writeln!(
+            f,
+            "  documents:       {} ({} deleted, awaiting compaction)",
+            self.documents, self.deleted_documents
+        )?;
+        writeln!(f, "  unique terms:    {}", self.unique_terms)?;
+        writeln!(f, "  total postings:  {}", self.total_postings)?;
+        writeln!(f, "  avg doc length:  {:.1} terms", self.avg_doc_length)?;
+        writeln!(f, "  buckets:         {}", self.buckets)?;
+        match self.index_bytes {
+            Some(bytes) => writeln!(f, "  database size:   {bytes} bytes")?,
+            None => writeln!(f, "  database size:   in memory")?,
+        }
+        writeln!(f, "  index files:     {} bytes", self.file_bytes)?;
+        writeln!(f, "Terms by number of buckets:")?;
+        for (buckets, terms) in &self.buckets_per_term {
+            writeln!(f, "  {buckets:>5} buckets: {terms} terms")?;
+        }
+        writeln!(
+            f,
+            "Top {} terms by document frequency:",
+            self.top_terms.len()
+        )?;
+        for (term, df) in &self.top_terms {
+            writeln!(f, "  {df:>10}  {term}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TermReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Term '{}' (position {}): document frequency {}, {} buckets",
+            self.term,
+            self.position,
+            self.document_frequency,
+            self.buckets.len()
+        )?;
+        for bucket in &self.buckets {
+            let range = match (bucket.first_doc_id, bucket.last_doc_id) {
+                (Some(first), Some(last)) => format!("doc ids {first}..={last}"),
+                _ => "no doc ids".to_string(),
+            };
+            writeln!(
+                f,
+                "  bucket {}: df {}, {} postings, {}",
+                bucket.bucket, bucket.document_frequency, bucket.postings, range
+            )?;
+        }
+        for posting in &self.sample {
+            let page = match (&posting.url, posting.deleted) {
+                (_, true) => "(deleted)",
+                (Some(url), false) => url.as_str(),
+                (None, false) => "(page missing)",
+            };
+            writeln!(
+                f,
+                "  doc {:>8}  positions {:?}  {}",
+                posting.doc_id, posting.positions, page
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_models::{InvertedIndexDoc, Page};
+    use mongodb::bson::oid::ObjectId;
+
+    /// Indexes `harvest` in pages 0..3 and `moon` in page 0, page 2 is deleted.
+    async fn storage() -> Result<(Storage, Vec<ObjectId>)> {
+        let storage = Storage::in_memory();
+        let mut page_ids = Vec::new();
+        for i in 0..3 {
+            let page = Page::new(
+                format!("https://example.com/{i}"),
+                String::new(),
+                String::new(),
+                vec![],
+                0,
+                false,
+            );
+            page_ids.push(storage.pages.insert(&page).await?);
+        }
+        let doc_ids = storage.doc_ids.assign(&page_ids).await?;
+        for (term, postings) in [("harvest", doc_ids.clone()), ("moon", vec![doc_ids[0]])] {
+            let positions = postings.iter().map(|&d| (d, vec![0, 4])).collect();
+            storage
+                .index
+                .insert(InvertedIndexDoc::new(
+                    term.to_string(),
+                    0,
+                    postings.len() as u64,
+                    postings,
+                    positions,
+                ))
+                .await?;
+        }
+        storage.doc_ids.delete_pages(&page_ids[2..]).await?;
+        Ok((storage, page_ids))
+    }
+
+    #[tokio::test]
+    async fn test_stats() -> Result<()> {
+        let (storage, _) = storage().await?;
+        let inspector = IndexInspector::from_storage(storage, TextAnalyzer::default());
+
+        let stats = inspector.stats(1).await?;
+        assert_eq!(stats.documents, 2);
+        assert_eq!(stats.deleted_documents, 1);
+        assert_eq!(stats.unique_terms, 2);
+        assert_eq!(stats.total_postings, 4);
+        assert_eq!(stats.buckets, 2);
+        assert_eq!(stats.buckets_per_term, BTreeMap::from([(1, 2)]));
+        // doc 0 has 4 positions, doc 1 has 2
+        assert_eq!(stats.avg_doc_length, 3.0);
+        assert_eq!(stats.top_terms, vec![("harvest".to_string(), 3)]);
+        assert_eq!(stats.index_bytes, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inspect_analyzes_and_samples() -> Result<()> {
+        let (storage, _) = storage().await?;
+        let inspector = IndexInspector::from_storage(storage, TextAnalyzer::default());
+
+        let reports = inspector.inspect("The Harvest", 5).await?;
+        assert_eq!(reports.len(), 1);
+        let report = &reports[0];
+        assert_eq!(report.term, "harvest");
+        assert_eq!(report.document_frequency, 3);
+        assert_eq!(report.buckets.len(), 1);
+        let urls: Vec<Option<&str>> = report.sample.iter().map(|p| p.url.as_deref()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                Some("https://example.com/0"),
+                Some("https://example.com/1"),
+                None
+            ]
+        );
+        assert!(report.sample[2].deleted);
+        assert_eq!(report.sample[0].positions, vec![0, 4]);
+
+        assert_eq!(inspector.inspect("harvest", 1).await?[0].sample.len(), 1);
+        assert!(inspector.inspect("the", 5).await?.is_empty());
+        Ok(())
+    }
+}
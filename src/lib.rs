@@ -1,8 +1,18 @@
 pub mod analyzer;
+pub mod analyzer_config;
 pub mod api;
+pub mod archive;
+pub mod completion;
 pub mod config;
 pub mod crawler;
 pub mod data_models;
 pub mod db;
+pub mod heap;
 pub mod indexer;
+pub mod inspect;
+pub mod postings;
 pub mod query_engine;
+pub mod segment;
+pub mod storage;
+pub mod term_dict;
+pub mod verify;
@@ -1,10 +1,31 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use futures::future;
+use harvest::analyzer_config::{AnalyzerRegistry, BODY_FIELD};
+use harvest::archive::IndexArchive;
+use harvest::config::CONFIG;
 use harvest::crawler::Crawler;
 use harvest::db::{Database, PageRepo};
-use harvest::indexer::Indexer;
+use harvest::indexer::{Indexer, MemoryAccounting};
+use harvest::inspect::{DEFAULT_SAMPLE_POSTINGS, DEFAULT_TOP_TERMS, IndexInspector};
+use harvest::postings::codec::Codec;
+use harvest::segment::{SEGMENT_FILE, Segment};
+use harvest::storage::Storage;
+use harvest::storage::file::{BLOCKS_DIR, FileBlockStore};
+use harvest::term_dict::{
+    DEFAULT_FUZZY_DISTANCE, DEFAULT_MAX_EXPANSIONS, MAX_FUZZY_DISTANCE, TERM_DICT_FILE,
+    TermDictionary,
+};
+use harvest::verify::IndexVerifier;
+
+#[cfg(feature = "count-allocations")]
+#[global_allocator]
+static GLOBAL: harvest::heap::CountingAllocator = harvest::heap::CountingAllocator;
+
+/// How often `serve` checks for a new index generation between queries.
+const INDEX_RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
 
 #[derive(Parser)]
 #[command(name = "harvest")]
@@ -36,14 +57,20 @@ enum Commands {
     },
     /// Index the documents that were previously crawled
     Index {
-        /// Number of pages to fetch per batch during indexing
-        #[arg(short, long, default_value_t = 10000)]
-        page_fetch_limit: i64,
+        #[command(subcommand)]
+        command: Option<IndexCommand>,
 
-        /// Memory budget in bytes for SPIMI indexing before flushing to disk
-        #[arg(short, long, default_value_t = 100_000_000)]
-        budget_bytes: usize,
+        #[command(flatten)]
+        options: IndexOptions,
     },
+    /// Delete pages from the index, they stop matching queries right away
+    Delete {
+        /// URL of a page to delete, can be repeated
+        #[arg(short, long, required = true)]
+        url: Vec<String>,
+    },
+    /// Purge the postings of deleted pages from the index
+    Compact,
     /// Start the web server to serve the search API and UI
     Serve {
         /// Port to bind the server to
@@ -53,6 +80,93 @@ enum Commands {
         /// Host to bind the server to
         #[arg(short = 'H', long, default_value = "127.0.0.1")]
         host: String,
+
+        /// Maximum number of index terms a prefix/wildcard/regex query term expands to
+        #[arg(long, default_value_t = DEFAULT_MAX_EXPANSIONS)]
+        max_expansions: usize,
+
+        /// Edit distance of fuzzy query terms (`word~`) and "did you mean" suggestions
+        #[arg(long, default_value_t = DEFAULT_FUZZY_DISTANCE,
+              value_parser = clap::value_parser!(u32).range(0..=MAX_FUZZY_DISTANCE as i64))]
+        fuzzy_distance: u32,
+    },
+}
+
+/// Options of index runs, also used by `index rebuild`.
+#[derive(Args)]
+struct IndexOptions {
+    /// Number of pages to fetch per batch during indexing
+    #[arg(short, long, default_value_t = 10000)]
+    page_fetch_limit: i64,
+
+    /// Memory budget in bytes for buffered tokens and SPIMI indexing before flushing to disk
+    #[arg(short, long, default_value_t = 100_000_000)]
+    budget_bytes: usize,
+
+    /// Codec of the postings and positions in the index segment
+    #[arg(long, value_enum, default_value_t = Codec::default())]
+    codec: Codec,
+
+    /// Pages analyzed in parallel, defaults to the number of CPU cores
+    #[arg(short = 'j', long)]
+    tokenize_parallelism: Option<usize>,
+
+    /// How blocks are measured against the budget, `allocator` needs the count-allocations feature
+    #[arg(long, value_enum, default_value_t = MemoryAccounting::default())]
+    memory_accounting: MemoryAccounting,
+
+    /// Blocks merged at once, more are merged into intermediate blocks first
+    #[arg(long, default_value_t = 64)]
+    merge_fan_in: usize,
+
+    /// Term ranges merged in parallel, defaults to the number of CPU cores
+    #[arg(long)]
+    merge_parallelism: Option<usize>,
+}
+
+#[derive(Subcommand)]
+enum IndexCommand {
+    /// Rewrite every term into sorted, densely packed buckets without deleted pages
+    Optimize,
+    /// Make the index generation before the last committed one current again
+    Rollback,
+    /// Index every page again into a new generation, with another analyzer
+    Rebuild {
+        /// Analyzer of the new generation, later runs and queries use it too. Defaults to the
+        /// analyzer of the body field in ANALYZERS_FILE, else `default`
+        #[arg(long)]
+        analyzer: Option<String>,
+    },
+    /// Print statistics of the current index generation
+    Stats {
+        /// Number of terms with the highest document frequency to list
+        #[arg(long, default_value_t = DEFAULT_TOP_TERMS)]
+        top: usize,
+    },
+    /// Print how a term is analyzed and indexed: its buckets and a sample of its postings
+    Inspect {
+        /// Term or text to analyze like a query
+        term: String,
+
+        /// Number of postings to show per analyzed term
+        #[arg(long, default_value_t = DEFAULT_SAMPLE_POSTINGS)]
+        sample: usize,
+    },
+    /// Check the index for corrupt buckets, dangling doc ids and leftovers of interrupted runs
+    Verify {
+        /// Fix the issues found and verify again
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Write the index, its doc ids and pages to a portable archive
+    Export {
+        /// File to write the archive to
+        path: PathBuf,
+    },
+    /// Replace the index, doc ids and pages by those of an archive written by `export`
+    Import {
+        /// Archive to import
+        path: PathBuf,
     },
 }
 
@@ -91,13 +205,75 @@ async fn async_main() -> anyhow::Result<()> {
             .await?;
         }
         Commands::Index {
-            page_fetch_limit,
-            budget_bytes,
+            command: Some(IndexCommand::Optimize),
+            ..
         } => {
-            run_index(page_fetch_limit, budget_bytes).await?;
+            run_optimize().await?;
         }
-        Commands::Serve { port, host } => {
-            run_serve(port, host).await?;
+        Commands::Index {
+            command: Some(IndexCommand::Rollback),
+            ..
+        } => {
+            run_rollback().await?;
+        }
+        Commands::Index {
+            command: Some(IndexCommand::Stats { top }),
+            ..
+        } => {
+            run_stats(top).await?;
+        }
+        Commands::Index {
+            command: Some(IndexCommand::Inspect { term, sample }),
+            ..
+        } => {
+            run_inspect(term, sample).await?;
+        }
+        Commands::Index {
+            command: Some(IndexCommand::Verify { repair }),
+            ..
+        } => {
+            run_verify(repair).await?;
+        }
+        Commands::Index {
+            command: Some(IndexCommand::Export { path }),
+            ..
+        } => {
+            run_export(path).await?;
+        }
+        Commands::Index {
+            command: Some(IndexCommand::Import { path }),
+            ..
+        } => {
+            run_import(path).await?;
+        }
+        Commands::Index {
+            command: Some(IndexCommand::Rebuild { analyzer }),
+            options,
+        } => {
+            let registry = AnalyzerRegistry::from_config()?;
+            let analyzer =
+                analyzer.unwrap_or_else(|| registry.field_analyzer(BODY_FIELD).to_string());
+            run_index(options, Some(analyzer)).await?;
+        }
+        Commands::Index {
+            command: None,
+            options,
+        } => {
+            run_index(options, None).await?;
+        }
+        Commands::Delete { url } => {
+            run_delete(url).await?;
+        }
+        Commands::Compact => {
+            run_compact().await?;
+        }
+        Commands::Serve {
+            port,
+            host,
+            max_expansions,
+            fuzzy_distance,
+        } => {
+            run_serve(port, host, max_expansions, fuzzy_distance).await?;
         }
     }
 
@@ -136,34 +312,205 @@ async fn run_crawl(
     Ok(())
 }
 
-async fn run_index(page_fetch_limit: i64, budget_bytes: usize) -> anyhow::Result<()> {
-    let db = Database::get().clone();
-    let pages_repo = Arc::new(PageRepo::new(&db));
-
+/// An index run, or a rebuild of the whole index with the analyzer `rebuild`.
+async fn run_index(options: IndexOptions, rebuild: Option<String>) -> anyhow::Result<()> {
+    let IndexOptions {
+        page_fetch_limit,
+        budget_bytes,
+        codec,
+        tokenize_parallelism,
+        memory_accounting,
+        merge_fan_in,
+        merge_parallelism,
+    } = options;
     log::info!(
-        "Starting indexing with page_fetch_limit={}, budget_bytes={}",
+        "Starting indexing with page_fetch_limit={}, budget_bytes={}, codec={}, memory_accounting={}",
         page_fetch_limit,
-        budget_bytes
+        budget_bytes,
+        codec,
+        memory_accounting
     );
 
-    let indexer = Arc::new(Indexer::new(pages_repo, page_fetch_limit, db));
-    indexer.run(budget_bytes).await?;
+    // runs keep analyzing with the analyzer the index is built with, a rebuild changes it
+    let storage = index_storage();
+    let registry = AnalyzerRegistry::from_config()?;
+    let analyzer = match &rebuild {
+        Some(analyzer) => registry.build(analyzer)?,
+        None => registry.build(storage.index.manifest().await?.run_analyzer())?,
+    };
+    let mut indexer = Indexer::from_storage(storage, page_fetch_limit)
+        .with_analyzer(analyzer)
+        .with_index_dir(&CONFIG.index_dir)
+        .with_codec(codec)
+        .with_memory_accounting(memory_accounting)
+        .with_merge_fan_in(merge_fan_in);
+    if let Some(parallelism) = tokenize_parallelism {
+        indexer = indexer.with_tokenize_parallelism(parallelism);
+    }
+    if let Some(parallelism) = merge_parallelism {
+        indexer = indexer.with_merge_parallelism(parallelism);
+    }
+    match rebuild {
+        Some(_) => Arc::new(indexer).rebuild(budget_bytes).await?,
+        None => Arc::new(indexer).run(budget_bytes).await?,
+    }
     log::info!("Indexing completed");
     Ok(())
 }
 
-async fn run_serve(port: u16, host: String) -> anyhow::Result<()> {
-    use harvest::analyzer::TextAnalyzer;
+async fn run_delete(urls: Vec<String>) -> anyhow::Result<()> {
+    let indexer = Indexer::from_storage(Storage::mongo(Database::get()), 1);
+    let deleted = indexer.delete_pages(&urls).await?;
+    log::info!("Deleted {} of {} pages", deleted, urls.len());
+    Ok(())
+}
+
+async fn run_compact() -> anyhow::Result<()> {
+    let purged = maintenance_indexer().await?.compact().await?;
+    log::info!("Compaction completed, {} documents purged", purged);
+    Ok(())
+}
+
+async fn run_optimize() -> anyhow::Result<()> {
+    let buckets = maintenance_indexer().await?.optimize().await?;
+    log::info!("Optimization completed, {} buckets written", buckets);
+    Ok(())
+}
+
+async fn run_rollback() -> anyhow::Result<()> {
+    let generation = maintenance_indexer().await?.rollback().await?;
+    log::info!("Rolled back to index generation {}", generation);
+    Ok(())
+}
+
+async fn run_stats(top: usize) -> anyhow::Result<()> {
+    let stats = index_inspector().await?.stats(top).await?;
+    print!("{stats}");
+    Ok(())
+}
+
+async fn run_inspect(term: String, sample: usize) -> anyhow::Result<()> {
+    let reports = index_inspector().await?.inspect(&term, sample).await?;
+    if reports.is_empty() {
+        println!("'{term}' analyzes to no terms, only stop words or too short words");
+    }
+    for report in reports {
+        print!("{report}");
+    }
+    Ok(())
+}
+
+async fn index_inspector() -> anyhow::Result<IndexInspector> {
+    let storage = Storage::mongo(Database::get());
+    let analyzer =
+        AnalyzerRegistry::from_config()?.build(storage.index.manifest().await?.analyzer())?;
+    Ok(IndexInspector::from_storage(storage, analyzer).with_index_dir(&CONFIG.index_dir))
+}
+
+async fn run_verify(repair: bool) -> anyhow::Result<()> {
+    let verifier = IndexVerifier::from_storage(index_storage());
+    let mut report = verifier.verify().await?;
+    print!("{report}");
+    if repair && !report.is_clean() {
+        let buckets = maintenance_indexer().await?.repair(&report).await?;
+        log::info!("Repair completed, {} buckets written", buckets);
+        report = verifier.verify().await?;
+        print!("{report}");
+    }
+    if !report.is_clean() {
+        anyhow::bail!("The index has {} issues", report.issues.len());
+    }
+    Ok(())
+}
+
+async fn run_export(path: PathBuf) -> anyhow::Result<()> {
+    let summary = IndexArchive::from_storage(Storage::mongo(Database::get()))
+        .export(&path)
+        .await?;
+    print!("{summary}");
+    log::info!("Index exported to {}", path.display());
+    Ok(())
+}
+
+async fn run_import(path: PathBuf) -> anyhow::Result<()> {
+    let summary = maintenance_indexer().await?.import(&path).await?;
+    print!("{summary}");
+    log::info!("Index imported from {}", path.display());
+    Ok(())
+}
+
+/// Stores of the index on MongoDB, with the SPIMI blocks in the index directory.
+fn index_storage() -> Storage {
+    Storage {
+        blocks: Arc::new(FileBlockStore::new(
+            std::path::Path::new(&CONFIG.index_dir).join(BLOCKS_DIR),
+        )),
+        ..Storage::mongo(Database::get())
+    }
+}
+
+/// Indexer rewriting the existing index, it keeps the codec of the current segment and analyzes
+/// with the analyzer of the generation index runs write to, so it can finish an interrupted run.
+async fn maintenance_indexer() -> anyhow::Result<Indexer> {
+    let segment_path = std::path::Path::new(&CONFIG.index_dir).join(SEGMENT_FILE);
+    let codec = Segment::open(&segment_path)
+        .map(|segment| segment.codec())
+        .unwrap_or_default();
+    let storage = index_storage();
+    let analyzer =
+        AnalyzerRegistry::from_config()?.build(storage.index.manifest().await?.run_analyzer())?;
+    Ok(Indexer::from_storage(storage, 1)
+        .with_index_dir(&CONFIG.index_dir)
+        .with_analyzer(analyzer)
+        .with_codec(codec))
+}
+
+async fn run_serve(
+    port: u16,
+    host: String,
+    max_expansions: usize,
+    fuzzy_distance: u32,
+) -> anyhow::Result<()> {
     use harvest::api::create_router;
     use harvest::query_engine::QueryEngine;
 
-    let db = Database::get().clone();
+    let storage = Storage::mongo(Database::get());
 
     log::info!("Initializing search engine...");
 
-    let analyzer = TextAnalyzer::default();
+    let analyzer =
+        AnalyzerRegistry::from_config()?.build(storage.index.manifest().await?.analyzer())?;
+    log::info!("Analyzing queries with analyzer '{}'", analyzer.name());
+
+    ensure_term_dictionary(&storage).await?;
+
+    let segment_path = std::path::Path::new(&CONFIG.index_dir).join(SEGMENT_FILE);
+    if !segment_path.exists() {
+        log::warn!(
+            "No segment at {}, reading postings from the database",
+            segment_path.display()
+        );
+    }
 
-    let query_engine = Arc::new(QueryEngine::new(db, analyzer));
+    // the term dictionary, completions and segment are reloaded when an index run commits
+    let query_engine = Arc::new(
+        QueryEngine::from_storage(storage, analyzer)
+            .with_max_expansions(max_expansions)
+            .with_fuzzy_distance(fuzzy_distance)
+            .with_index_dir(&CONFIG.index_dir)?,
+    );
+    tokio::spawn({
+        let query_engine = query_engine.clone();
+        async move {
+            let mut interval = tokio::time::interval(INDEX_RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = query_engine.reload().await {
+                    log::warn!("Reloading the index files failed: {:#}", e);
+                }
+            }
+        }
+    });
 
     let app = create_router(query_engine);
 
@@ -174,9 +521,31 @@ async fn run_serve(port: u16, host: String) -> anyhow::Result<()> {
     log::info!("Server running at http://{}", addr);
     log::info!("Serving static files from ./static");
     log::info!("API endpoint: POST http://{}/api/search", addr);
+    log::info!("API endpoint: GET http://{}/api/suggest?q=", addr);
 
     // Start server
     axum::serve(listener, app).await?;
 
     Ok(())
 }
+
+/// Rebuilds the term dictionary from the inverted index when the indexer wrote none (e.g. an
+/// index built before the dictionary existed).
+async fn ensure_term_dictionary(storage: &Storage) -> anyhow::Result<()> {
+    let path = std::path::Path::new(&CONFIG.index_dir).join(TERM_DICT_FILE);
+    let term_dict = TermDictionary::open(&path)?;
+    if !term_dict.is_empty() {
+        log::info!("Loaded term dictionary with {} terms", term_dict.len());
+        return Ok(());
+    }
+
+    log::warn!(
+        "No term dictionary at {}, rebuilding it from the inverted index",
+        path.display()
+    );
+    let terms = storage.index.term_document_frequencies().await?;
+    let term_dict = TermDictionary::build(terms)?;
+    term_dict.save(&path)?;
+    log::info!("Rebuilt term dictionary with {} terms", term_dict.len());
+    Ok(())
+}
@@ -0,0 +1,404 @@
+use anyhow::{Result, bail, ensure};
+use std::fmt;
+
+/// Values per block of the bit-packed codec.
+pub const BIT_PACKED_BLOCK_LEN: usize = 128;
+
+/// `(values per word, bits per value)` of every Simple-8b selector, most values first.
+/// Selectors 0 and 1 are runs of zeros.
+const SIMPLE8B_SELECTORS: [(usize, u32); 16] = [
+    (240, 0),
+    (120, 0),
+    (60, 1),
+    (30, 2),
+    (20, 3),
+    (15, 4),
+    (12, 5),
+    (10, 6),
+    (8, 7),
+    (7, 8),
+    (6, 10),
+    (5, 12),
+    (4, 15),
+    (3, 20),
+    (2, 30),
+    (1, 60),
+];
+
+/// Encoding of a list of integers (doc ids or positions).
+///
+/// Every encoded list starts with its length as a varint, so lists can be decoded back to back
+/// from the same buffer. Sorted lists, like postings, are delta encoded first with
+/// `encode_sorted` so the codecs only see small gaps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, clap::ValueEnum)]
+pub enum Codec {
+    /// LEB128, 7 bits per byte.
+    #[default]
+    VarByte,
+    /// As many values as fit in 60 bits of a 64 bit word, with a 4 bit selector.
+    Simple8b,
+    /// Blocks of 128 values, each packed with the bit width of its largest value.
+    BitPacked,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 3] = [Codec::VarByte, Codec::Simple8b, Codec::BitPacked];
+
+    /// Id of the codec in file headers.
+    pub fn id(self) -> u32 {
+        match self {
+            Codec::VarByte => 0,
+            Codec::Simple8b => 1,
+            Codec::BitPacked => 2,
+        }
+    }
+
+    pub fn from_id(id: u32) -> Result<Self> {
+        Codec::ALL
+            .into_iter()
+            .find(|codec| codec.id() == id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown codec id {}", id))
+    }
+
+    /// Appends `values` to `out`.
+    pub fn encode(self, values: &[u32], out: &mut Vec<u8>) {
+        write_varint(out, values.len() as u64);
+        match self {
+            Codec::VarByte => {
+                for &value in values {
+                    write_varint(out, value as u64);
+                }
+            }
+            Codec::Simple8b => encode_simple8b(values, out),
+            Codec::BitPacked => {
+                for block in values.chunks(BIT_PACKED_BLOCK_LEN) {
+                    encode_bit_packed_block(block, out);
+                }
+            }
+        }
+    }
+
+    /// Decodes a list written by `encode` at `pos` and appends it to `out`, `pos` ends up after it.
+    pub fn decode(self, bytes: &[u8], pos: &mut usize, out: &mut Vec<u32>) -> Result<()> {
+        let len = read_varint(bytes, pos)? as usize;
+        // a corrupted length must not turn into a huge allocation
+        out.reserve(len.min(bytes.len().saturating_sub(*pos) * 8 + BIT_PACKED_BLOCK_LEN));
+        match self {
+            Codec::VarByte => {
+                for _ in 0..len {
+                    let value = read_varint(bytes, pos)?;
+                    ensure!(value <= u32::MAX as u64, "value {} out of range", value);
+                    out.push(value as u32);
+                }
+            }
+            Codec::Simple8b => decode_simple8b(bytes, pos, len, out)?,
+            Codec::BitPacked => {
+                let mut remaining = len;
+                while remaining > 0 {
+                    let block_len = remaining.min(BIT_PACKED_BLOCK_LEN);
+                    decode_bit_packed_block(bytes, pos, block_len, out)?;
+                    remaining -= block_len;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends ascending `values` to `out` as gaps. Unsorted values still round trip,
+    /// they just don't compress.
+    pub fn encode_sorted(self, values: &[u32], out: &mut Vec<u8>) {
+        self.encode(&delta_encode(values), out);
+    }
+
+    /// Decodes a list written by `encode_sorted`, see `decode`.
+    pub fn decode_sorted(self, bytes: &[u8], pos: &mut usize, out: &mut Vec<u32>) -> Result<()> {
+        let start = out.len();
+        self.decode(bytes, pos, out)?;
+        delta_decode(&mut out[start..]);
+        Ok(())
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Codec::VarByte => "var-byte",
+            Codec::Simple8b => "simple8b",
+            Codec::BitPacked => "bit-packed",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Gaps between consecutive values, the first value as is.
+pub fn delta_encode(values: &[u32]) -> Vec<u32> {
+    let mut previous = 0;
+    values
+        .iter()
+        .map(|&value| {
+            let gap = value.wrapping_sub(previous);
+            previous = value;
+            gap
+        })
+        .collect()
+}
+
+/// Inverse of `delta_encode`, in place.
+pub fn delta_decode(gaps: &mut [u32]) {
+    let mut previous = 0_u32;
+    for gap in gaps {
+        previous = previous.wrapping_add(*gap);
+        *gap = previous;
+    }
+}
+
+/// LEB128: 7 bits per byte, high bit set on every byte but the last.
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let Some(&byte) = bytes.get(*pos) else {
+            bail!("varint out of bounds at {}", pos);
+        };
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("varint too long at {}", pos)
+}
+
+fn encode_simple8b(values: &[u32], out: &mut Vec<u8>) {
+    let mut rest = values;
+    while !rest.is_empty() {
+        // the last word may be partially filled, the length prefix tells the decoder where to stop
+        let (selector, (count, bits)) = SIMPLE8B_SELECTORS
+            .iter()
+            .copied()
+            .enumerate()
+            .find(|&(_, (count, bits))| {
+                rest.iter()
+                    .take(count)
+                    .all(|&value| (value as u64) < (1_u64 << bits))
+            })
+            .expect("60 bits fit any u32");
+        let taken = count.min(rest.len());
+        let mut word = selector as u64;
+        for (i, &value) in rest[..taken].iter().enumerate() {
+            word |= (value as u64) << (4 + i as u32 * bits);
+        }
+        out.extend_from_slice(&word.to_le_bytes());
+        rest = &rest[taken..];
+    }
+}
+
+fn decode_simple8b(bytes: &[u8], pos: &mut usize, len: usize, out: &mut Vec<u32>) -> Result<()> {
+    let mut remaining = len;
+    while remaining > 0 {
+        let Some(word) = bytes.get(*pos..*pos + 8) else {
+            bail!("simple8b word out of bounds at {}", pos);
+        };
+        let word = u64::from_le_bytes(word.try_into().unwrap());
+        *pos += 8;
+        let (count, bits) = SIMPLE8B_SELECTORS[(word & 0xf) as usize];
+        let taken = count.min(remaining);
+        let mask = (1_u64 << bits) - 1;
+        for i in 0..taken {
+            let value = (word >> (4 + i as u32 * bits)) & mask;
+            ensure!(value <= u32::MAX as u64, "value {} out of range", value);
+            out.push(value as u32);
+        }
+        remaining -= taken;
+    }
+    Ok(())
+}
+
+fn encode_bit_packed_block(block: &[u32], out: &mut Vec<u8>) {
+    let width = block
+        .iter()
+        .map(|value| u32::BITS - value.leading_zeros())
+        .max()
+        .unwrap_or(0);
+    out.push(width as u8);
+    let mut buffer = 0_u64;
+    let mut buffered = 0;
+    for &value in block {
+        buffer |= (value as u64) << buffered;
+        buffered += width;
+        while buffered >= 8 {
+            out.push(buffer as u8);
+            buffer >>= 8;
+            buffered -= 8;
+        }
+    }
+    if buffered > 0 {
+        out.push(buffer as u8);
+    }
+}
+
+fn decode_bit_packed_block(
+    bytes: &[u8],
+    pos: &mut usize,
+    block_len: usize,
+    out: &mut Vec<u32>,
+) -> Result<()> {
+    let Some(&width) = bytes.get(*pos) else {
+        bail!("bit-packed block out of bounds at {}", pos);
+    };
+    let width = width as u32;
+    ensure!(width <= u32::BITS, "bit width {} out of range", width);
+    let packed_len = (block_len * width as usize).div_ceil(8);
+    let Some(packed) = bytes.get(*pos + 1..*pos + 1 + packed_len) else {
+        bail!("bit-packed block out of bounds at {}", pos);
+    };
+    *pos += 1 + packed_len;
+
+    let mask = (1_u64 << width) - 1;
+    let mut buffer = 0_u64;
+    let mut buffered = 0;
+    let mut packed = packed.iter();
+    for _ in 0..block_len {
+        while buffered < width {
+            buffer |= (*packed.next().unwrap() as u64) << buffered;
+            buffered += 8;
+        }
+        out.push((buffer & mask) as u32);
+        buffer >>= width;
+        buffered -= width;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn roundtrip(codec: Codec, values: &[u32]) -> Vec<u32> {
+        let mut bytes = Vec::new();
+        codec.encode(values, &mut bytes);
+        let mut pos = 0;
+        let mut decoded = Vec::new();
+        codec.decode(&bytes, &mut pos, &mut decoded).unwrap();
+        assert_eq!(pos, bytes.len(), "{codec} left trailing bytes");
+        decoded
+    }
+
+    proptest! {
+        #[test]
+        fn prop_codecs_roundtrip(values in prop::collection::vec(any::<u32>(), 0..600)) {
+            for codec in Codec::ALL {
+                prop_assert_eq!(roundtrip(codec, &values), values.clone());
+            }
+        }
+
+        #[test]
+        fn prop_sorted_lists_roundtrip(
+            mut values in prop::collection::vec(0..5_000_000_u32, 0..600),
+            small in prop::collection::vec(0..4_u32, 0..600),
+        ) {
+            values.sort_unstable();
+            for codec in Codec::ALL {
+                for list in [&values, &small] {
+                    let mut bytes = Vec::new();
+                    codec.encode_sorted(list, &mut bytes);
+                    // decoding appends after what is already there
+                    let mut decoded = vec![7];
+                    codec.decode_sorted(&bytes, &mut 0, &mut decoded).unwrap();
+                    prop_assert_eq!(&decoded[1..], &list[..]);
+                }
+            }
+        }
+
+        #[test]
+        fn prop_decode_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            for codec in Codec::ALL {
+                let _ = codec.decode(&bytes, &mut 0, &mut Vec::new());
+            }
+        }
+    }
+
+    #[test]
+    fn test_varint_roundtrip() {
+        let values = [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX];
+        let mut buf = Vec::new();
+        for value in values {
+            write_varint(&mut buf, value);
+        }
+        let mut pos = 0;
+        for value in values {
+            assert_eq!(read_varint(&buf, &mut pos).unwrap(), value);
+        }
+        assert_eq!(pos, buf.len());
+        assert!(read_varint(&[0x80], &mut 0).is_err());
+    }
+
+    #[test]
+    fn test_lists_decode_back_to_back() {
+        for codec in Codec::ALL {
+            let mut bytes = Vec::new();
+            codec.encode_sorted(&[3, 9, 12], &mut bytes);
+            codec.encode(&[], &mut bytes);
+            codec.encode(&[u32::MAX, 0], &mut bytes);
+
+            let (mut pos, mut out) = (0, Vec::new());
+            codec.decode_sorted(&bytes, &mut pos, &mut out).unwrap();
+            codec.decode(&bytes, &mut pos, &mut out).unwrap();
+            codec.decode(&bytes, &mut pos, &mut out).unwrap();
+            assert_eq!(out, vec![3, 9, 12, u32::MAX, 0]);
+            assert_eq!(pos, bytes.len());
+        }
+    }
+
+    #[test]
+    fn test_dense_gaps_compress() {
+        let postings: Vec<u32> = (0..1024).map(|i| i * 2).collect();
+        let sizes: Vec<usize> = Codec::ALL
+            .into_iter()
+            .map(|codec| {
+                let mut bytes = Vec::new();
+                codec.encode_sorted(&postings, &mut bytes);
+                bytes.len()
+            })
+            .collect();
+        // one byte per gap for var-byte, 2 bits per gap for the others
+        assert_eq!(sizes[0], 2 + 1024);
+        assert_eq!(sizes[1], 2 + 8 * 1024_usize.div_ceil(30));
+        assert_eq!(sizes[2], 2 + 8 * (1 + 128 * 2 / 8));
+    }
+
+    #[test]
+    fn test_simple8b_zero_runs() {
+        let mut bytes = Vec::new();
+        Codec::Simple8b.encode(&[0; 360], &mut bytes);
+        // length, then one word of 240 zeros and one of 120
+        assert_eq!(bytes.len(), 2 + 2 * 8);
+        assert_eq!(roundtrip(Codec::Simple8b, &[0; 360]), vec![0; 360]);
+    }
+
+    #[test]
+    fn test_truncated_input_is_an_error() {
+        for codec in Codec::ALL {
+            let mut bytes = Vec::new();
+            codec.encode(&[1, 200, 70_000, 5], &mut bytes);
+            bytes.pop();
+            assert!(codec.decode(&bytes, &mut 0, &mut Vec::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_codec_ids() {
+        for codec in Codec::ALL {
+            assert_eq!(Codec::from_id(codec.id()).unwrap(), codec);
+        }
+        assert!(Codec::from_id(3).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+/// Index of the first element of `list[start..]` that is not less than `target`, `list.len()` when
+/// there is none. `list` must be sorted.
+///
+/// Galloping search: probes `start + 1, start + 2, start + 4, ...` and binary searches the last
+/// range, so skipping `d` elements costs `O(log d)` instead of `O(d)`. Intersecting a list of `m`
+/// elements with one of `n` this way costs `O(m log(n / m))`.
+pub fn gallop<T: Ord>(list: &[T], start: usize, target: &T) -> usize {
+    gallop_by(start, list.len(), |i| list[i] < *target)
+}
+
+/// `gallop` over any sorted sequence of `len` elements, `is_before(i)` tells whether element `i`
+/// is before the target.
+pub fn gallop_by(start: usize, len: usize, is_before: impl Fn(usize) -> bool) -> usize {
+    if start >= len || !is_before(start) {
+        return start;
+    }
+    // is_before(low) always holds, high is the first probe that is not before or `len`
+    let mut low = start;
+    let mut step = 1;
+    let mut high = loop {
+        let probe = low + step;
+        if probe >= len {
+            break len;
+        }
+        if !is_before(probe) {
+            break probe;
+        }
+        low = probe;
+        step *= 2;
+    };
+    while low + 1 < high {
+        let mid = low + (high - low) / 2;
+        if is_before(mid) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    high
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_gallop() {
+        let list = [1, 3, 3, 5, 8, 13, 21];
+        assert_eq!(gallop(&list, 0, &0), 0);
+        assert_eq!(gallop(&list, 0, &3), 1);
+        assert_eq!(gallop(&list, 2, &3), 2);
+        assert_eq!(gallop(&list, 0, &4), 3);
+        assert_eq!(gallop(&list, 4, &21), 6);
+        assert_eq!(gallop(&list, 0, &22), 7);
+        assert_eq!(gallop(&list, 7, &1), 7);
+        assert_eq!(gallop::<u32>(&[], 0, &1), 0);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_gallop_matches_linear_scan(
+            mut list in prop::collection::vec(0..1_000_u32, 0..300),
+            start in 0..310_usize,
+            target in 0..1_100_u32,
+        ) {
+            list.sort_unstable();
+            let start = start.min(list.len());
+            let expected = start + list[start..].iter().take_while(|&&v| v < target).count();
+            prop_assert_eq!(gallop(&list, start, &target), expected);
+        }
+    }
+}
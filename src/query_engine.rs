@@ -1,15 +1,35 @@
-use anyhow::Result;
-use futures::TryStreamExt;
-use mongodb::bson::doc;
+use anyhow::{Context, Result, ensure};
 use mongodb::bson::oid::ObjectId;
 use std::collections::{HashMap, hash_map::Entry};
 use std::hash::Hash;
-
-use crate::analyzer::TextAnalyzer;
-use crate::data_models::InvertedIndexDoc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::analyzer::{TextAnalyzer, TextToken};
+use crate::completion::{
+    COMPLETIONS_FILE, Completion, CompletionIndex, SURFACE_FORMS_FILE, SurfaceForms,
+};
+use crate::data_models::{DocId, InvertedIndexDoc};
 use crate::db::Database;
-use crate::db::collections;
+use crate::indexer::merge_sorted_lists_dedup;
+use crate::postings::skip::gallop;
+use crate::segment::{SEGMENT_FILE, Segment, SegmentStack, read_generation};
+use crate::storage::Storage;
+use crate::term_dict::{
+    DEFAULT_FUZZY_DISTANCE, DEFAULT_MAX_EXPANSIONS, TERM_DICT_FILE, TermDictionary, TermPattern,
+};
+
+/// Maximum number of corrected queries returned by `QueryEngine::suggest`.
+pub const MAX_SUGGESTIONS: usize = 3;
+
+/// How many replacement terms are considered for each misspelled query word.
+const CANDIDATES_PER_WORD: usize = 3;
+
+/// Below this length ratio of two posting lists, a linear merge beats galloping.
+const GALLOP_MIN_RATIO: usize = 8;
 
+/// Doc ids in both sorted lists. When one list is much longer, mismatches gallop ahead in it, so
+/// intersecting a short list with a long one is `O(m log(n / m))` instead of `O(m + n)`.
 pub fn intersect_two_postings<'a, T>(
     posting_list1: &'a [T],
     posting_list2: &'a [T],
@@ -17,6 +37,12 @@ pub fn intersect_two_postings<'a, T>(
 ) where
     T: Ord + Clone,
 {
+    let (shorter, longer) = if posting_list1.len() <= posting_list2.len() {
+        (posting_list1.len(), posting_list2.len())
+    } else {
+        (posting_list2.len(), posting_list1.len())
+    };
+    let skewed = longer >= shorter.saturating_mul(GALLOP_MIN_RATIO);
     let (mut p1i, mut p2i) = (0usize, 0usize);
     while p1i < posting_list1.len() && p2i < posting_list2.len() {
         match posting_list1[p1i].cmp(&posting_list2[p2i]) {
@@ -25,6 +51,12 @@ pub fn intersect_two_postings<'a, T>(
                 p1i += 1;
                 p2i += 1;
             }
+            std::cmp::Ordering::Less if skewed => {
+                p1i = gallop(posting_list1, p1i, &posting_list2[p2i])
+            }
+            std::cmp::Ordering::Greater if skewed => {
+                p2i = gallop(posting_list2, p2i, &posting_list1[p1i])
+            }
             std::cmp::Ordering::Less => p1i += 1,
             std::cmp::Ordering::Greater => p2i += 1,
         }
@@ -96,67 +128,405 @@ where
             p2 += 1;
         } else {
             if pl1[p1] < pl2[p2] {
-                p1 += 1;
+                p1 = gallop(pl1, p1, &pl2[p2]);
             } else {
+                p2 = gallop(pl2, p2, &pl1[p1]);
+            }
+        }
+    }
+    out
+}
+
+/// Like `positional_intersect`, but a match requires the second term at exactly `offset` positions
+/// after the first one (negative offsets mean before), which is what phrase queries need.
+/// Positions lists must be sorted.
+pub fn positional_intersect_with_offset<T>(
+    pl1: &[T],
+    positions_pl1: &HashMap<T, Vec<usize>>,
+    pl2: &[T],
+    positions_pl2: &HashMap<T, Vec<usize>>,
+    offset: isize,
+) -> Vec<PositionalMatch<T>>
+where
+    T: Ord + Clone + Hash,
+{
+    let mut out = Vec::new();
+    let (mut p1, mut p2) = (0, 0);
+    while p1 < pl1.len() && p2 < pl2.len() {
+        match pl1[p1].cmp(&pl2[p2]) {
+            std::cmp::Ordering::Equal => {
+                let positions_p1 = positions_pl1.get(&pl1[p1]).unwrap();
+                let positions_p2 = positions_pl2.get(&pl2[p2]).unwrap();
+                let mut pp2 = 0;
+                for &pos1 in positions_p1 {
+                    let Some(target) = pos1.checked_add_signed(offset) else {
+                        continue;
+                    };
+                    while pp2 < positions_p2.len() && positions_p2[pp2] < target {
+                        pp2 += 1;
+                    }
+                    if pp2 == positions_p2.len() {
+                        break;
+                    }
+                    if positions_p2[pp2] == target {
+                        out.push(PositionalMatch::new(pl1[p1].clone(), pos1, target));
+                    }
+                }
+                p1 += 1;
                 p2 += 1;
             }
+            std::cmp::Ordering::Less => p1 = gallop(pl1, p1, &pl2[p2]),
+            std::cmp::Ordering::Greater => p2 = gallop(pl2, p2, &pl1[p1]),
         }
     }
     out
 }
 
+/// Postings of a single term plus the positions of the term in each of those documents.
+type TermPostings = (Vec<DocId>, HashMap<DocId, Vec<usize>>);
+
+/// What a single query word is matched against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryClause {
+    /// An analyzed term, looked up as is.
+    Term(String),
+    /// A prefix/wildcard/regex term, expanded through the term dictionary.
+    Pattern(TermPattern),
+}
+
+/// A query clause along with its position relative to the first clause of the query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryToken {
+    pub clause: QueryClause,
+    pub pos: usize,
+}
+
+/// Splits a query into clauses. Pattern words (`harv*`, `h?rv*st`, `/regex/`) are kept verbatim,
+/// everything else goes through the analyzer. Positions are relative to the first clause.
+pub fn parse_query(analyzer: &TextAnalyzer, query: &str) -> Result<Vec<QueryToken>> {
+    if !query
+        .split_whitespace()
+        .any(|word| TermPattern::parse(word).is_some())
+    {
+        let tokens = analyzer.analyze_query(query)?;
+        return Ok(tokens
+            .into_iter()
+            .map(|t| QueryToken {
+                clause: QueryClause::Term(t.term),
+                pos: t.pos,
+            })
+            .collect());
+    }
+
+    let mut tokens = Vec::new();
+    let mut next_pos = 0;
+    for word in query.split_whitespace() {
+        if let Some(mut pattern) = TermPattern::parse(word) {
+            // fuzzy terms are stemmed like the index, so `harvsting~` becomes `harvst~`
+            if let TermPattern::Fuzzy { term, .. } = &mut pattern
+                && let [token] = analyzer.analyze(term.clone())?.as_slice()
+            {
+                *term = token.term.clone();
+            }
+            tokens.push(QueryToken {
+                clause: QueryClause::Pattern(pattern),
+                pos: next_pos,
+            });
+            next_pos += 1;
+            continue;
+        }
+        let raw_tokens = analyzer.tokenize(analyzer.char_filter(word.to_string()));
+        let raw_len = raw_tokens.len();
+        for t in analyzer.token_filter(raw_tokens) {
+            tokens.push(QueryToken {
+                clause: QueryClause::Term(t.term),
+                pos: next_pos + t.pos,
+            });
+        }
+        next_pos += raw_len;
+    }
+
+    if let Some(first) = tokens.first().map(|t| t.pos) {
+        for token in tokens.iter_mut() {
+            token.pos -= first;
+        }
+    }
+    Ok(tokens)
+}
+
+/// Proposes corrected versions of `query`, best first.
+///
+/// Every plain query word whose term is missing from `term_dict`, or has a more frequent term
+/// within `max_distance` edits, gets up to a few replacement candidates. A candidate term is
+/// suggested as the word it was most often indexed from in `surface_forms`, not as the analyzed
+/// term.
+/// Corrected queries are ranked by the summed document frequency of their terms and the original
+/// query is never returned.
+pub fn suggest_queries(
+    analyzer: &TextAnalyzer,
+    term_dict: &TermDictionary,
+    surface_forms: &SurfaceForms,
+    query: &str,
+    max_distance: u32,
+    limit: usize,
+) -> Result<Vec<String>> {
+    // (replacement word, document frequency) options for each query word
+    let mut options: Vec<Vec<(String, u64)>> = Vec::new();
+    let mut corrected = false;
+    for word in query.split_whitespace() {
+        let term = if TermPattern::parse(word).is_some() {
+            None
+        } else {
+            match analyzer.analyze(word.to_string())?.as_slice() {
+                [token] => Some(token.term.clone()),
+                _ => None,
+            }
+        };
+        let Some(term) = term else {
+            options.push(vec![(word.to_string(), 0)]);
+            continue;
+        };
+
+        let df = term_dict.get(&term).unwrap_or(0);
+        let mut word_options = Vec::new();
+        if df > 0 {
+            word_options.push((word.to_string(), df));
+        }
+        word_options.extend(
+            term_dict
+                .fuzzy_matches(&term, max_distance)?
+                .into_iter()
+                .filter(|m| m.document_frequency > df)
+                .take(CANDIDATES_PER_WORD)
+                .map(|m| {
+                    let word = surface_forms.most_frequent(&m.term).unwrap_or(m.term);
+                    (word, m.document_frequency)
+                }),
+        );
+        if word_options.is_empty() {
+            word_options.push((word.to_string(), 0));
+        }
+        corrected |= word_options.iter().any(|(w, _)| w != word);
+        options.push(word_options);
+    }
+    if !corrected {
+        return Ok(Vec::new());
+    }
+
+    // The score is a sum over independent per-word choices, so keeping the best `limit + 1`
+    // partial queries after each word is enough to find the best `limit` full ones
+    // (one more because the original query may be among them).
+    let mut beam: Vec<(Vec<&str>, u64)> = vec![(Vec::new(), 0)];
+    for word_options in &options {
+        let mut next = Vec::with_capacity(beam.len() * word_options.len());
+        for (words, score) in &beam {
+            for (word, df) in word_options {
+                let mut words = words.clone();
+                words.push(word);
+                next.push((words, score + df));
+            }
+        }
+        next.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+        next.truncate(limit + 1);
+        beam = next;
+    }
+
+    let original: Vec<&str> = query.split_whitespace().collect();
+    Ok(beam
+        .into_iter()
+        .filter(|(words, _)| *words != original)
+        .take(limit)
+        .map(|(words, _)| words.join(" "))
+        .collect())
+}
+
+/// The term dictionary, completions, surface forms and segments a query engine reads, swapped
+/// together when an index run commits a new generation.
+#[derive(Default)]
+struct IndexFiles {
+    /// Generation the files were written for, `None` when unknown.
+    generation: Option<u64>,
+    term_dict: TermDictionary,
+    completions: CompletionIndex,
+    surface_forms: SurfaceForms,
+    segments: Option<SegmentStack>,
+}
+
+impl IndexFiles {
+    fn open(index_dir: &Path) -> Result<Self> {
+        let record = read_generation(index_dir)?;
+        let term_dict = TermDictionary::open(&index_dir.join(TERM_DICT_FILE))?;
+        let completions = CompletionIndex::open(&index_dir.join(COMPLETIONS_FILE))?;
+        let surface_forms = SurfaceForms::open(&index_dir.join(SURFACE_FORMS_FILE))?;
+        let segments = if index_dir.join(SEGMENT_FILE).exists() {
+            let deltas = record.as_ref().map_or(&[][..], |record| &record.deltas);
+            Some(SegmentStack::open(index_dir, deltas)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            generation: record.map(|record| record.generation),
+            term_dict,
+            completions,
+            surface_forms,
+            segments,
+        })
+    }
+}
+
 pub struct QueryEngine {
-    db: Database,
+    storage: Storage,
     analyzer: TextAnalyzer,
+    max_expansions: usize,
+    fuzzy_distance: u32,
+    files: RwLock<Arc<IndexFiles>>,
+    index_dir: Option<PathBuf>,
+    /// Tombstoned doc ids with the `DocIdStore::deleted_version` they were read at
+    deleted: RwLock<Option<(u64, Arc<Vec<DocId>>)>>,
 }
 
 impl QueryEngine {
     pub fn new(db: Database, analyzer: TextAnalyzer) -> Self {
-        Self { db, analyzer }
+        Self::from_storage(Storage::mongo(&db), analyzer)
+    }
+
+    /// Query engine answering from the index of any storage backend.
+    pub fn from_storage(storage: Storage, analyzer: TextAnalyzer) -> Self {
+        Self {
+            storage,
+            analyzer,
+            max_expansions: DEFAULT_MAX_EXPANSIONS,
+            fuzzy_distance: DEFAULT_FUZZY_DISTANCE,
+            files: RwLock::default(),
+            index_dir: None,
+            deleted: RwLock::default(),
+        }
+    }
+
+    /// Use `term_dict` to expand prefix, wildcard and regex query terms.
+    pub fn with_term_dictionary(mut self, term_dict: TermDictionary) -> Self {
+        self.files_mut().term_dict = term_dict;
+        self
+    }
+
+    /// Cap on the number of index terms a single pattern query term expands to.
+    pub fn with_max_expansions(mut self, max_expansions: usize) -> Self {
+        self.max_expansions = max_expansions;
+        self
+    }
+
+    /// Edit distance of fuzzy terms written without one (`harvst~`) and of spelling suggestions.
+    pub fn with_fuzzy_distance(mut self, fuzzy_distance: u32) -> Self {
+        self.fuzzy_distance = fuzzy_distance;
+        self
+    }
+
+    /// Use `completions` to answer search-as-you-type lookups.
+    pub fn with_completions(mut self, completions: CompletionIndex) -> Self {
+        self.files_mut().completions = completions;
+        self
     }
 
-    pub fn db(&self) -> &Database {
-        &self.db
+    /// Use `surface_forms` to show suggested terms as the words they were indexed from.
+    pub fn with_surface_forms(mut self, surface_forms: SurfaceForms) -> Self {
+        self.files_mut().surface_forms = surface_forms;
+        self
+    }
+
+    /// Read postings from `segment` instead of the index store.
+    pub fn with_segment(mut self, segment: Segment) -> Self {
+        self.files_mut().segments = Some(SegmentStack::new(segment));
+        self
+    }
+
+    /// Use the term dictionary, completions, surface forms and segments in `index_dir`, and reload
+    /// them together once an index run wrote them for a new current generation, see `reload`.
+    pub fn with_index_dir(mut self, index_dir: impl Into<PathBuf>) -> Result<Self> {
+        let index_dir = index_dir.into();
+        *self.files.get_mut().unwrap() = Arc::new(IndexFiles::open(&index_dir)?);
+        self.index_dir = Some(index_dir);
+        Ok(self)
+    }
+
+    /// Reloads the index files when the current generation changed and the index run committing
+    /// it finished writing them, the next queries read the new files. Queries call it before
+    /// reading the index. Returns whether the files were reloaded.
+    pub async fn reload(&self) -> Result<bool> {
+        let current = self.storage.index.manifest().await?.current;
+        self.reload_for(current)
+    }
+
+    fn reload_for(&self, current: u64) -> Result<bool> {
+        let Some(index_dir) = &self.index_dir else {
+            return Ok(false);
+        };
+        if self.files().generation == Some(current) {
+            return Ok(false);
+        }
+        // an index run commits its generation before it writes the files
+        if read_generation(index_dir)?.map(|record| record.generation) != Some(current) {
+            return Ok(false);
+        }
+        let files = IndexFiles::open(index_dir)?;
+        log::info!(
+            "Reloaded the index files of generation {} ({} terms, {} completions)",
+            current,
+            files.term_dict.len(),
+            files.completions.len()
+        );
+        *self.files.write().unwrap() = Arc::new(files);
+        Ok(true)
+    }
+
+    fn files(&self) -> Arc<IndexFiles> {
+        self.files.read().unwrap().clone()
+    }
+
+    fn files_mut(&mut self) -> &mut IndexFiles {
+        Arc::get_mut(self.files.get_mut().unwrap())
+            .expect("index files are only shared while the engine is in use")
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
     }
 
     pub fn analyzer(&self) -> &TextAnalyzer {
         &self.analyzer
     }
 
-    fn intersect_postings(
-        terms: &[String],
-        tpp: &HashMap<String, (Vec<ObjectId>, HashMap<ObjectId, Vec<usize>>)>,
-    ) -> Vec<ObjectId> {
-        if tpp.is_empty() {
+    /// Intersects the postings of all query tokens, keeping only documents where every token
+    /// sits at its query offset relative to the others. Matching is anchored on the rarest token,
+    /// so a token at query offset `o` must appear at `anchor + (o - pivot_offset)`.
+    fn intersect_postings(tokens: &[TextToken], tpp: &HashMap<String, TermPostings>) -> Vec<DocId> {
+        if tpp.is_empty() || tokens.is_empty() {
             return Vec::new();
         }
-        let pivot = (0..terms.len())
-            .min_by_key(|&i| tpp[&terms[i]].0.len())
+        let pivot = (0..tokens.len())
+            .min_by_key(|&i| tpp[&tokens[i].term].0.len())
             .unwrap();
+        let pivot_pos = tokens[pivot].pos as isize;
 
-        let mut result = tpp[&terms[pivot]].0.to_vec();
-        let mut result_positions = tpp[&terms[pivot]].1.clone();
+        let mut result = tpp[&tokens[pivot].term].0.to_vec();
+        let mut result_positions = tpp[&tokens[pivot].term].1.clone();
 
-        for (term_idx, term) in terms.iter().enumerate() {
-            if term_idx == pivot {
+        for (token_idx, token) in tokens.iter().enumerate() {
+            if token_idx == pivot {
                 continue;
             }
-            // BUG: term chaining is wrong. it assumes simple left to right chaining
-            let k = term_idx.abs_diff(pivot);
-            let (pl, pos) = tpp.get(term).unwrap();
+            let offset = token.pos as isize - pivot_pos;
+            let (pl, pos) = tpp.get(&token.term).unwrap();
 
-            let matches = positional_intersect(&result, &result_positions, &pl.to_vec(), &pos, k);
+            let matches =
+                positional_intersect_with_offset(&result, &result_positions, pl, pos, offset);
 
             if matches.is_empty() {
                 return Vec::new();
             }
 
-            let mut new_positions: HashMap<ObjectId, Vec<usize>> = HashMap::new();
+            let mut new_positions: HashMap<DocId, Vec<usize>> = HashMap::new();
             for m in &matches {
-                new_positions
-                    .entry(m.doc_id.clone())
-                    .or_insert_with(Vec::new)
-                    .push(m.position1); // perserving the pivot position
+                new_positions.entry(m.doc_id).or_default().push(m.position1); // preserving the pivot position
             }
 
             // Extract unique doc_ids (in sorted order to maintain consistency)
@@ -169,14 +539,108 @@ impl QueryEngine {
         result
     }
 
-    pub async fn query(&self, query: &str) -> Result<Vec<ObjectId>> {
-        // Analyze the query text using the same pipeline as documents
-        let text_tokens = self.analyzer.analyze(query.to_string())?;
+    /// Merges the postings of several terms into one list, as if they were a single term.
+    fn union_postings<'a>(lists: impl Iterator<Item = &'a TermPostings>) -> TermPostings {
+        let mut postings = Vec::new();
+        let mut positions: HashMap<DocId, Vec<usize>> = HashMap::new();
+        for (pl, pos) in lists {
+            postings = merge_sorted_lists_dedup(&postings, pl);
+            for (doc_id, p) in pos {
+                positions.entry(*doc_id).or_default().extend(p);
+            }
+        }
+        for p in positions.values_mut() {
+            p.sort_unstable();
+            p.dedup();
+        }
+        (postings, positions)
+    }
 
-        let terms = text_tokens
+    /// Postings of `terms` read from `segments`. Only the terms of the rarest slot are decoded in
+    /// full, the others only in the blocks holding one of its documents, since no other document
+    /// can match.
+    fn segment_postings(
+        segments: &SegmentStack,
+        slots: &[TextToken],
+        patterns: &HashMap<String, Vec<String>>,
+        terms: &[String],
+    ) -> Result<Vec<InvertedIndexDoc>> {
+        let mut rarest: Option<(u64, &[String])> = None;
+        for slot in slots {
+            let slot_terms = patterns
+                .get(&slot.term)
+                .map(Vec::as_slice)
+                .unwrap_or(std::slice::from_ref(&slot.term));
+            let mut document_frequency = 0;
+            for term in slot_terms {
+                document_frequency += segments.document_frequency(term)?.unwrap_or(0);
+            }
+            if rarest.is_none_or(|(min, _)| document_frequency < min) {
+                rarest = Some((document_frequency, slot_terms));
+            }
+        }
+        let Some((_, rarest_terms)) = rarest else {
+            return Ok(Vec::new());
+        };
+
+        let mut docs = segments.find_by_terms(rarest_terms)?;
+        let candidates = docs.iter().fold(Vec::new(), |candidates, doc| {
+            merge_sorted_lists_dedup(&candidates, &doc.postings)
+        });
+        let others: Vec<String> = terms
             .iter()
-            .map(|t| t.term.clone())
-            .collect::<Vec<String>>();
+            .filter(|term| !rarest_terms.contains(term))
+            .cloned()
+            .collect();
+        docs.extend(segments.find_by_terms_in(&others, &candidates)?);
+        Ok(docs)
+    }
+
+    /// Doc ids of the documents matching `query`, ascending. See `page_ids` for their pages.
+    /// Fails when the current index generation was built with another analyzer than this engine's.
+    pub async fn query(&self, query: &str) -> Result<Vec<DocId>> {
+        let manifest = self.storage.index.manifest().await?;
+        ensure!(
+            manifest.analyzer() == self.analyzer.name(),
+            "Index generation {} is built with analyzer '{}', queries are analyzed with '{}'",
+            manifest.current,
+            manifest.analyzer(),
+            self.analyzer.name()
+        );
+        self.reload_for(manifest.current)?;
+        let files = self.files();
+        let query_tokens = parse_query(&self.analyzer, query)?;
+
+        // Every query token becomes a slot keyed by its term, or by the pattern syntax for patterns.
+        // A pattern slot matches wherever any of its expanded terms does.
+        let mut slots: Vec<TextToken> = Vec::with_capacity(query_tokens.len());
+        let mut patterns: HashMap<String, Vec<String>> = HashMap::new();
+        let mut terms: Vec<String> = Vec::new();
+        for token in &query_tokens {
+            let key = match &token.clause {
+                QueryClause::Term(term) => {
+                    terms.push(term.clone());
+                    term.clone()
+                }
+                QueryClause::Pattern(pattern) => {
+                    let mut pattern = pattern.clone();
+                    if let TermPattern::Fuzzy { distance, .. } = &mut pattern {
+                        distance.get_or_insert(self.fuzzy_distance);
+                    }
+                    let key = pattern.to_string();
+                    if !patterns.contains_key(&key) {
+                        let expanded = files.term_dict.expand(&pattern, self.max_expansions)?;
+                        terms.extend(expanded.iter().cloned());
+                        patterns.insert(key.clone(), expanded);
+                    }
+                    key
+                }
+            };
+            slots.push(TextToken {
+                term: key,
+                pos: token.pos,
+            });
+        }
         println!(
             "DEBUG, query: terms, query: {:?}, terms: {:?}",
             query, terms
@@ -184,32 +648,17 @@ impl QueryEngine {
         if terms.is_empty() {
             return Ok(Vec::new());
         }
-        let i_index = self.db.collection::<InvertedIndexDoc>(collections::INDEX);
-        let filter = doc! {
-            "term": {
-                "$in": terms.clone()
-            }
+        let index_docs: Vec<InvertedIndexDoc> = match &files.segments {
+            Some(segments) => Self::segment_postings(segments, &slots, &patterns, &terms)?,
+            None => self.storage.index.find_by_terms(&terms).await?,
         };
-        let options = mongodb::options::FindOptions::builder()
-            .sort(doc! {"bucket": 1})
-            .build();
-
-        let index_docs: Vec<InvertedIndexDoc> = i_index
-            .find(filter)
-            .with_options(options)
-            .await?
-            .try_collect()
-            .await?;
         println!("DEBUG, query result terms");
         for d in &index_docs {
             print!("{:?}, ", d.term);
         }
         println!("");
 
-        let mut term_posting_and_positions: HashMap<
-            String,
-            (Vec<ObjectId>, HashMap<ObjectId, Vec<usize>>),
-        > = HashMap::new();
+        let mut term_posting_and_positions: HashMap<String, TermPostings> = HashMap::new();
 
         for doc in index_docs {
             match term_posting_and_positions.entry(doc.term.clone()) {
@@ -224,20 +673,78 @@ impl QueryEngine {
                 }
             }
         }
-        let unique_terms: std::collections::HashSet<_> = terms.iter().collect();
-        if term_posting_and_positions.len() != unique_terms.len() {
+
+        for (key, expanded) in &patterns {
+            let union = Self::union_postings(
+                expanded
+                    .iter()
+                    .filter_map(|term| term_posting_and_positions.get(term)),
+            );
+            if !union.0.is_empty() {
+                term_posting_and_positions.insert(key.clone(), union);
+            }
+        }
+        if !slots
+            .iter()
+            .all(|slot| term_posting_and_positions.contains_key(&slot.term))
+        {
             return Ok(Vec::new());
         }
 
-        let mut posting_lists: Vec<(&[ObjectId], &HashMap<ObjectId, Vec<usize>>)> = Vec::new();
-        for term in &terms {
-            // process the terms in query order.
-            let (pl, pos) = term_posting_and_positions.get(term).unwrap();
-            posting_lists.push((pl.as_slice(), pos));
+        let mut result = Self::intersect_postings(&slots, &term_posting_and_positions);
+        if !result.is_empty() {
+            // deleted documents stay in the postings of the generations before a compaction
+            let deleted = self.deleted().await?;
+            result.retain(|doc_id| deleted.binary_search(doc_id).is_err());
         }
-        let result = Self::intersect_postings(&terms, &term_posting_and_positions);
         Ok(result)
     }
+
+    /// Tombstoned doc ids, read again from the doc id store only once they changed.
+    async fn deleted(&self) -> Result<Arc<Vec<DocId>>> {
+        let version = self.storage.doc_ids.deleted_version().await?;
+        if let Some((read_at, deleted)) = &*self.deleted.read().unwrap()
+            && *read_at == version
+        {
+            return Ok(deleted.clone());
+        }
+        let deleted = Arc::new(self.storage.doc_ids.deleted().await?);
+        *self.deleted.write().unwrap() = Some((version, deleted.clone()));
+        Ok(deleted)
+    }
+
+    /// Page ids of `doc_ids` in the same order, from the segments when there are some.
+    pub async fn page_ids(&self, doc_ids: &[DocId]) -> Result<Vec<ObjectId>> {
+        match &self.files().segments {
+            Some(segments) => doc_ids
+                .iter()
+                .map(|&doc_id| {
+                    segments
+                        .page_id(doc_id)
+                        .with_context(|| format!("Unknown doc id {doc_id}"))
+                })
+                .collect(),
+            None => self.storage.doc_ids.resolve(doc_ids).await,
+        }
+    }
+
+    /// Autocomplete of a partially typed query, see `CompletionIndex::complete`.
+    pub fn complete(&self, partial_query: &str, limit: usize) -> Vec<Completion> {
+        self.files().completions.complete(partial_query, limit)
+    }
+
+    /// "Did you mean" corrections of `query`, see `suggest_queries`.
+    pub fn suggest(&self, query: &str) -> Result<Vec<String>> {
+        let files = self.files();
+        suggest_queries(
+            &self.analyzer,
+            &files.term_dict,
+            &files.surface_forms,
+            query,
+            self.fuzzy_distance,
+            MAX_SUGGESTIONS,
+        )
+    }
 }
 
 #[cfg(test)]
@@ -286,6 +793,20 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_intersect_two_postings_skewed() {
+        let common: Vec<u32> = (0..100_000).step_by(2).collect();
+        let rare = vec![1, 2, 4_001, 50_000, 99_998, 100_002];
+
+        let mut out = Vec::new();
+        intersect_two_postings(&rare, &common, &mut out);
+        assert_eq!(out, vec![2, 50_000, 99_998]);
+
+        out.clear();
+        intersect_two_postings(&common, &rare, &mut out);
+        assert_eq!(out, vec![2, 50_000, 99_998]);
+    }
+
     // #[test]
     // fn test_intersect_postings_edgy_multilist_cascade() {
     //     {
@@ -493,7 +1014,7 @@ mod test {
         let expected = vec![
             PositionalMatch::new(7, 5, 4),
             PositionalMatch::new(7, 5, 6),
-            PositionalMatch::new(7, 10, 9)
+            PositionalMatch::new(7, 10, 9),
         ];
         assert_unordered_eq(out, expected);
     }
@@ -656,4 +1177,305 @@ mod test {
         );
         assert!(!out.is_empty());
     }
+
+    // ----------------------------
+    // Exact offset (phrase) intersection
+    // ----------------------------
+
+    #[test]
+    fn offset_intersect_requires_exact_gap() {
+        let pl = vec![1u32];
+        let pos1 = hm(vec![(1u32, vec![4, 10])]);
+        let pos2 = hm(vec![(1u32, vec![5, 13])]);
+
+        let out = positional_intersect_with_offset(&pl, &pos1, &pl, &pos2, 1);
+        assert_eq!(out, vec![PositionalMatch::new(1, 4, 5)]);
+
+        let out = positional_intersect_with_offset(&pl, &pos1, &pl, &pos2, 3);
+        assert_eq!(out, vec![PositionalMatch::new(1, 10, 13)]);
+
+        let out = positional_intersect_with_offset(&pl, &pos1, &pl, &pos2, 2);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn offset_intersect_negative_offset() {
+        let pl = vec![1u32, 2];
+        let pos1 = hm(vec![(1u32, vec![0, 7]), (2, vec![3])]);
+        let pos2 = hm(vec![(1u32, vec![5]), (2, vec![1])]);
+
+        let out = positional_intersect_with_offset(&pl, &pos1, &pl, &pos2, -2);
+        assert_unordered_eq(
+            out,
+            vec![PositionalMatch::new(1, 7, 5), PositionalMatch::new(2, 3, 1)],
+        );
+    }
+
+    fn doc(n: u8) -> DocId {
+        n as DocId
+    }
+
+    fn tok(term: &str, pos: usize) -> TextToken {
+        TextToken {
+            term: term.to_string(),
+            pos,
+        }
+    }
+
+    #[test]
+    fn intersect_postings_respects_stop_word_gaps() {
+        // doc 1: "state of the art" -> state@0 art@3
+        // doc 2: "state art"        -> state@0 art@1
+        let tpp: HashMap<String, TermPostings> = [
+            (
+                "state".to_string(),
+                (
+                    vec![doc(1), doc(2)],
+                    hm(vec![(doc(1), vec![0]), (doc(2), vec![0])]),
+                ),
+            ),
+            (
+                "art".to_string(),
+                (
+                    vec![doc(1), doc(2)],
+                    hm(vec![(doc(1), vec![3]), (doc(2), vec![1])]),
+                ),
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        let gapped = QueryEngine::intersect_postings(&[tok("state", 0), tok("art", 3)], &tpp);
+        assert_eq!(gapped, vec![doc(1)]);
+
+        let adjacent = QueryEngine::intersect_postings(&[tok("state", 0), tok("art", 1)], &tpp);
+        assert_eq!(adjacent, vec![doc(2)]);
+    }
+
+    #[test]
+    fn intersect_postings_anchors_on_rarest_term() {
+        // "a b c" where "c" is the rarest term; "a" must be 2 before "c" and "b" 1 before it.
+        let tpp: HashMap<String, TermPostings> = [
+            (
+                "a".to_string(),
+                (
+                    vec![doc(1), doc(2), doc(3)],
+                    hm(vec![
+                        (doc(1), vec![0]),
+                        (doc(2), vec![5]),
+                        (doc(3), vec![1]),
+                    ]),
+                ),
+            ),
+            (
+                "b".to_string(),
+                (
+                    vec![doc(1), doc(2), doc(3)],
+                    hm(vec![
+                        (doc(1), vec![1]),
+                        (doc(2), vec![6]),
+                        (doc(3), vec![2]),
+                    ]),
+                ),
+            ),
+            (
+                "c".to_string(),
+                (
+                    vec![doc(1), doc(2)],
+                    hm(vec![(doc(1), vec![2]), (doc(2), vec![9])]),
+                ),
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        let out = QueryEngine::intersect_postings(&[tok("a", 0), tok("b", 1), tok("c", 2)], &tpp);
+        assert_eq!(out, vec![doc(1)]);
+    }
+
+    // ----------------------------
+    // Query parsing with term patterns
+    // ----------------------------
+
+    fn analyzer() -> TextAnalyzer {
+        use crate::analyzer::*;
+        TextAnalyzer::new(
+            vec![],
+            Box::new(WhiteSpaceTokenizer),
+            vec![
+                Box::new(PunctuationStripFilter::default()),
+                Box::new(LowerCaseTokenFilter),
+                Box::new(StopWordTokenFilter),
+                Box::new(PorterStemmerTokenFilter),
+            ],
+        )
+    }
+
+    #[test]
+    fn parse_query_keeps_patterns_verbatim() {
+        let tokens = parse_query(&analyzer(), "the Harv* of h?rv*st /crawl(er|ing)/").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                QueryToken {
+                    clause: QueryClause::Pattern(TermPattern::Prefix("harv".to_string())),
+                    pos: 0,
+                },
+                QueryToken {
+                    clause: QueryClause::Pattern(TermPattern::Wildcard("h?rv*st".to_string())),
+                    pos: 2,
+                },
+                QueryToken {
+                    clause: QueryClause::Pattern(TermPattern::Regex("crawl(er|ing)".to_string())),
+                    pos: 3,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_query_without_patterns_matches_analyzer() {
+        let tokens = parse_query(&analyzer(), "castle of the kings").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                QueryToken {
+                    clause: QueryClause::Term("castl".to_string()),
+                    pos: 0,
+                },
+                QueryToken {
+                    clause: QueryClause::Term("king".to_string()),
+                    pos: 3,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_query_treats_a_trailing_question_mark_as_punctuation() {
+        let tokens = parse_query(&analyzer(), "what is harvesting?").unwrap();
+        assert_eq!(
+            tokens,
+            vec![QueryToken {
+                clause: QueryClause::Term("harvest".to_string()),
+                pos: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_query_stems_fuzzy_terms() {
+        let tokens = parse_query(&analyzer(), "castles~2").unwrap();
+        assert_eq!(
+            tokens,
+            vec![QueryToken {
+                clause: QueryClause::Pattern(TermPattern::Fuzzy {
+                    term: "castl".to_string(),
+                    distance: Some(2),
+                }),
+                pos: 0,
+            }]
+        );
+    }
+
+    fn suggestion_dict() -> TermDictionary {
+        TermDictionary::build([("harvest", 10), ("moon", 5), ("moan", 1), ("loan", 3)]).unwrap()
+    }
+
+    #[test]
+    fn suggest_queries_corrects_missing_terms() {
+        let suggestions = suggest_queries(
+            &analyzer(),
+            &suggestion_dict(),
+            &SurfaceForms::default(),
+            "harvst moon",
+            1,
+            3,
+        )
+        .unwrap();
+        assert_eq!(suggestions, vec!["harvest moon"]);
+    }
+
+    #[test]
+    fn suggest_queries_ranks_by_document_frequency() {
+        // "moan" is indexed, but "moon" and "loan" are more frequent one edit away
+        let suggestions = suggest_queries(
+            &analyzer(),
+            &suggestion_dict(),
+            &SurfaceForms::default(),
+            "the harvest moan",
+            1,
+            3,
+        )
+        .unwrap();
+        assert_eq!(suggestions, vec!["the harvest moon", "the harvest loan"]);
+    }
+
+    #[test]
+    fn suggest_queries_uses_surface_forms() {
+        let dict = TermDictionary::build([("castl", 7), ("king", 3)]).unwrap();
+        let surface_forms = SurfaceForms::build([
+            ("castl", "castles", 4),
+            ("castl", "castle", 9),
+            ("cast", "cast", 20),
+            ("king", "kings", 2),
+        ])
+        .unwrap();
+        let suggestions =
+            suggest_queries(&analyzer(), &dict, &surface_forms, "castels kings", 1, 3).unwrap();
+        assert_eq!(suggestions, vec!["castle kings"]);
+
+        // without a surface form the term is all there is
+        let suggestions = suggest_queries(
+            &analyzer(),
+            &dict,
+            &SurfaceForms::default(),
+            "castels",
+            1,
+            3,
+        )
+        .unwrap();
+        assert_eq!(suggestions, vec!["castl"]);
+    }
+
+    #[test]
+    fn suggest_queries_keeps_known_queries() {
+        let suggestions = suggest_queries(
+            &analyzer(),
+            &suggestion_dict(),
+            &SurfaceForms::default(),
+            "harvest moon",
+            1,
+            3,
+        )
+        .unwrap();
+        assert!(suggestions.is_empty());
+        let suggestions = suggest_queries(
+            &analyzer(),
+            &suggestion_dict(),
+            &SurfaceForms::default(),
+            "zzzz",
+            1,
+            3,
+        )
+        .unwrap();
+        assert!(suggestions.is_empty());
+    }
+
+    #[test]
+    fn union_postings_merges_docs_and_positions() {
+        let a: TermPostings = (
+            vec![doc(1), doc(3)],
+            hm(vec![(doc(1), vec![4]), (doc(3), vec![1])]),
+        );
+        let b: TermPostings = (
+            vec![doc(2), doc(3)],
+            hm(vec![(doc(2), vec![0]), (doc(3), vec![7, 1])]),
+        );
+
+        let (postings, positions) = QueryEngine::union_postings([&a, &b].into_iter());
+        assert_eq!(postings, vec![doc(1), doc(2), doc(3)]);
+        assert_eq!(positions[&doc(3)], vec![1, 7]);
+        assert_eq!(positions[&doc(2)], vec![0]);
+    }
 }
@@ -0,0 +1,999 @@
+use anyhow::{Context, Result, bail, ensure};
+use memmap2::Mmap;
+use mongodb::bson::oid::ObjectId;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::Path;
+
+use crate::data_models::{DocId, InvertedIndexDoc};
+use crate::postings::codec::Codec;
+use crate::postings::skip::{gallop, gallop_by};
+use crate::storage::{DocIdStore, IndexStore};
+
+/// File name of the base index segment, inside the index directory.
+pub const SEGMENT_FILE: &str = "index.seg";
+
+/// File name of the index generation the files of the index directory were written for and of
+/// the delta segments read on top of the base one, written after the segments.
+pub const GENERATION_FILE: &str = "generation";
+
+const MAGIC: &[u8; 8] = b"HVSTSEG6";
+/// magic, doc count, term count, codec id, first doc id and the offsets of the term table, term
+/// bytes, postings, positions and the end of the file.
+const HEADER_LEN: usize = 8 + 4 + 4 + 4 + 4 + 5 * 8;
+const PAGE_ID_LEN: usize = 12;
+/// term bytes offset, term length, document frequency, reserved, postings offset, positions offset.
+const TERM_ENTRY_LEN: usize = 4 + 4 + 4 + 4 + 8 + 8;
+/// Postings per block, every block has an entry in the skip table of its term.
+pub const BLOCK_LEN: usize = 128;
+/// last doc id, block postings offset, block positions offset.
+const SKIP_ENTRY_LEN: usize = 4 + 8 + 8;
+/// Doc table entry of deleted doc ids, which no posting refers to.
+const DELETED_PAGE: ObjectId = ObjectId::from_bytes([0; PAGE_ID_LEN]);
+/// Terms fetched from the index store at once while writing a segment.
+const TERMS_PER_FETCH: usize = 1_000;
+
+/// Immutable, memory mapped index segment.
+///
+/// ```text
+/// header     | magic | #docs | #terms | codec | first doc id | section offsets
+/// doc table  | #docs page ObjectIds indexed by doc id from the first one, zeros for deleted doc ids
+/// term table | #terms fixed size entries sorted by term, binary searched
+/// term bytes | the term strings
+/// postings   | per term: a skip table with an entry per block of `BLOCK_LEN` postings, then
+///            | the blocks, doc ids as gaps encoded with the segment's codec
+/// positions  | per term and posting: positions as gaps, encoded with the segment's codec
+/// ```
+///
+/// Postings are only decoded for the terms of a query, so opening a segment is O(1) and
+/// a query touches only the pages of the file it needs. The skip table holds the last doc id
+/// of every block, so a term can be intersected with a much rarer one without decoding the
+/// blocks that can't match.
+pub struct Segment {
+    mmap: Mmap,
+    first_doc_id: usize,
+    num_docs: usize,
+    num_terms: usize,
+    codec: Codec,
+    term_table: usize,
+    term_bytes: usize,
+    postings: usize,
+    positions: usize,
+}
+
+/// Postings of one term, decoded from a segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentPostings {
+    /// Doc ids, ascending.
+    pub doc_ids: Vec<DocId>,
+    /// Token positions in every document of `doc_ids`, ascending.
+    pub positions: Vec<Vec<usize>>,
+}
+
+impl SegmentPostings {
+    fn into_index_doc(self, term: &str) -> InvertedIndexDoc {
+        let positions = self.doc_ids.iter().copied().zip(self.positions).collect();
+        InvertedIndexDoc::new(
+            term.to_string(),
+            0,
+            self.doc_ids.len() as u64,
+            self.doc_ids,
+            positions,
+        )
+    }
+}
+
+/// Skip data of a block of postings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockInfo {
+    /// Doc id of the last posting of the block.
+    pub last_doc_id: DocId,
+    /// Number of postings in the block.
+    pub len: usize,
+    postings_start: usize,
+    positions_start: usize,
+}
+
+/// Where the skip table and the blocks of a term are in the file.
+struct TermBlocks {
+    skip_table: usize,
+    num_blocks: usize,
+    blocks: usize,
+    positions: usize,
+    document_frequency: usize,
+}
+
+struct TermEntry {
+    term_start: usize,
+    term_len: usize,
+    document_frequency: usize,
+    postings_start: usize,
+    positions_start: usize,
+}
+
+impl Segment {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open segment {}", path.display()))?;
+        // SAFETY: segments are never modified in place, `SegmentWriter::finish` replaces
+        // the file with a rename, so the mapped file stays valid while it's mapped.
+        let mmap = unsafe { Mmap::map(&file) }
+            .with_context(|| format!("Failed to map segment {}", path.display()))?;
+        Self::from_mmap(mmap).with_context(|| format!("Invalid segment {}", path.display()))
+    }
+
+    fn from_mmap(mmap: Mmap) -> Result<Self> {
+        ensure!(mmap.len() >= HEADER_LEN, "file too short");
+        ensure!(&mmap[..8] == MAGIC, "bad magic");
+        let num_docs = read_u32(&mmap, 8) as usize;
+        let num_terms = read_u32(&mmap, 12) as usize;
+        let codec = Codec::from_id(read_u32(&mmap, 16))?;
+        let first_doc_id = read_u32(&mmap, 20) as usize;
+        let [term_table, term_bytes, postings, positions, end] =
+            std::array::from_fn(|i| read_u64(&mmap, 24 + i * 8) as usize);
+
+        ensure!(end == mmap.len(), "truncated, expected {} bytes", end);
+        ensure!(
+            HEADER_LEN + num_docs * PAGE_ID_LEN == term_table
+                && term_table + num_terms * TERM_ENTRY_LEN == term_bytes
+                && term_bytes <= postings
+                && postings <= positions
+                && positions <= end,
+            "corrupted section offsets"
+        );
+        Ok(Self {
+            mmap,
+            first_doc_id,
+            num_docs,
+            num_terms,
+            codec,
+            term_table,
+            term_bytes,
+            postings,
+            positions,
+        })
+    }
+
+    pub fn num_docs(&self) -> usize {
+        self.num_docs
+    }
+
+    pub fn num_terms(&self) -> usize {
+        self.num_terms
+    }
+
+    /// Codec of the postings and positions.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Page id of the document `doc_id`, `None` when it's unknown or was deleted.
+    pub fn page_id(&self, doc_id: DocId) -> Option<ObjectId> {
+        let index = (doc_id as usize).checked_sub(self.first_doc_id)?;
+        if index >= self.num_docs {
+            return None;
+        }
+        let start = HEADER_LEN + index * PAGE_ID_LEN;
+        let bytes: [u8; PAGE_ID_LEN] = self.mmap[start..start + PAGE_ID_LEN].try_into().unwrap();
+        Some(ObjectId::from_bytes(bytes)).filter(|page_id| *page_id != DELETED_PAGE)
+    }
+
+    /// Postings of `term`, `None` when the term is not in the segment.
+    pub fn postings(&self, term: &str) -> Result<Option<SegmentPostings>> {
+        let Some(entry) = self.find_term(term)? else {
+            return Ok(None);
+        };
+        let blocks = self.term_blocks(&entry, term)?;
+        let mut doc_ids = Vec::with_capacity(entry.document_frequency);
+        let mut positions = Vec::with_capacity(entry.document_frequency);
+        let mut base = 0;
+        for i in 0..blocks.num_blocks {
+            let block = self.block(&blocks, i);
+            self.decode_block(&block, base, term, &mut doc_ids)?;
+            self.decode_block_positions(&block, &mut positions)?;
+            base = block.last_doc_id;
+        }
+        Ok(Some(SegmentPostings { doc_ids, positions }))
+    }
+
+    /// Postings of `term` restricted to the doc ids in `candidates`, which must be strictly ascending.
+    ///
+    /// Blocks without any candidate are skipped through the skip table without being decoded,
+    /// so this is much cheaper than `postings` when `term` is far more common than the candidates.
+    pub fn postings_in(&self, term: &str, candidates: &[DocId]) -> Result<Option<SegmentPostings>> {
+        let Some(entry) = self.find_term(term)? else {
+            return Ok(None);
+        };
+        let blocks = self.term_blocks(&entry, term)?;
+        let mut postings = SegmentPostings {
+            doc_ids: Vec::new(),
+            positions: Vec::new(),
+        };
+        let mut block_index = 0;
+        let mut decoded_block = None;
+        let mut doc_ids = Vec::with_capacity(BLOCK_LEN);
+        // decoded on the first match in the block
+        let mut positions = Vec::with_capacity(BLOCK_LEN);
+        let mut next = 0;
+        for &candidate in candidates {
+            block_index = gallop_by(block_index, blocks.num_blocks, |i| {
+                self.block(&blocks, i).last_doc_id < candidate
+            });
+            if block_index == blocks.num_blocks {
+                break;
+            }
+            if decoded_block != Some(block_index) {
+                let base = match block_index {
+                    0 => 0,
+                    i => self.block(&blocks, i - 1).last_doc_id,
+                };
+                doc_ids.clear();
+                positions.clear();
+                self.decode_block(&self.block(&blocks, block_index), base, term, &mut doc_ids)?;
+                decoded_block = Some(block_index);
+                next = 0;
+            }
+            next = gallop(&doc_ids, next, &candidate);
+            if doc_ids.get(next) == Some(&candidate) {
+                if positions.is_empty() {
+                    self.decode_block_positions(&self.block(&blocks, block_index), &mut positions)?;
+                }
+                postings.doc_ids.push(candidate);
+                postings
+                    .positions
+                    .push(std::mem::take(&mut positions[next]));
+            }
+        }
+        Ok(Some(postings))
+    }
+
+    /// Skip data of the blocks of `term`, `None` when the term is not in the segment.
+    pub fn blocks(&self, term: &str) -> Result<Option<Vec<BlockInfo>>> {
+        let Some(entry) = self.find_term(term)? else {
+            return Ok(None);
+        };
+        let blocks = self.term_blocks(&entry, term)?;
+        Ok(Some(
+            (0..blocks.num_blocks)
+                .map(|i| self.block(&blocks, i))
+                .collect(),
+        ))
+    }
+
+    /// Number of documents containing `term`, `None` when the term is not in the segment.
+    pub fn document_frequency(&self, term: &str) -> Result<Option<u64>> {
+        Ok(self
+            .find_term(term)?
+            .map(|entry| entry.document_frequency as u64))
+    }
+
+    /// Postings of the given terms in the shape of the inverted index store, a single bucket per term.
+    pub fn find_by_terms(&self, terms: &[String]) -> Result<Vec<InvertedIndexDoc>> {
+        let mut docs = Vec::new();
+        for term in terms {
+            if let Some(postings) = self.postings(term)? {
+                docs.push(postings.into_index_doc(term));
+            }
+        }
+        Ok(docs)
+    }
+
+    /// `find_by_terms` restricted to the ascending doc ids in `candidates`, see `postings_in`.
+    pub fn find_by_terms_in(
+        &self,
+        terms: &[String],
+        candidates: &[DocId],
+    ) -> Result<Vec<InvertedIndexDoc>> {
+        let mut docs = Vec::new();
+        for term in terms {
+            if let Some(postings) = self.postings_in(term, candidates)? {
+                docs.push(postings.into_index_doc(term));
+            }
+        }
+        Ok(docs)
+    }
+
+    /// Document frequency of every term, sorted by term.
+    pub fn term_document_frequencies(&self) -> Result<Vec<(String, u64)>> {
+        (0..self.num_terms)
+            .map(|i| {
+                let entry = self.term_entry(i);
+                Ok((
+                    self.term(&entry)?.to_string(),
+                    entry.document_frequency as u64,
+                ))
+            })
+            .collect()
+    }
+
+    fn find_term(&self, term: &str) -> Result<Option<TermEntry>> {
+        let (mut low, mut high) = (0, self.num_terms);
+        while low < high {
+            let mid = (low + high) / 2;
+            let entry = self.term_entry(mid);
+            match self.term(&entry)?.cmp(term) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(entry)),
+            }
+        }
+        Ok(None)
+    }
+
+    fn term_entry(&self, index: usize) -> TermEntry {
+        let start = self.term_table + index * TERM_ENTRY_LEN;
+        TermEntry {
+            term_start: read_u32(&self.mmap, start) as usize,
+            term_len: read_u32(&self.mmap, start + 4) as usize,
+            document_frequency: read_u32(&self.mmap, start + 8) as usize,
+            postings_start: read_u64(&self.mmap, start + 16) as usize,
+            positions_start: read_u64(&self.mmap, start + 24) as usize,
+        }
+    }
+
+    fn term(&self, entry: &TermEntry) -> Result<&str> {
+        let start = self.term_bytes + entry.term_start;
+        let bytes = self
+            .mmap
+            .get(start..start + entry.term_len)
+            .filter(|_| start + entry.term_len <= self.postings)
+            .context("term out of bounds")?;
+        std::str::from_utf8(bytes).context("term is not valid utf-8")
+    }
+
+    /// Locates the skip table and blocks of a term, checking they are inside the postings section.
+    fn term_blocks(&self, entry: &TermEntry, term: &str) -> Result<TermBlocks> {
+        let num_blocks = entry.document_frequency.div_ceil(BLOCK_LEN);
+        let skip_table = self.postings + entry.postings_start;
+        let blocks = skip_table + num_blocks * SKIP_ENTRY_LEN;
+        ensure!(
+            blocks <= self.positions && self.positions + entry.positions_start <= self.mmap.len(),
+            "skip table of term '{}' out of bounds",
+            term
+        );
+        Ok(TermBlocks {
+            skip_table,
+            num_blocks,
+            blocks,
+            positions: self.positions + entry.positions_start,
+            document_frequency: entry.document_frequency,
+        })
+    }
+
+    fn block(&self, blocks: &TermBlocks, index: usize) -> BlockInfo {
+        let start = blocks.skip_table + index * SKIP_ENTRY_LEN;
+        BlockInfo {
+            last_doc_id: read_u32(&self.mmap, start),
+            len: BLOCK_LEN.min(blocks.document_frequency - index * BLOCK_LEN),
+            postings_start: blocks.blocks + read_u64(&self.mmap, start + 4) as usize,
+            positions_start: blocks.positions + read_u64(&self.mmap, start + 12) as usize,
+        }
+    }
+
+    /// Appends the doc ids of `block` to `out`, `base` is the last doc id of the previous block.
+    fn decode_block(
+        &self,
+        block: &BlockInfo,
+        base: DocId,
+        term: &str,
+        out: &mut Vec<DocId>,
+    ) -> Result<()> {
+        let start = out.len();
+        let mut pos = block.postings_start;
+        self.codec
+            .decode_sorted(&self.mmap[..self.positions], &mut pos, out)?;
+        ensure!(
+            out.len() - start == block.len,
+            "{} postings in a block of term '{}', expected {}",
+            out.len() - start,
+            term,
+            block.len
+        );
+        for doc_id in &mut out[start..] {
+            *doc_id = doc_id
+                .checked_add(base)
+                .with_context(|| format!("doc id overflow for term '{}'", term))?;
+        }
+        if out.last() != Some(&block.last_doc_id)
+            || block.last_doc_id as usize >= self.first_doc_id + self.num_docs
+        {
+            bail!("corrupted block of term '{}'", term);
+        }
+        Ok(())
+    }
+
+    /// Appends the positions of every posting of `block` to `out`.
+    fn decode_block_positions(&self, block: &BlockInfo, out: &mut Vec<Vec<usize>>) -> Result<()> {
+        let mut pos = block.positions_start;
+        let mut doc_positions = Vec::new();
+        for _ in 0..block.len {
+            doc_positions.clear();
+            self.codec
+                .decode_sorted(&self.mmap, &mut pos, &mut doc_positions)?;
+            out.push(doc_positions.iter().map(|&p| p as usize).collect());
+        }
+        Ok(())
+    }
+}
+
+/// Writes a segment, one term at a time in term order.
+pub struct SegmentWriter {
+    page_ids: Vec<ObjectId>,
+    /// Lowest and highest doc id of the postings added
+    doc_ids: Option<(DocId, DocId)>,
+    codec: Codec,
+    num_terms: usize,
+    term_table: Vec<u8>,
+    term_bytes: Vec<u8>,
+    postings: Vec<u8>,
+    positions: Vec<u8>,
+    last_term: Option<String>,
+}
+
+impl SegmentWriter {
+    /// `page_ids` are the pages of all the doc ids the postings may refer to, indexed by doc id.
+    pub fn new(page_ids: Vec<ObjectId>) -> Self {
+        Self {
+            page_ids,
+            doc_ids: None,
+            codec: Codec::default(),
+            num_terms: 0,
+            term_table: Vec::new(),
+            term_bytes: Vec::new(),
+            postings: Vec::new(),
+            positions: Vec::new(),
+            last_term: None,
+        }
+    }
+
+    /// Encode postings and positions with `codec` instead of the default one.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Adds the postings of `term`, terms must be added in ascending order.
+    pub fn add_term(
+        &mut self,
+        term: &str,
+        postings: &[DocId],
+        positions: &HashMap<DocId, Vec<usize>>,
+    ) -> Result<()> {
+        if let Some(last) = &self.last_term
+            && last.as_str() >= term
+        {
+            bail!("Term '{}' added after '{}'", term, last);
+        }
+        if let Some(doc_id) = postings
+            .iter()
+            .find(|&&doc_id| doc_id as usize >= self.page_ids.len())
+        {
+            bail!("Unknown doc id {} for term '{}'", doc_id, term);
+        }
+        let mut doc_ids = postings.to_vec();
+        doc_ids.sort_unstable();
+        doc_ids.dedup();
+        if let (Some(&first), Some(&last)) = (doc_ids.first(), doc_ids.last()) {
+            self.doc_ids = Some(match self.doc_ids {
+                Some((low, high)) => (low.min(first), high.max(last)),
+                None => (first, last),
+            });
+        }
+
+        push_u32(&mut self.term_table, self.term_bytes.len() as u32);
+        push_u32(&mut self.term_table, term.len() as u32);
+        push_u32(&mut self.term_table, doc_ids.len() as u32);
+        push_u32(&mut self.term_table, 0);
+        push_u64(&mut self.term_table, self.postings.len() as u64);
+        push_u64(&mut self.term_table, self.positions.len() as u64);
+        self.term_bytes.extend_from_slice(term.as_bytes());
+
+        let num_blocks = doc_ids.len().div_ceil(BLOCK_LEN);
+        let skip_table = self.postings.len();
+        self.postings
+            .resize(skip_table + num_blocks * SKIP_ENTRY_LEN, 0);
+        let (blocks_start, positions_start) = (self.postings.len(), self.positions.len());
+        let mut base = 0;
+        for (i, block) in doc_ids.chunks(BLOCK_LEN).enumerate() {
+            let postings_offset = self.postings.len() - blocks_start;
+            let positions_offset = self.positions.len() - positions_start;
+            let gaps: Vec<DocId> = block.iter().map(|&doc_id| doc_id - base).collect();
+            self.codec.encode_sorted(&gaps, &mut self.postings);
+
+            for doc_id in block {
+                let mut doc_positions = positions
+                    .get(doc_id)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|&position| u32::try_from(position))
+                    .collect::<Result<Vec<u32>, _>>()
+                    .with_context(|| format!("Position out of range for term '{}'", term))?;
+                doc_positions.sort_unstable();
+                self.codec
+                    .encode_sorted(&doc_positions, &mut self.positions);
+            }
+
+            base = *block.last().unwrap();
+            let mut entry = Vec::with_capacity(SKIP_ENTRY_LEN);
+            push_u32(&mut entry, base);
+            push_u64(&mut entry, postings_offset as u64);
+            push_u64(&mut entry, positions_offset as u64);
+            let at = skip_table + i * SKIP_ENTRY_LEN;
+            self.postings[at..at + SKIP_ENTRY_LEN].copy_from_slice(&entry);
+        }
+
+        self.num_terms += 1;
+        self.last_term = Some(term.to_string());
+        Ok(())
+    }
+
+    /// Writes the segment to `path`, replacing any previous one atomically. The doc table only
+    /// spans the doc ids from the lowest to the highest one of the postings.
+    pub fn finish(self, path: &Path) -> Result<()> {
+        let (first_doc_id, page_ids) = match self.doc_ids {
+            Some((first, last)) => (first, &self.page_ids[first as usize..=last as usize]),
+            None => (0, &self.page_ids[..0]),
+        };
+        let term_table = HEADER_LEN + page_ids.len() * PAGE_ID_LEN;
+        let term_bytes = term_table + self.term_table.len();
+        let postings = term_bytes + self.term_bytes.len();
+        let positions = postings + self.postings.len();
+        let end = positions + self.positions.len();
+
+        let mut bytes = Vec::with_capacity(end);
+        bytes.extend_from_slice(MAGIC);
+        push_u32(&mut bytes, page_ids.len() as u32);
+        push_u32(&mut bytes, self.num_terms as u32);
+        push_u32(&mut bytes, self.codec.id());
+        push_u32(&mut bytes, first_doc_id);
+        for offset in [term_table, term_bytes, postings, positions, end] {
+            push_u64(&mut bytes, offset as u64);
+        }
+        for page_id in page_ids {
+            bytes.extend_from_slice(&page_id.bytes());
+        }
+        bytes.extend_from_slice(&self.term_table);
+        bytes.extend_from_slice(&self.term_bytes);
+        bytes.extend_from_slice(&self.postings);
+        bytes.extend_from_slice(&self.positions);
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("seg.tmp");
+        std::fs::write(&tmp_path, bytes)
+            .with_context(|| format!("Failed to write segment {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to replace segment {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// File name of the delta segment of the index run that committed `generation`.
+pub fn delta_segment_file(generation: u64) -> String {
+    format!("index_v{generation}.seg")
+}
+
+/// Generations of the delta segment files in `index_dir`.
+pub fn delta_segments(index_dir: &Path) -> Result<Vec<u64>> {
+    let mut generations = Vec::new();
+    for entry in std::fs::read_dir(index_dir)
+        .with_context(|| format!("Failed to list {}", index_dir.display()))?
+    {
+        let name = entry?.file_name();
+        let generation: Option<u64> = name.to_str().and_then(|name| {
+            name.strip_prefix("index_v")?
+                .strip_suffix(".seg")?
+                .parse()
+                .ok()
+        });
+        generations.extend(generation);
+    }
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+/// The generation the files of an index directory were written for, and the delta segments read
+/// on top of the base segment, oldest first.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SegmentGeneration {
+    pub generation: u64,
+    pub deltas: Vec<u64>,
+}
+
+/// Records that the files of `index_dir` were written for `record`, replacing the previous
+/// record atomically.
+pub fn write_generation(index_dir: &Path, record: &SegmentGeneration) -> Result<()> {
+    let path = index_dir.join(GENERATION_FILE);
+    let tmp_path = path.with_extension("tmp");
+    let line: Vec<String> = std::iter::once(record.generation)
+        .chain(record.deltas.iter().copied())
+        .map(|generation| generation.to_string())
+        .collect();
+    std::fs::write(&tmp_path, line.join(" "))
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, &path)
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+/// What the files of `index_dir` were written for, `None` when no index run recorded it.
+pub fn read_generation(index_dir: &Path) -> Result<Option<SegmentGeneration>> {
+    let path = index_dir.join(GENERATION_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let line = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let generations = line
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<u64>, _>>()
+        .with_context(|| format!("Invalid index generation in {}", path.display()))?;
+    let Some((&generation, deltas)) = generations.split_first() else {
+        bail!("Invalid index generation in {}", path.display());
+    };
+    Ok(Some(SegmentGeneration {
+        generation,
+        deltas: deltas.to_vec(),
+    }))
+}
+
+/// The base segment of an index directory and the delta segments on top of it, oldest first,
+/// read as a single segment. A delta segment holds the postings one index run added, with doc ids
+/// after those of the segments below, so the postings of a term are its postings in every
+/// segment one after the other.
+pub struct SegmentStack {
+    segments: Vec<Segment>,
+}
+
+impl SegmentStack {
+    pub fn new(base: Segment) -> Self {
+        Self {
+            segments: vec![base],
+        }
+    }
+
+    /// Opens the base segment of `index_dir` and the delta segments `deltas`.
+    pub fn open(index_dir: &Path, deltas: &[u64]) -> Result<Self> {
+        let mut segments = vec![Segment::open(&index_dir.join(SEGMENT_FILE))?];
+        for &generation in deltas {
+            segments.push(Segment::open(
+                &index_dir.join(delta_segment_file(generation)),
+            )?);
+        }
+        Ok(Self { segments })
+    }
+
+    /// The segments, base first.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Page id of the document `doc_id`, from the newest segment knowing it.
+    pub fn page_id(&self, doc_id: DocId) -> Option<ObjectId> {
+        self.segments
+            .iter()
+            .rev()
+            .find_map(|segment| segment.page_id(doc_id))
+    }
+
+    /// Number of documents containing `term`, `None` when no segment has it.
+    pub fn document_frequency(&self, term: &str) -> Result<Option<u64>> {
+        let mut total = None;
+        for segment in &self.segments {
+            if let Some(df) = segment.document_frequency(term)? {
+                *total.get_or_insert(0) += df;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Postings of the given terms in the shape of the inverted index store, a bucket per
+    /// segment holding the term, in segment order.
+    pub fn find_by_terms(&self, terms: &[String]) -> Result<Vec<InvertedIndexDoc>> {
+        self.stacked(|segment| segment.find_by_terms(terms))
+    }
+
+    /// `find_by_terms` restricted to the ascending doc ids in `candidates`, see
+    /// `Segment::postings_in`.
+    pub fn find_by_terms_in(
+        &self,
+        terms: &[String],
+        candidates: &[DocId],
+    ) -> Result<Vec<InvertedIndexDoc>> {
+        self.stacked(|segment| segment.find_by_terms_in(terms, candidates))
+    }
+
+    fn stacked(
+        &self,
+        find: impl Fn(&Segment) -> Result<Vec<InvertedIndexDoc>>,
+    ) -> Result<Vec<InvertedIndexDoc>> {
+        let mut docs = Vec::new();
+        for (bucket, segment) in self.segments.iter().enumerate() {
+            for mut doc in find(segment)? {
+                doc.bucket = bucket as i16;
+                docs.push(doc);
+            }
+        }
+        Ok(docs)
+    }
+}
+
+/// Writes a segment of the buckets `index` reads to `path`, with the page ids of the live doc ids
+/// in `doc_ids` its postings refer to and postings encoded with `codec`. Postings of deleted doc
+/// ids are left out, so the segment is compacted even when the index store is not.
+///
+/// Only the page ids and the compressed postings are held in memory.
+pub async fn write_segment(
+    index: &dyn IndexStore,
+    doc_ids: &dyn DocIdStore,
+    codec: Codec,
+    path: &Path,
+) -> Result<Segment> {
+    let terms: Vec<String> = index
+        .term_document_frequencies()
+        .await?
+        .into_iter()
+        .map(|(term, _)| term)
+        .collect();
+
+    let mut page_ids = Vec::new();
+    for (doc_id, page_id) in doc_ids.page_ids().await? {
+        ensure!(
+            doc_id as usize >= page_ids.len(),
+            "Doc ids are not sorted, doc id {} after {}",
+            doc_id,
+            page_ids.len() - 1
+        );
+        // deleted doc ids leave gaps
+        page_ids.resize(doc_id as usize, DELETED_PAGE);
+        page_ids.push(page_id);
+    }
+    let live: Vec<bool> = page_ids
+        .iter()
+        .map(|page_id| *page_id != DELETED_PAGE)
+        .collect();
+
+    let mut writer = SegmentWriter::new(page_ids).with_codec(codec);
+    for chunk in terms.chunks(TERMS_PER_FETCH) {
+        // buckets come sorted by bucket, not by term
+        let mut chunk_terms: BTreeMap<String, InvertedIndexDoc> = BTreeMap::new();
+        for doc in index.find_by_terms(chunk).await? {
+            match chunk_terms.entry(doc.term.clone()) {
+                std::collections::btree_map::Entry::Vacant(e) => {
+                    e.insert(doc);
+                }
+                std::collections::btree_map::Entry::Occupied(mut e) => {
+                    let merged = e.get_mut();
+                    merged.postings.extend(doc.postings);
+                    merged.positions.extend(doc.positions);
+                }
+            }
+        }
+        for (term, mut doc) in chunk_terms {
+            doc.postings
+                .retain(|&doc_id| live.get(doc_id as usize).copied().unwrap_or(false));
+            if !doc.postings.is_empty() {
+                writer.add_term(&term, &doc.postings, &doc.positions)?;
+            }
+        }
+    }
+    writer.finish(path)?;
+    Segment::open(path)
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir()
+            .join(format!("harvest_segment_{}", std::process::id()))
+            .join(name)
+    }
+
+    #[test]
+    fn test_write_and_read_segment() {
+        for codec in Codec::ALL {
+            let pages: Vec<ObjectId> = (0..3).map(|_| ObjectId::new()).collect();
+            let mut writer = SegmentWriter::new(pages.clone()).with_codec(codec);
+            writer
+                .add_term(
+                    "harvest",
+                    &[2, 0],
+                    &HashMap::from([(0, vec![7, 1]), (2, vec![300])]),
+                )
+                .unwrap();
+            writer.add_term("moon", &[1], &HashMap::new()).unwrap();
+            let path = temp_path(&format!("roundtrip_{codec}.seg"));
+            writer.finish(&path).unwrap();
+
+            let segment = Segment::open(&path).unwrap();
+            assert_eq!((segment.num_docs(), segment.num_terms()), (3, 2));
+            assert_eq!(segment.codec(), codec);
+            assert_eq!(segment.page_id(2), Some(pages[2]));
+            assert_eq!(segment.page_id(3), None);
+
+            assert_eq!(
+                segment.postings("harvest").unwrap(),
+                Some(SegmentPostings {
+                    doc_ids: vec![0, 2],
+                    positions: vec![vec![1, 7], vec![300]],
+                })
+            );
+            assert_eq!(segment.postings("harbor").unwrap(), None);
+
+            let found = segment
+                .find_by_terms(&["moon".to_string(), "missing".to_string()])
+                .unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].postings, vec![1]);
+            assert_eq!(found[0].positions[&1], Vec::<usize>::new());
+
+            assert_eq!(
+                segment.term_document_frequencies().unwrap(),
+                vec![("harvest".to_string(), 2), ("moon".to_string(), 1)]
+            );
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_skip_blocks() {
+        for codec in Codec::ALL {
+            let pages: Vec<ObjectId> = (0..3_000).map(|_| ObjectId::new()).collect();
+            let common: Vec<DocId> = (0..3_000).step_by(3).collect();
+            let positions: HashMap<DocId, Vec<usize>> = common
+                .iter()
+                .map(|&doc_id| (doc_id, (0..1 + doc_id as usize % 5).collect()))
+                .collect();
+            let mut writer = SegmentWriter::new(pages).with_codec(codec);
+            writer.add_term("common", &common, &positions).unwrap();
+            let path = temp_path(&format!("skip_{codec}.seg"));
+            writer.finish(&path).unwrap();
+            let segment = Segment::open(&path).unwrap();
+
+            let full = segment.postings("common").unwrap().unwrap();
+            assert_eq!(full.doc_ids, common);
+            assert_eq!(full.positions[1], vec![0, 1, 2, 3]);
+
+            let blocks = segment.blocks("common").unwrap().unwrap();
+            assert_eq!(blocks.len(), 1_000_usize.div_ceil(BLOCK_LEN));
+            assert_eq!(blocks[0].last_doc_id, common[BLOCK_LEN - 1]);
+            assert_eq!(blocks.last().unwrap().last_doc_id, 2_997);
+            assert_eq!(blocks.last().unwrap().len, 1_000 % BLOCK_LEN);
+            assert_eq!(segment.document_frequency("common").unwrap(), Some(1_000));
+
+            for candidates in [
+                vec![],
+                vec![0],
+                vec![1, 2, 4],
+                vec![3, 4, 382, 383, 384, 1_500, 2_997, 2_999],
+                (0..3_000).step_by(7).collect(),
+            ] {
+                let restricted = segment.postings_in("common", &candidates).unwrap().unwrap();
+                let expected: Vec<usize> = (0..common.len())
+                    .filter(|&i| candidates.contains(&common[i]))
+                    .collect();
+                assert_eq!(
+                    restricted.doc_ids,
+                    expected.iter().map(|&i| common[i]).collect::<Vec<_>>()
+                );
+                assert_eq!(
+                    restricted.positions,
+                    expected
+                        .iter()
+                        .map(|&i| full.positions[i].clone())
+                        .collect::<Vec<_>>()
+                );
+            }
+            assert_eq!(segment.postings_in("missing", &[0]).unwrap(), None);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_segment_stack() {
+        let pages: Vec<ObjectId> = (0..6).map(|_| ObjectId::new()).collect();
+        let dir = temp_path("stack");
+        let mut base = SegmentWriter::new(pages.clone());
+        base.add_term("harvest", &[0, 2], &HashMap::new()).unwrap();
+        base.add_term("moon", &[1], &HashMap::new()).unwrap();
+        base.finish(&dir.join(SEGMENT_FILE)).unwrap();
+        let mut delta = SegmentWriter::new(pages.clone());
+        delta
+            .add_term("harvest", &[4, 5], &HashMap::from([(5, vec![3])]))
+            .unwrap();
+        delta.finish(&dir.join(delta_segment_file(2))).unwrap();
+
+        // the delta's doc table only spans the doc ids of its postings
+        let segment = Segment::open(&dir.join(delta_segment_file(2))).unwrap();
+        assert_eq!(segment.num_docs(), 2);
+        assert_eq!(segment.page_id(3), None);
+        assert_eq!(delta_segments(&dir).unwrap(), vec![2]);
+
+        let stack = SegmentStack::open(&dir, &[2]).unwrap();
+        assert_eq!(stack.segments().len(), 2);
+        assert_eq!(stack.document_frequency("harvest").unwrap(), Some(4));
+        assert_eq!(stack.document_frequency("sun").unwrap(), None);
+        assert_eq!(stack.page_id(1), Some(pages[1]));
+        assert_eq!(stack.page_id(5), Some(pages[5]));
+        let postings: Vec<DocId> = stack
+            .find_by_terms(&["harvest".to_string()])
+            .unwrap()
+            .into_iter()
+            .flat_map(|doc| doc.postings)
+            .collect();
+        assert_eq!(postings, vec![0, 2, 4, 5]);
+        let found = stack
+            .find_by_terms_in(&["harvest".to_string()], &[2, 5])
+            .unwrap();
+        assert_eq!(found[1].positions[&5], vec![3]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_generation_record() {
+        let dir = temp_path("record");
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(read_generation(&dir).unwrap(), None);
+        let record = SegmentGeneration {
+            generation: 7,
+            deltas: vec![6, 7],
+        };
+        write_generation(&dir, &record).unwrap();
+        assert_eq!(read_generation(&dir).unwrap(), Some(record));
+        // records from before delta segments name the generation alone
+        std::fs::write(dir.join(GENERATION_FILE), "5").unwrap();
+        assert_eq!(
+            read_generation(&dir).unwrap().unwrap().deltas,
+            Vec::<u64>::new()
+        );
+        std::fs::write(dir.join(GENERATION_FILE), "").unwrap();
+        assert!(read_generation(&dir).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_writer_rejects_bad_input() {
+        let mut writer = SegmentWriter::new(vec![ObjectId::new()]);
+        writer.add_term("moon", &[0], &HashMap::new()).unwrap();
+        assert!(writer.add_term("harvest", &[0], &HashMap::new()).is_err());
+        assert!(writer.add_term("sun", &[1], &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_open_rejects_corrupted_file() {
+        let path = temp_path("corrupted.seg");
+        let mut writer = SegmentWriter::new(vec![ObjectId::new()]);
+        writer.add_term("moon", &[], &HashMap::new()).unwrap();
+        writer.finish(&path).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(Segment::open(&path).is_err());
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        std::fs::write(&path, bad_magic).unwrap();
+        assert!(Segment::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+use anyhow::{Context, Result, bail, ensure};
+use async_trait::async_trait;
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use mongodb::bson::oid::ObjectId;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::Mutex;
+
+use super::BlockStore;
+use crate::data_models::{DocId, SpimiDoc};
+use crate::db::Database;
+use crate::postings::codec::{Codec, read_varint, write_varint};
+
+/// Prefix of the names of SPIMI blocks.
+pub const SPIMI_BLOCK_PREFIX: &str = "spimi_block_";
+/// Directory of the SPIMI blocks, in the index directory.
+pub const BLOCKS_DIR: &str = "blocks";
+
+/// Extension of sealed block files, blocks being written end with `.blk.tmp`.
+const BLOCK_EXTENSION: &str = "blk";
+const MAGIC: &[u8; 8] = b"HVSTBLK1";
+/// Postings and positions of blocks are only read back once, by the merge.
+const BLOCK_CODEC: Codec = Codec::VarByte;
+
+/// SPIMI blocks as files of `dir`, one per block.
+///
+/// A block file is the magic followed by its documents in (term, bucket) order, each prefixed
+/// with its length as a little endian u32: term length and bytes, bucket, document frequency,
+/// then the postings and the positions of every posting, all compressed with `BLOCK_CODEC`.
+/// Blocks are written to a `.tmp` file renamed on seal, so only complete blocks are listed.
+pub struct FileBlockStore {
+    dir: PathBuf,
+    writers: Mutex<HashMap<String, BlockWriter>>,
+}
+
+struct BlockWriter {
+    file: BufWriter<File>,
+    /// (term, bucket) of the last document, documents must be appended in order.
+    last: Option<(String, i16)>,
+}
+
+impl FileBlockStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            writers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Blocks of the indexer of `db` when no directory is configured, in the temp directory.
+    pub fn for_database(db: &Database) -> Self {
+        Self::new(
+            std::env::temp_dir()
+                .join("harvest")
+                .join(db.name())
+                .join(BLOCKS_DIR),
+        )
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, block: &str) -> PathBuf {
+        self.dir.join(block).with_extension(BLOCK_EXTENSION)
+    }
+
+    fn tmp_path(&self, block: &str) -> PathBuf {
+        self.path(block).with_extension("blk.tmp")
+    }
+}
+
+/// Name of a new block, unique across runs.
+pub fn new_block_name() -> String {
+    format!("{SPIMI_BLOCK_PREFIX}{}", ObjectId::new().to_hex())
+}
+
+#[async_trait]
+impl BlockStore for FileBlockStore {
+    async fn append_to_block(&self, block: &str, doc: SpimiDoc) -> Result<()> {
+        let mut writers = self.writers.lock().await;
+        if !writers.contains_key(block) {
+            tokio::fs::create_dir_all(&self.dir)
+                .await
+                .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+            let path = self.tmp_path(block);
+            let mut file = BufWriter::new(
+                File::create(&path)
+                    .await
+                    .with_context(|| format!("Failed to create block {}", path.display()))?,
+            );
+            file.write_all(MAGIC).await?;
+            writers.insert(block.to_string(), BlockWriter { file, last: None });
+        }
+        let writer = writers.get_mut(block).expect("writer was just opened");
+
+        ensure!(
+            writer.last.as_ref().is_none_or(
+                |(term, bucket)| (term.as_str(), *bucket) < (doc.term.as_str(), doc.bucket)
+            ),
+            "Block {block} written out of order at term '{}' bucket {}",
+            doc.term,
+            doc.bucket
+        );
+        let record = encode_doc(&doc)?;
+        writer.file.write_u32_le(record.len() as u32).await?;
+        writer
+            .file
+            .write_all(&record)
+            .await
+            .with_context(|| format!("Failed to write to block {block}"))?;
+        writer.last = Some((doc.term, doc.bucket));
+        Ok(())
+    }
+
+    async fn seal_block(&self, block: &str) -> Result<()> {
+        let writer = self.writers.lock().await.remove(block);
+        let tmp_path = self.tmp_path(block);
+        match writer {
+            Some(mut writer) => {
+                writer.file.flush().await?;
+                writer.file.get_mut().sync_all().await?;
+            }
+            // an empty block
+            None => {
+                tokio::fs::create_dir_all(&self.dir).await?;
+                tokio::fs::write(&tmp_path, MAGIC).await?;
+            }
+        }
+        tokio::fs::rename(&tmp_path, self.path(block))
+            .await
+            .with_context(|| format!("Failed to seal block {block}"))?;
+        Ok(())
+    }
+
+    /// Names of the sealed blocks. Blocks left unsealed by a crash are removed.
+    async fn list_blocks(&self) -> Result<Vec<String>> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e).context("Failed to list SPIMI blocks"),
+        };
+        let writers = self.writers.lock().await;
+        let mut blocks = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+            if let Some(block) = file_name.strip_suffix(".blk") {
+                blocks.push(block.to_string());
+            } else if let Some(block) = file_name.strip_suffix(".blk.tmp")
+                && !writers.contains_key(block)
+            {
+                log::warn!("Removing partially written block {}", block);
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+        }
+        blocks.sort();
+        Ok(blocks)
+    }
+
+    async fn read_block(
+        &self,
+        block: &str,
+        from_term: Option<&str>,
+    ) -> Result<BoxStream<'static, Result<SpimiDoc>>> {
+        let path = self.path(block);
+        let mut file = BufReader::new(
+            File::open(&path)
+                .await
+                .with_context(|| format!("Failed to read block {}", path.display()))?,
+        );
+        let mut magic = [0; MAGIC.len()];
+        file.read_exact(&mut magic).await?;
+        ensure!(&magic == MAGIC, "{} is not a SPIMI block", path.display());
+
+        let from_term = from_term.map(str::to_string);
+        let docs = futures::stream::try_unfold(file, move |mut file| {
+            let from_term = from_term.clone();
+            async move {
+                loop {
+                    let Some(record) = read_record(&mut file).await? else {
+                        return Ok(None);
+                    };
+                    let mut pos = 0;
+                    let term = decode_term(&record, &mut pos)?;
+                    if from_term
+                        .as_deref()
+                        .is_some_and(|from| term.as_str() < from)
+                    {
+                        continue;
+                    }
+                    return Ok(Some((decode_doc(term, &record, &mut pos)?, file)));
+                }
+            }
+        });
+        Ok(docs.boxed())
+    }
+
+    async fn drop_block(&self, block: &str) -> Result<()> {
+        let path = self.path(block);
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to drop block {block}"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn encode_doc(doc: &SpimiDoc) -> Result<Vec<u8>> {
+    let mut record = Vec::new();
+    write_varint(&mut record, doc.term.len() as u64);
+    record.extend_from_slice(doc.term.as_bytes());
+    write_varint(&mut record, u16::try_from(doc.bucket)? as u64);
+    write_varint(&mut record, doc.document_frequency);
+    BLOCK_CODEC.encode_sorted(&doc.postings, &mut record);
+    for doc_id in &doc.postings {
+        let mut positions = doc
+            .positions
+            .get(doc_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .map(|&position| u32::try_from(position))
+            .collect::<Result<Vec<u32>, _>>()
+            .with_context(|| format!("Position out of range for term '{}'", doc.term))?;
+        positions.sort_unstable();
+        BLOCK_CODEC.encode_sorted(&positions, &mut record);
+    }
+    Ok(record)
+}
+
+fn decode_term(record: &[u8], pos: &mut usize) -> Result<String> {
+    let len = read_varint(record, pos)? as usize;
+    let Some(bytes) = record.get(*pos..*pos + len) else {
+        bail!("Term out of bounds at {}", pos);
+    };
+    *pos += len;
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
+
+fn decode_doc(term: String, record: &[u8], pos: &mut usize) -> Result<SpimiDoc> {
+    let bucket = i16::try_from(read_varint(record, pos)?)?;
+    let document_frequency = read_varint(record, pos)?;
+    let mut postings: Vec<DocId> = Vec::new();
+    BLOCK_CODEC.decode_sorted(record, pos, &mut postings)?;
+    let mut positions = HashMap::with_capacity(postings.len());
+    for &doc_id in &postings {
+        let mut doc_positions = Vec::new();
+        BLOCK_CODEC.decode_sorted(record, pos, &mut doc_positions)?;
+        positions.insert(
+            doc_id,
+            doc_positions.into_iter().map(|p| p as usize).collect(),
+        );
+    }
+    Ok(SpimiDoc::new(
+        term,
+        bucket,
+        document_frequency,
+        postings,
+        positions,
+    ))
+}
+
+/// The next length prefixed record of `file`, `None` at the end of the file.
+async fn read_record(file: &mut BufReader<File>) -> Result<Option<Vec<u8>>> {
+    let len = match file.read_u32_le().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut record = vec![0; len];
+    file.read_exact(&mut record)
+        .await
+        .context("Truncated SPIMI block")?;
+    Ok(Some(record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("harvest_blocks_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_blocks_round_trip() -> Result<()> {
+        let store = FileBlockStore::new(test_dir("round_trip"));
+        let positions = HashMap::from([(3, vec![1, 7]), (9, vec![2])]);
+        for (term, bucket) in [("harvest", 0), ("harvest", 1), ("moon", 0)] {
+            let doc = SpimiDoc::new(term.to_string(), bucket, 2, vec![3, 9], positions.clone());
+            store.append_to_block("spimi_block_a", doc).await?;
+        }
+        assert!(store.list_blocks().await?.is_empty());
+        store.seal_block("spimi_block_a").await?;
+        store.seal_block("spimi_block_b").await?;
+        assert_eq!(
+            store.list_blocks().await?,
+            vec!["spimi_block_a", "spimi_block_b"]
+        );
+
+        let docs: Vec<SpimiDoc> = store
+            .read_block("spimi_block_a", None)
+            .await?
+            .try_collect()
+            .await?;
+        let keys: Vec<(&str, i16)> = docs.iter().map(|d| (d.term.as_str(), d.bucket)).collect();
+        assert_eq!(keys, vec![("harvest", 0), ("harvest", 1), ("moon", 0)]);
+        assert_eq!(docs[2].postings, vec![3, 9]);
+        assert_eq!(docs[2].document_frequency, 2);
+        assert_eq!(docs[2].positions, positions);
+
+        let docs: Vec<SpimiDoc> = store
+            .read_block("spimi_block_a", Some("i"))
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(docs.len(), 1);
+        let empty: Vec<SpimiDoc> = store
+            .read_block("spimi_block_b", None)
+            .await?
+            .try_collect()
+            .await?;
+        assert!(empty.is_empty());
+
+        store.drop_block("spimi_block_a").await?;
+        assert_eq!(store.list_blocks().await?, vec!["spimi_block_b"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unsealed_and_unordered_blocks() -> Result<()> {
+        let dir = test_dir("unsealed");
+        let store = FileBlockStore::new(&dir);
+        let doc = |term: &str| SpimiDoc::new(term.to_string(), 0, 0, vec![], HashMap::new());
+        store.append_to_block("spimi_block_a", doc("moon")).await?;
+        assert!(
+            store
+                .append_to_block("spimi_block_a", doc("harvest"))
+                .await
+                .is_err()
+        );
+
+        // a crash leaves the block unsealed, a new store drops it
+        let store = FileBlockStore::new(&dir);
+        assert!(store.list_blocks().await?.is_empty());
+        assert!(!dir.join("spimi_block_a.blk.tmp").exists());
+        Ok(())
+    }
+}
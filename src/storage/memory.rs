@@ -0,0 +1,1111 @@
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use mongodb::bson::DateTime;
+use mongodb::bson::oid::ObjectId;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{BlockStore, CheckpointStore, DocIdStore, IndexStore, PageStore, QueryLogStore};
+use crate::data_models::{DocId, IndexManifest, InvertedIndexDoc, MergeCheckpoint, Page, SpimiDoc};
+
+/// Pages kept in id order, like the `_id` index of the pages collection.
+#[derive(Default)]
+pub struct MemoryPageStore {
+    pages: Mutex<BTreeMap<ObjectId, Page>>,
+}
+
+#[async_trait]
+impl PageStore for MemoryPageStore {
+    async fn insert(&self, page: &Page) -> Result<ObjectId> {
+        let mut pages = self.pages.lock().unwrap();
+        if pages.contains_key(&page.id) {
+            bail!("Duplicate page id {}", page.id);
+        }
+        pages.insert(page.id, page.clone());
+        Ok(page.id)
+    }
+
+    async fn upsert(&self, page: &Page) -> Result<ObjectId> {
+        let mut pages = self.pages.lock().unwrap();
+        let existing = pages.values().find(|p| p.url == page.url).map(|p| p.id);
+        let id = existing.unwrap_or(page.id);
+        let mut page = page.clone();
+        page.id = id;
+        if let Some(existing) = pages.get(&id)
+            && existing.html_body == page.html_body
+        {
+            page.indexed = existing.indexed;
+            page.index_run = existing.index_run;
+        }
+        pages.insert(id, page);
+        Ok(id)
+    }
+
+    async fn delete_by_url(&self, url: &str) -> Result<Option<ObjectId>> {
+        let mut pages = self.pages.lock().unwrap();
+        let id = pages.values().find(|p| p.url == url).map(|p| p.id);
+        if let Some(id) = id {
+            pages.remove(&id);
+        }
+        Ok(id)
+    }
+
+    async fn find_by_ids(&self, ids: &[ObjectId]) -> Result<Vec<Page>> {
+        let pages = self.pages.lock().unwrap();
+        Ok(ids.iter().filter_map(|id| pages.get(id).cloned()).collect())
+    }
+
+    async fn list_unindexed_paginated(
+        &self,
+        limit: i64,
+        last_cursor: Option<ObjectId>,
+    ) -> Result<(Vec<Page>, Option<ObjectId>)> {
+        let pages = self.pages.lock().unwrap();
+        let after = match last_cursor {
+            Some(cursor) => pages.range((
+                std::ops::Bound::Excluded(cursor),
+                std::ops::Bound::Unbounded,
+            )),
+            None => pages.range(..),
+        };
+        let page: Vec<Page> = after
+            .map(|(_, p)| p)
+            .filter(|p| !p.indexed)
+            .take(limit.max(1) as usize)
+            .cloned()
+            .collect();
+        let next_cursor = page.last().map(|p| p.id);
+        Ok((page, next_cursor))
+    }
+
+    async fn mark_many_as_indexed(&self, ids: &[ObjectId]) -> Result<u64> {
+        let mut pages = self.pages.lock().unwrap();
+        let mut modified = 0;
+        for id in ids {
+            if let Some(page) = pages.get_mut(id)
+                && !page.indexed
+            {
+                page.indexed = true;
+                modified += 1;
+            }
+        }
+        Ok(modified)
+    }
+
+    async fn mark_many_as_tokenized(&self, ids: &[ObjectId], index_run: ObjectId) -> Result<u64> {
+        let mut pages = self.pages.lock().unwrap();
+        let mut modified = 0;
+        for id in ids {
+            if let Some(page) = pages.get_mut(id) {
+                page.index_run = Some(index_run);
+                modified += 1;
+            }
+        }
+        Ok(modified)
+    }
+
+    async fn mark_runs_as_indexed(&self, index_runs: &[ObjectId]) -> Result<u64> {
+        let mut pages = self.pages.lock().unwrap();
+        let mut modified = 0;
+        for page in pages.values_mut() {
+            if !page.indexed && page.index_run.is_some_and(|run| index_runs.contains(&run)) {
+                page.indexed = true;
+                modified += 1;
+            }
+        }
+        Ok(modified)
+    }
+
+    async fn mark_all_as_unindexed(&self) -> Result<u64> {
+        let mut pages = self.pages.lock().unwrap();
+        let mut modified = 0;
+        for page in pages.values_mut().filter(|page| page.indexed) {
+            page.indexed = false;
+            modified += 1;
+        }
+        Ok(modified)
+    }
+
+    async fn mark_many_as_unindexed(&self, ids: &[ObjectId]) -> Result<u64> {
+        let mut pages = self.pages.lock().unwrap();
+        let mut modified = 0;
+        for id in ids {
+            if let Some(page) = pages.get_mut(id)
+                && page.indexed
+            {
+                page.indexed = false;
+                modified += 1;
+            }
+        }
+        Ok(modified)
+    }
+
+    async fn restore(&self, restored: &[Page]) -> Result<u64> {
+        let mut pages = self.pages.lock().unwrap();
+        for page in restored {
+            pages.retain(|id, p| *id == page.id || p.url != page.url);
+            pages.insert(page.id, page.clone());
+        }
+        Ok(restored.len() as u64)
+    }
+}
+
+type Buckets = BTreeMap<(String, i16), InvertedIndexDoc>;
+
+#[derive(Default)]
+struct IndexGenerations {
+    manifest: IndexManifest,
+    buckets: HashMap<u64, Buckets>,
+}
+
+/// The buckets of one generation, holding the lock on every generation.
+struct GenerationBuckets<'a> {
+    generations: MutexGuard<'a, IndexGenerations>,
+    generation: u64,
+}
+
+impl Deref for GenerationBuckets<'_> {
+    type Target = Buckets;
+
+    fn deref(&self) -> &Buckets {
+        &self.generations.buckets[&self.generation]
+    }
+}
+
+impl DerefMut for GenerationBuckets<'_> {
+    fn deref_mut(&mut self) -> &mut Buckets {
+        self.generations.buckets.get_mut(&self.generation).unwrap()
+    }
+}
+
+/// Inverted index generations, each with its buckets keyed by (term, bucket). The stores
+/// `begin_generation` and `generation` return share them, pinned to one generation.
+#[derive(Default)]
+pub struct MemoryIndexStore {
+    generations: Arc<Mutex<IndexGenerations>>,
+    generation: Option<u64>,
+}
+
+impl MemoryIndexStore {
+    /// Buckets of the generation the store writes.
+    fn buckets(&self) -> GenerationBuckets<'_> {
+        let mut generations = self.generations.lock().unwrap();
+        let generation = self.generation.unwrap_or(generations.manifest.current);
+        generations.buckets.entry(generation).or_default();
+        GenerationBuckets {
+            generations,
+            generation,
+        }
+    }
+
+    /// Generations the store reads, oldest first.
+    fn read_stack(&self, generations: &IndexGenerations) -> Vec<u64> {
+        let mut stack = match self.generation {
+            Some(generation) => vec![generation],
+            None => generations.manifest.stack(generations.manifest.current),
+        };
+        stack.reverse();
+        stack
+    }
+}
+
+/// Buckets of the `terms` (every term when `None`) of the generations in `stack`, oldest first,
+/// stacked into a single index.
+fn stacked_buckets(
+    generations: &IndexGenerations,
+    stack: &[u64],
+    terms: Option<&[String]>,
+) -> Vec<InvertedIndexDoc> {
+    let layers = stack
+        .iter()
+        .map(|generation| {
+            let Some(buckets) = generations.buckets.get(generation) else {
+                return Vec::new();
+            };
+            let mut docs: Vec<InvertedIndexDoc> = match terms {
+                Some(terms) => terms
+                    .iter()
+                    .flat_map(|term| {
+                        buckets
+                            .range((term.clone(), i16::MIN)..=(term.clone(), i16::MAX))
+                            .map(|(_, doc)| doc.clone())
+                    })
+                    .collect(),
+                None => buckets.values().cloned().collect(),
+            };
+            docs.sort_by_key(|doc| doc.bucket);
+            docs
+        })
+        .collect();
+    super::stack_layers(layers)
+}
+
+#[async_trait]
+impl IndexStore for MemoryIndexStore {
+    async fn get_last_bucket(&self, term: &str) -> Result<Option<InvertedIndexDoc>> {
+        Ok(self.find_by_terms(&[term.to_string()]).await?.pop())
+    }
+
+    async fn append_to_bucket(
+        &self,
+        doc_id: ObjectId,
+        new_postings: &[DocId],
+        new_positions: &HashMap<DocId, Vec<usize>>,
+    ) -> Result<bool> {
+        let mut buckets = self.buckets();
+        let Some(doc) = buckets.values_mut().find(|doc| doc.id == doc_id) else {
+            return Ok(false);
+        };
+        doc.postings.extend_from_slice(new_postings);
+        for (posting, positions) in new_positions {
+            doc.positions.insert(*posting, positions.clone());
+        }
+        doc.document_frequency += new_postings.len() as u64;
+        Ok(true)
+    }
+
+    async fn insert(&self, doc: InvertedIndexDoc) -> Result<ObjectId> {
+        let mut buckets = self.buckets();
+        let key = (doc.term.clone(), doc.bucket);
+        if buckets.contains_key(&key) {
+            bail!("Duplicate bucket {} for term '{}'", doc.bucket, doc.term);
+        }
+        let id = doc.id;
+        buckets.insert(key, doc);
+        Ok(id)
+    }
+
+    async fn insert_many(&self, docs: Vec<InvertedIndexDoc>) -> Result<()> {
+        for doc in docs {
+            self.insert(doc).await?;
+        }
+        Ok(())
+    }
+
+    async fn find_by_terms(&self, terms: &[String]) -> Result<Vec<InvertedIndexDoc>> {
+        let generations = self.generations.lock().unwrap();
+        let stack = self.read_stack(&generations);
+        Ok(stacked_buckets(&generations, &stack, Some(terms)))
+    }
+
+    async fn term_document_frequencies(&self) -> Result<Vec<(String, u64)>> {
+        let generations = self.generations.lock().unwrap();
+        let mut frequencies: BTreeMap<String, u64> = BTreeMap::new();
+        for generation in self.read_stack(&generations) {
+            for ((term, _), doc) in generations.buckets.get(&generation).into_iter().flatten() {
+                *frequencies.entry(term.clone()).or_default() += doc.document_frequency;
+            }
+        }
+        Ok(frequencies.into_iter().collect())
+    }
+
+    async fn manifest(&self) -> Result<IndexManifest> {
+        Ok(self.generations.lock().unwrap().manifest.clone())
+    }
+
+    async fn storage_bytes(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    async fn begin_generation(
+        &self,
+        extend_current: bool,
+        analyzer: &str,
+    ) -> Result<Arc<dyn IndexStore>> {
+        let mut generations = self.generations.lock().unwrap();
+        let manifest = &generations.manifest;
+        manifest.ensure_run_analyzer(extend_current, analyzer)?;
+        let generation = match manifest.building {
+            Some(generation) => generation,
+            None => {
+                let generation = manifest.current + 1;
+                let (buckets, layers) = match (extend_current, manifest.delta_layers()) {
+                    (true, Some(layers)) => (Buckets::new(), layers),
+                    (true, None) => {
+                        let mut stack = manifest.stack(manifest.current);
+                        stack.reverse();
+                        let buckets = stacked_buckets(&generations, &stack, None)
+                            .into_iter()
+                            .map(|doc| ((doc.term.clone(), doc.bucket), doc))
+                            .collect();
+                        (buckets, Vec::new())
+                    }
+                    (false, _) => (Buckets::new(), Vec::new()),
+                };
+                generations.buckets.insert(generation, buckets);
+                generations
+                    .manifest
+                    .begin_building(generation, layers, analyzer);
+                generation
+            }
+        };
+        Ok(Arc::new(MemoryIndexStore {
+            generations: self.generations.clone(),
+            generation: Some(generation),
+        }))
+    }
+
+    fn generation(&self, generation: u64) -> Arc<dyn IndexStore> {
+        Arc::new(MemoryIndexStore {
+            generations: self.generations.clone(),
+            generation: Some(generation),
+        })
+    }
+
+    async fn commit_generation(&self) -> Result<u64> {
+        let mut generations = self.generations.lock().unwrap();
+        let manifest = &mut generations.manifest;
+        let Some(building) = manifest.building else {
+            bail!("No index generation is being built");
+        };
+        for dropped in manifest.commit_building(building) {
+            generations.buckets.remove(&dropped);
+        }
+        Ok(building)
+    }
+
+    async fn rollback_generation(&self) -> Result<u64> {
+        let mut generations = self.generations.lock().unwrap();
+        let manifest = &mut generations.manifest;
+        if let Some(building) = manifest.building {
+            bail!("Index generation {building} is being built, finish the index run first");
+        }
+        let Some(previous) = manifest.previous else {
+            bail!("No previous index generation to roll back to");
+        };
+        for dropped in manifest.rollback_to(previous) {
+            generations.buckets.remove(&dropped);
+        }
+        Ok(previous)
+    }
+
+    async fn drop_previous_generation(&self) -> Result<()> {
+        let mut generations = self.generations.lock().unwrap();
+        for dropped in generations.manifest.drop_previous() {
+            generations.buckets.remove(&dropped);
+        }
+        Ok(())
+    }
+}
+
+/// Page ids indexed by doc id, plus the reverse lookup of the live doc ids, the doc ids replaced
+/// by uncommitted index runs and the tombstones.
+#[derive(Default)]
+pub struct MemoryDocIdStore {
+    state: Mutex<(Vec<ObjectId>, HashMap<ObjectId, DocId>)>,
+    replaced: Mutex<BTreeMap<DocId, ObjectId>>,
+    deleted: Mutex<BTreeMap<DocId, Tombstone>>,
+    deleted_version: AtomicU64,
+}
+
+/// The generations that replaced and purged a tombstoned doc id, see `DeletedDocId`.
+#[derive(Default, Clone, Copy)]
+struct Tombstone {
+    replaced_in: Option<u64>,
+    purged_in: Option<u64>,
+}
+
+#[async_trait]
+impl DocIdStore for MemoryDocIdStore {
+    async fn assign(&self, page_ids: &[ObjectId]) -> Result<Vec<DocId>> {
+        let mut state = self.state.lock().unwrap();
+        let (pages, doc_ids) = &mut *state;
+        Ok(page_ids
+            .iter()
+            .map(|page_id| {
+                *doc_ids.entry(*page_id).or_insert_with(|| {
+                    pages.push(*page_id);
+                    (pages.len() - 1) as DocId
+                })
+            })
+            .collect())
+    }
+
+    async fn resolve(&self, doc_ids: &[DocId]) -> Result<Vec<ObjectId>> {
+        let state = self.state.lock().unwrap();
+        let replaced = self.replaced.lock().unwrap();
+        let (pages, live) = &*state;
+        doc_ids
+            .iter()
+            .map(|doc_id| {
+                pages
+                    .get(*doc_id as usize)
+                    .filter(|page_id| {
+                        live.get(page_id) == Some(doc_id) || replaced.contains_key(doc_id)
+                    })
+                    .copied()
+                    .with_context(|| format!("Unknown doc id {doc_id}"))
+            })
+            .collect()
+    }
+
+    async fn page_ids(&self) -> Result<Vec<(DocId, ObjectId)>> {
+        let state = self.state.lock().unwrap();
+        let replaced = self.replaced.lock().unwrap();
+        let (pages, live) = &*state;
+        Ok(pages
+            .iter()
+            .enumerate()
+            .map(|(doc_id, page_id)| (doc_id as DocId, *page_id))
+            .filter(|(doc_id, page_id)| {
+                live.get(page_id) == Some(doc_id) || replaced.contains_key(doc_id)
+            })
+            .collect())
+    }
+
+    async fn replace_pages(
+        &self,
+        page_ids: &[ObjectId],
+        index_run: ObjectId,
+    ) -> Result<Vec<DocId>> {
+        let mut state = self.state.lock().unwrap();
+        let mut replaced = self.replaced.lock().unwrap();
+        let (pages, live) = &mut *state;
+        let replacing: HashSet<&ObjectId> = page_ids.iter().collect();
+        for (doc_id, run) in replaced.iter_mut() {
+            if replacing.contains(&pages[*doc_id as usize]) {
+                *run = index_run;
+            }
+        }
+        let mut doc_ids: Vec<DocId> = page_ids
+            .iter()
+            .filter_map(|page_id| live.remove(page_id))
+            .collect();
+        doc_ids.sort_unstable();
+        replaced.extend(doc_ids.iter().map(|doc_id| (*doc_id, index_run)));
+        Ok(doc_ids)
+    }
+
+    async fn commit_replaced(
+        &self,
+        index_runs: &[ObjectId],
+        generation: u64,
+    ) -> Result<Vec<DocId>> {
+        let mut replaced = self.replaced.lock().unwrap();
+        let mut deleted = self.deleted.lock().unwrap();
+        let doc_ids: Vec<DocId> = replaced
+            .iter()
+            .filter(|(_, run)| index_runs.contains(run))
+            .map(|(doc_id, _)| *doc_id)
+            .collect();
+        for doc_id in &doc_ids {
+            replaced.remove(doc_id);
+        }
+        let tombstone = Tombstone {
+            replaced_in: Some(generation),
+            purged_in: None,
+        };
+        deleted.extend(doc_ids.iter().map(|doc_id| (*doc_id, tombstone)));
+        self.deleted_version.fetch_add(1, Ordering::SeqCst);
+        Ok(doc_ids)
+    }
+
+    async fn revive_replaced(&self, generation: u64) -> Result<Vec<ObjectId>> {
+        let mut state = self.state.lock().unwrap();
+        let mut deleted = self.deleted.lock().unwrap();
+        let (pages, live) = &mut *state;
+        // the lowest doc id of a page is the one it had before the rolled back generations
+        let mut revived: BTreeMap<ObjectId, DocId> = BTreeMap::new();
+        for (doc_id, tombstone) in deleted.iter_mut() {
+            if tombstone
+                .replaced_in
+                .is_some_and(|replaced_in| replaced_in > generation)
+            {
+                let page_id = pages[*doc_id as usize];
+                if live.contains_key(&page_id) {
+                    revived.entry(page_id).or_insert(*doc_id);
+                }
+                tombstone.replaced_in = None;
+            }
+        }
+        for (page_id, doc_id) in &revived {
+            deleted.remove(doc_id);
+            live.insert(*page_id, *doc_id);
+        }
+        if !revived.is_empty() {
+            self.deleted_version.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(revived.into_keys().collect())
+    }
+
+    async fn delete_pages(&self, page_ids: &[ObjectId]) -> Result<Vec<DocId>> {
+        let mut state = self.state.lock().unwrap();
+        let mut replaced = self.replaced.lock().unwrap();
+        let mut deleted = self.deleted.lock().unwrap();
+        let (pages, live) = &mut *state;
+        let deleting: HashSet<&ObjectId> = page_ids.iter().collect();
+        let mut doc_ids: Vec<DocId> = replaced
+            .keys()
+            .copied()
+            .filter(|doc_id| deleting.contains(&pages[*doc_id as usize]))
+            .collect();
+        for doc_id in &doc_ids {
+            replaced.remove(doc_id);
+        }
+        doc_ids.extend(page_ids.iter().filter_map(|page_id| live.remove(page_id)));
+        doc_ids.sort_unstable();
+        deleted.extend(doc_ids.iter().map(|doc_id| (*doc_id, Tombstone::default())));
+        self.deleted_version.fetch_add(1, Ordering::SeqCst);
+        Ok(doc_ids)
+    }
+
+    async fn deleted(&self) -> Result<Vec<DocId>> {
+        Ok(self.deleted.lock().unwrap().keys().copied().collect())
+    }
+
+    async fn deleted_version(&self) -> Result<u64> {
+        Ok(self.deleted_version.load(Ordering::SeqCst))
+    }
+
+    async fn purge_deleted(&self, doc_ids: &[DocId], generation: u64) -> Result<()> {
+        let mut deleted = self.deleted.lock().unwrap();
+        for doc_id in doc_ids {
+            if let Some(tombstone) = deleted.get_mut(doc_id) {
+                tombstone.purged_in = Some(generation);
+            }
+        }
+        Ok(())
+    }
+
+    async fn forget_purged(&self, oldest: u64, current: u64) -> Result<u64> {
+        let mut deleted = self.deleted.lock().unwrap();
+        for tombstone in deleted.values_mut() {
+            if tombstone
+                .purged_in
+                .is_some_and(|generation| generation > current)
+            {
+                tombstone.purged_in = None;
+            }
+        }
+        let before = deleted.len();
+        deleted.retain(|_, tombstone| {
+            tombstone
+                .purged_in
+                .is_none_or(|generation| generation > oldest)
+        });
+        let forgotten = (before - deleted.len()) as u64;
+        if forgotten > 0 {
+            self.deleted_version.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(forgotten)
+    }
+
+    async fn restore(&self, page_ids: &[(DocId, ObjectId)], deleted: &[DocId]) -> Result<()> {
+        let next = page_ids
+            .iter()
+            .map(|(doc_id, _)| doc_id)
+            .chain(deleted)
+            .max()
+            .map_or(0, |doc_id| *doc_id as usize + 1);
+        // doc ids without a live page point to fresh ids no page has
+        let mut pages: Vec<ObjectId> = (0..next).map(|_| ObjectId::new()).collect();
+        let mut live: HashMap<ObjectId, DocId> = HashMap::new();
+        let mut deleted: BTreeSet<DocId> = deleted.iter().copied().collect();
+        for (doc_id, page_id) in page_ids {
+            pages[*doc_id as usize] = *page_id;
+            let kept = live.entry(*page_id).or_insert(*doc_id);
+            if *kept != *doc_id {
+                deleted.insert((*kept).max(*doc_id));
+                *kept = (*kept).min(*doc_id);
+            }
+        }
+        *self.state.lock().unwrap() = (pages, live);
+        self.replaced.lock().unwrap().clear();
+        *self.deleted.lock().unwrap() = deleted
+            .into_iter()
+            .map(|doc_id| (doc_id, Tombstone::default()))
+            .collect();
+        self.deleted_version.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// SPIMI blocks as vectors of documents, sorted when the block is sealed.
+#[derive(Default)]
+pub struct MemoryBlockStore {
+    blocks: Mutex<HashMap<String, Vec<SpimiDoc>>>,
+}
+
+#[async_trait]
+impl BlockStore for MemoryBlockStore {
+    async fn append_to_block(&self, block: &str, doc: SpimiDoc) -> Result<()> {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.entry(block.to_string()).or_default().push(doc);
+        Ok(())
+    }
+
+    async fn seal_block(&self, block: &str) -> Result<()> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let docs = blocks.entry(block.to_string()).or_default();
+        docs.sort_by(|a, b| a.term.cmp(&b.term).then_with(|| a.bucket.cmp(&b.bucket)));
+        Ok(())
+    }
+
+    async fn list_blocks(&self) -> Result<Vec<String>> {
+        let blocks = self.blocks.lock().unwrap();
+        Ok(blocks.keys().cloned().collect())
+    }
+
+    async fn read_block(
+        &self,
+        block: &str,
+        from_term: Option<&str>,
+    ) -> Result<BoxStream<'static, Result<SpimiDoc>>> {
+        let blocks = self.blocks.lock().unwrap();
+        let Some(docs) = blocks.get(block) else {
+            bail!("Unknown block {block}");
+        };
+        let docs: Vec<Result<SpimiDoc>> = docs
+            .iter()
+            .filter(|doc| from_term.is_none_or(|term| doc.term.as_str() >= term))
+            .cloned()
+            .map(Ok)
+            .collect();
+        Ok(futures::stream::iter(docs).boxed())
+    }
+
+    async fn drop_block(&self, block: &str) -> Result<()> {
+        self.blocks.lock().unwrap().remove(block);
+        Ok(())
+    }
+}
+
+/// Merge checkpoints keyed by block name.
+#[derive(Default)]
+pub struct MemoryCheckpointStore {
+    checkpoints: Mutex<HashMap<String, MergeCheckpoint>>,
+}
+
+#[async_trait]
+impl CheckpointStore for MemoryCheckpointStore {
+    async fn get_or_insert(&self, checkpoint: MergeCheckpoint) -> Result<MergeCheckpoint> {
+        let mut checkpoints = self.checkpoints.lock().unwrap();
+        Ok(checkpoints
+            .entry(checkpoint.collection_name.clone())
+            .or_insert(checkpoint)
+            .clone())
+    }
+
+    async fn update_progress(&self, collection_name: &str, term: &str, bucket: i16) -> Result<()> {
+        let mut checkpoints = self.checkpoints.lock().unwrap();
+        if let Some(cp) = checkpoints.get_mut(collection_name) {
+            cp.last_merged_term = Some(term.to_string());
+            cp.last_merged_bucket = bucket;
+            cp.updated_at = DateTime::now();
+        }
+        Ok(())
+    }
+
+    async fn mark_completed(&self, collection_name: &str) -> Result<()> {
+        let mut checkpoints = self.checkpoints.lock().unwrap();
+        if let Some(cp) = checkpoints.get_mut(collection_name) {
+            cp.completed = true;
+            cp.updated_at = DateTime::now();
+        }
+        Ok(())
+    }
+
+    async fn get_incomplete(&self) -> Result<Vec<MergeCheckpoint>> {
+        let checkpoints = self.checkpoints.lock().unwrap();
+        Ok(checkpoints
+            .values()
+            .filter(|cp| !cp.completed)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_completed(&self) -> Result<u64> {
+        let mut checkpoints = self.checkpoints.lock().unwrap();
+        let before = checkpoints.len();
+        checkpoints.retain(|_, cp| !cp.completed);
+        Ok((before - checkpoints.len()) as u64)
+    }
+}
+
+/// Query search counts, `(count, pending)` per normalized query.
+#[derive(Default)]
+pub struct MemoryQueryLogStore {
+    queries: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+#[async_trait]
+impl QueryLogStore for MemoryQueryLogStore {
+    async fn record(&self, query: &str) -> Result<()> {
+        let mut queries = self.queries.lock().unwrap();
+        let (count, pending) = queries.entry(query.to_string()).or_default();
+        *count += 1;
+        *pending += 1;
+        Ok(())
+    }
+
+    async fn take_pending(&self) -> Result<Vec<(String, u64)>> {
+        let mut queries = self.queries.lock().unwrap();
+        Ok(queries
+            .iter_mut()
+            .filter(|(_, (_, pending))| *pending > 0)
+            .map(|(query, (_, pending))| (query.clone(), std::mem::take(pending)))
+            .collect())
+    }
+
+    async fn take_all(&self) -> Result<Vec<(String, u64)>> {
+        let mut queries = self.queries.lock().unwrap();
+        Ok(queries
+            .iter_mut()
+            .map(|(query, (count, pending))| {
+                *pending = 0;
+                (query.clone(), *count)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::DEFAULT_ANALYZER;
+    use crate::data_models::MAX_GENERATION_LAYERS;
+    use futures::TryStreamExt;
+
+    fn page(url: &str) -> Page {
+        Page::new(
+            url.to_string(),
+            "title".to_string(),
+            "body".to_string(),
+            vec![],
+            0,
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_pages_upsert_and_pagination() -> Result<()> {
+        let store = MemoryPageStore::default();
+        let mut ids = Vec::new();
+        for i in 0..5 {
+            ids.push(
+                store
+                    .insert(&page(&format!("https://example.com/{i}")))
+                    .await?,
+            );
+        }
+
+        // upsert keeps the id of the page with the same url
+        let id = store.upsert(&page("https://example.com/0")).await?;
+        assert_eq!(id, ids[0]);
+        store.mark_many_as_indexed(&ids[1..3]).await?;
+        // an unchanged page stays indexed, a changed one is indexed again
+        store.upsert(&page("https://example.com/1")).await?;
+        let mut changed = page("https://example.com/2");
+        changed.html_body = "new body".to_string();
+        store.upsert(&changed).await?;
+
+        let (first, cursor) = store.list_unindexed_paginated(2, None).await?;
+        let urls: Vec<&str> = first.iter().map(|p| p.url.as_str()).collect();
+        assert_eq!(urls, vec!["https://example.com/0", "https://example.com/2"]);
+
+        let (rest, _) = store.list_unindexed_paginated(10, cursor).await?;
+        assert_eq!(rest.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pages_indexed_by_run() -> Result<()> {
+        let store = MemoryPageStore::default();
+        let mut ids = Vec::new();
+        for i in 0..3 {
+            ids.push(
+                store
+                    .insert(&page(&format!("https://example.com/{i}")))
+                    .await?,
+            );
+        }
+        let (run, other_run) = (ObjectId::new(), ObjectId::new());
+        store.mark_many_as_tokenized(&ids[..2], run).await?;
+        store.mark_many_as_tokenized(&ids[2..], other_run).await?;
+        // a page changed after being tokenized waits for the next run
+        let mut changed = page("https://example.com/1");
+        changed.html_body = "new body".to_string();
+        store.upsert(&changed).await?;
+
+        assert_eq!(store.mark_runs_as_indexed(&[run]).await?, 1);
+        let (unindexed, _) = store.list_unindexed_paginated(10, None).await?;
+        let urls: Vec<&str> = unindexed.iter().map(|p| p.url.as_str()).collect();
+        assert_eq!(urls, vec!["https://example.com/1", "https://example.com/2"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_buckets() -> Result<()> {
+        let store = MemoryIndexStore::default();
+        let doc_id: DocId = 7;
+        for (term, bucket) in [("moon", 1), ("harvest", 0), ("moon", 0)] {
+            store
+                .insert(InvertedIndexDoc::new(
+                    term.to_string(),
+                    bucket,
+                    1,
+                    vec![doc_id],
+                    HashMap::from([(doc_id, vec![0])]),
+                ))
+                .await?;
+        }
+
+        let last = store.get_last_bucket("moon").await?.unwrap();
+        assert_eq!(last.bucket, 1);
+        let other: DocId = 9;
+        assert!(
+            store
+                .append_to_bucket(last.id, &[other], &HashMap::from([(other, vec![3])]))
+                .await?
+        );
+
+        let docs = store.find_by_terms(&["moon".to_string()]).await?;
+        assert_eq!(
+            docs.iter().map(|d| d.bucket).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert_eq!(docs[1].postings, vec![doc_id, other]);
+        assert_eq!(
+            store.term_document_frequencies().await?,
+            vec![("harvest".to_string(), 1), ("moon".to_string(), 3)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_doc_ids_assign_and_resolve() -> Result<()> {
+        let store = MemoryDocIdStore::default();
+        let (a, b, c) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        assert_eq!(store.assign(&[a, b, a]).await?, vec![0, 1, 0]);
+        assert_eq!(store.assign(&[c, b]).await?, vec![2, 1]);
+        assert_eq!(store.resolve(&[2, 0]).await?, vec![c, a]);
+        assert!(store.resolve(&[3]).await.is_err());
+        assert_eq!(store.page_ids().await?, vec![(0, a), (1, b), (2, c)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_doc_ids_delete_pages() -> Result<()> {
+        let store = MemoryDocIdStore::default();
+        let (a, b) = (ObjectId::new(), ObjectId::new());
+        assert_eq!(store.assign(&[a, b]).await?, vec![0, 1]);
+        assert_eq!(store.delete_pages(&[a, ObjectId::new()]).await?, vec![0]);
+        assert_eq!(store.deleted().await?, vec![0]);
+        assert!(store.resolve(&[0]).await.is_err());
+        // a deleted page indexed again gets a new doc id
+        assert_eq!(store.assign(&[a]).await?, vec![2]);
+        assert_eq!(store.page_ids().await?, vec![(1, b), (2, a)]);
+
+        // a tombstone is kept while a generation older than the one that purged it is
+        let version = store.deleted_version().await?;
+        store.purge_deleted(&[0], 2).await?;
+        assert_eq!(store.forget_purged(1, 2).await?, 0);
+        assert_eq!(store.deleted().await?, vec![0]);
+        // rolling back the generation that purged it keeps it until a later one purges it
+        assert_eq!(store.forget_purged(0, 1).await?, 0);
+        assert_eq!(store.forget_purged(2, 2).await?, 0);
+        assert_eq!(store.deleted_version().await?, version);
+
+        store.purge_deleted(&[0], 2).await?;
+        assert_eq!(store.forget_purged(2, 2).await?, 1);
+        assert!(store.deleted().await?.is_empty());
+        assert_ne!(store.deleted_version().await?, version);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_doc_ids_replace_pages() -> Result<()> {
+        let store = MemoryDocIdStore::default();
+        let (a, b) = (ObjectId::new(), ObjectId::new());
+        let (run, next_run) = (ObjectId::new(), ObjectId::new());
+        assert_eq!(store.assign(&[a, b]).await?, vec![0, 1]);
+        assert_eq!(store.replace_pages(&[a], run).await?, vec![0]);
+        assert_eq!(store.assign(&[a]).await?, vec![2]);
+        // the replaced doc id stays live until its run commits
+        assert_eq!(store.resolve(&[0]).await?, vec![a]);
+        assert_eq!(store.page_ids().await?, vec![(0, a), (1, b), (2, a)]);
+        assert!(store.deleted().await?.is_empty());
+
+        // a run that replaces the page again takes over the pending doc id
+        assert_eq!(store.replace_pages(&[a], next_run).await?, vec![2]);
+        assert!(store.commit_replaced(&[run], 1).await?.is_empty());
+        assert_eq!(store.commit_replaced(&[next_run], 1).await?, vec![0, 2]);
+        assert_eq!(store.deleted().await?, vec![0, 2]);
+        assert!(store.resolve(&[0]).await.is_err());
+
+        // rolling back the generation that replaced them gives the page its first doc id back
+        assert_eq!(store.assign(&[a]).await?, vec![3]);
+        assert!(store.revive_replaced(1).await?.is_empty());
+        assert_eq!(store.revive_replaced(0).await?, vec![a]);
+        assert_eq!(store.assign(&[a]).await?, vec![0]);
+        assert_eq!(store.deleted().await?, vec![2]);
+        assert!(store.revive_replaced(0).await?.is_empty());
+        Ok(())
+    }
+
+    async fn terms(store: &MemoryIndexStore) -> Result<Vec<String>> {
+        Ok(store
+            .term_document_frequencies()
+            .await?
+            .into_iter()
+            .map(|(term, _)| term)
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn test_index_generations() -> Result<()> {
+        let store = MemoryIndexStore::default();
+        let bucket =
+            |term: &str| InvertedIndexDoc::new(term.to_string(), 0, 1, vec![1], HashMap::new());
+        store.insert(bucket("moon")).await?;
+        assert!(store.commit_generation().await.is_err());
+
+        let next = store.begin_generation(true, DEFAULT_ANALYZER).await?;
+        next.insert(bucket("harvest")).await?;
+        // readers see the current generation until the commit
+        assert_eq!(terms(&store).await?, vec!["moon"]);
+        // an interrupted run resumes the same generation
+        store
+            .begin_generation(true, DEFAULT_ANALYZER)
+            .await?
+            .insert(bucket("sun"))
+            .await?;
+        assert!(store.rollback_generation().await.is_err());
+        assert_eq!(store.commit_generation().await?, 1);
+        assert_eq!(terms(&store).await?, vec!["harvest", "moon", "sun"]);
+
+        store
+            .begin_generation(false, DEFAULT_ANALYZER)
+            .await?
+            .insert(bucket("blue"))
+            .await?;
+        assert_eq!(store.commit_generation().await?, 2);
+        assert_eq!(terms(&store).await?, vec!["blue"]);
+        let manifest = store.manifest().await?;
+        assert_eq!((manifest.current, manifest.previous), (2, Some(1)));
+        // generation 1 is a delta on generation 0, both are kept for the rollback
+        assert_eq!(manifest.previous_layers, vec![0]);
+        assert_eq!(store.generations.lock().unwrap().buckets.len(), 3);
+
+        assert_eq!(store.rollback_generation().await?, 1);
+        assert_eq!(terms(&store).await?, vec!["harvest", "moon", "sun"]);
+        assert!(store.rollback_generation().await.is_err());
+        assert_eq!(store.generations.lock().unwrap().buckets.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_generation_layers_fold() -> Result<()> {
+        let store = MemoryIndexStore::default();
+        let bucket = |doc_id: DocId| {
+            InvertedIndexDoc::new("moon".to_string(), 0, 1, vec![doc_id], HashMap::new())
+        };
+        store.insert(bucket(0)).await?;
+        for doc_id in 1..=MAX_GENERATION_LAYERS as DocId {
+            let next = store.begin_generation(true, DEFAULT_ANALYZER).await?;
+            // a delta starts empty, the buckets of its run start from 0
+            assert!(next.get_last_bucket("moon").await?.is_none());
+            next.insert(bucket(doc_id)).await?;
+            store.commit_generation().await?;
+        }
+        let manifest = store.manifest().await?;
+        assert_eq!(manifest.current_layers.len(), MAX_GENERATION_LAYERS);
+        let buckets = store.find_by_terms(&["moon".to_string()]).await?;
+        let expected: Vec<(i16, Vec<DocId>)> = (0..=MAX_GENERATION_LAYERS)
+            .map(|i| (i as i16, vec![i as DocId]))
+            .collect();
+        let stacked = |buckets: Vec<InvertedIndexDoc>| -> Vec<(i16, Vec<DocId>)> {
+            buckets
+                .into_iter()
+                .map(|doc| (doc.bucket, doc.postings))
+                .collect()
+        };
+        assert_eq!(stacked(buckets), expected);
+        assert_eq!(
+            store.get_last_bucket("moon").await?.unwrap().bucket,
+            MAX_GENERATION_LAYERS as i16
+        );
+
+        // the next generation folds the layers into a base generation
+        let folded = store.begin_generation(true, DEFAULT_ANALYZER).await?;
+        assert_eq!(
+            stacked(folded.find_by_terms(&["moon".to_string()]).await?),
+            expected
+        );
+        store.commit_generation().await?;
+        assert!(store.manifest().await?.current_layers.is_empty());
+        assert_eq!(
+            stacked(store.find_by_terms(&["moon".to_string()]).await?),
+            expected
+        );
+
+        // the layers are dropped once the folded generation can't be rolled back to either
+        store.begin_generation(true, DEFAULT_ANALYZER).await?;
+        store.commit_generation().await?;
+        assert_eq!(store.generations.lock().unwrap().buckets.len(), 2);
+        assert_eq!(
+            store.term_document_frequencies().await?,
+            vec![("moon".to_string(), MAX_GENERATION_LAYERS as u64 + 1)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blocks_read_sorted_from_term() -> Result<()> {
+        let store = MemoryBlockStore::default();
+        for (term, bucket) in [("moon", 0), ("harvest", 1), ("harvest", 0)] {
+            store
+                .append_to_block(
+                    "spimi_block_a",
+                    SpimiDoc::new(term.to_string(), bucket, 0, vec![], HashMap::new()),
+                )
+                .await?;
+        }
+        store.seal_block("spimi_block_a").await?;
+        store.seal_block("spimi_block_b").await?;
+        assert_eq!(store.list_blocks().await?.len(), 2);
+
+        let docs: Vec<SpimiDoc> = store
+            .read_block("spimi_block_a", None)
+            .await?
+            .try_collect()
+            .await?;
+        let keys: Vec<(&str, i16)> = docs.iter().map(|d| (d.term.as_str(), d.bucket)).collect();
+        assert_eq!(keys, vec![("harvest", 0), ("harvest", 1), ("moon", 0)]);
+
+        let docs: Vec<SpimiDoc> = store
+            .read_block("spimi_block_a", Some("i"))
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(docs.len(), 1);
+
+        store.drop_block("spimi_block_a").await?;
+        assert_eq!(store.list_blocks().await?, vec!["spimi_block_b"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_log_take_pending() -> Result<()> {
+        let store = MemoryQueryLogStore::default();
+        store.record("harvest moon").await?;
+        store.record("harvest moon").await?;
+        assert_eq!(
+            store.take_pending().await?,
+            vec![("harvest moon".to_string(), 2)]
+        );
+        assert!(store.take_pending().await?.is_empty());
+
+        store.record("harvest moon").await?;
+        assert_eq!(
+            store.take_all().await?,
+            vec![("harvest moon".to_string(), 3)]
+        );
+        assert!(store.take_pending().await?.is_empty());
+        Ok(())
+    }
+}
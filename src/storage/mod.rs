@@ -0,0 +1,292 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use mongodb::bson::oid::ObjectId;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::data_models::{DocId, IndexManifest, InvertedIndexDoc, MergeCheckpoint, Page, SpimiDoc};
+use crate::db::Database;
+
+pub mod file;
+pub mod memory;
+pub mod mongo;
+
+/// Crawled pages.
+#[async_trait]
+pub trait PageStore: Send + Sync {
+    async fn insert(&self, page: &Page) -> Result<ObjectId>;
+
+    /// Insert the page, or replace the page with the same URL keeping its id. A replaced page
+    /// stays indexed unless its body changed.
+    async fn upsert(&self, page: &Page) -> Result<ObjectId>;
+
+    /// Deletes the page at `url`, returning its id.
+    async fn delete_by_url(&self, url: &str) -> Result<Option<ObjectId>>;
+
+    /// Pages with the given ids, in no particular order. Unknown ids are skipped.
+    async fn find_by_ids(&self, ids: &[ObjectId]) -> Result<Vec<Page>>;
+
+    /// Up to `limit` unindexed pages with an id greater than `last_cursor`, in id order,
+    /// along with the cursor to pass for the next page.
+    async fn list_unindexed_paginated(
+        &self,
+        limit: i64,
+        last_cursor: Option<ObjectId>,
+    ) -> Result<(Vec<Page>, Option<ObjectId>)>;
+
+    async fn mark_many_as_indexed(&self, ids: &[ObjectId]) -> Result<u64>;
+
+    /// Records that the pages were tokenized by the index run `index_run`. They stay unindexed
+    /// until the run's postings are merged.
+    async fn mark_many_as_tokenized(&self, ids: &[ObjectId], index_run: ObjectId) -> Result<u64>;
+
+    /// Marks the pages tokenized by any of `index_runs` as indexed, unless they changed since.
+    async fn mark_runs_as_indexed(&self, index_runs: &[ObjectId]) -> Result<u64>;
+
+    /// Marks every page unindexed, so the next index run indexes them all again.
+    async fn mark_all_as_unindexed(&self) -> Result<u64>;
+
+    /// Marks the pages unindexed, so the next index run indexes them again.
+    async fn mark_many_as_unindexed(&self, ids: &[ObjectId]) -> Result<u64>;
+
+    /// Writes `pages` as they are, replacing the pages with the same id or URL.
+    async fn restore(&self, pages: &[Page]) -> Result<u64>;
+}
+
+/// The merged inverted index, one document per (term, bucket). Reads go through the current
+/// generation and the ones it's layered on, writes go to the current generation. Stores from
+/// `begin_generation` and `generation` read and write the one generation they are pinned to.
+#[async_trait]
+pub trait IndexStore: Send + Sync {
+    /// The bucket with the highest number for `term`.
+    async fn get_last_bucket(&self, term: &str) -> Result<Option<InvertedIndexDoc>>;
+
+    /// Append postings and positions to the bucket document `doc_id`.
+    async fn append_to_bucket(
+        &self,
+        doc_id: ObjectId,
+        new_postings: &[DocId],
+        new_positions: &HashMap<DocId, Vec<usize>>,
+    ) -> Result<bool>;
+
+    async fn insert(&self, doc: InvertedIndexDoc) -> Result<ObjectId>;
+
+    /// Inserts new bucket documents in one write.
+    async fn insert_many(&self, docs: Vec<InvertedIndexDoc>) -> Result<()>;
+
+    /// Every bucket of the given terms, sorted by bucket. The buckets of a term are numbered
+    /// across layers, oldest layer first.
+    async fn find_by_terms(&self, terms: &[String]) -> Result<Vec<InvertedIndexDoc>>;
+
+    /// Document frequency of every term, summed over its buckets and sorted by term.
+    async fn term_document_frequencies(&self) -> Result<Vec<(String, u64)>>;
+
+    /// The generations of the index.
+    async fn manifest(&self) -> Result<IndexManifest>;
+
+    /// Bytes the generations read take in the backing store with their indexes, `None` when
+    /// they're not stored on disk.
+    async fn storage_bytes(&self) -> Result<Option<u64>>;
+
+    /// A store on the generation an index run writes to: the one an interrupted run left behind,
+    /// or else a new empty one. With `extend_current` the new generation is a delta layered on
+    /// the current one, or a base generation copying it once it has `MAX_GENERATION_LAYERS`
+    /// layers. Readers keep seeing the current generation until `commit_generation`. A new
+    /// generation is recorded as built with `analyzer`, resuming or extending a generation built
+    /// with another analyzer fails.
+    async fn begin_generation(
+        &self,
+        extend_current: bool,
+        analyzer: &str,
+    ) -> Result<Arc<dyn IndexStore>>;
+
+    /// A store on `generation` alone, without the generations it's layered on.
+    fn generation(&self, generation: u64) -> Arc<dyn IndexStore>;
+
+    /// Makes the generation being built current, keeping the replaced one for
+    /// `rollback_generation` and dropping the generations neither one reads. Returns the new
+    /// current generation.
+    async fn commit_generation(&self) -> Result<u64>;
+
+    /// Makes the previous generation current again and drops the generations it doesn't read.
+    /// Returns the new current generation.
+    async fn rollback_generation(&self) -> Result<u64>;
+
+    /// Drops the previous generation and the generations only it reads, the current one can't
+    /// be rolled back anymore.
+    async fn drop_previous_generation(&self) -> Result<()>;
+}
+
+/// Stacks the buckets read from the layers of a generation, oldest layer first, into the buckets
+/// of a single index: the buckets of a term are renumbered in layer order, then sorted by bucket.
+pub(crate) fn stack_layers(layers: Vec<Vec<InvertedIndexDoc>>) -> Vec<InvertedIndexDoc> {
+    if layers.len() == 1 {
+        return layers.into_iter().next().unwrap();
+    }
+    let mut buckets: HashMap<String, i16> = HashMap::new();
+    let mut docs: Vec<InvertedIndexDoc> = layers
+        .into_iter()
+        .flatten()
+        .map(|mut doc| {
+            let next = buckets.entry(doc.term.clone()).or_default();
+            doc.bucket = *next;
+            *next += 1;
+            doc
+        })
+        .collect();
+    docs.sort_by_key(|doc| doc.bucket);
+    docs
+}
+
+/// Dense doc ids of the indexed pages.
+#[async_trait]
+pub trait DocIdStore: Send + Sync {
+    /// Doc ids of `page_ids` in the same order, pages seen for the first time get the next free ids.
+    async fn assign(&self, page_ids: &[ObjectId]) -> Result<Vec<DocId>>;
+
+    /// Page ids of `doc_ids` in the same order, unknown and deleted doc ids are an error.
+    async fn resolve(&self, doc_ids: &[DocId]) -> Result<Vec<ObjectId>>;
+
+    /// Every live `(doc id, page id)` pair, sorted by doc id. A page tokenized again by an
+    /// uncommitted index run is listed with its new doc id and the replaced one.
+    async fn page_ids(&self) -> Result<Vec<(DocId, ObjectId)>>;
+
+    /// Gives the pages that have a doc id a new one on their next `assign`, for `index_run` to
+    /// index them again, and returns the replaced doc ids. Replaced doc ids stay live, the
+    /// current generation still has their postings, until `commit_replaced` tombstones them.
+    /// Doc ids these pages had replaced by an earlier run now wait for `index_run` too.
+    async fn replace_pages(&self, page_ids: &[ObjectId], index_run: ObjectId)
+    -> Result<Vec<DocId>>;
+
+    /// Tombstones the doc ids replaced by `index_runs` once their generation `generation` is
+    /// committed, and returns them.
+    async fn commit_replaced(&self, index_runs: &[ObjectId], generation: u64)
+    -> Result<Vec<DocId>>;
+
+    /// Gives the pages whose doc ids a generation after `generation` replaced their doc id from
+    /// before back, once the index was rolled back to `generation`, and returns them. Their newer
+    /// doc ids are dropped, only the rolled back generations had postings of them. Deleted pages
+    /// stay deleted.
+    async fn revive_replaced(&self, generation: u64) -> Result<Vec<ObjectId>>;
+
+    /// Tombstones the doc ids of `page_ids`, replaced ones included, and returns them. Pages
+    /// without one are skipped. Assigning a doc id to such a page again gives it a new one.
+    async fn delete_pages(&self, page_ids: &[ObjectId]) -> Result<Vec<DocId>>;
+
+    /// Tombstoned doc ids, ascending. Their postings are ignored until no generation the index
+    /// keeps has them anymore, see `forget_purged`.
+    async fn deleted(&self) -> Result<Vec<DocId>>;
+
+    /// A number that changes whenever the tombstones do, to cache `deleted`.
+    async fn deleted_version(&self) -> Result<u64>;
+
+    /// Records that `generation` was written without the postings of the tombstoned `doc_ids`.
+    async fn purge_deleted(&self, doc_ids: &[DocId], generation: u64) -> Result<()>;
+
+    /// Drops the tombstones purged by `oldest`, the oldest generation the index keeps, or by an
+    /// older one. Tombstones purged by a generation after `current` were purged by a generation
+    /// rolled back, whose number gets reused, and count as not purged again. Returns the number
+    /// of tombstones dropped.
+    async fn forget_purged(&self, oldest: u64, current: u64) -> Result<u64>;
+
+    /// Replaces every doc id and tombstone by the given ones. A page listed with several doc ids
+    /// keeps the lowest and the others are tombstoned. Doc ids assigned afterwards come after the
+    /// highest restored one.
+    async fn restore(&self, page_ids: &[(DocId, ObjectId)], deleted: &[DocId]) -> Result<()>;
+}
+
+/// Temporary SPIMI blocks written by the inversion and consumed by the merge.
+#[async_trait]
+pub trait BlockStore: Send + Sync {
+    async fn append_to_block(&self, block: &str, doc: SpimiDoc) -> Result<()>;
+
+    /// Called once a block is fully written, the block exists afterwards even if it's empty.
+    async fn seal_block(&self, block: &str) -> Result<()>;
+
+    /// Names of all the blocks that were not dropped yet.
+    async fn list_blocks(&self) -> Result<Vec<String>>;
+
+    /// Streams the documents of `block` sorted by (term, bucket), starting at `from_term` if given.
+    async fn read_block(
+        &self,
+        block: &str,
+        from_term: Option<&str>,
+    ) -> Result<BoxStream<'static, Result<SpimiDoc>>>;
+
+    async fn drop_block(&self, block: &str) -> Result<()>;
+}
+
+/// Merge progress of every SPIMI block, to resume a merge after a crash.
+#[async_trait]
+pub trait CheckpointStore: Send + Sync {
+    async fn get_or_create(&self, collection_name: &str) -> Result<MergeCheckpoint> {
+        self.get_or_insert(MergeCheckpoint::new(collection_name.to_string()))
+            .await
+    }
+
+    /// The checkpoint named like `checkpoint`, which is inserted if there is none yet.
+    async fn get_or_insert(&self, checkpoint: MergeCheckpoint) -> Result<MergeCheckpoint>;
+
+    async fn update_progress(&self, collection_name: &str, term: &str, bucket: i16) -> Result<()>;
+
+    async fn mark_completed(&self, collection_name: &str) -> Result<()>;
+
+    async fn get_incomplete(&self) -> Result<Vec<MergeCheckpoint>>;
+
+    async fn delete_completed(&self) -> Result<u64>;
+}
+
+/// Queries searched through the API, fed into the completions by the indexer.
+#[async_trait]
+pub trait QueryLogStore: Send + Sync {
+    /// Count one more search of `query` (expected to be normalized).
+    async fn record(&self, query: &str) -> Result<()>;
+
+    /// The searches recorded since the last call as `(query, searches)`.
+    async fn take_pending(&self) -> Result<Vec<(String, u64)>>;
+
+    /// Every search recorded as `(query, searches)`, taking the pending ones too.
+    async fn take_all(&self) -> Result<Vec<(String, u64)>>;
+}
+
+/// One handle on every store of a backend.
+///
+/// Components talk to storage through these traits instead of `mongodb::Collection`, so the
+/// crawl -> index -> query pipeline runs on MongoDB (`Storage::mongo`) in production and fully
+/// in memory (`Storage::in_memory`) in tests and small deployments.
+#[derive(Clone)]
+pub struct Storage {
+    pub pages: Arc<dyn PageStore>,
+    pub index: Arc<dyn IndexStore>,
+    pub doc_ids: Arc<dyn DocIdStore>,
+    pub blocks: Arc<dyn BlockStore>,
+    pub checkpoints: Arc<dyn CheckpointStore>,
+    pub query_log: Arc<dyn QueryLogStore>,
+}
+
+impl Storage {
+    /// Stores backed by the collections of `db`.
+    pub fn mongo(db: &Database) -> Self {
+        Self {
+            pages: Arc::new(crate::db::PageRepo::new(db)),
+            index: Arc::new(crate::db::InvertedIndexRepo::new(db)),
+            doc_ids: Arc::new(crate::db::DocIdRepo::new(db)),
+            blocks: Arc::new(file::FileBlockStore::for_database(db)),
+            checkpoints: Arc::new(crate::db::MergeCheckpointRepo::new(db)),
+            query_log: Arc::new(crate::db::QueryLogRepo::new(db)),
+        }
+    }
+
+    /// Fresh, empty stores living in memory, gone when the last handle is dropped.
+    pub fn in_memory() -> Self {
+        Self {
+            pages: Arc::new(memory::MemoryPageStore::default()),
+            index: Arc::new(memory::MemoryIndexStore::default()),
+            doc_ids: Arc::new(memory::MemoryDocIdStore::default()),
+            blocks: Arc::new(memory::MemoryBlockStore::default()),
+            checkpoints: Arc::new(memory::MemoryCheckpointStore::default()),
+            query_log: Arc::new(memory::MemoryQueryLogStore::default()),
+        }
+    }
+}
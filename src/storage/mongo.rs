@@ -0,0 +1,223 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::{CheckpointStore, DocIdStore, IndexStore, PageStore, QueryLogStore};
+use crate::data_models::{DocId, IndexManifest, InvertedIndexDoc, MergeCheckpoint, Page};
+use crate::db::{DocIdRepo, InvertedIndexRepo, MergeCheckpointRepo, PageRepo, QueryLogRepo};
+
+#[async_trait]
+impl PageStore for PageRepo {
+    async fn insert(&self, page: &Page) -> Result<ObjectId> {
+        PageRepo::insert(self, page).await
+    }
+
+    async fn upsert(&self, page: &Page) -> Result<ObjectId> {
+        PageRepo::upsert(self, page).await
+    }
+
+    async fn delete_by_url(&self, url: &str) -> Result<Option<ObjectId>> {
+        PageRepo::delete_by_url(self, url).await
+    }
+
+    async fn find_by_ids(&self, ids: &[ObjectId]) -> Result<Vec<Page>> {
+        PageRepo::find_by_ids(self, ids).await
+    }
+
+    async fn list_unindexed_paginated(
+        &self,
+        limit: i64,
+        last_cursor: Option<ObjectId>,
+    ) -> Result<(Vec<Page>, Option<ObjectId>)> {
+        PageRepo::list_unindexed_paginated(self, limit, last_cursor).await
+    }
+
+    async fn mark_many_as_indexed(&self, ids: &[ObjectId]) -> Result<u64> {
+        PageRepo::mark_many_as_indexed(self, ids).await
+    }
+
+    async fn mark_many_as_tokenized(&self, ids: &[ObjectId], index_run: ObjectId) -> Result<u64> {
+        PageRepo::mark_many_as_tokenized(self, ids, index_run).await
+    }
+
+    async fn mark_runs_as_indexed(&self, index_runs: &[ObjectId]) -> Result<u64> {
+        PageRepo::mark_runs_as_indexed(self, index_runs).await
+    }
+
+    async fn mark_all_as_unindexed(&self) -> Result<u64> {
+        PageRepo::mark_all_as_unindexed(self).await
+    }
+
+    async fn mark_many_as_unindexed(&self, ids: &[ObjectId]) -> Result<u64> {
+        PageRepo::mark_many_as_unindexed(self, ids).await
+    }
+
+    async fn restore(&self, pages: &[Page]) -> Result<u64> {
+        PageRepo::restore(self, pages).await
+    }
+}
+
+#[async_trait]
+impl IndexStore for InvertedIndexRepo {
+    async fn get_last_bucket(&self, term: &str) -> Result<Option<InvertedIndexDoc>> {
+        InvertedIndexRepo::get_last_bucket(self, term).await
+    }
+
+    async fn append_to_bucket(
+        &self,
+        doc_id: ObjectId,
+        new_postings: &[DocId],
+        new_positions: &HashMap<DocId, Vec<usize>>,
+    ) -> Result<bool> {
+        InvertedIndexRepo::append_to_bucket(self, doc_id, new_postings, new_positions).await
+    }
+
+    async fn insert(&self, doc: InvertedIndexDoc) -> Result<ObjectId> {
+        InvertedIndexRepo::insert(self, doc).await
+    }
+
+    async fn insert_many(&self, docs: Vec<InvertedIndexDoc>) -> Result<()> {
+        InvertedIndexRepo::insert_many(self, docs).await
+    }
+
+    async fn find_by_terms(&self, terms: &[String]) -> Result<Vec<InvertedIndexDoc>> {
+        InvertedIndexRepo::find_by_terms(self, terms).await
+    }
+
+    async fn term_document_frequencies(&self) -> Result<Vec<(String, u64)>> {
+        InvertedIndexRepo::term_document_frequencies(self).await
+    }
+
+    async fn manifest(&self) -> Result<IndexManifest> {
+        InvertedIndexRepo::manifest(self).await
+    }
+
+    async fn storage_bytes(&self) -> Result<Option<u64>> {
+        Ok(Some(InvertedIndexRepo::storage_bytes(self).await?))
+    }
+
+    async fn begin_generation(
+        &self,
+        extend_current: bool,
+        analyzer: &str,
+    ) -> Result<Arc<dyn IndexStore>> {
+        Ok(Arc::new(
+            InvertedIndexRepo::begin_generation(self, extend_current, analyzer).await?,
+        ))
+    }
+
+    fn generation(&self, generation: u64) -> Arc<dyn IndexStore> {
+        Arc::new(self.pinned(generation))
+    }
+
+    async fn commit_generation(&self) -> Result<u64> {
+        InvertedIndexRepo::commit_generation(self).await
+    }
+
+    async fn rollback_generation(&self) -> Result<u64> {
+        InvertedIndexRepo::rollback_generation(self).await
+    }
+
+    async fn drop_previous_generation(&self) -> Result<()> {
+        InvertedIndexRepo::drop_previous_generation(self).await
+    }
+}
+
+#[async_trait]
+impl DocIdStore for DocIdRepo {
+    async fn assign(&self, page_ids: &[ObjectId]) -> Result<Vec<DocId>> {
+        DocIdRepo::assign(self, page_ids).await
+    }
+
+    async fn resolve(&self, doc_ids: &[DocId]) -> Result<Vec<ObjectId>> {
+        DocIdRepo::resolve(self, doc_ids).await
+    }
+
+    async fn page_ids(&self) -> Result<Vec<(DocId, ObjectId)>> {
+        DocIdRepo::page_ids(self).await
+    }
+
+    async fn replace_pages(
+        &self,
+        page_ids: &[ObjectId],
+        index_run: ObjectId,
+    ) -> Result<Vec<DocId>> {
+        DocIdRepo::replace_pages(self, page_ids, index_run).await
+    }
+
+    async fn commit_replaced(
+        &self,
+        index_runs: &[ObjectId],
+        generation: u64,
+    ) -> Result<Vec<DocId>> {
+        DocIdRepo::commit_replaced(self, index_runs, generation).await
+    }
+
+    async fn revive_replaced(&self, generation: u64) -> Result<Vec<ObjectId>> {
+        DocIdRepo::revive_replaced(self, generation).await
+    }
+
+    async fn delete_pages(&self, page_ids: &[ObjectId]) -> Result<Vec<DocId>> {
+        DocIdRepo::delete_pages(self, page_ids).await
+    }
+
+    async fn deleted(&self) -> Result<Vec<DocId>> {
+        DocIdRepo::deleted(self).await
+    }
+
+    async fn deleted_version(&self) -> Result<u64> {
+        DocIdRepo::deleted_version(self).await
+    }
+
+    async fn purge_deleted(&self, doc_ids: &[DocId], generation: u64) -> Result<()> {
+        DocIdRepo::purge_deleted(self, doc_ids, generation).await
+    }
+
+    async fn forget_purged(&self, oldest: u64, current: u64) -> Result<u64> {
+        DocIdRepo::forget_purged(self, oldest, current).await
+    }
+
+    async fn restore(&self, page_ids: &[(DocId, ObjectId)], deleted: &[DocId]) -> Result<()> {
+        DocIdRepo::restore(self, page_ids, deleted).await
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for MergeCheckpointRepo {
+    async fn get_or_insert(&self, checkpoint: MergeCheckpoint) -> Result<MergeCheckpoint> {
+        MergeCheckpointRepo::get_or_insert(self, checkpoint).await
+    }
+
+    async fn update_progress(&self, collection_name: &str, term: &str, bucket: i16) -> Result<()> {
+        MergeCheckpointRepo::update_progress(self, collection_name, term, bucket).await
+    }
+
+    async fn mark_completed(&self, collection_name: &str) -> Result<()> {
+        MergeCheckpointRepo::mark_completed(self, collection_name).await
+    }
+
+    async fn get_incomplete(&self) -> Result<Vec<MergeCheckpoint>> {
+        MergeCheckpointRepo::get_incomplete(self).await
+    }
+
+    async fn delete_completed(&self) -> Result<u64> {
+        MergeCheckpointRepo::delete_completed(self).await
+    }
+}
+
+#[async_trait]
+impl QueryLogStore for QueryLogRepo {
+    async fn record(&self, query: &str) -> Result<()> {
+        QueryLogRepo::record(self, query).await
+    }
+
+    async fn take_pending(&self) -> Result<Vec<(String, u64)>> {
+        QueryLogRepo::take_pending(self).await
+    }
+
+    async fn take_all(&self) -> Result<Vec<(String, u64)>> {
+        QueryLogRepo::take_all(self).await
+    }
+}
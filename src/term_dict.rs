@@ -0,0 +1,542 @@
+use anyhow::{Context, Result};
+use fst::automaton::{Automaton, Levenshtein, Str};
+use fst::{IntoStreamer, Map, MapBuilder, Streamer};
+use regex::Regex;
+use std::fmt;
+use std::path::Path;
+
+/// File name of the term dictionary inside the index directory.
+pub const TERM_DICT_FILE: &str = "terms.fst";
+
+/// Default cap on how many index terms a single prefix/wildcard/regex term may expand to.
+pub const DEFAULT_MAX_EXPANSIONS: usize = 50;
+
+/// Default edit distance of fuzzy terms (`harvst~`) and spelling suggestions.
+pub const DEFAULT_FUZZY_DISTANCE: u32 = 1;
+
+/// Largest supported edit distance, Levenshtein automata grow quickly past it.
+pub const MAX_FUZZY_DISTANCE: u32 = 2;
+
+/// Sorted dictionary of every indexed term, mapped to its document frequency.
+///
+/// Backed by an FST so prefix, wildcard and regex lookups don't need to touch the inverted index.
+/// It is rebuilt at the end of every merge in `Indexer::merge_persisted_blocks`.
+#[derive(Default)]
+pub struct TermDictionary {
+    map: Map<Vec<u8>>,
+}
+
+impl TermDictionary {
+    /// Builds a dictionary from `(term, document_frequency)` pairs.
+    /// Terms are sorted and duplicated terms have their frequencies summed.
+    pub fn build<I, S>(terms: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (S, u64)>,
+        S: Into<String>,
+    {
+        let mut terms: Vec<(String, u64)> =
+            terms.into_iter().map(|(t, df)| (t.into(), df)).collect();
+        terms.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut builder = MapBuilder::memory();
+        let mut iter = terms.into_iter().peekable();
+        while let Some((term, mut df)) = iter.next() {
+            while let Some((_, next_df)) = iter.next_if(|(next, _)| *next == term) {
+                df += next_df;
+            }
+            builder
+                .insert(&term, df)
+                .context("Failed to insert term into term dictionary")?;
+        }
+        Self::from_bytes(builder.into_inner()?)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let map = Map::new(bytes).context("Invalid term dictionary")?;
+        Ok(Self { map })
+    }
+
+    /// Loads the dictionary stored at `path`, an index without one yields an empty dictionary.
+    pub fn open(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Failed to read term dictionary {}", path.display()))?;
+        Self::from_bytes(bytes)
+    }
+
+    /// Writes the dictionary to `path`, replacing any previous one atomically.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("fst.tmp");
+        std::fs::write(&tmp_path, self.map.as_fst().as_bytes())
+            .with_context(|| format!("Failed to write term dictionary {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to replace term dictionary {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Returns a new dictionary containing the terms of both, adding up frequencies of shared terms.
+    pub fn merge(&self, other: &TermDictionary) -> Result<Self> {
+        let mut builder = MapBuilder::memory();
+        let mut union = self.map.op().add(&other.map).union();
+        while let Some((term, values)) = union.next() {
+            let df = values.iter().map(|v| v.value).sum();
+            builder.insert(term, df)?;
+        }
+        Self::from_bytes(builder.into_inner()?)
+    }
+
+    /// Returns a new dictionary with the terms `keep` returns true for, in one pass over the terms.
+    pub fn filter<F>(&self, mut keep: F) -> Result<Self>
+    where
+        F: FnMut(&str, u64) -> bool,
+    {
+        let mut builder = MapBuilder::memory();
+        let mut stream = self.map.stream();
+        while let Some((term, df)) = stream.next() {
+            if keep(&String::from_utf8_lossy(term), df) {
+                builder.insert(term, df)?;
+            }
+        }
+        Self::from_bytes(builder.into_inner()?)
+    }
+
+    /// The FST of the dictionary, for walks over its nodes.
+    pub fn as_fst(&self) -> &fst::raw::Fst<Vec<u8>> {
+        self.map.as_fst()
+    }
+
+    /// Document frequency of `term`, `None` if it's not indexed.
+    pub fn get(&self, term: &str) -> Option<u64> {
+        self.map.get(term)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Expands a term pattern to the matching index terms.
+    /// When more than `max_expansions` terms match, the ones with the highest document frequency win.
+    pub fn expand(&self, pattern: &TermPattern, max_expansions: usize) -> Result<Vec<String>> {
+        let mut matches = match pattern {
+            TermPattern::Prefix(prefix) => self.search(Str::new(prefix).starts_with(), |_| true),
+            TermPattern::Wildcard(wildcard) => {
+                let literal: String = wildcard.chars().take_while(|c| !is_wildcard(*c)).collect();
+                let wildcard: Vec<char> = wildcard.chars().collect();
+                self.search(Str::new(&literal).starts_with(), |term| {
+                    wildcard_match(&wildcard, &term.chars().collect::<Vec<char>>())
+                })
+            }
+            TermPattern::Regex(regex) => {
+                let regex = Regex::new(&format!("^(?:{regex})$"))
+                    .with_context(|| format!("Invalid regex term /{regex}/"))?;
+                self.search(fst::automaton::AlwaysMatch, |term| regex.is_match(term))
+            }
+            TermPattern::Fuzzy { term, distance } => {
+                // closer terms win over more frequent ones
+                let mut matches =
+                    self.fuzzy_matches(term, distance.unwrap_or(DEFAULT_FUZZY_DISTANCE))?;
+                matches.truncate(max_expansions);
+                return Ok(matches.into_iter().map(|m| m.term).collect());
+            }
+        };
+
+        if matches.len() > max_expansions {
+            log::debug!(
+                "Term pattern {} matched {} terms, keeping the {} most frequent",
+                pattern,
+                matches.len(),
+                max_expansions
+            );
+            matches.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            matches.truncate(max_expansions);
+        }
+        Ok(matches.into_iter().map(|(term, _)| term).collect())
+    }
+
+    /// Every term within `distance` edits of `term` (itself included when indexed),
+    /// closest first and then by descending document frequency.
+    pub fn fuzzy_matches(&self, term: &str, distance: u32) -> Result<Vec<FuzzyMatch>> {
+        let distance = distance.min(MAX_FUZZY_DISTANCE);
+        let automaton = Levenshtein::new(term, distance)
+            .with_context(|| format!("Failed to build fuzzy automaton for {term}~{distance}"))?;
+        let query: Vec<char> = term.chars().collect();
+        let mut matches: Vec<FuzzyMatch> = self
+            .search(automaton, |_| true)
+            .into_iter()
+            .map(|(t, document_frequency)| FuzzyMatch {
+                distance: edit_distance(&query, &t.chars().collect::<Vec<char>>()),
+                term: t,
+                document_frequency,
+            })
+            .collect();
+        matches.sort_by(|a, b| {
+            a.distance
+                .cmp(&b.distance)
+                .then_with(|| b.document_frequency.cmp(&a.document_frequency))
+                .then_with(|| a.term.cmp(&b.term))
+        });
+        Ok(matches)
+    }
+
+    /// Calls `f` with every term starting with `prefix` and its document frequency, in term order.
+    pub fn for_each_with_prefix<F>(&self, prefix: &str, mut f: F)
+    where
+        F: FnMut(&str, u64),
+    {
+        let mut stream = self
+            .map
+            .search(Str::new(prefix).starts_with())
+            .into_stream();
+        while let Some((term, df)) = stream.next() {
+            f(&String::from_utf8_lossy(term), df);
+        }
+    }
+
+    fn search<A, F>(&self, automaton: A, keep: F) -> Vec<(String, u64)>
+    where
+        A: Automaton,
+        F: Fn(&str) -> bool,
+    {
+        let mut out = Vec::new();
+        let mut stream = self.map.search(automaton).into_stream();
+        while let Some((term, df)) = stream.next() {
+            // terms are inserted from `String`s, so they are always valid utf-8
+            let term = String::from_utf8_lossy(term);
+            if keep(&term) {
+                out.push((term.into_owned(), df));
+            }
+        }
+        out
+    }
+}
+
+/// An index term found by `TermDictionary::fuzzy_matches`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuzzyMatch {
+    pub term: String,
+    pub document_frequency: u64,
+    pub distance: u32,
+}
+
+/// A query term that expands to several index terms instead of being looked up as is.
+///
+/// - `harv*` is a prefix query
+/// - `h?rv*st` is a wildcard query, `?` matches one character and `*` any number of them. A
+///   trailing `?` ends a question (`harvest?`) and is not a wildcard
+/// - `/harv.+/` is a regex query, matched against the whole term
+/// - `harvst~` / `harvst~2` is a fuzzy query, matching terms within the given edit distance
+///   (the engine's default when omitted)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TermPattern {
+    Prefix(String),
+    Wildcard(String),
+    Regex(String),
+    Fuzzy { term: String, distance: Option<u32> },
+}
+
+impl TermPattern {
+    /// Parses a raw (whitespace separated) query word, `None` if it's a plain word.
+    pub fn parse(raw: &str) -> Option<TermPattern> {
+        if raw.len() > 2 && raw.starts_with('/') && raw.ends_with('/') {
+            return Some(TermPattern::Regex(raw[1..raw.len() - 1].to_string()));
+        }
+        // the index only holds lowercased terms
+        let raw = raw.to_lowercase();
+        if let Some((term, distance)) = raw.rsplit_once('~') {
+            let distance = match distance {
+                "" => None,
+                d => Some(d.parse().ok()?),
+            };
+            if term.is_empty() || term.contains(is_wildcard) || term.contains('~') {
+                return None;
+            }
+            return Some(TermPattern::Fuzzy {
+                term: term.to_string(),
+                distance,
+            });
+        }
+        let raw = raw.trim_end_matches('?');
+        if !raw.contains(is_wildcard) || !raw.chars().any(|c| c.is_alphanumeric()) {
+            return None;
+        }
+        match raw.strip_suffix('*') {
+            Some(prefix) if !prefix.contains(is_wildcard) => {
+                Some(TermPattern::Prefix(prefix.to_string()))
+            }
+            _ => Some(TermPattern::Wildcard(raw.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for TermPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TermPattern::Prefix(prefix) => write!(f, "{prefix}*"),
+            TermPattern::Wildcard(wildcard) => write!(f, "{wildcard}"),
+            TermPattern::Regex(regex) => write!(f, "/{regex}/"),
+            TermPattern::Fuzzy {
+                term,
+                distance: None,
+            } => write!(f, "{term}~"),
+            TermPattern::Fuzzy {
+                term,
+                distance: Some(distance),
+            } => write!(f, "{term}~{distance}"),
+        }
+    }
+}
+
+fn is_wildcard(c: char) -> bool {
+    c == '*' || c == '?'
+}
+
+/// Glob match where `?` matches exactly one char and `*` matches any run of chars.
+fn wildcard_match(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` seen and the text position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, star_t)) = backtrack {
+            // let the last `*` swallow one more char and retry
+            p = star + 1;
+            t = star_t + 1;
+            backtrack = Some((star, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Levenshtein distance (insertions, deletions and substitutions) between two words.
+fn edit_distance(a: &[char], b: &[char]) -> u32 {
+    let mut prev: Vec<u32> = (0..=b.len() as u32).collect();
+    let mut curr = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        curr[0] = i as u32 + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + u32::from(ca != cb);
+            curr[j + 1] = substitution.min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dict() -> TermDictionary {
+        TermDictionary::build([
+            ("harvest", 10),
+            ("harvard", 3),
+            ("harbor", 7),
+            ("hervest", 1),
+            ("crawl", 4),
+            ("harvest", 2),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_build_sums_duplicate_terms() {
+        let dict = dict();
+        assert_eq!(dict.len(), 5);
+        assert_eq!(dict.get("harvest"), Some(12));
+        assert_eq!(dict.get("missing"), None);
+    }
+
+    #[test]
+    fn test_merge_adds_frequencies() {
+        let merged = dict()
+            .merge(&TermDictionary::build([("crawl", 1), ("index", 5)]).unwrap())
+            .unwrap();
+        assert_eq!(merged.len(), 6);
+        assert_eq!(merged.get("crawl"), Some(5));
+        assert_eq!(merged.get("index"), Some(5));
+    }
+
+    #[test]
+    fn test_filter_keeps_matching_terms() {
+        let filtered = dict()
+            .filter(|term, df| term.starts_with("har") && df > 3)
+            .unwrap();
+        assert_eq!(filtered.len(), 2);
+        assert_eq!(filtered.get("harvest"), Some(12));
+        assert_eq!(filtered.get("harbor"), Some(7));
+    }
+
+    #[test]
+    fn test_save_and_open_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("harvest_term_dict_{}", std::process::id()));
+        let path = dir.join(TERM_DICT_FILE);
+        dict().save(&path).unwrap();
+        let loaded = TermDictionary::open(&path).unwrap();
+        assert_eq!(loaded.get("harbor"), Some(7));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(TermDictionary::open(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_patterns() {
+        assert_eq!(TermPattern::parse("harvest"), None);
+        assert_eq!(TermPattern::parse("*"), None);
+        assert_eq!(
+            TermPattern::parse("Harv*"),
+            Some(TermPattern::Prefix("harv".to_string()))
+        );
+        assert_eq!(
+            TermPattern::parse("h?rv*st"),
+            Some(TermPattern::Wildcard("h?rv*st".to_string()))
+        );
+        assert_eq!(
+            TermPattern::parse("/har(b|v).*/"),
+            Some(TermPattern::Regex("har(b|v).*".to_string()))
+        );
+        // a question mark ending the word is punctuation
+        assert_eq!(TermPattern::parse("harvest?"), None);
+        assert_eq!(TermPattern::parse("harvest??"), None);
+        assert_eq!(
+            TermPattern::parse("harv*?"),
+            Some(TermPattern::Prefix("harv".to_string()))
+        );
+        assert_eq!(
+            TermPattern::parse("h?rvest?"),
+            Some(TermPattern::Wildcard("h?rvest".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_expand_prefix() {
+        let mut terms = dict()
+            .expand(&TermPattern::Prefix("harv".to_string()), 10)
+            .unwrap();
+        terms.sort();
+        assert_eq!(terms, vec!["harvard", "harvest"]);
+    }
+
+    #[test]
+    fn test_expand_wildcard() {
+        let mut terms = dict()
+            .expand(&TermPattern::Wildcard("h?rv*st".to_string()), 10)
+            .unwrap();
+        terms.sort();
+        assert_eq!(terms, vec!["harvest", "hervest"]);
+    }
+
+    #[test]
+    fn test_expand_regex() {
+        let mut terms = dict()
+            .expand(&TermPattern::Regex("har(bor|vard)".to_string()), 10)
+            .unwrap();
+        terms.sort();
+        assert_eq!(terms, vec!["harbor", "harvard"]);
+        assert!(
+            dict()
+                .expand(&TermPattern::Regex("(".to_string()), 10)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_expand_caps_by_document_frequency() {
+        let terms = dict()
+            .expand(&TermPattern::Prefix("h".to_string()), 2)
+            .unwrap();
+        assert_eq!(terms, vec!["harvest", "harbor"]);
+    }
+
+    #[test]
+    fn test_parse_fuzzy_patterns() {
+        assert_eq!(
+            TermPattern::parse("Harvst~"),
+            Some(TermPattern::Fuzzy {
+                term: "harvst".to_string(),
+                distance: None
+            })
+        );
+        assert_eq!(
+            TermPattern::parse("harvst~2"),
+            Some(TermPattern::Fuzzy {
+                term: "harvst".to_string(),
+                distance: Some(2)
+            })
+        );
+        assert_eq!(TermPattern::parse("~"), None);
+        assert_eq!(TermPattern::parse("harvst~x"), None);
+        assert_eq!(
+            TermPattern::parse("harvst~2").unwrap().to_string(),
+            "harvst~2"
+        );
+    }
+
+    #[test]
+    fn test_fuzzy_matches_rank_by_distance_then_frequency() {
+        let dict = dict();
+        let matches = dict.fuzzy_matches("harvst", 2).unwrap();
+        let terms: Vec<(&str, u32)> = matches
+            .iter()
+            .map(|m| (m.term.as_str(), m.distance))
+            .collect();
+        assert_eq!(terms, vec![("harvest", 1), ("hervest", 2)]);
+
+        let terms = dict
+            .expand(
+                &TermPattern::Fuzzy {
+                    term: "harvst".to_string(),
+                    distance: None,
+                },
+                10,
+            )
+            .unwrap();
+        assert_eq!(terms, vec!["harvest"]);
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        let d = |a: &str, b: &str| {
+            edit_distance(
+                &a.chars().collect::<Vec<_>>(),
+                &b.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(d("harvest", "harvest"), 0);
+        assert_eq!(d("harvst", "harvest"), 1);
+        assert_eq!(d("kitten", "sitting"), 3);
+        assert_eq!(d("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        let m = |p: &str, t: &str| {
+            wildcard_match(
+                &p.chars().collect::<Vec<_>>(),
+                &t.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(m("h*", "harvest"));
+        assert!(m("*st", "harvest"));
+        assert!(m("h*v*t", "harvest"));
+        assert!(m("?arvest", "harvest"));
+        assert!(!m("?arvest", "arvest"));
+        assert!(!m("h*x", "harvest"));
+        assert!(m("**", ""));
+    }
+}
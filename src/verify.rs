@@ -0,0 +1,532 @@
+use anyhow::Result;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+use crate::data_models::{DocId, InvertedIndexDoc};
+use crate::indexer::TERMS_PER_FETCH;
+use crate::storage::Storage;
+
+/// Pages looked up at a time when checking that doc ids point to pages.
+const PAGES_PER_FETCH: usize = 10_000;
+
+/// Doc ids listed at most per issue, the count is always exact.
+const MAX_LISTED_DOC_IDS: usize = 10;
+
+/// Consistency checks of the current index generation and of what index runs leave behind.
+pub struct IndexVerifier {
+    storage: Storage,
+}
+
+/// Something `IndexVerifier::verify` found wrong.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    /// Postings of a bucket out of doc id order.
+    UnsortedPostings { term: String, bucket: i16 },
+    /// Doc ids listed more than once in a bucket.
+    DuplicatePostings {
+        term: String,
+        bucket: i16,
+        duplicates: usize,
+    },
+    /// Stored document frequency different from the number of postings.
+    DocumentFrequencyDrift {
+        term: String,
+        bucket: i16,
+        stored: u64,
+        postings: usize,
+    },
+    /// Positions of doc ids missing from the postings.
+    PositionsWithoutPostings {
+        term: String,
+        bucket: i16,
+        doc_ids: Vec<DocId>,
+    },
+    /// Postings without positions, phrase queries can't match them.
+    PostingsWithoutPositions {
+        term: String,
+        bucket: i16,
+        doc_ids: Vec<DocId>,
+    },
+    /// Positions of a document out of order or repeated.
+    UnsortedPositions {
+        term: String,
+        bucket: i16,
+        doc_id: DocId,
+    },
+    /// Doc ids in more than one bucket of a term.
+    DuplicateAcrossBuckets { term: String, doc_ids: Vec<DocId> },
+    /// Doc ids that are neither tombstoned nor the doc id of an existing page.
+    DanglingDocIds {
+        term: String,
+        bucket: i16,
+        doc_ids: Vec<DocId>,
+    },
+    /// SPIMI blocks no merge cleaned up.
+    LeftoverBlocks { blocks: Vec<String> },
+    /// Checkpoints of a merge that didn't complete.
+    IncompleteCheckpoints { checkpoints: Vec<String> },
+    /// A generation an interrupted index run was building.
+    GenerationBuilding { generation: u64 },
+}
+
+/// The outcome of `IndexVerifier::verify`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    pub terms: usize,
+    pub buckets: usize,
+    pub issues: Vec<Issue>,
+    /// Every dangling doc id found, `repair` drops their postings.
+    pub dangling_doc_ids: BTreeSet<DocId>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Whether some bucket has an issue, which takes rewriting the index to repair.
+    pub fn has_bucket_issues(&self) -> bool {
+        self.issues.iter().any(Issue::is_bucket_issue)
+    }
+
+    /// Whether an interrupted index run left blocks, checkpoints or a generation behind.
+    pub fn has_run_leftovers(&self) -> bool {
+        self.issues.iter().any(|issue| !issue.is_bucket_issue())
+    }
+}
+
+impl Issue {
+    fn is_bucket_issue(&self) -> bool {
+        !matches!(
+            self,
+            Issue::LeftoverBlocks { .. }
+                | Issue::IncompleteCheckpoints { .. }
+                | Issue::GenerationBuilding { .. }
+        )
+    }
+}
+
+impl IndexVerifier {
+    pub fn from_storage(storage: Storage) -> Self {
+        Self { storage }
+    }
+
+    /// Checks every bucket of the current generation, then looks for the blocks, checkpoints and
+    /// generation an interrupted index run leaves behind. Reads every bucket once.
+    pub async fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let live = self.live_doc_ids().await?;
+        let deleted: HashSet<DocId> = self.storage.doc_ids.deleted().await?.into_iter().collect();
+
+        let terms: Vec<String> = self
+            .storage
+            .index
+            .term_document_frequencies()
+            .await?
+            .into_iter()
+            .map(|(term, _)| term)
+            .collect();
+        report.terms = terms.len();
+        for chunk in terms.chunks(TERMS_PER_FETCH) {
+            let mut term_doc_ids: HashMap<String, HashMap<DocId, usize>> = HashMap::new();
+            for doc in self.storage.index.find_by_terms(chunk).await? {
+                report.buckets += 1;
+                check_bucket(&doc, &mut report.issues);
+
+                let dangling: Vec<DocId> = doc
+                    .postings
+                    .iter()
+                    .copied()
+                    .filter(|doc_id| !live.contains(doc_id) && !deleted.contains(doc_id))
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .collect();
+                if !dangling.is_empty() {
+                    report.dangling_doc_ids.extend(&dangling);
+                    report.issues.push(Issue::DanglingDocIds {
+                        term: doc.term.clone(),
+                        bucket: doc.bucket,
+                        doc_ids: dangling,
+                    });
+                }
+
+                let unique: HashSet<DocId> = doc.postings.iter().copied().collect();
+                let buckets = term_doc_ids.entry(doc.term).or_default();
+                for doc_id in unique {
+                    *buckets.entry(doc_id).or_default() += 1;
+                }
+            }
+
+            let mut across: Vec<(String, Vec<DocId>)> = term_doc_ids
+                .into_iter()
+                .filter_map(|(term, doc_ids)| {
+                    let mut repeated: Vec<DocId> = doc_ids
+                        .into_iter()
+                        .filter(|(_, buckets)| *buckets > 1)
+                        .map(|(doc_id, _)| doc_id)
+                        .collect();
+                    repeated.sort_unstable();
+                    (!repeated.is_empty()).then_some((term, repeated))
+                })
+                .collect();
+            across.sort();
+            for (term, doc_ids) in across {
+                report
+                    .issues
+                    .push(Issue::DuplicateAcrossBuckets { term, doc_ids });
+            }
+        }
+
+        let blocks = self.storage.blocks.list_blocks().await?;
+        if !blocks.is_empty() {
+            report.issues.push(Issue::LeftoverBlocks { blocks });
+        }
+        let checkpoints: Vec<String> = self
+            .storage
+            .checkpoints
+            .get_incomplete()
+            .await?
+            .into_iter()
+            .map(|cp| cp.collection_name)
+            .collect();
+        if !checkpoints.is_empty() {
+            report
+                .issues
+                .push(Issue::IncompleteCheckpoints { checkpoints });
+        }
+        if let Some(generation) = self.storage.index.manifest().await?.building {
+            report.issues.push(Issue::GenerationBuilding { generation });
+        }
+        Ok(report)
+    }
+
+    /// Live doc ids whose page exists.
+    async fn live_doc_ids(&self) -> Result<HashSet<DocId>> {
+        let page_ids = self.storage.doc_ids.page_ids().await?;
+        let mut live = HashSet::with_capacity(page_ids.len());
+        for chunk in page_ids.chunks(PAGES_PER_FETCH) {
+            let ids: Vec<_> = chunk.iter().map(|(_, page_id)| *page_id).collect();
+            let existing: HashSet<_> = self
+                .storage
+                .pages
+                .find_by_ids(&ids)
+                .await?
+                .into_iter()
+                .map(|page| page.id)
+                .collect();
+            live.extend(
+                chunk
+                    .iter()
+                    .filter(|(_, page_id)| existing.contains(page_id))
+                    .map(|(doc_id, _)| *doc_id),
+            );
+        }
+        Ok(live)
+    }
+}
+
+/// Checks the postings, document frequency and positions of a single bucket.
+fn check_bucket(doc: &InvertedIndexDoc, issues: &mut Vec<Issue>) {
+    let (term, bucket) = (&doc.term, doc.bucket);
+    if doc.postings.windows(2).any(|pair| pair[0] > pair[1]) {
+        issues.push(Issue::UnsortedPostings {
+            term: term.clone(),
+            bucket,
+        });
+    }
+    let unique: BTreeSet<DocId> = doc.postings.iter().copied().collect();
+    if unique.len() != doc.postings.len() {
+        issues.push(Issue::DuplicatePostings {
+            term: term.clone(),
+            bucket,
+            duplicates: doc.postings.len() - unique.len(),
+        });
+    }
+    if doc.document_frequency != doc.postings.len() as u64 {
+        issues.push(Issue::DocumentFrequencyDrift {
+            term: term.clone(),
+            bucket,
+            stored: doc.document_frequency,
+            postings: doc.postings.len(),
+        });
+    }
+
+    let mut without_postings: Vec<DocId> = doc
+        .positions
+        .keys()
+        .filter(|doc_id| !unique.contains(doc_id))
+        .copied()
+        .collect();
+    if !without_postings.is_empty() {
+        without_postings.sort_unstable();
+        issues.push(Issue::PositionsWithoutPostings {
+            term: term.clone(),
+            bucket,
+            doc_ids: without_postings,
+        });
+    }
+    let without_positions: Vec<DocId> = unique
+        .iter()
+        .filter(|doc_id| !doc.positions.contains_key(doc_id))
+        .copied()
+        .collect();
+    if !without_positions.is_empty() {
+        issues.push(Issue::PostingsWithoutPositions {
+            term: term.clone(),
+            bucket,
+            doc_ids: without_positions,
+        });
+    }
+
+    let mut unsorted: Vec<DocId> = doc
+        .positions
+        .iter()
+        .filter(|(_, positions)| positions.windows(2).any(|pair| pair[0] >= pair[1]))
+        .map(|(doc_id, _)| *doc_id)
+        .collect();
+    unsorted.sort_unstable();
+    for doc_id in unsorted {
+        issues.push(Issue::UnsortedPositions {
+            term: term.clone(),
+            bucket,
+            doc_id,
+        });
+    }
+}
+
+/// The first doc ids of `doc_ids` and how many there are.
+struct DocIds<'a>(&'a [DocId]);
+
+impl fmt::Display for DocIds<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let listed: Vec<String> = self
+            .0
+            .iter()
+            .take(MAX_LISTED_DOC_IDS)
+            .map(DocId::to_string)
+            .collect();
+        write!(f, "{} doc ids [{}", self.0.len(), listed.join(", "))?;
+        if self.0.len() > MAX_LISTED_DOC_IDS {
+            write!(f, ", ...")?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::UnsortedPostings { term, bucket } => {
+                write!(f, "'{term}' bucket {bucket}: postings out of order")
+            }
+            Issue::DuplicatePostings {
+                term,
+                bucket,
+                duplicates,
+            } => write!(
+                f,
+                "'{term}' bucket {bucket}: {duplicates} duplicate postings"
+            ),
+            Issue::DocumentFrequencyDrift {
+                term,
+                bucket,
+                stored,
+                postings,
+            } => write!(
+                f,
+                "'{term}' bucket {bucket}: document frequency {stored} for {postings} postings"
+            ),
+            Issue::PositionsWithoutPostings {
+                term,
+                bucket,
+                doc_ids,
+            } => write!(
+                f,
+                "'{term}' bucket {bucket}: positions of {} without postings",
+                DocIds(doc_ids)
+            ),
+            Issue::PostingsWithoutPositions {
+                term,
+                bucket,
+                doc_ids,
+            } => write!(
+                f,
+                "'{term}' bucket {bucket}: postings of {} without positions",
+                DocIds(doc_ids)
+            ),
+            Issue::UnsortedPositions {
+                term,
+                bucket,
+                doc_id,
+            } => write!(
+                f,
+                "'{term}' bucket {bucket}: positions of doc {doc_id} out of order"
+            ),
+            Issue::DuplicateAcrossBuckets { term, doc_ids } => {
+                write!(f, "'{term}': {} in more than one bucket", DocIds(doc_ids))
+            }
+            Issue::DanglingDocIds {
+                term,
+                bucket,
+                doc_ids,
+            } => write!(
+                f,
+                "'{term}' bucket {bucket}: {} without a page",
+                DocIds(doc_ids)
+            ),
+            Issue::LeftoverBlocks { blocks } => write!(
+                f,
+                "{} SPIMI blocks left by an interrupted index run: {}",
+                blocks.len(),
+                blocks.join(", ")
+            ),
+            Issue::IncompleteCheckpoints { checkpoints } => write!(
+                f,
+                "{} incomplete merge checkpoints: {}",
+                checkpoints.len(),
+                checkpoints.join(", ")
+            ),
+            Issue::GenerationBuilding { generation } => write!(
+                f,
+                "generation {generation} is being built by an interrupted index run"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Verified {} terms in {} buckets: {} issues",
+            self.terms,
+            self.buckets,
+            self.issues.len()
+        )?;
+        for issue in &self.issues {
+            writeln!(f, "  {issue}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_models::Page;
+    use mongodb::bson::oid::ObjectId;
+
+    async fn storage_with_pages(pages: usize) -> Result<(Storage, Vec<ObjectId>, Vec<DocId>)> {
+        let storage = Storage::in_memory();
+        let mut page_ids: Vec<ObjectId> = Vec::new();
+        for i in 0..pages {
+            let page = Page::new(
+                format!("https://example.com/{i}"),
+                String::new(),
+                String::new(),
+                vec![],
+                0,
+                false,
+            );
+            page_ids.push(storage.pages.insert(&page).await?);
+        }
+        let doc_ids = storage.doc_ids.assign(&page_ids).await?;
+        Ok((storage, page_ids, doc_ids))
+    }
+
+    fn bucket(term: &str, bucket: i16, df: u64, postings: Vec<DocId>) -> InvertedIndexDoc {
+        let positions = postings.iter().map(|&doc_id| (doc_id, vec![0])).collect();
+        InvertedIndexDoc::new(term.to_string(), bucket, df, postings, positions)
+    }
+
+    #[tokio::test]
+    async fn test_clean_index() -> Result<()> {
+        let (storage, page_ids, doc_ids) = storage_with_pages(2).await?;
+        storage
+            .index
+            .insert(bucket("harvest", 0, 2, doc_ids.clone()))
+            .await?;
+        // postings of deleted pages wait for compaction, they're not dangling
+        storage.pages.delete_by_url("https://example.com/1").await?;
+        storage.doc_ids.delete_pages(&page_ids[1..]).await?;
+
+        let report = IndexVerifier::from_storage(storage).verify().await?;
+        assert!(report.is_clean(), "{report}");
+        assert_eq!((report.terms, report.buckets), (1, 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finds_corrupt_buckets() -> Result<()> {
+        let (storage, _, doc_ids) = storage_with_pages(3).await?;
+        let (a, b, c) = (doc_ids[0], doc_ids[1], doc_ids[2]);
+        let mut corrupt = bucket("harvest", 0, 5, vec![b, a, a]);
+        corrupt.positions.insert(c, vec![3, 1]);
+        corrupt.positions.remove(&b);
+        storage.index.insert(corrupt).await?;
+        storage
+            .index
+            .insert(bucket("harvest", 1, 2, vec![a, 99]))
+            .await?;
+        storage.blocks.seal_block("spimi_block_left").await?;
+        storage
+            .checkpoints
+            .get_or_create("spimi_block_left")
+            .await?;
+
+        let report = IndexVerifier::from_storage(storage).verify().await?;
+        let term = || "harvest".to_string();
+        assert_eq!(
+            report.issues,
+            vec![
+                Issue::UnsortedPostings {
+                    term: term(),
+                    bucket: 0
+                },
+                Issue::DuplicatePostings {
+                    term: term(),
+                    bucket: 0,
+                    duplicates: 1
+                },
+                Issue::DocumentFrequencyDrift {
+                    term: term(),
+                    bucket: 0,
+                    stored: 5,
+                    postings: 3
+                },
+                Issue::PositionsWithoutPostings {
+                    term: term(),
+                    bucket: 0,
+                    doc_ids: vec![c]
+                },
+                Issue::PostingsWithoutPositions {
+                    term: term(),
+                    bucket: 0,
+                    doc_ids: vec![b]
+                },
+                Issue::UnsortedPositions {
+                    term: term(),
+                    bucket: 0,
+                    doc_id: c
+                },
+                Issue::DanglingDocIds {
+                    term: term(),
+                    bucket: 1,
+                    doc_ids: vec![99]
+                },
+                Issue::DuplicateAcrossBuckets {
+                    term: term(),
+                    doc_ids: vec![a]
+                },
+                Issue::LeftoverBlocks {
+                    blocks: vec!["spimi_block_left".to_string()]
+                },
+                Issue::IncompleteCheckpoints {
+                    checkpoints: vec!["spimi_block_left".to_string()]
+                },
+            ]
+        );
+        assert_eq!(report.dangling_doc_ids, BTreeSet::from([99]));
+        Ok(())
+    }
+}
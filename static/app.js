@@ -5,29 +5,55 @@ const loadingSpinner = document.getElementById('loading-spinner');
 const errorMessage = document.getElementById('error-message');
 const resultsInfo = document.getElementById('results-info');
 const emptyState = document.getElementById('empty-state');
+const completionsList = document.getElementById('completions');
 
 // State
 let debounceTimer;
 const DEBOUNCE_DELAY = 500; // ms
+let completeTimer;
+const COMPLETE_DELAY = 100; // ms
 
 // Event Listeners
 searchInput.addEventListener('input', handleSearchInput);
 
 function handleSearchInput(e) {
     clearTimeout(debounceTimer);
+    clearTimeout(completeTimer);
     const query = e.target.value.trim();
 
     if (!query) {
+        completionsList.innerHTML = '';
         showEmptyState();
         return;
     }
 
+    // Completions use the raw value, a trailing space means the last word is finished
+    const partialQuery = e.target.value;
+    completeTimer = setTimeout(() => {
+        fetchCompletions(partialQuery);
+    }, COMPLETE_DELAY);
+
     // Debounce search
     debounceTimer = setTimeout(() => {
         performSearch(query);
     }, DEBOUNCE_DELAY);
 }
 
+async function fetchCompletions(partialQuery) {
+    try {
+        const response = await fetch(`/api/suggest?q=${encodeURIComponent(partialQuery)}`);
+        if (!response.ok) return;
+
+        const data = await response.json();
+        completionsList.innerHTML = data.completions
+            .map(completion => `<option value="${escapeHtml(completion)}"></option>`)
+            .join('');
+    } catch (error) {
+        // Autocomplete is best effort, the search itself still works
+        console.error('Autocomplete error:', error);
+    }
+}
+
 async function performSearch(query) {
     try {
         // Show loading state
@@ -78,6 +104,7 @@ function displayResults(data) {
                 <p>Try different keywords or check your spelling</p>
             </div>
         `;
+        resultsContainer.insertAdjacentHTML('afterbegin', renderSuggestions(data.suggestions));
         hideResultsInfo();
         return;
     }
@@ -86,7 +113,7 @@ function displayResults(data) {
     showResultsInfo(data.total_results, data.processing_time_ms);
 
     // Render result cards with highlighting
-    resultsContainer.innerHTML = data.results.map((result, index) => {
+    resultsContainer.innerHTML = renderSuggestions(data.suggestions) + data.results.map((result, index) => {
         // Highlight terms in snippet
         const highlightedSnippet = highlightTerms(result.snippet, data.highlighted_terms || []);
         
@@ -108,6 +135,25 @@ function displayResults(data) {
     }).join('');
 }
 
+// "Did you mean" links, clicking one searches for the corrected query
+function renderSuggestions(suggestions) {
+    if (!suggestions || suggestions.length === 0) return '';
+
+    const links = suggestions.map(suggestion =>
+        `<a href="#" class="suggestion" data-query="${escapeHtml(suggestion)}">${escapeHtml(suggestion)}</a>`
+    ).join(', ');
+    return `<div class="suggestions">Did you mean: ${links}?</div>`;
+}
+
+resultsContainer.addEventListener('click', (e) => {
+    const link = e.target.closest('.suggestion');
+    if (!link) return;
+
+    e.preventDefault();
+    searchInput.value = link.dataset.query;
+    performSearch(link.dataset.query);
+});
+
 // Highlight search terms in text
 function highlightTerms(text, terms) {
     // Strip HTML tags and get plain text content
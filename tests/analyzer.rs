@@ -429,6 +429,266 @@ mod tokenizer_tests {
             assert_eq!(result, vec!["test@example.com", "hello#world", "$100"]);
         }
     }
+
+    mod unicode_word_tokenizer {
+        use super::*;
+
+        fn words(tokenizer: &UnicodeWordTokenizer, text: &str) -> Vec<String> {
+            tokenizer.tokenize(text.to_string())
+        }
+
+        #[test]
+        fn test_empty_and_punctuation_only() {
+            let tokenizer = UnicodeWordTokenizer::default();
+            assert!(words(&tokenizer, "").is_empty());
+            assert!(words(&tokenizer, " -- !!! ... ").is_empty());
+        }
+
+        #[test]
+        fn test_latin_punctuation() {
+            let tokenizer = UnicodeWordTokenizer::default();
+            assert_eq!(
+                words(&tokenizer, "foo/bar (baz), \"quoted\" end."),
+                vec!["foo", "bar", "baz", "quoted", "end"]
+            );
+            assert_eq!(
+                words(&tokenizer, "Crème brûlée costs 3.50 today"),
+                vec!["Crème", "brûlée", "costs", "3.50", "today"]
+            );
+        }
+
+        #[test]
+        fn test_hyphens() {
+            let split = UnicodeWordTokenizer::default();
+            assert_eq!(
+                words(&split, "e-mail state-of-the-art"),
+                vec!["e", "mail", "state", "of", "the", "art"]
+            );
+            let joined = UnicodeWordTokenizer::default().with_hyphenated_words(true);
+            assert_eq!(
+                words(&joined, "e-mail state-of-the-art - dash -x y-"),
+                vec!["e-mail", "state-of-the-art", "dash", "x", "y"]
+            );
+        }
+
+        #[test]
+        fn test_apostrophes() {
+            let kept = UnicodeWordTokenizer::default();
+            assert_eq!(
+                words(&kept, "don't stop 'quoted' it’s"),
+                vec!["don't", "stop", "quoted", "it’s"]
+            );
+            let split = UnicodeWordTokenizer::default().with_apostrophes(false);
+            assert_eq!(
+                words(&split, "don't stop it’s"),
+                vec!["don", "t", "stop", "it", "s"]
+            );
+        }
+
+        #[test]
+        fn test_urls_and_emails() {
+            let text = "See https://example.com/a-b?q=1, (www.rust-lang.org) or mail bob.smith@example.co.uk.";
+            let split = UnicodeWordTokenizer::default();
+            assert!(words(&split, text).contains(&"example.com".to_string()));
+            assert!(!words(&split, text).contains(&"https://example.com/a-b?q=1".to_string()));
+
+            let kept = UnicodeWordTokenizer::default().with_urls_and_emails(true);
+            assert_eq!(
+                words(&kept, text),
+                vec![
+                    "See",
+                    "https://example.com/a-b?q=1",
+                    "www.rust-lang.org",
+                    "or",
+                    "mail",
+                    "bob.smith@example.co.uk"
+                ]
+            );
+            // an @ alone is not an email
+            assert_eq!(words(&kept, "@home a@b"), vec!["home", "a", "b"]);
+        }
+
+        #[test]
+        fn test_cyrillic() {
+            let tokenizer = UnicodeWordTokenizer::default();
+            assert_eq!(
+                words(&tokenizer, "Привет, мир! Поисковая-система."),
+                vec!["Привет", "мир", "Поисковая", "система"]
+            );
+        }
+
+        #[test]
+        fn test_arabic() {
+            let tokenizer = UnicodeWordTokenizer::default();
+            assert_eq!(
+                words(&tokenizer, "مرحبا بالعالم، محرك البحث."),
+                vec!["مرحبا", "بالعالم", "محرك", "البحث"]
+            );
+        }
+
+        #[test]
+        fn test_cjk() {
+            let tokenizer = UnicodeWordTokenizer::default();
+            // ideographs have no word boundaries of their own, each one is a word
+            assert_eq!(
+                words(&tokenizer, "搜索引擎。"),
+                vec!["搜", "索", "引", "擎"]
+            );
+            // katakana runs stay together
+            assert_eq!(words(&tokenizer, "カタカナ"), vec!["カタカナ"]);
+            // hangul words are separated by spaces
+            assert_eq!(
+                words(&tokenizer, "검색 엔진, Rust"),
+                vec!["검색", "엔진", "Rust"]
+            );
+        }
+
+        #[test]
+        fn test_analyzer_positions() {
+            let analyzer = TextAnalyzer::new(
+                vec![],
+                Box::new(UnicodeWordTokenizer::default()),
+                vec![Box::new(LowerCaseTokenFilter)],
+            );
+            let tokens = analyzer.analyze("Foo/Bar, baz".to_string()).unwrap();
+            let got: Vec<(&str, usize)> = tokens.iter().map(|t| (t.term.as_str(), t.pos)).collect();
+            assert_eq!(got, vec![("foo", 0), ("bar", 1), ("baz", 2)]);
+        }
+    }
+
+    mod cjk_bigram_tokenizer {
+        use super::*;
+
+        fn tokens(text: &str) -> Vec<String> {
+            CjkBigramTokenizer.tokenize(text.to_string())
+        }
+
+        #[test]
+        fn test_chinese_bigrams() {
+            assert_eq!(tokens("搜索引擎"), vec!["搜索", "索引", "引擎"]);
+        }
+
+        #[test]
+        fn test_single_character_stays_unigram() {
+            assert_eq!(tokens("书"), vec!["书"]);
+            assert_eq!(tokens("书。"), vec!["书"]);
+        }
+
+        #[test]
+        fn test_punctuation_breaks_runs() {
+            assert_eq!(tokens("搜索，引擎"), vec!["搜索", "引擎"]);
+        }
+
+        #[test]
+        fn test_japanese_kana_and_kanji() {
+            assert_eq!(tokens("東京タワー"), vec!["東京", "京タ", "タワ", "ワー"]);
+            assert_eq!(tokens("ひらがな"), vec!["ひら", "らが", "がな"]);
+        }
+
+        #[test]
+        fn test_korean() {
+            assert_eq!(
+                tokens("검색엔진 좋다"),
+                vec!["검색", "색엔", "엔진", "좋다"]
+            );
+        }
+
+        #[test]
+        fn test_mixed_scripts_keep_other_words() {
+            assert_eq!(
+                tokens("Rust语言 is 很快, don't worry"),
+                vec!["Rust", "语言", "is", "很快", "don't", "worry"]
+            );
+        }
+
+        #[test]
+        fn test_empty_and_whitespace() {
+            assert!(tokens("").is_empty());
+            assert!(tokens("  。 ").is_empty());
+        }
+    }
+
+    mod ngram_tokenizer {
+        use super::*;
+
+        #[test]
+        fn test_ngrams_by_start_then_length() {
+            let tokenizer = NGramTokenizer::new(2, 3);
+            assert_eq!(
+                tokenizer.tokenize("abcd".to_string()),
+                vec!["ab", "abc", "bc", "bcd", "cd"]
+            );
+        }
+
+        #[test]
+        fn test_words_shorter_than_min_gram_are_dropped() {
+            let tokenizer = NGramTokenizer::new(3, 3);
+            assert_eq!(
+                tokenizer.tokenize("a an the, moon".to_string()),
+                vec!["the", "moo", "oon"]
+            );
+        }
+
+        #[test]
+        fn test_counts_characters_not_bytes() {
+            let tokenizer = NGramTokenizer::new(2, 2);
+            assert_eq!(
+                tokenizer.tokenize("brûlé 搜索引".to_string()),
+                vec!["br", "rû", "ûl", "lé", "搜索", "索引"]
+            );
+        }
+
+        #[test]
+        fn test_default() {
+            assert_eq!(
+                NGramTokenizer::default().tokenize("abc".to_string()),
+                vec!["ab", "abc", "bc"]
+            );
+        }
+
+        #[test]
+        #[should_panic(expected = "min_gram")]
+        fn test_invalid_lengths() {
+            NGramTokenizer::new(3, 2);
+        }
+    }
+
+    mod edge_ngram_tokenizer {
+        use super::*;
+
+        #[test]
+        fn test_prefixes_of_every_word() {
+            let tokenizer = EdgeNGramTokenizer::new(1, 3);
+            assert_eq!(
+                tokenizer.tokenize("Harvest, io".to_string()),
+                vec!["H", "Ha", "Har", "i", "io"]
+            );
+        }
+
+        #[test]
+        fn test_min_gram() {
+            let tokenizer = EdgeNGramTokenizer::new(2, 4);
+            assert_eq!(
+                tokenizer.tokenize("a moon".to_string()),
+                vec!["mo", "moo", "moon"]
+            );
+        }
+
+        #[test]
+        fn test_cjk_prefixes() {
+            let tokenizer = EdgeNGramTokenizer::new(1, 2);
+            assert_eq!(
+                tokenizer.tokenize("搜索引擎".to_string()),
+                vec!["搜", "搜索"]
+            );
+        }
+
+        #[test]
+        #[should_panic(expected = "min_gram")]
+        fn test_invalid_lengths() {
+            EdgeNGramTokenizer::new(0, 2);
+        }
+    }
 }
 
 // TokenFilter Tests
@@ -7,17 +7,17 @@ use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use harvest::data_models::{InvertedIndexDoc, MergeCheckpoint, Page, SpimiDoc};
-use harvest::db::{Database, MergeCheckpointRepo, PageRepo};
+use harvest::data_models::{DocId, InvertedIndexDoc, MergeCheckpoint, Page, SpimiDoc};
+use harvest::db::{Database, InvertedIndexRepo, MergeCheckpointRepo, PageRepo};
 use harvest::indexer::{DictItem, Indexer, SpimiBlock, merge_sorted_lists_dedup};
+use harvest::storage::BlockStore;
+use harvest::storage::file::FileBlockStore;
 
 /// Constant matching the one in indexer.rs for test verification.
 /// MongoDB has a 16MB document limit, so we use 100K docs per chunk (~5MB documents).
 const DOCIDS_PER_MONGO_DOCUMENT: usize = 100_000;
 
 mod test_helpers {
-    use mongodb::bson;
-
     use super::*;
     use std::sync::atomic::{AtomicUsize, Ordering};
 
@@ -61,34 +61,23 @@ mod test_helpers {
         )
     }
 
-    /// Generate a vector of sorted ObjectIds for testing.
-    /// Uses incrementing hex strings to ensure deterministic ordering.
-    pub fn generate_sorted_object_ids(count: usize) -> Vec<ObjectId> {
-        (0..count)
-            .map(|i| {
-                // Create a 24-character hex string with the index padded
-                let hex = format!("{:024x}", i);
-                ObjectId::parse_str(&hex).unwrap()
-            })
-            .collect()
+    /// Generate a vector of sorted doc ids for testing, `0..count`.
+    pub fn generate_sorted_doc_ids(count: usize) -> Vec<DocId> {
+        (0..count as DocId).collect()
     }
 
-    /// Generate ObjectIds with a prefix to ensure they sort in a specific range.
-    /// Useful for creating distinct ranges across multiple blocks.
-    pub fn generate_sorted_object_ids_with_prefix(count: usize, prefix: u32) -> Vec<ObjectId> {
-        (0..count)
-            .map(|i| {
-                // Use prefix in high bits, index in low bits
-                let hex = format!("{:08x}{:016x}", prefix, i);
-                ObjectId::parse_str(&hex).unwrap()
-            })
+    /// Generate doc ids with a prefix to ensure they sort in a specific range.
+    /// Useful for creating distinct ranges across multiple blocks, `count` must stay below 1M.
+    pub fn generate_sorted_doc_ids_with_prefix(count: usize, prefix: u32) -> Vec<DocId> {
+        (0..count as DocId)
+            .map(|i| prefix * 1_000_000 + i)
             .collect()
     }
 
     /// Create a DictItem with postings and positions.
     /// Each doc_id gets positions starting from `base_position`.
     pub fn create_dict_item_with_positions(
-        doc_ids: &[ObjectId],
+        doc_ids: &[DocId],
         positions_per_doc: usize,
         base_position: usize,
     ) -> DictItem {
@@ -110,7 +99,7 @@ mod test_helpers {
     /// Create a SpimiBlock with a single term and many documents.
     pub fn create_large_block_single_term(
         term: &str,
-        doc_ids: &[ObjectId],
+        doc_ids: &[DocId],
         positions_per_doc: usize,
     ) -> SpimiBlock {
         let dict_item = create_dict_item_with_positions(doc_ids, positions_per_doc, 0);
@@ -124,9 +113,7 @@ mod test_helpers {
     }
 
     /// Create a SpimiBlock with multiple terms, each with their own doc_ids.
-    pub fn create_block_with_terms(
-        terms_with_docs: Vec<(&str, Vec<ObjectId>, usize)>,
-    ) -> SpimiBlock {
+    pub fn create_block_with_terms(terms_with_docs: Vec<(&str, Vec<DocId>, usize)>) -> SpimiBlock {
         let mut dictionary = HashMap::new();
         let mut sorted_terms = Vec::new();
 
@@ -144,27 +131,10 @@ mod test_helpers {
         }
     }
 
-    /// Get all SpimiDoc documents from a collection
-    pub async fn get_spimi_docs_from_collection(
-        db: &Database,
-        collection_name: &str,
-        mut projection: Option<bson::Document>,
-    ) -> Result<Vec<SpimiDoc>> {
-        let default_projection = doc! {
-            "term":1,
-            "id": 1,
-            "bucket": 1,
-            "document_frequency": 1,
-            "postings": 1,
-            "positions": 1
-        };
-        if projection.is_none() {
-            projection = Some(default_projection);
-        }
-        let collection = db.collection::<SpimiDoc>(collection_name);
-        let docs: Vec<SpimiDoc> = collection
-            .find(doc! {})
-            .projection(projection.unwrap())
+    /// Get all SpimiDoc documents of a block, in (term, bucket) order
+    pub async fn get_spimi_docs_from_block(db: &Database, block: &str) -> Result<Vec<SpimiDoc>> {
+        let docs: Vec<SpimiDoc> = FileBlockStore::for_database(db)
+            .read_block(block, None)
             .await?
             .try_collect()
             .await?;
@@ -187,7 +157,7 @@ mod test_helpers {
         if projection.is_none() {
             projection = Some(default_projection);
         }
-        let collection = db.collection::<InvertedIndexDoc>("inverted_index");
+        let collection = InvertedIndexRepo::new(db).collection().await?;
         let docs: Vec<InvertedIndexDoc> = collection
             .find(doc! {})
             .projection(projection.unwrap())
@@ -202,7 +172,7 @@ mod test_helpers {
         db: &Database,
         term: &str,
     ) -> Result<Vec<InvertedIndexDoc>> {
-        let collection = db.collection::<InvertedIndexDoc>("inverted_index");
+        let collection = InvertedIndexRepo::new(db).collection().await?;
         let docs: Vec<InvertedIndexDoc> = collection
             .find(doc! { "term": term })
             .await?
@@ -211,26 +181,14 @@ mod test_helpers {
         Ok(docs)
     }
 
-    /// Count SPIMI block collections in the database
-    pub async fn count_spimi_block_collections(db: &Database) -> Result<usize> {
-        let filter = doc! {
-            "name": {
-                "$regex": r"^spimi_block_"
-            }
-        };
-        let collections = db.database().list_collection_names().filter(filter).await?;
-        Ok(collections.len())
+    /// Count the SPIMI blocks of the database
+    pub async fn count_spimi_blocks(db: &Database) -> Result<usize> {
+        Ok(get_spimi_block_names(db).await?.len())
     }
 
-    /// Get all SPIMI block collection names
-    pub async fn get_spimi_block_collection_names(db: &Database) -> Result<Vec<String>> {
-        let filter = doc! {
-            "name": {
-                "$regex": r"^spimi_block_"
-            }
-        };
-        let collections = db.database().list_collection_names().filter(filter).await?;
-        Ok(collections)
+    /// Get all SPIMI block names of the database
+    pub async fn get_spimi_block_names(db: &Database) -> Result<Vec<String>> {
+        FileBlockStore::for_database(db).list_blocks().await
     }
 }
 
@@ -269,7 +227,7 @@ async fn test_pages_to_token_stream_single_page_single_term() -> Result<()> {
     let page = create_test_page("http://example.com", "elephant");
     let pages: Vec<Arc<Page>> = vec![Arc::new(page.clone())];
 
-    indexer.pages_to_token_stream(&pages)?;
+    indexer.pages_to_token_stream(&pages).await?;
 
     let tokens = indexer.drain_tokens().await;
     // Should have at least 1 token after filtering
@@ -292,7 +250,7 @@ async fn test_pages_to_token_stream_single_page_multiple_terms() -> Result<()> {
     let page = create_test_page("http://example.com", "elephant giraffe zebra penguin");
     let pages: Vec<Arc<Page>> = vec![Arc::new(page.clone())];
 
-    indexer.pages_to_token_stream(&pages)?;
+    indexer.pages_to_token_stream(&pages).await?;
 
     let tokens = indexer.drain_tokens().await;
     // Should have multiple tokens (may be less than 4 due to stemming/filtering)
@@ -316,7 +274,7 @@ async fn test_pages_to_token_stream_multiple_pages() -> Result<()> {
     let page2 = create_test_page("http://example2.com", "zebra penguin");
     let pages: Vec<Arc<Page>> = vec![Arc::new(page1.clone()), Arc::new(page2.clone())];
 
-    indexer.pages_to_token_stream(&pages)?;
+    indexer.pages_to_token_stream(&pages).await?;
 
     let tokens = indexer.drain_tokens().await;
     // Should have multiple tokens from both pages
@@ -337,7 +295,7 @@ async fn test_pages_to_token_stream_empty_pages() -> Result<()> {
     let mut indexer = Indexer::new(pages_repo, 100, db.clone());
 
     let pages: Vec<Arc<Page>> = vec![];
-    indexer.pages_to_token_stream(&pages)?;
+    indexer.pages_to_token_stream(&pages).await?;
 
     let tokens = indexer.drain_tokens().await;
     assert!(tokens.is_empty());
@@ -355,7 +313,7 @@ async fn test_pages_to_token_stream_page_with_empty_content() -> Result<()> {
     let page = create_test_page("http://example.com", "");
     let pages: Vec<Arc<Page>> = vec![Arc::new(page)];
 
-    indexer.pages_to_token_stream(&pages)?;
+    indexer.pages_to_token_stream(&pages).await?;
 
     let tokens = indexer.drain_tokens().await;
     assert!(tokens.is_empty());
@@ -373,7 +331,7 @@ async fn test_pages_to_token_stream_preserves_order() -> Result<()> {
     let page = create_test_page("http://example.com", "alpha beta gamma delta");
     let pages: Vec<Arc<Page>> = vec![Arc::new(page)];
 
-    indexer.pages_to_token_stream(&pages)?;
+    indexer.pages_to_token_stream(&pages).await?;
 
     let tokens = indexer.drain_tokens().await;
     assert_eq!(tokens.len(), 4);
@@ -391,7 +349,7 @@ async fn test_pages_to_token_stream_handles_extra_whitespace() -> Result<()> {
     let page = create_test_page("http://example.com", "elephant   giraffe\t\tzebra\npenguin");
     let pages: Vec<Arc<Page>> = vec![Arc::new(page)];
 
-    indexer.pages_to_token_stream(&pages)?;
+    indexer.pages_to_token_stream(&pages).await?;
 
     let tokens = indexer.drain_tokens().await;
     // Whitespace should be normalized, should have multiple tokens
@@ -431,7 +389,7 @@ async fn test_spimi_invert_single_token() -> Result<()> {
     let pages_repo = Arc::new(PageRepo::new(&db));
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
-    let doc_id = ObjectId::new();
+    let doc_id: DocId = 1;
     let mut dictionary = HashMap::new();
     let mut dict_item = DictItem::new();
     dict_item.postings.push(doc_id);
@@ -454,9 +412,9 @@ async fn test_spimi_invert_multiple_tokens_same_term() -> Result<()> {
     let pages_repo = Arc::new(PageRepo::new(&db));
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
-    let doc_id1 = ObjectId::new();
-    let doc_id2 = ObjectId::new();
-    let doc_id3 = ObjectId::new();
+    let doc_id1: DocId = 1;
+    let doc_id2: DocId = 2;
+    let doc_id3: DocId = 3;
 
     let mut dictionary = HashMap::new();
     let mut dict_item = DictItem::new();
@@ -484,7 +442,7 @@ async fn test_spimi_invert_multiple_tokens_different_terms() -> Result<()> {
     let pages_repo = Arc::new(PageRepo::new(&db));
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
-    let doc_id = ObjectId::new();
+    let doc_id: DocId = 1;
 
     let mut dictionary = HashMap::new();
 
@@ -535,7 +493,8 @@ async fn test_spimi_invert_triggers_flush_on_budget_exceeded() -> Result<()> {
     let collections = db.database().list_collection_names().await?;
 
     // Should have created the inverted_index collection
-    let has_inverted_index = collections.iter().any(|name| name == "inverted_index");
+    let index = InvertedIndexRepo::new(&db).collection().await?;
+    let has_inverted_index = collections.iter().any(|name| name == index.name());
     assert!(has_inverted_index, "Should have created inverted index");
 
     cleanup_test_db(&db, &db_name).await?;
@@ -567,7 +526,7 @@ async fn test_persist_block_to_disk_single_term() -> Result<()> {
     let pages_repo = Arc::new(PageRepo::new(&db));
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
-    let doc_id = ObjectId::new();
+    let doc_id: DocId = 1;
     let mut dictionary = HashMap::new();
     let mut dict_item = DictItem::new();
     dict_item.postings.push(doc_id);
@@ -581,13 +540,10 @@ async fn test_persist_block_to_disk_single_term() -> Result<()> {
 
     indexer.persist_block_to_disk(block).await?;
 
-    let collections = db.database().list_collection_names().await?;
-    let has_spimi_block = collections
-        .iter()
-        .any(|name| name.starts_with("spimi_block_"));
-    assert!(
-        has_spimi_block,
-        "Should have created a SPIMI block collection"
+    assert_eq!(
+        count_spimi_blocks(&db).await?,
+        1,
+        "Should have created a SPIMI block"
     );
 
     cleanup_test_db(&db, &db_name).await?;
@@ -600,8 +556,8 @@ async fn test_persist_block_to_disk_multiple_terms() -> Result<()> {
     let pages_repo = Arc::new(PageRepo::new(&db));
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
-    let doc_id1 = ObjectId::new();
-    let doc_id2 = ObjectId::new();
+    let doc_id1: DocId = 1;
+    let doc_id2: DocId = 2;
 
     let mut dictionary = HashMap::new();
 
@@ -642,7 +598,7 @@ async fn test_persist_block_to_disk_verifies_data_stored() -> Result<()> {
     let pages_repo = Arc::new(PageRepo::new(&db));
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
-    let doc_id = ObjectId::new();
+    let doc_id: DocId = 1;
     let mut dictionary = HashMap::new();
     let mut dict_item = DictItem::new();
     dict_item.postings.push(doc_id);
@@ -656,19 +612,9 @@ async fn test_persist_block_to_disk_verifies_data_stored() -> Result<()> {
 
     indexer.persist_block_to_disk(block).await?;
 
-    let collections = db.database().list_collection_names().await?;
-    let spimi_collection = collections
-        .iter()
-        .find(|name| name.starts_with("spimi_block_"))
-        .expect("Should have a SPIMI block collection");
-
-    use futures::stream::TryStreamExt;
-    let collection = db.collection::<SpimiDoc>(spimi_collection);
-    let docs: Vec<SpimiDoc> = collection
-        .find(mongodb::bson::doc! {})
-        .await?
-        .try_collect()
-        .await?;
+    let blocks = get_spimi_block_names(&db).await?;
+    let spimi_block = blocks.first().expect("Should have a SPIMI block");
+    let docs = get_spimi_docs_from_block(&db, spimi_block).await?;
 
     assert_eq!(docs.len(), 1);
     assert_eq!(docs[0].term, "testterm");
@@ -693,21 +639,17 @@ async fn test_persist_block_to_disk_single_term_300k_docs() -> Result<()> {
 
     // Use 3x the chunk size (300K docs) to create 3 buckets
     let doc_count = DOCIDS_PER_MONGO_DOCUMENT * 3; // 300K
-    let doc_ids = generate_sorted_object_ids(doc_count);
+    let doc_ids = generate_sorted_doc_ids(doc_count);
     let block = create_large_block_single_term("massive_term", &doc_ids, 1);
 
     indexer.persist_block_to_disk(block).await?;
 
     // Verify collection was created
-    let collections = get_spimi_block_collection_names(&db).await?;
-    assert_eq!(
-        collections.len(),
-        1,
-        "Should have exactly 1 SPIMI block collection"
-    );
+    let blocks = get_spimi_block_names(&db).await?;
+    assert_eq!(blocks.len(), 1, "Should have exactly 1 SPIMI block");
 
-    let spimi_collection = &collections[0];
-    let docs = get_spimi_docs_from_collection(&db, spimi_collection, Option::None).await?;
+    let spimi_block = &blocks[0];
+    let docs = get_spimi_docs_from_block(&db, spimi_block).await?;
 
     // Should have 3 documents (buckets 0, 1, 2)
     assert_eq!(
@@ -760,13 +702,13 @@ async fn test_persist_block_to_disk_exactly_100k_docs() -> Result<()> {
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
     let doc_count = DOCIDS_PER_MONGO_DOCUMENT; // Exactly 100K
-    let doc_ids = generate_sorted_object_ids(doc_count);
+    let doc_ids = generate_sorted_doc_ids(doc_count);
     let block = create_large_block_single_term("exact_100k_term", &doc_ids, 1);
 
     indexer.persist_block_to_disk(block).await?;
 
-    let collections = get_spimi_block_collection_names(&db).await?;
-    let docs = get_spimi_docs_from_collection(&db, &collections[0], Option::None).await?;
+    let blocks = get_spimi_block_names(&db).await?;
+    let docs = get_spimi_docs_from_block(&db, &blocks[0]).await?;
 
     assert_eq!(
         docs.len(),
@@ -789,13 +731,13 @@ async fn test_persist_block_to_disk_100k_plus_1_docs() -> Result<()> {
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
     let doc_count = DOCIDS_PER_MONGO_DOCUMENT + 1; // 100K + 1
-    let doc_ids = generate_sorted_object_ids(doc_count);
+    let doc_ids = generate_sorted_doc_ids(doc_count);
     let block = create_large_block_single_term("overflow_term", &doc_ids, 1);
 
     indexer.persist_block_to_disk(block).await?;
 
-    let collections = get_spimi_block_collection_names(&db).await?;
-    let docs = get_spimi_docs_from_collection(&db, &collections[0], Option::None).await?;
+    let blocks = get_spimi_block_names(&db).await?;
+    let docs = get_spimi_docs_from_block(&db, &blocks[0]).await?;
 
     assert_eq!(docs.len(), 2, "Should have 2 SpimiDoc for 100K+1 docs");
 
@@ -833,7 +775,7 @@ async fn test_persist_block_to_disk_multiple_terms_multi_bucket() -> Result<()>
     let mut sorted_terms = Vec::new();
 
     for (idx, (term, count)) in term_configs.iter().enumerate() {
-        let doc_ids = generate_sorted_object_ids_with_prefix(*count, idx as u32);
+        let doc_ids = generate_sorted_doc_ids_with_prefix(*count, idx as u32);
         let dict_item = create_dict_item_with_positions(&doc_ids, 1, 0);
         dictionary.insert(term.to_string(), dict_item);
         sorted_terms.push(term.to_string());
@@ -847,8 +789,8 @@ async fn test_persist_block_to_disk_multiple_terms_multi_bucket() -> Result<()>
 
     indexer.persist_block_to_disk(block).await?;
 
-    let collections = get_spimi_block_collection_names(&db).await?;
-    let docs = get_spimi_docs_from_collection(&db, &collections[0], Option::None).await?;
+    let blocks = get_spimi_block_names(&db).await?;
+    let docs = get_spimi_docs_from_block(&db, &blocks[0]).await?;
 
     // Count documents per term
     let mut term_doc_counts: HashMap<String, Vec<&SpimiDoc>> = HashMap::new();
@@ -913,7 +855,7 @@ async fn test_persist_block_to_disk_positions_across_buckets() -> Result<()> {
 
     // Create 250K docs with positions (3 buckets at 100K each)
     let doc_count = 250_000;
-    let doc_ids = generate_sorted_object_ids(doc_count);
+    let doc_ids = generate_sorted_doc_ids(doc_count);
 
     // Each doc has 2 positions
     let dict_item = create_dict_item_with_positions(&doc_ids, 2, 100);
@@ -927,8 +869,8 @@ async fn test_persist_block_to_disk_positions_across_buckets() -> Result<()> {
 
     indexer.persist_block_to_disk(block).await?;
 
-    let collections = get_spimi_block_collection_names(&db).await?;
-    let docs = get_spimi_docs_from_collection(&db, &collections[0], Option::None).await?;
+    let blocks = get_spimi_block_names(&db).await?;
+    let docs = get_spimi_docs_from_block(&db, &blocks[0]).await?;
 
     assert_eq!(docs.len(), 3, "Should have 3 buckets for 250K docs");
 
@@ -992,7 +934,7 @@ async fn test_persist_block_to_disk_multiple_positions_per_doc() -> Result<()> {
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
     let doc_count = 100_000;
-    let doc_ids = generate_sorted_object_ids(doc_count);
+    let doc_ids = generate_sorted_doc_ids(doc_count);
 
     // Each document has 10 positions (word appears 10 times in each doc)
     let dict_item = create_dict_item_with_positions(&doc_ids, 10, 0);
@@ -1006,8 +948,8 @@ async fn test_persist_block_to_disk_multiple_positions_per_doc() -> Result<()> {
 
     indexer.persist_block_to_disk(block).await?;
 
-    let collections = get_spimi_block_collection_names(&db).await?;
-    let docs = get_spimi_docs_from_collection(&db, &collections[0], Option::None).await?;
+    let blocks = get_spimi_block_names(&db).await?;
+    let docs = get_spimi_docs_from_block(&db, &blocks[0]).await?;
 
     assert_eq!(docs.len(), 1, "Should have 1 bucket for 100K docs");
 
@@ -1036,7 +978,7 @@ async fn test_persist_block_to_disk_term_not_in_dictionary() -> Result<()> {
         sorted_terms: vec!["ghost_term".to_string(), "real_term".to_string()],
         dictionary: {
             let mut d = HashMap::new();
-            let doc_id = ObjectId::new();
+            let doc_id: DocId = 1;
             let mut dict_item = DictItem::new();
             dict_item.postings.push(doc_id);
             dict_item.positions.insert(doc_id, vec![0]);
@@ -1050,8 +992,8 @@ async fn test_persist_block_to_disk_term_not_in_dictionary() -> Result<()> {
     indexer.persist_block_to_disk(block).await?;
 
     // Verify only real_term was persisted
-    let collections = get_spimi_block_collection_names(&db).await?;
-    let docs = get_spimi_docs_from_collection(&db, &collections[0], Option::None).await?;
+    let blocks = get_spimi_block_names(&db).await?;
+    let docs = get_spimi_docs_from_block(&db, &blocks[0]).await?;
 
     assert_eq!(docs.len(), 1, "Only real_term should be persisted");
     assert_eq!(docs[0].term, "real_term");
@@ -1069,13 +1011,13 @@ async fn test_persist_block_to_disk_single_term_500k_docs() -> Result<()> {
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
     let doc_count = DOCIDS_PER_MONGO_DOCUMENT * 5; // 500K
-    let doc_ids = generate_sorted_object_ids(doc_count);
+    let doc_ids = generate_sorted_doc_ids(doc_count);
     let block = create_large_block_single_term("huge_term", &doc_ids, 1);
 
     indexer.persist_block_to_disk(block).await?;
 
-    let collections = get_spimi_block_collection_names(&db).await?;
-    let docs = get_spimi_docs_from_collection(&db, &collections[0], Option::None).await?;
+    let blocks = get_spimi_block_names(&db).await?;
+    let docs = get_spimi_docs_from_block(&db, &blocks[0]).await?;
 
     // Should have 5 documents (buckets 0, 1, 2, 3, 4)
     assert_eq!(
@@ -1099,31 +1041,30 @@ async fn test_persist_block_to_disk_single_term_500k_docs() -> Result<()> {
     Ok(())
 }
 
-/// Test that MongoDB index is created on the term field.
+/// Test that a persisted block is a sealed file whose documents read back in term order.
 #[tokio::test]
-async fn test_persist_block_to_disk_creates_term_index() -> Result<()> {
+async fn test_persist_block_to_disk_writes_sorted_file() -> Result<()> {
     let (db, db_name) = create_test_db().await?;
     let pages_repo = Arc::new(PageRepo::new(&db));
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
-    let doc_ids = generate_sorted_object_ids(1000);
-    let block = create_large_block_single_term("indexed_term", &doc_ids, 1);
-
+    let block = create_block_with_terms(vec![
+        ("zebra", generate_sorted_doc_ids(10), 1),
+        ("apple", generate_sorted_doc_ids(10), 1),
+        ("mango", generate_sorted_doc_ids(10), 1),
+    ]);
     indexer.persist_block_to_disk(block).await?;
 
-    let collections = get_spimi_block_collection_names(&db).await?;
-    let collection = db.collection::<SpimiDoc>(&collections[0]);
+    let blocks = get_spimi_block_names(&db).await?;
+    let block_file = FileBlockStore::for_database(&db)
+        .dir()
+        .join(&blocks[0])
+        .with_extension("blk");
+    assert!(block_file.exists(), "Block should be sealed to its file");
 
-    // List indexes on the collection
-    use futures::TryStreamExt;
-    let indexes: Vec<_> = collection.list_indexes().await?.try_collect().await?;
-
-    // Should have at least 2 indexes: _id (default) and term
-    assert!(indexes.len() >= 2, "Should have at least 2 indexes");
-
-    // Verify term index exists
-    let has_term_index = indexes.iter().any(|idx| idx.keys.get("term").is_some());
-    assert!(has_term_index, "Should have an index on 'term' field");
+    let docs = get_spimi_docs_from_block(&db, &blocks[0]).await?;
+    let terms: Vec<&str> = docs.iter().map(|doc| doc.term.as_str()).collect();
+    assert_eq!(terms, vec!["apple", "mango", "zebra"]);
 
     cleanup_test_db(&db, &db_name).await?;
     Ok(())
@@ -1158,7 +1099,8 @@ async fn test_spin_indexer_with_pages_in_db() -> Result<()> {
 
     // Verify inverted index was created
     let collections = db.database().list_collection_names().await?;
-    let has_inverted_index = collections.iter().any(|name| name == "inverted_index");
+    let index = InvertedIndexRepo::new(&db).collection().await?;
+    let has_inverted_index = collections.iter().any(|name| name == index.name());
     assert!(has_inverted_index, "Should have created inverted index");
 
     cleanup_test_db(&db, &db_name).await?;
@@ -1181,7 +1123,8 @@ async fn test_spin_indexer_pagination() -> Result<()> {
 
     // Verify inverted index was created
     let collections = db.database().list_collection_names().await?;
-    let has_inverted_index = collections.iter().any(|name| name == "inverted_index");
+    let index = InvertedIndexRepo::new(&db).collection().await?;
+    let has_inverted_index = collections.iter().any(|name| name == index.name());
     assert!(has_inverted_index, "Should have created inverted index");
 
     cleanup_test_db(&db, &db_name).await?;
@@ -1220,7 +1163,7 @@ fn test_merge_sorted_lists_large_lists() {
 #[test]
 fn test_spimi_block_creation() {
     let mut dictionary = HashMap::new();
-    let doc_id = ObjectId::new();
+    let doc_id: DocId = 1;
     let mut dict_item = DictItem::new();
     dict_item.postings.push(doc_id);
     dict_item.positions.insert(doc_id, vec![0]);
@@ -1238,7 +1181,7 @@ fn test_spimi_block_creation() {
 #[test]
 fn test_spimi_block_with_multiple_postings() {
     let mut dictionary = HashMap::new();
-    let doc_ids: Vec<ObjectId> = (0..10).map(|_| ObjectId::new()).collect();
+    let doc_ids: Vec<DocId> = (0..10).collect();
     let mut dict_item = DictItem::new();
     for (i, &doc_id) in doc_ids.iter().enumerate() {
         dict_item.postings.push(doc_id);
@@ -1275,7 +1218,8 @@ async fn test_merge_persisted_blocks_no_blocks() -> Result<()> {
 
     // Verify no inverted_index collection was created (or it's empty)
     let collections = db.database().list_collection_names().await?;
-    let has_inverted_index = collections.iter().any(|n| n == "inverted_index");
+    let index = InvertedIndexRepo::new(&db).collection().await?;
+    let has_inverted_index = collections.iter().any(|n| n == index.name());
 
     // It's acceptable to either not create the collection or have it empty
     if has_inverted_index {
@@ -1301,20 +1245,20 @@ async fn test_merge_persisted_blocks_6_blocks_same_term() -> Result<()> {
     // Create 6 blocks, each with the same term but different doc_ids
     let docs_per_block = 100_000;
     for i in 0..6 {
-        let doc_ids = generate_sorted_object_ids_with_prefix(docs_per_block, i as u32);
+        let doc_ids = generate_sorted_doc_ids_with_prefix(docs_per_block, i as u32);
         let block = create_large_block_single_term("common_term", &doc_ids, 1);
         indexer.persist_block_to_disk(block).await?;
     }
 
     // Verify 6 blocks were created
-    let block_count = count_spimi_block_collections(&db).await?;
+    let block_count = count_spimi_blocks(&db).await?;
     assert_eq!(block_count, 6, "Should have 6 SPIMI blocks before merge");
 
     // Merge the blocks
     indexer.merge_persisted_blocks().await?;
 
     // Verify SPIMI blocks are cleaned up
-    let block_count_after = count_spimi_block_collections(&db).await?;
+    let block_count_after = count_spimi_blocks(&db).await?;
     assert_eq!(
         block_count_after, 0,
         "SPIMI blocks should be deleted after merge"
@@ -1351,7 +1295,7 @@ async fn test_merge_persisted_blocks_6_blocks_500k_total_docs() -> Result<()> {
     // This will force mid-merge flushes (5 buckets at 100K each)
     let docs_per_block = 84_000; // ~84K * 6 = ~504K
     for i in 0..6 {
-        let doc_ids = generate_sorted_object_ids_with_prefix(docs_per_block, i as u32);
+        let doc_ids = generate_sorted_doc_ids_with_prefix(docs_per_block, i as u32);
         let block = create_large_block_single_term("large_term", &doc_ids, 1);
         indexer.persist_block_to_disk(block).await?;
     }
@@ -1388,7 +1332,7 @@ async fn test_merge_persisted_blocks_6_blocks_500k_total_docs() -> Result<()> {
     );
 
     // Verify SPIMI blocks are cleaned up
-    let block_count = count_spimi_block_collections(&db).await?;
+    let block_count = count_spimi_blocks(&db).await?;
     assert_eq!(block_count, 0, "SPIMI blocks should be deleted after merge");
 
     cleanup_test_db(&db, &db_name).await?;
@@ -1423,7 +1367,7 @@ async fn test_merge_persisted_blocks_interleaved_terms() -> Result<()> {
         let mut terms_with_docs = Vec::new();
         for (term_idx, term) in terms.iter().enumerate() {
             let prefix = (block_idx * 100 + term_idx) as u32;
-            let doc_ids = generate_sorted_object_ids_with_prefix(10_000, prefix);
+            let doc_ids = generate_sorted_doc_ids_with_prefix(10_000, prefix);
             terms_with_docs.push((*term, doc_ids, 1));
         }
         let block = create_block_with_terms(terms_with_docs);
@@ -1480,11 +1424,11 @@ async fn test_merge_persisted_blocks_positions_vacant_entry() -> Result<()> {
 
     // Create 2 blocks with completely different doc_ids for the same term
     // This ensures all position entries are Vacant during merge
-    let doc_ids_1 = generate_sorted_object_ids_with_prefix(1000, 1);
+    let doc_ids_1 = generate_sorted_doc_ids_with_prefix(1000, 1);
     let block1 = create_large_block_single_term("unique_docs_term", &doc_ids_1, 2);
     indexer.persist_block_to_disk(block1).await?;
 
-    let doc_ids_2 = generate_sorted_object_ids_with_prefix(1000, 2);
+    let doc_ids_2 = generate_sorted_doc_ids_with_prefix(1000, 2);
     let block2 = create_large_block_single_term("unique_docs_term", &doc_ids_2, 2);
     indexer.persist_block_to_disk(block2).await?;
 
@@ -1518,7 +1462,7 @@ async fn test_merge_persisted_blocks_positions_occupied_entry() -> Result<()> {
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
     // Create shared doc_ids that will appear in multiple blocks
-    let shared_doc_ids = generate_sorted_object_ids(500);
+    let shared_doc_ids = generate_sorted_doc_ids(500);
 
     // Block 1: shared docs with positions [0, 1]
     let dict_item_1 = create_dict_item_with_positions(&shared_doc_ids, 2, 0);
@@ -1589,11 +1533,11 @@ async fn test_merge_persisted_blocks_final_flush() -> Result<()> {
 
     // Create blocks with less than 100k total docs (won't trigger mid-merge flush)
     // Final flush is the only path that writes to inverted_index
-    let doc_ids_1 = generate_sorted_object_ids_with_prefix(40_000, 1);
+    let doc_ids_1 = generate_sorted_doc_ids_with_prefix(40_000, 1);
     let block1 = create_large_block_single_term("final_flush_term", &doc_ids_1, 1);
     indexer.persist_block_to_disk(block1).await?;
 
-    let doc_ids_2 = generate_sorted_object_ids_with_prefix(40_000, 2);
+    let doc_ids_2 = generate_sorted_doc_ids_with_prefix(40_000, 2);
     let block2 = create_large_block_single_term("final_flush_term", &doc_ids_2, 1);
     indexer.persist_block_to_disk(block2).await?;
 
@@ -1613,7 +1557,7 @@ async fn test_merge_persisted_blocks_final_flush() -> Result<()> {
 }
 
 /// Test merge_persisted_blocks cleanup verification.
-/// Verifies all SPIMI block collections are deleted after merge.
+/// Verifies all SPIMI blocks are deleted after merge.
 #[tokio::test]
 async fn test_merge_persisted_blocks_cleanup() -> Result<()> {
     let (db, db_name) = create_test_db().await?;
@@ -1622,13 +1566,13 @@ async fn test_merge_persisted_blocks_cleanup() -> Result<()> {
 
     // Create 6 blocks
     for i in 0..6 {
-        let doc_ids = generate_sorted_object_ids_with_prefix(1000, i as u32);
+        let doc_ids = generate_sorted_doc_ids_with_prefix(1000, i as u32);
         let block = create_large_block_single_term(&format!("term_{}", i), &doc_ids, 1);
         indexer.persist_block_to_disk(block).await?;
     }
 
     // Verify blocks exist before merge
-    let block_names_before = get_spimi_block_collection_names(&db).await?;
+    let block_names_before = get_spimi_block_names(&db).await?;
     assert_eq!(
         block_names_before.len(),
         6,
@@ -1639,7 +1583,7 @@ async fn test_merge_persisted_blocks_cleanup() -> Result<()> {
     indexer.merge_persisted_blocks().await?;
 
     // Verify all blocks are deleted
-    let block_names_after = get_spimi_block_collection_names(&db).await?;
+    let block_names_after = get_spimi_block_names(&db).await?;
     assert!(
         block_names_after.is_empty(),
         "All SPIMI blocks should be deleted after merge, found: {:?}",
@@ -1662,8 +1606,8 @@ async fn test_merge_persisted_blocks_multiple_terms_300k_each() -> Result<()> {
     // Term "big_alpha" appears in all blocks with 50K docs each = 300K total (3 buckets)
     // Term "big_beta" appears in all blocks with 60K docs each = 360K total (4 buckets)
     for block_idx in 0..6 {
-        let alpha_docs = generate_sorted_object_ids_with_prefix(50_000, (block_idx * 2) as u32);
-        let beta_docs = generate_sorted_object_ids_with_prefix(60_000, (block_idx * 2 + 1) as u32);
+        let alpha_docs = generate_sorted_doc_ids_with_prefix(50_000, (block_idx * 2) as u32);
+        let beta_docs = generate_sorted_doc_ids_with_prefix(60_000, (block_idx * 2 + 1) as u32);
 
         let block = create_block_with_terms(vec![
             ("big_alpha", alpha_docs, 1),
@@ -1728,15 +1672,15 @@ async fn test_merge_persisted_blocks_single_block() -> Result<()> {
 
     // Create single block with multiple terms
     let block = create_block_with_terms(vec![
-        ("single_alpha", generate_sorted_object_ids(10_000), 1),
+        ("single_alpha", generate_sorted_doc_ids(10_000), 1),
         (
             "single_beta",
-            generate_sorted_object_ids_with_prefix(20_000, 1),
+            generate_sorted_doc_ids_with_prefix(20_000, 1),
             2,
         ),
         (
             "single_gamma",
-            generate_sorted_object_ids_with_prefix(5_000, 2),
+            generate_sorted_doc_ids_with_prefix(5_000, 2),
             3,
         ),
     ]);
@@ -1756,7 +1700,7 @@ async fn test_merge_persisted_blocks_single_block() -> Result<()> {
     assert!(terms.contains("single_gamma"));
 
     // Verify cleanup
-    let block_count = count_spimi_block_collections(&db).await?;
+    let block_count = count_spimi_blocks(&db).await?;
     assert_eq!(block_count, 0);
 
     cleanup_test_db(&db, &db_name).await?;
@@ -1770,15 +1714,11 @@ async fn test_merge_persisted_blocks_postings_sorted() -> Result<()> {
     let pages_repo = Arc::new(PageRepo::new(&db));
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
-    // Create blocks with interleaved sorted ObjectIds
+    // Create blocks with interleaved sorted doc ids
     // Block 1: IDs 0, 2, 4, 6, 8...
     // Block 2: IDs 1, 3, 5, 7, 9...
-    let even_ids: Vec<ObjectId> = (0..5000u64)
-        .map(|i| ObjectId::parse_str(&format!("{:024x}", i * 2)).unwrap())
-        .collect();
-    let odd_ids: Vec<ObjectId> = (0..5000u64)
-        .map(|i| ObjectId::parse_str(&format!("{:024x}", i * 2 + 1)).unwrap())
-        .collect();
+    let even_ids: Vec<DocId> = (0..5000).map(|i| i * 2).collect();
+    let odd_ids: Vec<DocId> = (0..5000).map(|i| i * 2 + 1).collect();
 
     let block1 = create_large_block_single_term("sorted_term", &even_ids, 1);
     indexer.persist_block_to_disk(block1).await?;
@@ -1870,11 +1810,11 @@ async fn test_integration_full_indexer_flow() -> Result<()> {
     // ==========================================================================
 
     for block_idx in 0..6u32 {
-        let mut terms_with_docs: Vec<(&str, Vec<ObjectId>, usize)> = Vec::new();
+        let mut terms_with_docs: Vec<(&str, Vec<DocId>, usize)> = Vec::new();
 
         // mega_a: 60K (blocks 0-4) or 50K (block 5)
         let mega_a_count = if block_idx < 5 { 60_000 } else { 50_000 };
-        let mega_a_docs = generate_sorted_object_ids_with_prefix(mega_a_count, block_idx * 100);
+        let mega_a_docs = generate_sorted_doc_ids_with_prefix(mega_a_count, block_idx * 100);
         terms_with_docs.push(("mega_a", mega_a_docs, 2));
         *expected_term_totals
             .entry("mega_a".to_string())
@@ -1883,7 +1823,7 @@ async fn test_integration_full_indexer_flow() -> Result<()> {
 
         // mega_b: 80K (blocks 0-4 only)
         if block_idx < 5 {
-            let mega_b_docs = generate_sorted_object_ids_with_prefix(80_000, block_idx * 100 + 1);
+            let mega_b_docs = generate_sorted_doc_ids_with_prefix(80_000, block_idx * 100 + 1);
             terms_with_docs.push(("mega_b", mega_b_docs, 3));
             *expected_term_totals
                 .entry("mega_b".to_string())
@@ -1893,7 +1833,7 @@ async fn test_integration_full_indexer_flow() -> Result<()> {
 
         // small_c: 15K (blocks 0, 2, 4)
         if block_idx % 2 == 0 {
-            let small_c_docs = generate_sorted_object_ids_with_prefix(15_000, block_idx * 100 + 2);
+            let small_c_docs = generate_sorted_doc_ids_with_prefix(15_000, block_idx * 100 + 2);
             terms_with_docs.push(("small_c", small_c_docs, 1));
             *expected_term_totals
                 .entry("small_c".to_string())
@@ -1903,7 +1843,7 @@ async fn test_integration_full_indexer_flow() -> Result<()> {
 
         // small_d: 10K (blocks 1, 3, 5)
         if block_idx % 2 == 1 {
-            let small_d_docs = generate_sorted_object_ids_with_prefix(10_000, block_idx * 100 + 3);
+            let small_d_docs = generate_sorted_doc_ids_with_prefix(10_000, block_idx * 100 + 3);
             terms_with_docs.push(("small_d", small_d_docs, 1));
             *expected_term_totals
                 .entry("small_d".to_string())
@@ -1912,7 +1852,7 @@ async fn test_integration_full_indexer_flow() -> Result<()> {
         }
 
         // tiny_e: 5K (all blocks)
-        let tiny_e_docs = generate_sorted_object_ids_with_prefix(5_000, block_idx * 100 + 4);
+        let tiny_e_docs = generate_sorted_doc_ids_with_prefix(5_000, block_idx * 100 + 4);
         terms_with_docs.push(("tiny_e", tiny_e_docs, 1));
         *expected_term_totals
             .entry("tiny_e".to_string())
@@ -1921,7 +1861,7 @@ async fn test_integration_full_indexer_flow() -> Result<()> {
 
         // unique_fN: 3K (only in block N)
         let unique_term = format!("unique_f{}", block_idx);
-        let unique_docs = generate_sorted_object_ids_with_prefix(3_000, block_idx * 100 + 5);
+        let unique_docs = generate_sorted_doc_ids_with_prefix(3_000, block_idx * 100 + 5);
         // We need to convert this to a static str for the tuple, so we'll handle it differently
         expected_term_totals.insert(unique_term.clone(), 3_000);
         expected_term_positions.insert(unique_term.clone(), 1);
@@ -1950,7 +1890,7 @@ async fn test_integration_full_indexer_flow() -> Result<()> {
     // STEP 3: Verify SPIMI blocks were created correctly
     // ==========================================================================
 
-    let block_count = count_spimi_block_collections(&db).await?;
+    let block_count = count_spimi_blocks(&db).await?;
     assert_eq!(block_count, 6, "Should have created 6 SPIMI blocks");
 
     // ==========================================================================
@@ -1963,7 +1903,7 @@ async fn test_integration_full_indexer_flow() -> Result<()> {
     // STEP 5: Verify all SPIMI blocks are cleaned up
     // ==========================================================================
 
-    let block_count_after = count_spimi_block_collections(&db).await?;
+    let block_count_after = count_spimi_blocks(&db).await?;
     assert_eq!(
         block_count_after, 0,
         "All SPIMI blocks should be deleted after merge"
@@ -2128,7 +2068,7 @@ async fn test_integration_shared_documents_across_blocks() -> Result<()> {
     let indexer = Indexer::new(pages_repo, 100, db.clone());
 
     // Create shared doc IDs that will appear in ALL blocks
-    let shared_docs = generate_sorted_object_ids(1000);
+    let shared_docs = generate_sorted_doc_ids(1000);
 
     // Create 6 blocks, each with the shared docs for "shared_term"
     // Each block adds different positions for the same documents
@@ -2140,7 +2080,7 @@ async fn test_integration_shared_documents_across_blocks() -> Result<()> {
         dictionary.insert("shared_term".to_string(), dict_item);
 
         // Also add some unique docs per block
-        let unique_docs = generate_sorted_object_ids_with_prefix(5000, block_idx * 100);
+        let unique_docs = generate_sorted_doc_ids_with_prefix(5000, block_idx * 100);
         let unique_dict_item = create_dict_item_with_positions(&unique_docs, 1, 0);
         dictionary.insert("mixed_term".to_string(), unique_dict_item);
 
@@ -2207,10 +2147,7 @@ async fn test_merge_checkpoint_created_and_updated() -> Result<()> {
     let indexer = Arc::new(Indexer::new(pages_repo, 100, db.clone()));
 
     // Create a block with multiple terms
-    let terms = vec![
-        ("apple", vec![ObjectId::new()], 1),
-        ("banana", vec![ObjectId::new()], 1),
-    ];
+    let terms = vec![("apple", vec![1], 1), ("banana", vec![2], 1)];
     let block = create_block_with_terms(terms.clone());
     indexer.persist_block_to_disk(block).await?;
 
@@ -2244,9 +2181,9 @@ async fn test_merge_resumes_from_checkpoint() -> Result<()> {
     let indexer = Arc::new(Indexer::new(pages_repo, 100, db.clone()));
 
     // Create a block with 3 terms
-    let doc_id_apple = ObjectId::new();
-    let doc_id_banana = ObjectId::new();
-    let doc_id_cherry = ObjectId::new();
+    let doc_id_apple: DocId = 1;
+    let doc_id_banana: DocId = 2;
+    let doc_id_cherry: DocId = 3;
 
     let terms = vec![
         ("apple", vec![doc_id_apple], 1),
@@ -2257,8 +2194,8 @@ async fn test_merge_resumes_from_checkpoint() -> Result<()> {
 
     // We manually insert the block so we can get its name
     indexer.persist_block_to_disk(block).await?;
-    let collections = get_spimi_block_collection_names(&db).await?;
-    let block_name = &collections[0];
+    let blocks = get_spimi_block_names(&db).await?;
+    let block_name = &blocks[0];
 
     let cp = MergeCheckpoint {
         id: ObjectId::new(),
@@ -2267,6 +2204,9 @@ async fn test_merge_resumes_from_checkpoint() -> Result<()> {
         last_merged_bucket: 0, // bucket 0 of apple is done
         updated_at: mongodb::bson::DateTime::now(),
         completed: false,
+        range_start: None,
+        range_end: None,
+        index_run: None,
     };
     db.collection::<MergeCheckpoint>(harvest::db::collections::MERGE_CHECKPOINTS)
         .insert_one(cp)
@@ -0,0 +1,161 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Mutex;
+
+use harvest::analyzer::TextAnalyzer;
+use harvest::data_models::{Page, SpimiDoc};
+use harvest::heap::{self, CountingAllocator};
+use harvest::indexer::{Indexer, MemoryAccounting, SpimiDictionary};
+use harvest::query_engine::QueryEngine;
+use harvest::storage::file::FileBlockStore;
+use harvest::storage::{BlockStore, Storage};
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// The heap is shared by the tests of this file, they measure it one at a time.
+static HEAP: Mutex<()> = Mutex::const_new(());
+
+/// How far the observed heap may be from the accounted bytes.
+const TOLERANCE: f64 = 0.05;
+
+/// Term of the `i`th token of a document, a few common terms and a long tail of rare ones.
+fn term(doc_id: u32, i: u32) -> String {
+    match i % 4 {
+        0 => format!("common{}", i % 10),
+        1 => format!("doc{}word{}", doc_id % 50, i % 30),
+        _ => format!("rare{}x{}", doc_id, i),
+    }
+}
+
+#[test]
+fn test_estimated_bytes_match_heap() {
+    let _heap = HEAP.blocking_lock();
+    for budget_bytes in [64 << 10, 1 << 20, 8 << 20] {
+        let heap_start = heap::allocated_bytes();
+        let mut dict = SpimiDictionary::new();
+        'fill: for doc_id in 0.. {
+            for i in 0..200 {
+                dict.add(term(doc_id, i), doc_id, i as usize);
+                if dict.heap_bytes() >= budget_bytes {
+                    break 'fill;
+                }
+            }
+        }
+
+        let observed = heap::allocated_bytes() - heap_start;
+        let error = observed.abs_diff(budget_bytes) as f64 / budget_bytes as f64;
+        assert!(
+            error <= TOLERANCE,
+            "budget {budget_bytes}: observed {observed} bytes, accounted {}",
+            dict.heap_bytes()
+        );
+    }
+}
+
+/// Blocks on disk, off the measured heap, counting the blocks sealed and the peak heap up to the
+/// last seal, the end of the SPIMI phase of a run.
+struct SealCountingBlocks {
+    inner: FileBlockStore,
+    sealed: AtomicUsize,
+    peak_bytes: AtomicUsize,
+}
+
+#[async_trait]
+impl BlockStore for SealCountingBlocks {
+    async fn append_to_block(&self, block: &str, doc: SpimiDoc) -> Result<()> {
+        self.inner.append_to_block(block, doc).await
+    }
+
+    async fn seal_block(&self, block: &str) -> Result<()> {
+        self.sealed.fetch_add(1, Ordering::SeqCst);
+        self.peak_bytes
+            .fetch_max(heap::peak_bytes(), Ordering::SeqCst);
+        self.inner.seal_block(block).await
+    }
+
+    async fn list_blocks(&self) -> Result<Vec<String>> {
+        self.inner.list_blocks().await
+    }
+
+    async fn read_block(
+        &self,
+        block: &str,
+        from_term: Option<&str>,
+    ) -> Result<BoxStream<'static, Result<SpimiDoc>>> {
+        self.inner.read_block(block, from_term).await
+    }
+
+    async fn drop_block(&self, block: &str) -> Result<()> {
+        self.inner.drop_block(block).await
+    }
+}
+
+/// The `n`th page of the run, 50 words of its own and a shared one.
+fn page(n: usize) -> Page {
+    let content = (0..50)
+        .map(|i| format!("word{} page{}", i, n))
+        .collect::<Vec<_>>()
+        .join(" ");
+    Page::new(
+        format!("https://example.com/{n}"),
+        format!("Page {n}"),
+        format!("<p>{content} harvest</p>"),
+        vec![],
+        0,
+        false,
+    )
+}
+
+#[tokio::test]
+async fn test_allocator_accounting_flushes_blocks() -> Result<()> {
+    // held across the awaits below, so a tokio mutex
+    let _heap = HEAP.lock().await;
+    assert!(heap::is_counting());
+    let dir = std::env::temp_dir().join(format!("harvest_heap_blocks_{}", std::process::id()));
+    let blocks = Arc::new(SealCountingBlocks {
+        inner: FileBlockStore::new(&dir),
+        sealed: AtomicUsize::new(0),
+        peak_bytes: AtomicUsize::new(0),
+    });
+    let storage = Storage {
+        blocks: blocks.clone(),
+        ..Storage::in_memory()
+    };
+    for n in 0..1000 {
+        storage.pages.insert(&page(n)).await?;
+    }
+
+    // a first run sets up the analyzer's statics and the runtime's threads, kept past the run
+    let warm_up = Storage::in_memory();
+    warm_up.pages.insert(&page(0)).await?;
+    Arc::new(Indexer::from_storage(warm_up, 5))
+        .run(1 << 20)
+        .await?;
+
+    let budget_bytes = 1 << 20;
+    let indexer = Arc::new(
+        Indexer::from_storage(storage.clone(), 5)
+            .with_memory_accounting(MemoryAccounting::Allocator),
+    );
+    let heap_start = heap::allocated_bytes();
+    heap::reset_peak();
+    indexer.run(budget_bytes).await?;
+
+    let sealed = blocks.sealed.load(Ordering::SeqCst);
+    let peak = blocks.peak_bytes.load(Ordering::SeqCst) - heap_start;
+    assert!(sealed > 1, "only {sealed} block flushed");
+    assert!(
+        peak as f64 <= budget_bytes as f64 * (1.0 + TOLERANCE),
+        "peak heap {peak} bytes over the budget of {budget_bytes}"
+    );
+
+    let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+    assert_eq!(query_engine.query("harvest").await?.len(), 1000);
+    assert_eq!(query_engine.query("page7").await?.len(), 1);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
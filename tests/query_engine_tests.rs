@@ -1,15 +1,15 @@
 use anyhow::Result;
 use futures::stream::TryStreamExt;
 use mongodb::bson::doc;
-use mongodb::bson::oid::ObjectId;
 use std::collections::HashMap;
 use std::sync::Arc;
 
 use harvest::analyzer::TextAnalyzer;
-use harvest::data_models::{InvertedIndexDoc, Page};
+use harvest::data_models::{DocId, InvertedIndexDoc, Page};
 use harvest::db::{Database, PageRepo, collections};
 use harvest::indexer::Indexer;
 use harvest::query_engine::QueryEngine;
+use harvest::term_dict::TermDictionary;
 
 mod test_helpers {
     use super::*;
@@ -56,6 +56,7 @@ mod test_helpers {
                 Box::new(harvest::analyzer::PorterStemmerTokenFilter),
             ],
         )
+        .with_name(harvest::analyzer::DEFAULT_ANALYZER)
     }
 
     /// Helper to insert InvertedIndexDoc documents into the database
@@ -68,15 +69,9 @@ mod test_helpers {
         Ok(())
     }
 
-    /// Generate a vector of sorted ObjectIds for testing.
-    /// Uses incrementing hex strings to ensure deterministic ordering.
-    pub fn generate_sorted_object_ids(count: usize) -> Vec<ObjectId> {
-        (0..count)
-            .map(|i| {
-                let hex = format!("{:024x}", i);
-                ObjectId::parse_str(&hex).unwrap()
-            })
-            .collect()
+    /// Generate a vector of sorted doc ids for testing, `0..count`.
+    pub fn generate_sorted_doc_ids(count: usize) -> Vec<DocId> {
+        (0..count as DocId).collect()
     }
 }
 
@@ -118,7 +113,7 @@ async fn test_query_no_matching_terms() -> Result<()> {
     let query_engine = QueryEngine::new(db.clone(), analyzer);
 
     // Insert some index docs for different terms
-    let doc_ids = generate_sorted_object_ids(5);
+    let doc_ids = generate_sorted_doc_ids(5);
     let index_docs = vec![
         InvertedIndexDoc::new(
             "elephant".to_string(),
@@ -155,7 +150,7 @@ async fn test_query_single_term_single_bucket() -> Result<()> {
     let query_engine = QueryEngine::new(db.clone(), analyzer);
 
     // Insert index doc for "elephant" term
-    let doc_ids = generate_sorted_object_ids(5);
+    let doc_ids = generate_sorted_doc_ids(5);
     let elephant_docs = vec![doc_ids[0], doc_ids[2], doc_ids[4]];
     let index_docs = vec![InvertedIndexDoc::new(
         "eleph".to_string(), // Stemmed form of "elephant"
@@ -189,7 +184,7 @@ async fn test_query_single_term_multiple_buckets() -> Result<()> {
     let query_engine = QueryEngine::new(db.clone(), analyzer);
 
     // Insert index docs for "elephant" term across 3 buckets
-    let doc_ids = generate_sorted_object_ids(15);
+    let doc_ids = generate_sorted_doc_ids(15);
     let bucket0_docs = vec![doc_ids[0], doc_ids[1], doc_ids[2], doc_ids[3], doc_ids[4]];
     let bucket1_docs = vec![doc_ids[5], doc_ids[6], doc_ids[7], doc_ids[8]];
     let bucket2_docs = vec![doc_ids[9], doc_ids[10], doc_ids[11]];
@@ -227,7 +222,7 @@ async fn test_query_single_term_multiple_buckets() -> Result<()> {
         "Should return all 12 documents across 3 buckets"
     );
 
-    // Verify results are merged correctly (sorted by ObjectId)
+    // Verify results are merged correctly (sorted by doc id)
     let mut all_expected_docs = vec![];
     all_expected_docs.extend(bucket0_docs);
     all_expected_docs.extend(bucket1_docs);
@@ -250,7 +245,7 @@ async fn test_query_multiple_terms_all_match() -> Result<()> {
     let query_engine = QueryEngine::new(db.clone(), analyzer);
 
     // Create docs where some documents contain both terms
-    let doc_ids = generate_sorted_object_ids(10);
+    let doc_ids = generate_sorted_doc_ids(10);
 
     // "eleph" appears in docs: 0, 2, 4, 5, 7
     let elephant_docs = vec![doc_ids[0], doc_ids[2], doc_ids[4], doc_ids[5], doc_ids[7]];
@@ -263,7 +258,7 @@ async fn test_query_multiple_terms_all_match() -> Result<()> {
 
     // Create positions for each doc - for matching docs, positions must be adjacent (within k=1)
     // Shared docs are: 2, 4, 5 - these need adjacent positions
-    let elephant_positions: HashMap<ObjectId, Vec<usize>> = vec![
+    let elephant_positions: HashMap<DocId, Vec<usize>> = vec![
         (doc_ids[0], vec![100]), // not shared, position doesn't matter
         (doc_ids[2], vec![10]),  // shared - position 10
         (doc_ids[4], vec![20]),  // shared - position 20
@@ -273,7 +268,7 @@ async fn test_query_multiple_terms_all_match() -> Result<()> {
     .into_iter()
     .collect();
 
-    let giraffe_positions: HashMap<ObjectId, Vec<usize>> = vec![
+    let giraffe_positions: HashMap<DocId, Vec<usize>> = vec![
         (doc_ids[2], vec![11]),  // shared - adjacent to elephant's 10
         (doc_ids[4], vec![21]),  // shared - adjacent to elephant's 20
         (doc_ids[5], vec![31]),  // shared - adjacent to elephant's 30
@@ -324,7 +319,7 @@ async fn test_query_multiple_terms_no_intersection() -> Result<()> {
     let query_engine = QueryEngine::new(db.clone(), analyzer);
 
     // Create docs where no document contains both terms
-    let doc_ids = generate_sorted_object_ids(10);
+    let doc_ids = generate_sorted_doc_ids(10);
 
     // "eleph" appears in docs: 0, 1, 2
     let elephant_docs = vec![doc_ids[0], doc_ids[1], doc_ids[2]];
@@ -372,11 +367,11 @@ async fn test_query_with_text_analysis() -> Result<()> {
     let query_engine = QueryEngine::new(db.clone(), analyzer);
 
     // Insert index docs using stemmed/analyzed terms
-    let doc_ids = generate_sorted_object_ids(10);
+    let doc_ids = generate_sorted_doc_ids(10);
 
     // Create positions - "run" and "jump" appear adjacent in docs 3 and 5
     let run_docs = vec![doc_ids[1], doc_ids[3], doc_ids[5]];
-    let run_positions: HashMap<ObjectId, Vec<usize>> = vec![
+    let run_positions: HashMap<DocId, Vec<usize>> = vec![
         (doc_ids[1], vec![5]),
         (doc_ids[3], vec![10]),
         (doc_ids[5], vec![15]),
@@ -385,7 +380,7 @@ async fn test_query_with_text_analysis() -> Result<()> {
     .collect();
 
     let jump_docs = vec![doc_ids[3], doc_ids[5]];
-    let jump_positions: HashMap<ObjectId, Vec<usize>> = vec![
+    let jump_positions: HashMap<DocId, Vec<usize>> = vec![
         (doc_ids[3], vec![11]), // adjacent to "run" at position 10
         (doc_ids[5], vec![16]), // adjacent to "run" at position 15
     ]
@@ -427,7 +422,7 @@ async fn test_query_multiple_terms_partial_missing() -> Result<()> {
     let query_engine = QueryEngine::new(db.clone(), analyzer);
 
     // Insert index doc for only one of the query terms
-    let doc_ids = generate_sorted_object_ids(5);
+    let doc_ids = generate_sorted_doc_ids(5);
     let elephant_docs = vec![doc_ids[0], doc_ids[2], doc_ids[4]];
 
     let index_docs = vec![InvertedIndexDoc::new(
@@ -457,11 +452,11 @@ async fn test_query_with_stop_words_filtered() -> Result<()> {
     let query_engine = QueryEngine::new(db.clone(), analyzer);
 
     // Insert index docs (stop words like "the", "a", "is" are not indexed)
-    let doc_ids = generate_sorted_object_ids(5);
+    let doc_ids = generate_sorted_doc_ids(5);
 
     // Create positions - "quick" and "fox" appear adjacent in docs 0 and 2
     let quick_docs = vec![doc_ids[0], doc_ids[2], doc_ids[4]];
-    let quick_positions: HashMap<ObjectId, Vec<usize>> = vec![
+    let quick_positions: HashMap<DocId, Vec<usize>> = vec![
         (doc_ids[0], vec![5]),
         (doc_ids[2], vec![10]),
         (doc_ids[4], vec![15]),
@@ -470,7 +465,7 @@ async fn test_query_with_stop_words_filtered() -> Result<()> {
     .collect();
 
     let fox_docs = vec![doc_ids[0], doc_ids[2]];
-    let fox_positions: HashMap<ObjectId, Vec<usize>> = vec![
+    let fox_positions: HashMap<DocId, Vec<usize>> = vec![
         (doc_ids[0], vec![6]),  // adjacent to "quick" at position 5
         (doc_ids[2], vec![11]), // adjacent to "quick" at position 10
     ]
@@ -510,7 +505,7 @@ async fn test_query_complex_intersection_three_terms() -> Result<()> {
     let query_engine = QueryEngine::new(db.clone(), analyzer);
 
     // Create a scenario with 3 terms and complex overlaps
-    let doc_ids = generate_sorted_object_ids(20);
+    let doc_ids = generate_sorted_doc_ids(20);
 
     // "alpha" in: 0, 2, 3, 5, 7, 10, 12, 15
     let alpha_docs = vec![
@@ -552,7 +547,7 @@ async fn test_query_complex_intersection_three_terms() -> Result<()> {
 
     // Create positions for each term - they need to be adjacent for positional intersection
     // Docs in intersection: 3, 5, 10, 15
-    let alpha_positions: HashMap<ObjectId, Vec<usize>> = vec![
+    let alpha_positions: HashMap<DocId, Vec<usize>> = vec![
         (doc_ids[0], vec![100]),
         (doc_ids[2], vec![100]),
         (doc_ids[3], vec![10]), // intersection doc
@@ -565,7 +560,7 @@ async fn test_query_complex_intersection_three_terms() -> Result<()> {
     .into_iter()
     .collect();
 
-    let beta_positions: HashMap<ObjectId, Vec<usize>> = vec![
+    let beta_positions: HashMap<DocId, Vec<usize>> = vec![
         (doc_ids[2], vec![200]),
         (doc_ids[3], vec![11]), // intersection doc, adjacent to alpha
         (doc_ids[5], vec![21]), // intersection doc, adjacent to alpha
@@ -578,7 +573,7 @@ async fn test_query_complex_intersection_three_terms() -> Result<()> {
     .into_iter()
     .collect();
 
-    let gamma_positions: HashMap<ObjectId, Vec<usize>> = vec![
+    let gamma_positions: HashMap<DocId, Vec<usize>> = vec![
         (doc_ids[3], vec![12]), // intersection doc, adjacent to beta
         (doc_ids[5], vec![22]), // intersection doc, adjacent to beta
         (doc_ids[9], vec![300]),
@@ -630,6 +625,140 @@ async fn test_query_complex_intersection_three_terms() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_query_prefix_and_wildcard_terms() -> Result<()> {
+    let (db, db_name) = create_test_db().await?;
+    let doc_ids = generate_sorted_doc_ids(4);
+
+    // "harvest moon" in doc 0, "harvard moon" in doc 1, "harbor moon" in doc 2, "moon" alone in doc 3
+    let index_docs = vec![
+        InvertedIndexDoc::new(
+            "harvest".to_string(),
+            0,
+            1,
+            vec![doc_ids[0]],
+            vec![(doc_ids[0], vec![0])].into_iter().collect(),
+        ),
+        InvertedIndexDoc::new(
+            "harvard".to_string(),
+            0,
+            1,
+            vec![doc_ids[1]],
+            vec![(doc_ids[1], vec![3])].into_iter().collect(),
+        ),
+        InvertedIndexDoc::new(
+            "harbor".to_string(),
+            0,
+            1,
+            vec![doc_ids[2]],
+            vec![(doc_ids[2], vec![0])].into_iter().collect(),
+        ),
+        InvertedIndexDoc::new(
+            "moon".to_string(),
+            0,
+            4,
+            doc_ids.clone(),
+            vec![
+                (doc_ids[0], vec![1]),
+                (doc_ids[1], vec![4]),
+                (doc_ids[2], vec![1]),
+                (doc_ids[3], vec![0]),
+            ]
+            .into_iter()
+            .collect(),
+        ),
+    ];
+    insert_inverted_index_docs(&db, index_docs).await?;
+
+    let term_dict =
+        TermDictionary::build([("harvest", 1), ("harvard", 1), ("harbor", 1), ("moon", 4)])?;
+    let query_engine =
+        QueryEngine::new(db.clone(), create_text_analyzer()).with_term_dictionary(term_dict);
+
+    let results = query_engine.query("harv*").await?;
+    assert_eq!(results, vec![doc_ids[0], doc_ids[1]]);
+
+    let results = query_engine.query("harv* moon").await?;
+    assert_eq!(results, vec![doc_ids[0], doc_ids[1]]);
+
+    let results = query_engine.query("h?rb?r moon").await?;
+    assert_eq!(results, vec![doc_ids[2]]);
+
+    let results = query_engine.query("/har(vest|bor)/").await?;
+    assert_eq!(results, vec![doc_ids[0], doc_ids[2]]);
+
+    let results = query_engine.query("zebra*").await?;
+    assert!(results.is_empty(), "Pattern without matching terms");
+
+    // expansion cap keeps a single term
+    let query_engine = QueryEngine::new(db.clone(), create_text_analyzer())
+        .with_term_dictionary(TermDictionary::build([("harvest", 5), ("harvard", 1)])?)
+        .with_max_expansions(1);
+    let results = query_engine.query("harv*").await?;
+    assert_eq!(results, vec![doc_ids[0]]);
+
+    cleanup_test_db(&db, &db_name).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_query_fuzzy_terms() -> Result<()> {
+    let (db, db_name) = create_test_db().await?;
+    let doc_ids = generate_sorted_doc_ids(2);
+
+    // "harvest moon" in doc 0, "hervest moon" in doc 1
+    let index_docs = vec![
+        InvertedIndexDoc::new(
+            "harvest".to_string(),
+            0,
+            1,
+            vec![doc_ids[0]],
+            vec![(doc_ids[0], vec![0])].into_iter().collect(),
+        ),
+        InvertedIndexDoc::new(
+            "hervest".to_string(),
+            0,
+            1,
+            vec![doc_ids[1]],
+            vec![(doc_ids[1], vec![0])].into_iter().collect(),
+        ),
+        InvertedIndexDoc::new(
+            "moon".to_string(),
+            0,
+            2,
+            doc_ids.clone(),
+            vec![(doc_ids[0], vec![1]), (doc_ids[1], vec![1])]
+                .into_iter()
+                .collect(),
+        ),
+    ];
+    insert_inverted_index_docs(&db, index_docs).await?;
+
+    let term_dict = TermDictionary::build([("harvest", 1), ("hervest", 1), ("moon", 2)])?;
+    let query_engine =
+        QueryEngine::new(db.clone(), create_text_analyzer()).with_term_dictionary(term_dict);
+
+    let results = query_engine.query("harvst moon").await?;
+    assert!(results.is_empty(), "Typo without fuzzy marker");
+    assert_eq!(query_engine.suggest("harvst moon")?, vec!["harvest moon"]);
+
+    let results = query_engine.query("harvst~ moon").await?;
+    assert_eq!(results, vec![doc_ids[0]]);
+
+    let results = query_engine.query("harvst~2 moon").await?;
+    assert_eq!(results, vec![doc_ids[0], doc_ids[1]]);
+
+    // the engine's fuzzy distance applies when the term doesn't give one
+    let query_engine = QueryEngine::new(db.clone(), create_text_analyzer())
+        .with_term_dictionary(TermDictionary::build([("harvest", 1), ("hervest", 1)])?)
+        .with_fuzzy_distance(2);
+    let results = query_engine.query("harvst~").await?;
+    assert_eq!(results, vec![doc_ids[0], doc_ids[1]]);
+
+    cleanup_test_db(&db, &db_name).await?;
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_query_terms_different_bucket_counts() -> Result<()> {
     let (db, db_name) = create_test_db().await?;
@@ -637,7 +766,7 @@ async fn test_query_terms_different_bucket_counts() -> Result<()> {
     let query_engine = QueryEngine::new(db.clone(), analyzer);
 
     // Create scenario where different terms have different numbers of buckets
-    let doc_ids = generate_sorted_object_ids(30);
+    let doc_ids = generate_sorted_doc_ids(30);
 
     // Term "common" has 2 buckets with many docs
     let common_bucket0 = vec![
@@ -666,7 +795,7 @@ async fn test_query_terms_different_bucket_counts() -> Result<()> {
     let expected_intersection = vec![doc_ids[2], doc_ids[10], doc_ids[20]];
 
     // Create positions for "common" term in bucket 0
-    let common_bucket0_positions: HashMap<ObjectId, Vec<usize>> = vec![
+    let common_bucket0_positions: HashMap<DocId, Vec<usize>> = vec![
         (doc_ids[0], vec![5]),
         (doc_ids[2], vec![10]), // intersection doc
         (doc_ids[4], vec![15]),
@@ -680,7 +809,7 @@ async fn test_query_terms_different_bucket_counts() -> Result<()> {
     .collect();
 
     // Create positions for "common" term in bucket 1
-    let common_bucket1_positions: HashMap<ObjectId, Vec<usize>> = vec![
+    let common_bucket1_positions: HashMap<DocId, Vec<usize>> = vec![
         (doc_ids[16], vec![45]),
         (doc_ids[18], vec![50]),
         (doc_ids[20], vec![55]), // intersection doc
@@ -692,7 +821,7 @@ async fn test_query_terms_different_bucket_counts() -> Result<()> {
     .collect();
 
     // Create positions for "rare" term - adjacent to "common" positions for intersection docs
-    let rare_bucket0_positions: HashMap<ObjectId, Vec<usize>> = vec![
+    let rare_bucket0_positions: HashMap<DocId, Vec<usize>> = vec![
         (doc_ids[2], vec![11]),  // adjacent to common at 10
         (doc_ids[10], vec![31]), // adjacent to common at 30
         (doc_ids[20], vec![56]), // adjacent to common at 55
@@ -770,7 +899,8 @@ async fn assert_query_match(
     );
 
     // 4. Fetch the actual Page document to verify URL
-    let filter = doc! { "_id": { "$in": results } };
+    let page_ids = query_engine.page_ids(&results).await?;
+    let filter = doc! { "_id": { "$in": page_ids } };
     let pages: Vec<Page> = pages_collection.find(filter).await?.try_collect().await?;
 
     // 5. Assert the URL matches
@@ -849,7 +979,7 @@ async fn test_query_phrase_with_full_indexing_pipeline() -> Result<()> {
     // --- Testing ---
     let analyzer = create_text_analyzer();
     let query_engine = QueryEngine::new(db.clone(), analyzer);
-    let pages_collection = query_engine.db().collection::<Page>(collections::PAGES);
+    let pages_collection = db.collection::<Page>(collections::PAGES);
 
     // Test 1: Karpathy Basics
     assert_query_match(
@@ -925,7 +1055,7 @@ async fn test_edge_cases_and_ambiguity() -> Result<()> {
 
     // Shared Prefix Trap
     // Doc: "The quick brown fox..."
-    // Index: "quick"(0), "brown"(1), "fox"(2) ... ("The" is stripped)
+    // Index: "quick"(1), "brown"(2), "fox"(3) ... ("The" is stripped, keeping its position)
     let page_fox = Page::new(
         "https://example.com/fox".to_string(),
         "Fox Page".to_string(),
@@ -946,7 +1076,7 @@ async fn test_edge_cases_and_ambiguity() -> Result<()> {
 
     // Order Chaos
     // Doc: "We are discussing learning deep concepts..."
-    // Index: "discussing"(0), "learning"(1), "deep"(2)... ("We", "are" stripped)
+    // Index: "discussing"(2), "learning"(3), "deep"(4)... ("We", "are" stripped)
     let page_reverse = Page::new(
         "https://example.com/reverse".to_string(),
         "Reverse Page".to_string(),
@@ -968,9 +1098,9 @@ async fn test_edge_cases_and_ambiguity() -> Result<()> {
 
     // Distance/Proximity Limit Test
     // Doc: "The Magic is a stone Kingdom..."
-    // Stop words removed: "The", "is", "a"
-    // Remaining Index: "Magic"(0), "stone"(1), "Kingdom"(2)
-    // Gap: Kingdom is at pos 2, Magic at pos 0. Diff = 2.
+    // Stop words removed: "The", "is", "a", but they keep their positions
+    // Remaining Index: "Magic"(1), "stone"(4), "Kingdom"(5)
+    // Gap: Kingdom is at pos 5, Magic at pos 1. Diff = 4.
     let page_distance = Page::new(
         "https://example.com/distance".to_string(),
         "Distance Page".to_string(),
@@ -992,7 +1122,7 @@ async fn test_edge_cases_and_ambiguity() -> Result<()> {
 
     let analyzer = create_text_analyzer();
     let query_engine = QueryEngine::new(db.clone(), analyzer);
-    let pages_collection = query_engine.db().collection::<Page>(collections::PAGES);
+    let pages_collection = db.collection::<Page>(collections::PAGES);
 
     // --- EDGE CASE 1: Shared Prefixes ---
     // Query: "The quick brown fox" -> Filtered: "quick", "brown", "fox"
@@ -1027,21 +1157,15 @@ async fn test_edge_cases_and_ambiguity() -> Result<()> {
     // --- EDGE CASE 3: Distance Sensitivity ---
     // Query: "Magic Kingdom" -> Filtered: "magic", "kingdom"
     // Query Distance: 1 (Adjacent)
-    // Document Distance: 2 ("magic"(0) ... "stone"(1) ... "kingdom"(2))
-    // Result: 2 > 1. Should FAIL.
+    // Document Distance: 4 ("magic"(1) ... "stone"(4) ... "kingdom"(5))
+    // Result: 4 != 1. Should FAIL.
     assert_query_match(&query_engine, &pages_collection, "Magic Kingdom", None).await?;
 
     // --- EDGE CASE 4: Reverse Order ---
     // Query: "deep learning"
     // Document: "...learning deep..."
-    // Because our logic uses `abs_diff`, this currently MATCHES.
-    assert_query_match(
-        &query_engine,
-        &pages_collection,
-        "deep learning",
-        Some("https://example.com/reverse"),
-    )
-    .await?;
+    // Phrase terms must keep their query order, so this should NOT match.
+    assert_query_match(&query_engine, &pages_collection, "deep learning", None).await?;
 
     cleanup_test_db(&db, &db_name).await?;
     Ok(())
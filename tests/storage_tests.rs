@@ -0,0 +1,1584 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use harvest::analyzer::{DEFAULT_ANALYZER, TextAnalyzer};
+use harvest::analyzer_config::AnalyzerRegistry;
+use harvest::archive::IndexArchive;
+use harvest::completion::COMPLETION_COUNTS_DIR;
+use harvest::data_models::{DocId, InvertedIndexDoc, MergeCheckpoint, Page, SpimiDoc};
+use harvest::indexer::Indexer;
+use harvest::query_engine::QueryEngine;
+use harvest::segment::{
+    SEGMENT_FILE, Segment, SegmentGeneration, delta_segments, read_generation, write_generation,
+};
+use harvest::storage::file::FileBlockStore;
+use harvest::storage::{CheckpointStore, Storage};
+use harvest::term_dict::TermDictionary;
+use harvest::verify::IndexVerifier;
+
+mod test_helpers {
+    use super::*;
+
+    pub fn create_test_page(url: &str, content: &str) -> Page {
+        Page::new(
+            url.to_string(),
+            format!("Title for {}", url),
+            content.to_string(),
+            vec![],
+            0,
+            false,
+        )
+    }
+
+    pub async fn urls_for(
+        storage: &Storage,
+        query_engine: &QueryEngine,
+        query: &str,
+    ) -> Result<Vec<String>> {
+        let doc_ids = query_engine.query(query).await?;
+        let page_ids = query_engine.page_ids(&doc_ids).await?;
+        let mut urls: Vec<String> = storage
+            .pages
+            .find_by_ids(&page_ids)
+            .await?
+            .into_iter()
+            .map(|p| p.url)
+            .collect();
+        urls.sort();
+        Ok(urls)
+    }
+}
+
+use test_helpers::*;
+
+#[tokio::test]
+async fn test_index_and_query_in_memory() -> Result<()> {
+    let storage = Storage::in_memory();
+    storage
+        .pages
+        .insert(&create_test_page(
+            "https://example.com/moon",
+            "<p>The harvest moon rises over the fields</p>",
+        ))
+        .await?;
+    storage
+        .pages
+        .insert(&create_test_page(
+            "https://example.com/sun",
+            "<p>The sun sets over the harvest</p>",
+        ))
+        .await?;
+
+    // a tiny budget flushes several blocks, so the k-way merge is exercised too
+    let indexer = Arc::new(Indexer::from_storage(storage.clone(), 1));
+    indexer.run(64).await?;
+
+    // every block was merged and dropped, every page marked as indexed
+    assert!(storage.blocks.list_blocks().await?.is_empty());
+    assert!(storage.checkpoints.get_incomplete().await?.is_empty());
+    let (unindexed, _) = storage.pages.list_unindexed_paginated(10, None).await?;
+    assert!(unindexed.is_empty());
+
+    let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/moon", "https://example.com/sun"]
+    );
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest moon").await?,
+        vec!["https://example.com/moon"]
+    );
+    assert_eq!(
+        urls_for(&storage, &query_engine, "\"moon harvest\"").await?,
+        Vec::<String>::new()
+    );
+    // a question mark ending the query is punctuation, not a wildcard
+    assert_eq!(
+        urls_for(&storage, &query_engine, "where does the harvest moon rise?").await?,
+        vec!["https://example.com/moon"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_incremental_index_in_memory() -> Result<()> {
+    let storage = Storage::in_memory();
+    storage
+        .pages
+        .insert(&create_test_page(
+            "https://example.com/1",
+            "<p>harvest moon</p>",
+        ))
+        .await?;
+    Arc::new(Indexer::from_storage(storage.clone(), 10))
+        .run(1024)
+        .await?;
+
+    storage
+        .pages
+        .insert(&create_test_page(
+            "https://example.com/2",
+            "<p>harvest festival</p>",
+        ))
+        .await?;
+    Arc::new(Indexer::from_storage(storage.clone(), 10))
+        .run(1024)
+        .await?;
+
+    // the second run writes a delta generation holding its postings alone, read after the first
+    let manifest = storage.index.manifest().await?;
+    assert_eq!((manifest.current, manifest.current_layers), (2, vec![1, 0]));
+    let frequencies: HashMap<String, u64> = storage
+        .index
+        .term_document_frequencies()
+        .await?
+        .into_iter()
+        .collect();
+    assert_eq!(frequencies["harvest"], 2);
+    let buckets = storage
+        .index
+        .find_by_terms(&["harvest".to_string()])
+        .await?;
+    let buckets: Vec<(i16, Vec<DocId>)> = buckets
+        .into_iter()
+        .map(|doc| (doc.bucket, doc.postings))
+        .collect();
+    assert_eq!(buckets, vec![(0, vec![0]), (1, vec![1])]);
+    let delta = storage
+        .index
+        .generation(2)
+        .find_by_terms(&["harvest".to_string()])
+        .await?;
+    assert_eq!(delta.len(), 1);
+
+    let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/1", "https://example.com/2"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_merge_resumes_from_checkpoint_in_memory() -> Result<()> {
+    let storage = Storage::in_memory();
+    let indexer = Indexer::from_storage(storage.clone(), 10);
+
+    // a block whose merge crashed after flushing "harvest"
+    for term in ["harvest", "moon"] {
+        storage
+            .blocks
+            .append_to_block(
+                "spimi_block_test",
+                SpimiDoc::new(
+                    term.to_string(),
+                    0,
+                    1,
+                    vec![0],
+                    HashMap::from([(0, vec![0])]),
+                ),
+            )
+            .await?;
+    }
+    storage.blocks.seal_block("spimi_block_test").await?;
+    storage
+        .checkpoints
+        .get_or_create("spimi_block_test")
+        .await?;
+    storage
+        .checkpoints
+        .update_progress("spimi_block_test", "harvest", 0)
+        .await?;
+
+    indexer.merge_persisted_blocks().await?;
+
+    // "harvest" is not merged twice, "moon" is merged
+    let frequencies = storage.index.term_document_frequencies().await?;
+    assert_eq!(frequencies, vec![("moon".to_string(), 1)]);
+    assert!(storage.blocks.list_blocks().await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_with_file_blocks() -> Result<()> {
+    let block_dir =
+        std::env::temp_dir().join(format!("harvest_storage_blocks_{}", std::process::id()));
+    let storage = Storage {
+        blocks: Arc::new(FileBlockStore::new(&block_dir)),
+        ..Storage::in_memory()
+    };
+    for (url, content) in [
+        (
+            "https://example.com/moon",
+            "<p>The harvest moon rises over the fields</p>",
+        ),
+        (
+            "https://example.com/sun",
+            "<p>The sun sets over the harvest</p>",
+        ),
+    ] {
+        storage
+            .pages
+            .insert(&create_test_page(url, content))
+            .await?;
+    }
+
+    // a tiny budget writes a block file every few tokens
+    Arc::new(Indexer::from_storage(storage.clone(), 1))
+        .run(64)
+        .await?;
+
+    let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/moon", "https://example.com/sun"]
+    );
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest moon").await?,
+        vec!["https://example.com/moon"]
+    );
+    // merged blocks are removed
+    assert_eq!(std::fs::read_dir(&block_dir)?.count(), 0);
+    std::fs::remove_dir_all(&block_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_query_from_segment() -> Result<()> {
+    let index_dir =
+        std::env::temp_dir().join(format!("harvest_storage_segment_{}", std::process::id()));
+    let storage = Storage::in_memory();
+    storage
+        .pages
+        .insert(&create_test_page(
+            "https://example.com/moon",
+            "<p>The harvest moon rises over the fields</p>",
+        ))
+        .await?;
+    storage
+        .pages
+        .insert(&create_test_page(
+            "https://example.com/sun",
+            "<p>The sun sets over the harvest</p>",
+        ))
+        .await?;
+    Arc::new(Indexer::from_storage(storage.clone(), 10).with_index_dir(&index_dir))
+        .run(1024)
+        .await?;
+
+    // postings only come from the segment, the index store of this engine is empty
+    let segment = Segment::open(&index_dir.join(SEGMENT_FILE))?;
+    assert_eq!(segment.num_docs(), 2);
+    let query_storage = Storage {
+        pages: storage.pages.clone(),
+        ..Storage::in_memory()
+    };
+    let query_engine = QueryEngine::from_storage(query_storage.clone(), TextAnalyzer::default())
+        .with_segment(segment);
+    assert_eq!(
+        urls_for(&query_storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/moon", "https://example.com/sun"]
+    );
+    assert_eq!(
+        urls_for(&query_storage, &query_engine, "\"harvest moon\"").await?,
+        vec!["https://example.com/moon"]
+    );
+
+    std::fs::remove_dir_all(&index_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_query_engine_reloads_index_files_of_new_generation() -> Result<()> {
+    let index_dir =
+        std::env::temp_dir().join(format!("harvest_storage_reload_{}", std::process::id()));
+    let storage = Storage::in_memory();
+    let indexer =
+        || Arc::new(Indexer::from_storage(storage.clone(), 10).with_index_dir(&index_dir));
+    storage
+        .pages
+        .insert(&create_test_page(
+            "https://example.com/moon",
+            "<p>harvest moon</p>",
+        ))
+        .await?;
+    indexer().run(1 << 20).await?;
+    let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default())
+        .with_index_dir(&index_dir)?;
+    assert!(!query_engine.reload().await?);
+    // words of a single document are not completed
+    assert!(query_engine.complete("harv", 5).is_empty());
+
+    storage
+        .pages
+        .insert(&create_test_page(
+            "https://example.com/sun",
+            "<p>harvest sun</p>",
+        ))
+        .await?;
+    indexer().run(1 << 20).await?;
+    // files not yet written for the committed generation are not loaded
+    let current = storage.index.manifest().await?.current;
+    // the second run only wrote a delta segment of its generation
+    let written = read_generation(&index_dir)?.unwrap();
+    assert_eq!(
+        (written.generation, &written.deltas),
+        (current, &vec![current])
+    );
+    let stale = SegmentGeneration {
+        generation: current - 1,
+        deltas: Vec::new(),
+    };
+    write_generation(&index_dir, &stale)?;
+    assert!(urls_for(&storage, &query_engine, "su*").await?.is_empty());
+
+    // the first query after they are swaps in the new segment, dictionary and completions
+    write_generation(&index_dir, &written)?;
+    assert_eq!(
+        urls_for(&storage, &query_engine, "su*").await?,
+        vec!["https://example.com/sun"]
+    );
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/moon", "https://example.com/sun"]
+    );
+    let completions: Vec<String> = query_engine
+        .complete("harv", 5)
+        .into_iter()
+        .map(|c| c.text)
+        .collect();
+    assert_eq!(completions, vec!["harvest"]);
+    assert!(query_engine.complete("sun", 5).is_empty());
+    assert!(!query_engine.reload().await?);
+
+    std::fs::remove_dir_all(&index_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_completions_follow_rollback_and_compaction() -> Result<()> {
+    let index_dir = std::env::temp_dir().join(format!(
+        "harvest_storage_completions_{}",
+        std::process::id()
+    ));
+    let storage = Storage::in_memory();
+    let indexer =
+        || Arc::new(Indexer::from_storage(storage.clone(), 10).with_index_dir(&index_dir));
+    let complete = |query: &str| -> Result<Vec<(String, u64)>> {
+        let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default())
+            .with_index_dir(&index_dir)?;
+        Ok(query_engine
+            .complete(query, 10)
+            .into_iter()
+            .map(|c| (c.text, c.weight))
+            .collect())
+    };
+    storage.query_log.record("harvest moon").await?;
+
+    // a budget this small spills the completion counts of every block
+    for (url, content) in [
+        ("https://example.com/moon", "<p>harvest moon</p>"),
+        ("https://example.com/sun", "<p>harvest sun</p>"),
+    ] {
+        storage
+            .pages
+            .insert(&create_test_page(url, content))
+            .await?;
+    }
+    indexer().run(256).await?;
+    assert!(!index_dir.join(COMPLETION_COUNTS_DIR).exists());
+    storage
+        .pages
+        .insert(&create_test_page(
+            "https://example.com/field",
+            "<p>harvest field</p>",
+        ))
+        .await?;
+    indexer().run(1 << 20).await?;
+    assert_eq!(
+        complete("harv")?,
+        vec![("harvest moon".to_string(), 20), ("harvest".to_string(), 3)]
+    );
+    assert_eq!(complete("title for https://example.com/f")?.len(), 1);
+
+    // the page indexed by the rolled back run is no longer completed
+    indexer().rollback().await?;
+    assert_eq!(
+        complete("harv")?,
+        vec![("harvest moon".to_string(), 20), ("harvest".to_string(), 2)]
+    );
+    assert!(complete("title for https://example.com/f")?.is_empty());
+    assert_eq!(complete("title for https://example.com/")?.len(), 2);
+
+    // nor is a deleted one once compacted, "harvest" is left in a single document
+    indexer()
+        .delete_pages(&["https://example.com/moon".to_string()])
+        .await?;
+    assert_eq!(indexer().compact().await?, 1);
+    assert_eq!(complete("harv")?, vec![("harvest moon".to_string(), 20)]);
+    assert_eq!(
+        complete("title for https://example.com/")?,
+        vec![("title for https://example.com/sun".to_string(), 5)]
+    );
+
+    std::fs::remove_dir_all(&index_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_query_from_segment_with_skewed_terms() -> Result<()> {
+    let index_dir =
+        std::env::temp_dir().join(format!("harvest_storage_skewed_{}", std::process::id()));
+    let storage = Storage::in_memory();
+    // "harvest" spans several skip blocks, "moon" is in a few of them
+    for i in 0..400 {
+        let content = if i % 97 == 0 {
+            "<p>The harvest moon rises</p>"
+        } else {
+            "<p>The harvest is late</p>"
+        };
+        storage
+            .pages
+            .insert(&create_test_page(
+                &format!("https://example.com/{i:03}"),
+                content,
+            ))
+            .await?;
+    }
+    Arc::new(Indexer::from_storage(storage.clone(), 100).with_index_dir(&index_dir))
+        .run(1 << 20)
+        .await?;
+
+    let segment = Segment::open(&index_dir.join(SEGMENT_FILE))?;
+    assert!(segment.blocks("harvest")?.unwrap().len() > 1);
+    let terms = storage.index.term_document_frequencies().await?;
+    let from_segment = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default())
+        .with_term_dictionary(TermDictionary::build(terms.clone())?)
+        .with_segment(segment);
+    let from_store = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default())
+        .with_term_dictionary(TermDictionary::build(terms)?);
+
+    let expected: Vec<String> = (0..400)
+        .filter(|i| i % 97 == 0)
+        .map(|i| format!("https://example.com/{i:03}"))
+        .collect();
+    for query in ["harvest moon", "\"harvest moon\"", "harv* moon"] {
+        assert_eq!(urls_for(&storage, &from_segment, query).await?, expected);
+        assert_eq!(urls_for(&storage, &from_store, query).await?, expected);
+    }
+
+    std::fs::remove_dir_all(&index_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_update_and_compact() -> Result<()> {
+    let index_dir =
+        std::env::temp_dir().join(format!("harvest_storage_compact_{}", std::process::id()));
+    let storage = Storage::in_memory();
+    for (url, content) in [
+        ("https://example.com/moon", "<p>The harvest moon rises</p>"),
+        (
+            "https://example.com/sun",
+            "<p>The sun sets over the harvest</p>",
+        ),
+        ("https://example.com/fields", "<p>Harvest fields</p>"),
+    ] {
+        storage
+            .pages
+            .insert(&create_test_page(url, content))
+            .await?;
+    }
+    let indexer =
+        || Arc::new(Indexer::from_storage(storage.clone(), 10).with_index_dir(&index_dir));
+    indexer().run(1024).await?;
+    let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+
+    // deleted pages stop matching right away
+    let deleted = indexer()
+        .delete_pages(&[
+            "https://example.com/sun".to_string(),
+            "https://example.com/missing".to_string(),
+        ])
+        .await?;
+    assert_eq!(deleted, 1);
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/fields", "https://example.com/moon"]
+    );
+
+    // a changed page is reindexed under a new doc id, unchanged ones are left alone
+    storage
+        .pages
+        .upsert(&create_test_page(
+            "https://example.com/moon",
+            "<p>The blue moon rises</p>",
+        ))
+        .await?;
+    storage
+        .pages
+        .upsert(&create_test_page(
+            "https://example.com/fields",
+            "<p>Harvest fields</p>",
+        ))
+        .await?;
+    indexer().run(1024).await?;
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/fields"]
+    );
+    assert_eq!(
+        urls_for(&storage, &query_engine, "moon").await?,
+        vec!["https://example.com/moon"]
+    );
+    assert_eq!(storage.doc_ids.deleted().await?.len(), 2);
+
+    // compaction writes a generation without the tombstoned doc ids and rewrites the segment,
+    // the tombstones are kept while the generation before it is
+    assert_eq!(indexer().compact().await?, 2);
+    assert_eq!(storage.doc_ids.deleted().await?.len(), 2);
+    let live: Vec<u32> = storage
+        .doc_ids
+        .page_ids()
+        .await?
+        .into_iter()
+        .map(|(doc_id, _)| doc_id)
+        .collect();
+    for doc in storage
+        .index
+        .find_by_terms(&["harvest".to_string(), "moon".to_string()])
+        .await?
+    {
+        assert!(doc.postings.iter().all(|doc_id| live.contains(doc_id)));
+        assert_eq!(doc.positions.len(), doc.postings.len());
+    }
+    let frequencies: HashMap<String, u64> = storage
+        .index
+        .term_document_frequencies()
+        .await?
+        .into_iter()
+        .collect();
+    assert_eq!((frequencies["harvest"], frequencies["moon"]), (1, 1));
+    assert!(!frequencies.contains_key("sun"));
+
+    let segment = Segment::open(&index_dir.join(SEGMENT_FILE))?;
+    assert_eq!(segment.document_frequency("harvest")?, Some(1));
+    assert_eq!(segment.document_frequency("sun")?, None);
+    let from_segment =
+        QueryEngine::from_storage(storage.clone(), TextAnalyzer::default()).with_segment(segment);
+    assert_eq!(
+        urls_for(&storage, &from_segment, "moon").await?,
+        vec!["https://example.com/moon"]
+    );
+
+    // the next run drops the generations before the compaction, and the tombstones with them
+    storage
+        .pages
+        .insert(&create_test_page("https://example.com/rain", "<p>Rain</p>"))
+        .await?;
+    indexer().run(1024).await?;
+    assert!(storage.doc_ids.deleted().await?.is_empty());
+
+    std::fs::remove_dir_all(&index_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_optimize_fragmented_index() -> Result<()> {
+    let index_dir =
+        std::env::temp_dir().join(format!("harvest_storage_optimize_{}", std::process::id()));
+    let storage = Storage::in_memory();
+    let mut page_ids = Vec::new();
+    for i in 0..4 {
+        let page = create_test_page(
+            &format!("https://example.com/{i}"),
+            "<p>The harvest moon</p>",
+        );
+        page_ids.push(storage.pages.insert(&page).await?);
+    }
+    assert_eq!(storage.doc_ids.assign(&page_ids).await?, vec![0, 1, 2, 3]);
+
+    // buckets left behind by incremental runs, partially filled and out of order
+    for (term, bucket, postings) in [
+        ("harvest", 0, vec![3, 1]),
+        ("harvest", 1, vec![0]),
+        ("harvest", 2, vec![2, 1]),
+        ("moon", 0, vec![2]),
+    ] {
+        let positions: HashMap<u32, Vec<usize>> =
+            postings.iter().map(|&doc_id| (doc_id, vec![1])).collect();
+        storage
+            .index
+            .insert(InvertedIndexDoc::new(
+                term.to_string(),
+                bucket,
+                postings.len() as u64,
+                postings,
+                positions,
+            ))
+            .await?;
+    }
+    storage.doc_ids.delete_pages(&page_ids[2..3]).await?;
+
+    let indexer = Indexer::from_storage(storage.clone(), 10).with_index_dir(&index_dir);
+    assert_eq!(indexer.optimize().await?, 1);
+    assert_eq!(storage.doc_ids.deleted().await?, vec![2]);
+
+    let docs = storage
+        .index
+        .find_by_terms(&["harvest".to_string(), "moon".to_string()])
+        .await?;
+    assert_eq!(docs.len(), 1);
+    assert_eq!((docs[0].term.as_str(), docs[0].bucket), ("harvest", 0));
+    assert_eq!(docs[0].postings, vec![0, 1, 3]);
+    assert_eq!(docs[0].document_frequency, 3);
+    assert_eq!(docs[0].positions[&1], vec![1]);
+    assert!(!docs[0].positions.contains_key(&2));
+
+    let segment = Segment::open(&index_dir.join(SEGMENT_FILE))?;
+    assert_eq!(segment.document_frequency("harvest")?, Some(3));
+    assert_eq!(segment.document_frequency("moon")?, None);
+    let query_engine =
+        QueryEngine::from_storage(storage.clone(), TextAnalyzer::default()).with_segment(segment);
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec![
+            "https://example.com/0",
+            "https://example.com/1",
+            "https://example.com/3"
+        ]
+    );
+
+    std::fs::remove_dir_all(&index_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_generations_and_rollback() -> Result<()> {
+    let index_dir =
+        std::env::temp_dir().join(format!("harvest_storage_rollback_{}", std::process::id()));
+    let storage = Storage::in_memory();
+    let indexer =
+        || Arc::new(Indexer::from_storage(storage.clone(), 10).with_index_dir(&index_dir));
+    let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+
+    for (url, content) in [
+        ("https://example.com/moon", "<p>The harvest moon</p>"),
+        ("https://example.com/fields", "<p>Harvest fields</p>"),
+    ] {
+        storage
+            .pages
+            .insert(&create_test_page(url, content))
+            .await?;
+        indexer().run(1024).await?;
+    }
+    let manifest = storage.index.manifest().await?;
+    assert_eq!((manifest.current, manifest.previous), (2, Some(1)));
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/fields", "https://example.com/moon"]
+    );
+    assert_eq!(delta_segments(&index_dir)?, vec![2]);
+
+    // the rollback rewrites the base segment and drops the delta segment of generation 2
+    assert_eq!(indexer().rollback().await?, 1);
+    assert!(delta_segments(&index_dir)?.is_empty());
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/moon"]
+    );
+    let segment = Segment::open(&index_dir.join(SEGMENT_FILE))?;
+    assert_eq!(segment.document_frequency("harvest")?, Some(1));
+    assert_eq!(segment.document_frequency("field")?, None);
+    assert!(indexer().rollback().await.is_err());
+    // the page indexed by the rolled back run is indexed again by the next one
+    let (unindexed, _) = storage.pages.list_unindexed_paginated(10, None).await?;
+    let unindexed: Vec<&str> = unindexed.iter().map(|page| page.url.as_str()).collect();
+    assert_eq!(unindexed, vec!["https://example.com/fields"]);
+
+    // rolling back past an optimize keeps filtering the documents it purged
+    storage
+        .pages
+        .insert(&create_test_page(
+            "https://example.com/sun",
+            "<p>Harvest sun</p>",
+        ))
+        .await?;
+    indexer().run(1024).await?;
+    indexer()
+        .delete_pages(&["https://example.com/moon".to_string()])
+        .await?;
+    assert_eq!(indexer().optimize().await?, 3);
+    // the moon page's doc id and the one the fields page had in the rolled back generation
+    assert_eq!(storage.doc_ids.deleted().await?.len(), 2);
+    assert_eq!(indexer().rollback().await?, 2);
+    assert_eq!(storage.doc_ids.deleted().await?.len(), 2);
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/fields", "https://example.com/sun"]
+    );
+
+    std::fs::remove_dir_all(&index_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rollback_reindexes_pages_changed_since() -> Result<()> {
+    let storage = Storage::in_memory();
+    insert_harvest_pages(&storage).await?;
+    let indexer = || Arc::new(Indexer::from_storage(storage.clone(), 10));
+    indexer().run(1 << 20).await?;
+    let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+
+    storage
+        .pages
+        .upsert(&create_test_page(
+            "https://example.com/moon",
+            "<p>blue moon</p>",
+        ))
+        .await?;
+    indexer().run(1 << 20).await?;
+    assert_eq!(
+        urls_for(&storage, &query_engine, "blue").await?,
+        vec!["https://example.com/moon"]
+    );
+
+    // the page gets its old doc id back, with the postings of its old content
+    indexer().rollback().await?;
+    let (unindexed, _) = storage.pages.list_unindexed_paginated(10, None).await?;
+    let unindexed: Vec<&str> = unindexed.iter().map(|page| page.url.as_str()).collect();
+    assert_eq!(unindexed, vec!["https://example.com/moon"]);
+
+    indexer().run(1 << 20).await?;
+    assert_eq!(
+        urls_for(&storage, &query_engine, "blue").await?,
+        vec!["https://example.com/moon"]
+    );
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/sun"]
+    );
+    assert!(
+        IndexVerifier::from_storage(storage.clone())
+            .verify()
+            .await?
+            .is_clean()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_parallel_tokenization_is_deterministic() -> Result<()> {
+    let mut indexes = Vec::new();
+    for parallelism in [1, 8] {
+        let storage = Storage::in_memory();
+        for i in 0..50 {
+            let content = format!(
+                "<p>harvest {} moon</p><div>{}</div>",
+                "word ".repeat(i % 7),
+                (0..i)
+                    .map(|n| format!("term{} ", n % 5))
+                    .collect::<String>()
+            );
+            storage
+                .pages
+                .insert(&create_test_page(
+                    &format!("https://example.com/{i}"),
+                    &content,
+                ))
+                .await?;
+        }
+        Arc::new(Indexer::from_storage(storage.clone(), 16).with_tokenize_parallelism(parallelism))
+            .run(2048)
+            .await?;
+
+        let terms: Vec<String> = storage
+            .index
+            .term_document_frequencies()
+            .await?
+            .into_iter()
+            .map(|(term, _)| term)
+            .collect();
+        let mut postings = Vec::new();
+        for doc in storage.index.find_by_terms(&terms).await? {
+            let mut positions: Vec<(u32, Vec<usize>)> = doc.positions.into_iter().collect();
+            positions.sort();
+            postings.push((doc.term, doc.postings, positions));
+        }
+        postings.sort();
+        indexes.push(postings);
+    }
+    assert!(!indexes[0].is_empty());
+    assert_eq!(indexes[0], indexes[1]);
+    Ok(())
+}
+
+/// Writes `num_blocks` blocks sharing most of their 20 terms, block `i` holds the postings of doc `i`.
+async fn write_overlapping_blocks(storage: &Storage, num_blocks: u32) -> Result<Vec<String>> {
+    let mut blocks = Vec::new();
+    for i in 0..num_blocks {
+        let block = format!("spimi_block_{i:03}");
+        for j in (0..20).filter(|j| (i + j) % 3 != 0) {
+            let doc = SpimiDoc::new(
+                format!("term{j:02}"),
+                0,
+                1,
+                vec![i],
+                HashMap::from([(i, vec![j as usize])]),
+            );
+            storage.blocks.append_to_block(&block, doc).await?;
+        }
+        storage.blocks.seal_block(&block).await?;
+        blocks.push(block);
+    }
+    Ok(blocks)
+}
+
+async fn index_contents(storage: &Storage) -> Result<Vec<(String, Vec<u32>)>> {
+    let terms: Vec<String> = (0..20).map(|j| format!("term{j:02}")).collect();
+    let mut contents: Vec<(String, Vec<u32>)> = storage
+        .index
+        .find_by_terms(&terms)
+        .await?
+        .into_iter()
+        .map(|doc| (doc.term, doc.postings))
+        .collect();
+    contents.sort();
+    Ok(contents)
+}
+
+#[tokio::test]
+async fn test_multi_level_parallel_merge_matches_serial_merge() -> Result<()> {
+    let serial = Storage::in_memory();
+    write_overlapping_blocks(&serial, 40).await?;
+    Indexer::from_storage(serial.clone(), 10)
+        .with_merge_parallelism(1)
+        .merge_persisted_blocks()
+        .await?;
+
+    let parallel = Storage::in_memory();
+    write_overlapping_blocks(&parallel, 40).await?;
+    // 40 blocks merged 3 at a time take three intermediate levels
+    Indexer::from_storage(parallel.clone(), 10)
+        .with_merge_fan_in(3)
+        .with_merge_parallelism(4)
+        .merge_persisted_blocks()
+        .await?;
+
+    let expected = index_contents(&serial).await?;
+    assert_eq!(expected.len(), 20);
+    assert_eq!(index_contents(&parallel).await?, expected);
+    assert_eq!(
+        parallel.index.term_document_frequencies().await?,
+        serial.index.term_document_frequencies().await?
+    );
+    assert!(parallel.blocks.list_blocks().await?.is_empty());
+    assert!(parallel.checkpoints.get_incomplete().await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_parallel_merge_resumes_term_ranges() -> Result<()> {
+    let storage = Storage::in_memory();
+    let blocks = write_overlapping_blocks(&storage, 6).await?;
+
+    // a merge of two term ranges that crashed after the second range flushed "term12"
+    for block in &blocks {
+        storage
+            .checkpoints
+            .get_or_insert(MergeCheckpoint::with_range(
+                format!("{block}#0"),
+                None,
+                Some("term10".to_string()),
+            ))
+            .await?;
+        let second = format!("{block}#1");
+        storage
+            .checkpoints
+            .get_or_insert(MergeCheckpoint::with_range(
+                second.clone(),
+                Some("term10".to_string()),
+                None,
+            ))
+            .await?;
+        storage
+            .checkpoints
+            .update_progress(&second, "term12", 0)
+            .await?;
+    }
+
+    Indexer::from_storage(storage.clone(), 10)
+        .with_merge_fan_in(2)
+        .with_merge_parallelism(8)
+        .merge_persisted_blocks()
+        .await?;
+
+    // the ranges are kept, only the terms the crashed merge got to are left out
+    let terms: Vec<String> = index_contents(&storage)
+        .await?
+        .into_iter()
+        .map(|(term, _)| term)
+        .collect();
+    let expected: Vec<String> = (0..20)
+        .filter(|j| !(10..=12).contains(j))
+        .map(|j| format!("term{j:02}"))
+        .collect();
+    assert_eq!(terms, expected);
+    assert!(storage.blocks.list_blocks().await?.is_empty());
+    Ok(())
+}
+
+/// Inserts a page about the harvest moon and one about the harvest sun, returns their ids.
+async fn insert_harvest_pages(storage: &Storage) -> Result<Vec<ObjectId>> {
+    let mut page_ids = Vec::new();
+    for (url, content) in [
+        ("https://example.com/moon", "<p>harvest moon</p>"),
+        ("https://example.com/sun", "<p>harvest sun</p>"),
+    ] {
+        page_ids.push(
+            storage
+                .pages
+                .insert(&create_test_page(url, content))
+                .await?,
+        );
+    }
+    Ok(page_ids)
+}
+
+fn first_positions(doc_ids: &[u32]) -> HashMap<u32, Vec<usize>> {
+    doc_ids.iter().map(|&doc_id| (doc_id, vec![0])).collect()
+}
+
+#[tokio::test]
+async fn test_run_interrupted_before_merging_is_tokenized_again() -> Result<()> {
+    let storage = Storage::in_memory();
+    let page_ids = insert_harvest_pages(&storage).await?;
+
+    // a run that tokenized the pages into a block and crashed before merging it
+    let crashed_run = ObjectId::new();
+    storage
+        .pages
+        .mark_many_as_tokenized(&page_ids, crashed_run)
+        .await?;
+    let doc_ids = storage.doc_ids.assign(&page_ids).await?;
+    let doc = SpimiDoc::new(
+        "harvest".to_string(),
+        0,
+        2,
+        doc_ids.clone(),
+        first_positions(&doc_ids),
+    );
+    storage
+        .blocks
+        .append_to_block("spimi_block_crashed", doc)
+        .await?;
+    storage.blocks.seal_block("spimi_block_crashed").await?;
+
+    Arc::new(Indexer::from_storage(storage.clone(), 10))
+        .run(1 << 20)
+        .await?;
+
+    let (unindexed, _) = storage.pages.list_unindexed_paginated(10, None).await?;
+    assert!(unindexed.is_empty());
+    // the block of the crashed run is dropped, the pages are in the index once
+    let harvest = storage
+        .index
+        .find_by_terms(&["harvest".to_string()])
+        .await?;
+    assert_eq!(harvest.len(), 1);
+    assert_eq!(harvest[0].postings.len(), 2);
+    assert!(harvest[0].postings.iter().all(|id| !doc_ids.contains(id)));
+
+    let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/moon", "https://example.com/sun"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_interrupted_while_merging_is_completed() -> Result<()> {
+    let storage = Storage::in_memory();
+    let page_ids = insert_harvest_pages(&storage).await?;
+
+    // a run that merged "harvest" of its block and crashed before committing
+    let crashed_run = ObjectId::new();
+    storage
+        .pages
+        .mark_many_as_tokenized(&page_ids, crashed_run)
+        .await?;
+    let doc_ids = storage.doc_ids.assign(&page_ids).await?;
+    for (term, postings) in [("harvest", doc_ids.clone()), ("moon", vec![doc_ids[0]])] {
+        let doc = SpimiDoc::new(
+            term.to_string(),
+            0,
+            postings.len() as u64,
+            postings.clone(),
+            first_positions(&postings),
+        );
+        storage
+            .blocks
+            .append_to_block("spimi_block_crashed", doc)
+            .await?;
+    }
+    storage.blocks.seal_block("spimi_block_crashed").await?;
+    let building = storage
+        .index
+        .begin_generation(true, DEFAULT_ANALYZER)
+        .await?;
+    building
+        .insert(InvertedIndexDoc::new(
+            "harvest".to_string(),
+            0,
+            2,
+            doc_ids.clone(),
+            first_positions(&doc_ids),
+        ))
+        .await?;
+    storage
+        .checkpoints
+        .get_or_insert(MergeCheckpoint {
+            index_run: Some(crashed_run),
+            ..MergeCheckpoint::new("spimi_block_crashed".to_string())
+        })
+        .await?;
+    storage
+        .checkpoints
+        .update_progress("spimi_block_crashed", "harvest", 0)
+        .await?;
+
+    // the pages stay unindexed until the merge commits
+    let (unindexed, _) = storage.pages.list_unindexed_paginated(10, None).await?;
+    assert_eq!(unindexed.len(), 2);
+
+    Arc::new(Indexer::from_storage(storage.clone(), 10))
+        .run(1 << 20)
+        .await?;
+
+    // the merge is completed instead of tokenizing the pages again
+    let (unindexed, _) = storage.pages.list_unindexed_paginated(10, None).await?;
+    assert!(unindexed.is_empty());
+    assert!(storage.doc_ids.deleted().await?.is_empty());
+    assert_eq!(
+        storage.index.term_document_frequencies().await?,
+        vec![("harvest".to_string(), 2), ("moon".to_string(), 1)]
+    );
+    assert!(storage.checkpoints.get_incomplete().await?.is_empty());
+    Ok(())
+}
+
+/// Checkpoints whose progress updates fail while `fail` is set, like a crash right after a merge
+/// wrote its postings.
+struct FailingProgress {
+    inner: Arc<dyn CheckpointStore>,
+    fail: AtomicBool,
+}
+
+#[async_trait]
+impl CheckpointStore for FailingProgress {
+    async fn get_or_insert(&self, checkpoint: MergeCheckpoint) -> Result<MergeCheckpoint> {
+        self.inner.get_or_insert(checkpoint).await
+    }
+
+    async fn update_progress(&self, collection_name: &str, term: &str, bucket: i16) -> Result<()> {
+        anyhow::ensure!(
+            !self.fail.load(Ordering::SeqCst),
+            "crashed before the checkpoint"
+        );
+        self.inner
+            .update_progress(collection_name, term, bucket)
+            .await
+    }
+
+    async fn mark_completed(&self, collection_name: &str) -> Result<()> {
+        self.inner.mark_completed(collection_name).await
+    }
+
+    async fn get_incomplete(&self) -> Result<Vec<MergeCheckpoint>> {
+        self.inner.get_incomplete().await
+    }
+
+    async fn delete_completed(&self) -> Result<u64> {
+        self.inner.delete_completed().await
+    }
+}
+
+#[tokio::test]
+async fn test_merge_crashed_between_write_and_checkpoint_writes_nothing_twice() -> Result<()> {
+    let checkpoints = Arc::new(FailingProgress {
+        inner: Storage::in_memory().checkpoints,
+        fail: AtomicBool::new(false),
+    });
+    let storage = Storage {
+        checkpoints: checkpoints.clone(),
+        ..Storage::in_memory()
+    };
+    storage
+        .pages
+        .insert(&create_test_page(
+            "https://example.com/field",
+            "<p>harvest field</p>",
+        ))
+        .await?;
+    let indexer = || Arc::new(Indexer::from_storage(storage.clone(), 10));
+    indexer().run(1 << 20).await?;
+
+    // the merge appends to the "harvest" bucket and inserts "moon" and "sun", then crashes
+    insert_harvest_pages(&storage).await?;
+    checkpoints.fail.store(true, Ordering::SeqCst);
+    let err = indexer().run(1 << 20).await.unwrap_err();
+    assert!(format!("{err:#}").contains("crashed"), "{err:#}");
+    assert_eq!(storage.checkpoints.get_incomplete().await?.len(), 1);
+
+    checkpoints.fail.store(false, Ordering::SeqCst);
+    indexer().run(1 << 20).await?;
+    assert_eq!(
+        storage.index.term_document_frequencies().await?,
+        vec![
+            ("field".to_string(), 1),
+            ("harvest".to_string(), 3),
+            ("moon".to_string(), 1),
+            ("sun".to_string(), 1),
+        ]
+    );
+    assert!(
+        IndexVerifier::from_storage(storage.clone())
+            .verify()
+            .await?
+            .is_clean()
+    );
+    let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+    assert_eq!(query_engine.query("harvest").await?.len(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_changed_page_stays_searchable_until_its_run_commits() -> Result<()> {
+    let checkpoints = Arc::new(FailingProgress {
+        inner: Storage::in_memory().checkpoints,
+        fail: AtomicBool::new(false),
+    });
+    let storage = Storage {
+        checkpoints: checkpoints.clone(),
+        ..Storage::in_memory()
+    };
+    insert_harvest_pages(&storage).await?;
+    let indexer = || Arc::new(Indexer::from_storage(storage.clone(), 10));
+    indexer().run(1 << 20).await?;
+    let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+
+    // the run reindexing the changed page crashes while merging
+    storage
+        .pages
+        .upsert(&create_test_page(
+            "https://example.com/moon",
+            "<p>blue moon</p>",
+        ))
+        .await?;
+    checkpoints.fail.store(true, Ordering::SeqCst);
+    assert!(indexer().run(1 << 20).await.is_err());
+    assert!(storage.doc_ids.deleted().await?.is_empty());
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/moon", "https://example.com/sun"]
+    );
+    assert!(urls_for(&storage, &query_engine, "blue").await?.is_empty());
+
+    // once the resumed run commits, the page matches its new content only
+    checkpoints.fail.store(false, Ordering::SeqCst);
+    indexer().run(1 << 20).await?;
+    assert_eq!(storage.doc_ids.deleted().await?.len(), 1);
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/sun"]
+    );
+    assert_eq!(
+        urls_for(&storage, &query_engine, "blue").await?,
+        vec!["https://example.com/moon"]
+    );
+    assert!(
+        IndexVerifier::from_storage(storage.clone())
+            .verify()
+            .await?
+            .is_clean()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_verify_and_repair_corrupt_index() -> Result<()> {
+    let storage = Storage::in_memory();
+    insert_harvest_pages(&storage).await?;
+    let indexer = Arc::new(Indexer::from_storage(storage.clone(), 10));
+    indexer.clone().run(1 << 20).await?;
+    let verifier = IndexVerifier::from_storage(storage.clone());
+    assert!(verifier.verify().await?.is_clean());
+
+    // an append gone wrong: postings out of order, a drifted document frequency and a doc id
+    // of no page
+    let moon = storage.index.get_last_bucket("moon").await?.unwrap();
+    storage
+        .index
+        .append_to_bucket(moon.id, &[99, 0], &first_positions(&[99]))
+        .await?;
+    // and the blocks of a run interrupted before merging
+    storage
+        .blocks
+        .append_to_block(
+            "spimi_block_left",
+            SpimiDoc::new("sun".to_string(), 0, 1, vec![1], first_positions(&[1])),
+        )
+        .await?;
+    storage.blocks.seal_block("spimi_block_left").await?;
+
+    let report = verifier.verify().await?;
+    assert!(report.has_bucket_issues());
+    assert!(report.has_run_leftovers());
+    assert_eq!(
+        report.dangling_doc_ids.into_iter().collect::<Vec<_>>(),
+        vec![99]
+    );
+
+    indexer.repair(&verifier.verify().await?).await?;
+    let report = verifier.verify().await?;
+    assert!(report.is_clean(), "{report}");
+    let moon = storage.index.find_by_terms(&["moon".to_string()]).await?;
+    assert_eq!(moon[0].postings, vec![0]);
+    assert_eq!(moon[0].document_frequency, 1);
+    Ok(())
+}
+
+async fn all_buckets(storage: &Storage) -> Result<Vec<(String, i16, Vec<u32>)>> {
+    let terms: Vec<String> = storage
+        .index
+        .term_document_frequencies()
+        .await?
+        .into_iter()
+        .map(|(term, _)| term)
+        .collect();
+    let mut buckets: Vec<_> = storage
+        .index
+        .find_by_terms(&terms)
+        .await?
+        .into_iter()
+        .map(|doc| (doc.term, doc.bucket, doc.postings))
+        .collect();
+    buckets.sort();
+    Ok(buckets)
+}
+
+#[tokio::test]
+async fn test_export_and_import_index() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("harvest_storage_archive_{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let archive = dir.join("index.hvst");
+
+    let source = Storage::in_memory();
+    insert_harvest_pages(&source).await?;
+    let source_indexer = Arc::new(Indexer::from_storage(source.clone(), 10));
+    source_indexer.clone().run(1 << 20).await?;
+    source_indexer
+        .delete_pages(&["https://example.com/sun".to_string()])
+        .await?;
+    let exported = IndexArchive::from_storage(source.clone())
+        .export(&archive)
+        .await?;
+    assert_eq!(exported.counts.doc_ids, 1);
+    assert_eq!(exported.counts.deleted, 1);
+    assert_eq!(exported.counts.pages, 1);
+
+    // the target has an index of its own, with a page the archive doesn't have
+    let target = Storage::in_memory();
+    target
+        .pages
+        .insert(&create_test_page(
+            "https://example.com/rain",
+            "<p>harvest rain</p>",
+        ))
+        .await?;
+    let target_dir = dir.join("target");
+    let target_indexer =
+        Arc::new(Indexer::from_storage(target.clone(), 10).with_index_dir(&target_dir));
+    target_indexer.clone().run(1 << 20).await?;
+
+    let imported = target_indexer.import(&archive).await?;
+    assert_eq!(imported.counts, exported.counts);
+    assert_eq!(all_buckets(&target).await?, all_buckets(&source).await?);
+    assert_eq!(
+        target.doc_ids.page_ids().await?,
+        source.doc_ids.page_ids().await?
+    );
+    assert_eq!(target.doc_ids.deleted().await?, vec![1]);
+    // the replaced generation had postings under doc ids the archive gives other pages
+    assert_eq!(target.index.manifest().await?.previous, None);
+    assert!(target_indexer.rollback().await.is_err());
+    assert!(
+        IndexVerifier::from_storage(target.clone())
+            .verify()
+            .await?
+            .is_clean()
+    );
+
+    let query_engine = QueryEngine::from_storage(target.clone(), TextAnalyzer::default())
+        .with_segment(Segment::open(&target_dir.join(SEGMENT_FILE))?);
+    assert_eq!(
+        urls_for(&target, &query_engine, "harvest").await?,
+        vec!["https://example.com/moon"]
+    );
+
+    // the page the archive doesn't have is indexed again, with a doc id after the restored ones
+    let (unindexed, _) = target.pages.list_unindexed_paginated(10, None).await?;
+    assert_eq!(unindexed.len(), 1);
+    assert_eq!(unindexed[0].url, "https://example.com/rain");
+    target_indexer.clone().run(1 << 20).await?;
+    let query_engine = QueryEngine::from_storage(target.clone(), TextAnalyzer::default());
+    assert_eq!(
+        urls_for(&target, &query_engine, "harvest").await?,
+        vec!["https://example.com/moon", "https://example.com/rain"]
+    );
+    assert_eq!(target.doc_ids.page_ids().await?[1].0, 2);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rebuild_with_another_analyzer() -> Result<()> {
+    let index_dir =
+        std::env::temp_dir().join(format!("harvest_storage_rebuild_{}", std::process::id()));
+    let storage = Storage::in_memory();
+    for (url, content) in [
+        ("https://example.com/moon", "<p>harvesting moons</p>"),
+        ("https://example.com/sun", "<p>the harvest sun</p>"),
+    ] {
+        storage
+            .pages
+            .insert(&create_test_page(url, content))
+            .await?;
+    }
+    Arc::new(Indexer::from_storage(storage.clone(), 10).with_index_dir(&index_dir))
+        .run(1 << 20)
+        .await?;
+    let stemmed = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+    assert_eq!(
+        urls_for(&storage, &stemmed, "harvest").await?,
+        vec!["https://example.com/moon", "https://example.com/sun"]
+    );
+
+    // runs keep the analyzer of the index
+    let simple = || {
+        Arc::new(
+            Indexer::from_storage(storage.clone(), 10)
+                .with_index_dir(&index_dir)
+                .with_analyzer(TextAnalyzer::named("simple").unwrap()),
+        )
+    };
+    let err = simple().run(1 << 20).await.unwrap_err();
+    assert!(err.to_string().contains("rebuild"), "{err}");
+
+    simple().rebuild(1 << 20).await?;
+    let manifest = storage.index.manifest().await?;
+    assert_eq!(manifest.analyzer(), "simple");
+    assert_eq!(
+        manifest.previous_analyzer,
+        Some(DEFAULT_ANALYZER.to_string())
+    );
+    assert!(
+        IndexVerifier::from_storage(storage.clone())
+            .verify()
+            .await?
+            .is_clean()
+    );
+
+    let err = stemmed.query("harvest").await.unwrap_err();
+    assert!(err.to_string().contains("'simple'"), "{err}");
+    let unstemmed = QueryEngine::from_storage(storage.clone(), TextAnalyzer::named("simple")?)
+        .with_segment(Segment::open(&index_dir.join(SEGMENT_FILE))?);
+    assert_eq!(
+        urls_for(&storage, &unstemmed, "harvest").await?,
+        vec!["https://example.com/sun"]
+    );
+    assert_eq!(
+        urls_for(&storage, &unstemmed, "\"the harvest\"").await?,
+        vec!["https://example.com/sun"]
+    );
+    assert_eq!(
+        urls_for(&storage, &unstemmed, "harvesting").await?,
+        vec!["https://example.com/moon"]
+    );
+    assert!(
+        Arc::new(Indexer::from_storage(storage.clone(), 10))
+            .run(1 << 20)
+            .await
+            .is_err()
+    );
+
+    // rolling back brings the previous analyzer back
+    Indexer::from_storage(storage.clone(), 10)
+        .rollback()
+        .await?;
+    assert_eq!(storage.index.manifest().await?.analyzer(), DEFAULT_ANALYZER);
+
+    std::fs::remove_dir_all(&index_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rollback_of_a_rebuild_restores_the_old_doc_ids() -> Result<()> {
+    let storage = Storage::in_memory();
+    insert_harvest_pages(&storage).await?;
+    Arc::new(Indexer::from_storage(storage.clone(), 10))
+        .run(1 << 20)
+        .await?;
+    let query_engine = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+    let old_doc_ids = storage.doc_ids.page_ids().await?;
+
+    Arc::new(
+        Indexer::from_storage(storage.clone(), 10)
+            .with_analyzer(TextAnalyzer::named("simple").unwrap()),
+    )
+    .rebuild(1 << 20)
+    .await?;
+    assert_eq!(storage.doc_ids.deleted().await?.len(), 2);
+
+    // the pages get back the doc ids the restored generation has their postings under
+    Indexer::from_storage(storage.clone(), 10)
+        .rollback()
+        .await?;
+    assert_eq!(storage.doc_ids.page_ids().await?, old_doc_ids);
+    assert!(storage.doc_ids.deleted().await?.is_empty());
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/moon", "https://example.com/sun"]
+    );
+    assert!(
+        IndexVerifier::from_storage(storage.clone())
+            .verify()
+            .await?
+            .is_clean()
+    );
+
+    // their content may have changed since, the next run indexes it again
+    let (unindexed, _) = storage.pages.list_unindexed_paginated(10, None).await?;
+    assert_eq!(unindexed.len(), 2);
+    Arc::new(Indexer::from_storage(storage.clone(), 10))
+        .run(1 << 20)
+        .await?;
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/moon", "https://example.com/sun"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_queries_read_the_old_generation_until_a_rebuild_commits() -> Result<()> {
+    let checkpoints = Arc::new(FailingProgress {
+        inner: Storage::in_memory().checkpoints,
+        fail: AtomicBool::new(false),
+    });
+    let storage = Storage {
+        checkpoints: checkpoints.clone(),
+        ..Storage::in_memory()
+    };
+    insert_harvest_pages(&storage).await?;
+    Arc::new(Indexer::from_storage(storage.clone(), 10))
+        .run(1 << 20)
+        .await?;
+    let stemmed = QueryEngine::from_storage(storage.clone(), TextAnalyzer::default());
+    let simple = || {
+        Arc::new(
+            Indexer::from_storage(storage.clone(), 10)
+                .with_analyzer(TextAnalyzer::named("simple").unwrap()),
+        )
+    };
+
+    // the rebuild crashes while merging, every page already has its new doc id
+    checkpoints.fail.store(true, Ordering::SeqCst);
+    assert!(simple().rebuild(1 << 20).await.is_err());
+    assert!(storage.index.manifest().await?.building.is_some());
+    assert!(storage.doc_ids.deleted().await?.is_empty());
+    assert_eq!(
+        urls_for(&storage, &stemmed, "harvest").await?,
+        vec!["https://example.com/moon", "https://example.com/sun"]
+    );
+
+    // the next run commits the rebuilt generation and tombstones the old doc ids
+    checkpoints.fail.store(false, Ordering::SeqCst);
+    simple().run(1 << 20).await?;
+    assert_eq!(storage.index.manifest().await?.analyzer(), "simple");
+    assert_eq!(storage.doc_ids.deleted().await?.len(), 2);
+    let unstemmed = QueryEngine::from_storage(storage.clone(), TextAnalyzer::named("simple")?);
+    assert_eq!(
+        urls_for(&storage, &unstemmed, "harvest").await?,
+        vec!["https://example.com/moon", "https://example.com/sun"]
+    );
+    assert!(
+        IndexVerifier::from_storage(storage.clone())
+            .verify()
+            .await?
+            .is_clean()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cjk_pages_searchable_with_bigram_analyzer() -> Result<()> {
+    let index_dir =
+        std::env::temp_dir().join(format!("harvest_storage_cjk_{}", std::process::id()));
+    let storage = Storage::in_memory();
+    for (url, content) in [
+        (
+            "https://example.com/engine",
+            "<p>我们的搜索引擎 Harvest</p>",
+        ),
+        ("https://example.com/motor", "<p>引擎很大</p>"),
+    ] {
+        storage
+            .pages
+            .insert(&create_test_page(url, content))
+            .await?;
+    }
+    let registry = AnalyzerRegistry::from_json(
+        r#"{"analyzers": {"cjk": {
+            "char_filters": [{"type": "html_strip"}],
+            "tokenizer": {"type": "cjk_bigram"},
+            "token_filters": [{"type": "lowercase"}]
+        }}}"#,
+        Default::default(),
+    )?;
+    Arc::new(
+        Indexer::from_storage(storage.clone(), 10)
+            .with_index_dir(&index_dir)
+            .with_analyzer(registry.build("cjk")?),
+    )
+    .rebuild(1 << 20)
+    .await?;
+    assert_eq!(storage.index.manifest().await?.analyzer(), "cjk");
+
+    let query_engine = QueryEngine::from_storage(storage.clone(), registry.build("cjk")?);
+    assert_eq!(
+        urls_for(&storage, &query_engine, "搜索引擎").await?,
+        vec!["https://example.com/engine"]
+    );
+    assert_eq!(
+        urls_for(&storage, &query_engine, "\"搜索引擎\"").await?,
+        vec!["https://example.com/engine"]
+    );
+    assert_eq!(
+        urls_for(&storage, &query_engine, "引擎").await?,
+        vec!["https://example.com/engine", "https://example.com/motor"]
+    );
+    assert_eq!(
+        urls_for(&storage, &query_engine, "harvest").await?,
+        vec!["https://example.com/engine"]
+    );
+
+    std::fs::remove_dir_all(&index_dir)?;
+    Ok(())
+}